@@ -0,0 +1,387 @@
+//! Two-phase responses: acknowledging requests before processing them.
+//!
+//! For expensive requests, knowing that the server received a request is
+//! useful long before the response arrives. A server started with
+//! `serve_with_acks` answers every request twice, with the same request ID:
+//! first with an `ACK <id>` frame, written as soon as the request is read,
+//! then with the actual response once the service is done.
+//!
+//! An `AckClient` consumes the acknowledgements: calls resolve with the final
+//! response, and `call_with_ack` takes a callback invoked when the
+//! acknowledgement arrives. Since a request is always acknowledged before it
+//! is responded to, a response that happens to look like an acknowledgement
+//! is still delivered to the caller.
+
+use {LineCodec, Validate};
+
+use futures::{future, Future, Stream, Sink, Poll, Async, StartSend, AsyncSink};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_proto::{TcpClient, TcpServer};
+use tokio_proto::multiplex::{RequestId, ServerProto, ClientProto, ClientService};
+use tokio_service::{Service, NewService};
+
+use std::io;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+/// Callback invoked when a request is acknowledged
+type OnAck = Box<FnMut()>;
+
+/// Callbacks of the requests not yet written, in the order they were called
+type Pending = Rc<RefCell<VecDeque<Option<OnAck>>>>;
+
+/// Client handle for servers started with `serve_with_acks`.
+pub struct AckClient {
+    inner: Validate<ClientService<TcpStream, AckProto>>,
+    pending: Pending,
+}
+
+/// Server transport acknowledging every request as soon as it is read.
+pub struct SendAcks<T> {
+    upstream: T,
+    // Requests read but not acknowledged yet
+    acks: VecDeque<RequestId>,
+}
+
+/// Client transport intercepting acknowledgements.
+pub struct ReceiveAcks<T> {
+    upstream: T,
+    pending: Pending,
+    // Requests awaiting their acknowledgement, along with their callback
+    unacked: HashMap<RequestId, Option<OnAck>>,
+}
+
+/// Client protocol definition
+struct AckProto {
+    pending: Pending,
+}
+
+/// Server protocol definition
+struct AckServerProto;
+
+/// Start a server acknowledging every request before processing it,
+/// listening for connections on `addr`.
+pub fn serve_with_acks<T>(addr: SocketAddr, new_service: T)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    let new_service = Validate { inner: new_service };
+
+    TcpServer::new(AckServerProto, addr)
+        .serve(new_service);
+}
+
+/// The acknowledgement frame payload for `request_id`
+fn ack(request_id: RequestId) -> String {
+    format!("ACK {}", request_id)
+}
+
+impl AckClient {
+    /// Establish a connection to a server started with `serve_with_acks`.
+    pub fn connect(addr: &SocketAddr, handle: &Handle) -> Box<Future<Item = AckClient, Error = io::Error>> {
+        let pending = Rc::new(RefCell::new(VecDeque::new()));
+        let proto = AckProto { pending: pending.clone() };
+
+        let ret = TcpClient::new(proto)
+            .connect(addr, handle)
+            .map(move |client_service| {
+                AckClient {
+                    inner: Validate { inner: client_service },
+                    pending: pending,
+                }
+            });
+
+        Box::new(ret)
+    }
+
+    /// Send a request, calling `on_ack` once the server acknowledged it.
+    ///
+    /// The returned future resolves with the final response.
+    pub fn call_with_ack<F>(&self, req: String, on_ack: F) -> Box<Future<Item = String, Error = io::Error>>
+        where F: FnOnce() + 'static,
+    {
+        let mut on_ack = Some(on_ack);
+
+        self.send(req, Some(Box::new(move || {
+            if let Some(on_ack) = on_ack.take() {
+                on_ack();
+            }
+        })))
+    }
+
+    fn send(&self, req: String, on_ack: Option<OnAck>) -> Box<Future<Item = String, Error = io::Error>> {
+        // Rejected requests are never written, so they must not queue a
+        // callback, otherwise callbacks would be matched with the wrong
+        // requests.
+        if req.contains('\n') {
+            let err = io::Error::new(io::ErrorKind::InvalidInput, "message contained new line");
+            return Box::new(future::err(err));
+        }
+
+        // Requests are written in the order they are called
+        self.pending.borrow_mut().push_back(on_ack);
+
+        self.inner.call(req)
+    }
+}
+
+impl Service for AckClient {
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        self.send(req, None)
+    }
+}
+
+impl<T> SendAcks<T> {
+    /// Wrap `upstream`
+    pub fn new(upstream: T) -> SendAcks<T> {
+        SendAcks {
+            upstream: upstream,
+            acks: VecDeque::new(),
+        }
+    }
+}
+
+impl<T> SendAcks<T>
+    where T: Sink<SinkItem = (RequestId, String), SinkError = io::Error>,
+{
+    /// Hand pending acknowledgements to the upstream. Returns true once they
+    /// are all buffered.
+    fn write_acks(&mut self) -> io::Result<bool> {
+        while let Some(request_id) = self.acks.pop_front() {
+            if let AsyncSink::NotReady(_) = try!(self.upstream.start_send((request_id, ack(request_id)))) {
+                self.acks.push_front(request_id);
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl<T> Stream for SendAcks<T>
+    where T: Stream<Item = (RequestId, String), Error = io::Error>,
+          T: Sink<SinkItem = (RequestId, String), SinkError = io::Error>,
+{
+    type Item = (RequestId, String);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        match try_ready!(self.upstream.poll()) {
+            Some((request_id, line)) => {
+                self.acks.push_back(request_id);
+
+                // Buffer the acknowledgement right away, it is flushed along
+                // with the next write.
+                try!(self.write_acks());
+
+                Ok(Async::Ready(Some((request_id, line))))
+            }
+            None => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+impl<T> Sink for SendAcks<T>
+    where T: Sink<SinkItem = (RequestId, String), SinkError = io::Error>,
+{
+    type SinkItem = (RequestId, String);
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, io::Error> {
+        // Acknowledgements go out before any response
+        if !try!(self.write_acks()) {
+            return Ok(AsyncSink::NotReady(item));
+        }
+
+        self.upstream.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        let done = try!(self.write_acks());
+
+        try_ready!(self.upstream.poll_complete());
+
+        if !done {
+            // Flushing freed up space, so try again
+            return self.poll_complete();
+        }
+
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<T> ReceiveAcks<T> {
+    /// Wrap `upstream`, taking the callback of each written request from
+    /// `pending`.
+    fn new(upstream: T, pending: Pending) -> ReceiveAcks<T> {
+        ReceiveAcks {
+            upstream: upstream,
+            pending: pending,
+            unacked: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Stream for ReceiveAcks<T>
+    where T: Stream<Item = (RequestId, String), Error = io::Error>,
+{
+    type Item = (RequestId, String);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        loop {
+            match try_ready!(self.upstream.poll()) {
+                Some((request_id, line)) => {
+                    match self.unacked.remove(&request_id) {
+                        Some(on_ack) => {
+                            if line == ack(request_id) {
+                                if let Some(mut on_ack) = on_ack {
+                                    on_ack();
+                                }
+
+                                // Not a response, keep reading
+                                continue;
+                            }
+
+                            // The server did not acknowledge this request
+                            return Ok(Async::Ready(Some((request_id, line))));
+                        }
+                        None => return Ok(Async::Ready(Some((request_id, line)))),
+                    }
+                }
+                None => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+}
+
+impl<T> Sink for ReceiveAcks<T>
+    where T: Sink<SinkItem = (RequestId, String), SinkError = io::Error>,
+{
+    type SinkItem = (RequestId, String);
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, io::Error> {
+        let request_id = item.0;
+        let res = try!(self.upstream.start_send(item));
+
+        if res.is_ready() {
+            let on_ack = self.pending.borrow_mut().pop_front().and_then(|on_ack| on_ack);
+            self.unacked.insert(request_id, on_ack);
+        }
+
+        Ok(res)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.upstream.poll_complete()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for AckProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = ReceiveAcks<Framed<T, LineCodec>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(ReceiveAcks::new(io.framed(LineCodec), self.pending.clone()))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for AckServerProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = SendAcks<Framed<T, LineCodec>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(SendAcks::new(io.framed(LineCodec)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AckClient, AckServerProto};
+
+    use futures::{future, Future, Stream};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+    use tokio_timer::Timer;
+
+    use std::io;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    /// Responds after 100ms
+    struct Slow {
+        timer: Timer,
+    }
+
+    impl Service for Slow {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = Box<Future<Item = String, Error = io::Error>>;
+
+        fn call(&self, req: String) -> Self::Future {
+            let resp = self.timer.sleep(Duration::from_millis(100))
+                .map(move |_| req)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+
+            Box::new(resp)
+        }
+    }
+
+    #[test]
+    fn ack_arrives_before_slow_response() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+
+        let server = listener.incoming().for_each(move |(socket, _)| {
+            AckServerProto.bind_server(&handle2, socket, Slow { timer: Timer::default() });
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let client = core.run(AckClient::connect(&addr, &handle)).unwrap();
+
+        let events = Rc::new(RefCell::new(vec![]));
+        let acked = events.clone();
+        let responded = events.clone();
+
+        let resp = client.call_with_ack("hello".to_string(), move || acked.borrow_mut().push("ack"))
+            .map(move |resp| {
+                responded.borrow_mut().push("response");
+                resp
+            });
+
+        assert_eq!(core.run(resp).unwrap(), "hello");
+        assert_eq!(*events.borrow(), vec!["ack", "response"]);
+
+        // Plain calls skip the acknowledgement as well
+        let resps = core.run(future::join_all(vec![client.call("a".to_string()), client.call("b".to_string())])).unwrap();
+        assert_eq!(resps, vec!["a", "b"]);
+    }
+}
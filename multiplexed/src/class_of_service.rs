@@ -0,0 +1,325 @@
+//! Weighted fair queuing by class of service.
+//!
+//! A request may be tagged with a class of service, such as `gold` or
+//! `bronze`, encoded as a `[cos=<class>] ` prefix on the line.
+//!
+//! The `WeightedFairQueue` middleware limits how many requests the inner
+//! service processes at once. When the service is busy, requests are queued
+//! per class, and each time a slot frees up, a class is picked so that under
+//! contention, classes are dispatched in proportion to their weights. With
+//! weights of 4 for `gold` and 1 for `bronze`, four `gold` requests are
+//! dispatched for every `bronze` one, as long as both have requests queued.
+//! Within a class, requests are dispatched in the order they were received.
+//!
+//! Requests without a class, or with a class that has no configured weight,
+//! share the default class, which has a weight of 1.
+
+use semaphore::{Semaphore, Permit, Queue};
+
+use futures::Future;
+use futures::sync::oneshot;
+use tokio_service::{Service, NewService};
+
+use std::{f64, io};
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+const PREFIX: &'static str = "[cos=";
+
+/// The weights of the classes of service
+#[derive(Debug, Clone, Default)]
+pub struct ClassWeights {
+    weights: HashMap<String, u32>,
+}
+
+/// A `Service` middleware dispatching queued requests by class of service.
+pub struct WeightedFairQueue<T> {
+    inner: Rc<T>,
+    semaphore: Semaphore<Scheduler>,
+}
+
+/// Builds a `WeightedFairQueue` service for each new connection.
+pub struct NewWeightedFairQueue<T> {
+    inner: T,
+    weights: ClassWeights,
+    max_concurrent: usize,
+}
+
+/// Queued requests, by class
+struct Scheduler {
+    weights: ClassWeights,
+    classes: HashMap<String, Class>,
+    // Pass of the most recently dispatched class
+    vtime: f64,
+}
+
+/// The queued requests of a class.
+///
+/// Each dispatch advances the pass of the class by the inverse of its weight,
+/// and the class with the lowest pass is dispatched next.
+struct Class {
+    pass: f64,
+    queue: VecDeque<oneshot::Sender<Permit<Scheduler>>>,
+}
+
+/// Prefix `req` with `class`, to be decoded by the `WeightedFairQueue`
+/// middleware on the server.
+///
+/// # Panics
+///
+/// Panics if `class` contains a space or a ']'.
+pub fn encode(req: &str, class: &str) -> String {
+    assert!(is_valid(class), "invalid class of service");
+    format!("{}{}] {}", PREFIX, class, req)
+}
+
+/// Returns true if `class` can be encoded, that is, if it contains neither a
+/// space nor a ']'.
+pub fn is_valid(class: &str) -> bool {
+    !class.contains(' ') && !class.contains(']')
+}
+
+/// Split a request into its class of service (if any) and the actual request
+/// line.
+///
+/// Lines with a malformed prefix are returned unchanged.
+pub fn decode(req: String) -> (Option<String>, String) {
+    if !req.starts_with(PREFIX) {
+        return (None, req);
+    }
+
+    match req[PREFIX.len()..].find("] ") {
+        Some(end) => {
+            let end = PREFIX.len() + end;
+            (Some(req[PREFIX.len()..end].to_string()), req[end + 2..].to_string())
+        }
+        None => (None, req),
+    }
+}
+
+impl ClassWeights {
+    /// Create an empty set of weights, where every request belongs to the
+    /// default class
+    pub fn new() -> ClassWeights {
+        ClassWeights::default()
+    }
+
+    /// Set the weight of `class`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `weight` is zero.
+    pub fn weight(mut self, class: &str, weight: u32) -> ClassWeights {
+        assert!(weight > 0, "weight must be at least 1");
+        self.weights.insert(class.to_string(), weight);
+        self
+    }
+
+    /// Returns the class requests tagged with `class` are queued in
+    fn class_of(&self, class: Option<String>) -> String {
+        match class {
+            Some(ref class) if self.weights.contains_key(class) => class.clone(),
+            _ => String::new(),
+        }
+    }
+
+    fn get(&self, class: &str) -> u32 {
+        self.weights.get(class).cloned().unwrap_or(1)
+    }
+}
+
+impl<T> WeightedFairQueue<T> {
+    /// Create a new `WeightedFairQueue`, processing at most `max_concurrent`
+    /// requests at once.
+    pub fn new(inner: T, weights: ClassWeights, max_concurrent: usize) -> WeightedFairQueue<T> {
+        assert!(max_concurrent > 0, "max_concurrent must be at least 1");
+
+        WeightedFairQueue {
+            inner: Rc::new(inner),
+            semaphore: Semaphore::new(max_concurrent, Scheduler {
+                weights: weights,
+                classes: HashMap::new(),
+                vtime: 0.0,
+            }),
+        }
+    }
+}
+
+impl<T> Service for WeightedFairQueue<T>
+    where T: Service<Request = String, Response = String, Error = io::Error> + 'static,
+          T::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        let (class, req) = decode(req);
+
+        let inner = self.inner.clone();
+
+        Box::new(self.semaphore.acquire(class).and_then(move |slot| {
+            // The request now holds a slot, release it once done
+            inner.call(req)
+                .then(move |res| {
+                    drop(slot);
+                    res
+                })
+        }))
+    }
+}
+
+impl<T> NewWeightedFairQueue<T> {
+    /// Create a new `NewWeightedFairQueue`
+    pub fn new(inner: T, weights: ClassWeights, max_concurrent: usize) -> NewWeightedFairQueue<T> {
+        NewWeightedFairQueue {
+            inner: inner,
+            weights: weights,
+            max_concurrent: max_concurrent,
+        }
+    }
+}
+
+impl<T> NewService for NewWeightedFairQueue<T>
+    where T: NewService<Request = String, Response = String, Error = io::Error>,
+          T::Instance: 'static,
+          <T::Instance as Service>::Future: 'static
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Instance = WeightedFairQueue<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = try!(self.inner.new_service());
+        Ok(WeightedFairQueue::new(inner, self.weights.clone(), self.max_concurrent))
+    }
+}
+
+impl Queue for Scheduler {
+    type Key = Option<String>;
+
+    fn push(&mut self, class: Option<String>, tx: oneshot::Sender<Permit<Scheduler>>) {
+        let class = self.weights.class_of(class);
+        let vtime = self.vtime;
+
+        let class = self.classes.entry(class).or_insert_with(|| {
+            Class {
+                pass: vtime,
+                queue: VecDeque::new(),
+            }
+        });
+
+        // A class that was idle does not get to catch up on the dispatches
+        // it did not use
+        if class.queue.is_empty() && class.pass < vtime {
+            class.pass = vtime;
+        }
+
+        class.queue.push_back(tx);
+    }
+
+    /// Pop the next request of the class that is due
+    fn pop(&mut self) -> Option<oneshot::Sender<Permit<Scheduler>>> {
+        let mut next = None;
+        let mut min = f64::INFINITY;
+
+        for (name, class) in &self.classes {
+            if !class.queue.is_empty() && class.pass < min {
+                min = class.pass;
+                next = Some(name.clone());
+            }
+        }
+
+        let name = match next {
+            Some(name) => name,
+            None => return None,
+        };
+
+        let stride = 1.0 / self.weights.get(&name) as f64;
+        let class = self.classes.get_mut(&name).unwrap();
+
+        self.vtime = class.pass;
+        class.pass += stride;
+        class.queue.pop_front()
+    }
+
+    fn len(&self) -> usize {
+        self.classes.values().map(|class| class.queue.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, encode, is_valid, ClassWeights, WeightedFairQueue};
+
+    use futures::future;
+    use tokio_core::reactor::Core;
+    use tokio_service::Service;
+
+    use std::io;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Echoes requests, recording the order they are dispatched in
+    struct Record(Rc<RefCell<Vec<String>>>);
+
+    impl Service for Record {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            self.0.borrow_mut().push(req.clone());
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn classes_with_a_space_or_bracket_are_invalid() {
+        assert!(is_valid("gold"));
+        assert!(!is_valid("gold plated"));
+        assert!(!is_valid("gold]"));
+    }
+
+    #[test]
+    fn encoded_class_round_trips() {
+        assert_eq!(decode(encode("hello", "gold")), (Some("gold".to_string()), "hello".to_string()));
+        assert_eq!(decode("hello".to_string()), (None, "hello".to_string()));
+    }
+
+    #[test]
+    fn gold_is_served_at_a_higher_rate_than_bronze() {
+        let mut core = Core::new().unwrap();
+
+        let dispatched = Rc::new(RefCell::new(vec![]));
+        let weights = ClassWeights::new().weight("gold", 4).weight("bronze", 1);
+        let service = WeightedFairQueue::new(Record(dispatched.clone()), weights, 1);
+
+        // Keep the service busy, so that every other request is queued
+        let mut calls = vec![service.call("busy".to_string())];
+
+        for _ in 0..10 {
+            calls.push(service.call(encode("bronze", "bronze")));
+            calls.push(service.call(encode("gold", "gold")));
+        }
+
+        core.run(future::join_all(calls)).unwrap();
+
+        let dispatched = dispatched.borrow();
+        assert_eq!(dispatched.len(), 21);
+
+        // Under contention, four gold requests are dispatched for every bronze
+        // one
+        for window in dispatched[1..].chunks(5).take(2) {
+            let gold = window.iter().filter(|req| *req == "gold").count();
+            assert_eq!(gold, 4, "dispatched {:?}", window);
+        }
+
+        // Once gold is drained, bronze gets every slot
+        assert_eq!(dispatched[11..].iter().filter(|req| *req == "gold").count(), 2);
+    }
+}
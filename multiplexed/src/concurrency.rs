@@ -0,0 +1,245 @@
+//! Limiting the number of requests a service processes at once.
+//!
+//! On a multiplexed connection, many requests may be in flight, and all of
+//! them are dispatched to the service as soon as they are received. The
+//! `ConcurrencyLimitService` middleware protects a service that isn't safe to
+//! call highly concurrently: at most `max` calls to the inner service are
+//! active at a time, acting as a semaphore. Excess requests are queued and
+//! dispatched in the order they were received, as calls complete.
+//!
+//! This is independent of how many requests the connection accepts. See the
+//! `priority` module to dispatch queued requests by priority instead.
+
+use semaphore::{Semaphore, Permit, Queue};
+
+use futures::Future;
+use futures::sync::oneshot;
+use tokio_service::{Service, NewService};
+
+use std::io;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+/// A `Service` middleware capping the number of concurrent calls to the inner
+/// service.
+pub struct ConcurrencyLimitService<T> {
+    inner: Rc<T>,
+    semaphore: Semaphore<Fifo>,
+}
+
+/// Builds a `ConcurrencyLimitService` for each new connection.
+pub struct NewConcurrencyLimit<T> {
+    inner: T,
+    max: usize,
+}
+
+/// Queued requests, in the order they were received
+struct Fifo {
+    waiting: VecDeque<oneshot::Sender<Permit<Fifo>>>,
+}
+
+impl<T> ConcurrencyLimitService<T> {
+    /// Create a new `ConcurrencyLimitService`, allowing at most `max`
+    /// concurrent calls to `inner`.
+    pub fn new(inner: T, max: usize) -> ConcurrencyLimitService<T> {
+        assert!(max > 0, "max must be at least 1");
+
+        ConcurrencyLimitService {
+            inner: Rc::new(inner),
+            semaphore: Semaphore::new(max, Fifo { waiting: VecDeque::new() }),
+        }
+    }
+
+    /// Returns the number of calls to the inner service currently active
+    pub fn active(&self) -> usize {
+        self.semaphore.active()
+    }
+
+    /// Returns the number of requests waiting for a permit
+    pub fn queued(&self) -> usize {
+        self.semaphore.queued()
+    }
+}
+
+impl<T> Service for ConcurrencyLimitService<T>
+    where T: Service<Request = String, Response = String, Error = io::Error> + 'static,
+          T::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        let inner = self.inner.clone();
+
+        Box::new(self.semaphore.acquire(()).and_then(move |permit| {
+            // The request now holds a permit, release it once done
+            inner.call(req)
+                .then(move |res| {
+                    drop(permit);
+                    res
+                })
+        }))
+    }
+}
+
+impl<T> NewConcurrencyLimit<T> {
+    /// Create a new `NewConcurrencyLimit`
+    pub fn new(inner: T, max: usize) -> NewConcurrencyLimit<T> {
+        NewConcurrencyLimit {
+            inner: inner,
+            max: max,
+        }
+    }
+}
+
+impl<T> NewService for NewConcurrencyLimit<T>
+    where T: NewService<Request = String, Response = String, Error = io::Error>,
+          T::Instance: 'static,
+          <T::Instance as Service>::Future: 'static
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Instance = ConcurrencyLimitService<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = try!(self.inner.new_service());
+        Ok(ConcurrencyLimitService::new(inner, self.max))
+    }
+}
+
+impl Queue for Fifo {
+    type Key = ();
+
+    fn push(&mut self, _: (), tx: oneshot::Sender<Permit<Fifo>>) {
+        self.waiting.push_back(tx);
+    }
+
+    fn pop(&mut self) -> Option<oneshot::Sender<Permit<Fifo>>> {
+        // The oldest request is handed the permit first
+        self.waiting.pop_front()
+    }
+
+    fn len(&self) -> usize {
+        self.waiting.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ConcurrencyLimitService;
+
+    use futures::{future, Future};
+    use tokio_core::reactor::Core;
+    use tokio_service::Service;
+    use tokio_timer::Timer;
+
+    use std::{cmp, io};
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn dropped_calls_release_their_permit() {
+        let service = ConcurrencyLimitService::new(Echo, 1);
+
+        let first = service.call("first".to_string());
+        let second = service.call("second".to_string());
+
+        assert_eq!(service.active(), 1);
+        assert_eq!(service.queued(), 1);
+
+        // The permit is handed over to the queued call
+        drop(first);
+        assert_eq!(service.active(), 1);
+        assert_eq!(service.queued(), 0);
+
+        // Which releases it without ever being polled
+        drop(second);
+        assert_eq!(service.active(), 0);
+    }
+
+    #[test]
+    fn permits_skip_dropped_calls() {
+        let service = ConcurrencyLimitService::new(Echo, 1);
+
+        let first = service.call("first".to_string());
+        let second = service.call("second".to_string());
+
+        drop(second);
+        drop(first);
+
+        assert_eq!(service.active(), 0);
+        assert_eq!(service.queued(), 0);
+    }
+
+    /// Responds after a delay, tracking the number of active calls
+    struct Slow {
+        timer: Timer,
+        active: Rc<Cell<usize>>,
+        peak: Rc<Cell<usize>>,
+    }
+
+    impl Service for Slow {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = Box<Future<Item = String, Error = io::Error>>;
+
+        fn call(&self, req: String) -> Self::Future {
+            let active = self.active.clone();
+
+            active.set(active.get() + 1);
+            self.peak.set(cmp::max(self.peak.get(), active.get()));
+
+            Box::new(self.timer.sleep(Duration::from_millis(10))
+                .then(move |res| {
+                    active.set(active.get() - 1);
+                    res.map(|()| req)
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                }))
+        }
+    }
+
+    #[test]
+    fn burst_is_capped_at_max_active_calls() {
+        let mut core = Core::new().unwrap();
+
+        let active = Rc::new(Cell::new(0));
+        let peak = Rc::new(Cell::new(0));
+
+        let service = ConcurrencyLimitService::new(Slow {
+            timer: Timer::default(),
+            active: active.clone(),
+            peak: peak.clone(),
+        }, 3);
+
+        let reqs: Vec<String> = (0..10).map(|i| i.to_string()).collect();
+        let calls: Vec<_> = reqs.iter().map(|req| service.call(req.clone())).collect();
+
+        assert_eq!(service.queued(), 7);
+
+        let resps = core.run(future::join_all(calls)).unwrap();
+
+        assert_eq!(resps, reqs);
+        assert_eq!(peak.get(), 3);
+        assert_eq!(active.get(), 0);
+        assert_eq!(service.active(), 0);
+    }
+}
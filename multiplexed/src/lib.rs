@@ -3,11 +3,13 @@
 
 #![deny(warnings, missing_docs)]
 
+#[macro_use]
 extern crate futures;
 extern crate tokio_io;
 extern crate tokio_core;
 extern crate tokio_proto;
 extern crate tokio_service;
+extern crate tokio_timer;
 extern crate bytes;
 
 use futures::{future, Future};
@@ -19,11 +21,28 @@ use tokio_core::reactor::Handle;
 use tokio_proto::{TcpClient, TcpServer};
 use tokio_proto::multiplex::{RequestId, ServerProto, ClientProto, ClientService};
 use tokio_service::{Service, NewService};
+use tokio_timer::Timer;
 
 use bytes::{BytesMut, Buf, BufMut, BigEndian};
 
 use std::{io, str};
+use std::cell::{Cell, RefCell};
 use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+pub mod ack;
+pub mod class_of_service;
+pub mod concurrency;
+pub mod opaque;
+pub mod priority;
+pub mod request_ids;
+pub mod throttle;
+
+mod request_timeouts;
+mod semaphore;
+
+use request_timeouts::{Calls, Cancellable, RequestTimeouts};
 
 /// Multiplexed line-based client handle
 ///
@@ -31,13 +50,42 @@ use std::net::SocketAddr;
 /// details of how the inner service is structured. Specifically, we don't want
 /// the type signature of our client to be:
 ///
-///   Validate<ClientService<TcpStream, LineProto>>
+///   Validate<Cancellable<ClientService<TcpStream, ClientLineProto>>>
 ///
 /// This also allows adding higher level API functions that are protocol
 /// specific. For example, our line client has a `ping()` function, which sends
 /// a "ping" request.
+///
+/// # Cancellation
+///
+/// A call may be cancelled by dropping its response future. The request is
+/// immediately no longer counted by `in_flight`, and its request ID is
+/// released. The server still processes it and eventually sends a response,
+/// which is discarded.
+///
+/// # Invalid frames
+///
+/// A request or response that is not valid UTF-8 fails with `InvalidData`,
+/// without affecting the other requests in flight. As a consequence, the
+/// `[error invalid utf-8]` response is reserved.
+///
+/// # Timeouts
+///
+/// By default, a call waits for its response indefinitely. With
+/// `with_request_timeout`, calls that are not answered in time fail with
+/// `TimedOut`. Unlike a cancelled call, the request ID is released right away,
+/// and the late response, if any, is discarded. As a consequence, the
+/// `[error timed out]` response is reserved.
 pub struct Client {
-    inner: Validate<ClientService<TcpStream, LineProto>>,
+    inner: Validate<Cancellable<ClientService<TcpStream, ClientLineProto>>>,
+    in_flight: Rc<Cell<usize>>,
+    // Shared with the transport, which enforces it
+    timeout: Rc<RefCell<Option<(Timer, Duration)>>>,
+}
+
+/// Decrements the in-flight count when the request completes or is dropped
+struct InFlight {
+    count: Rc<Cell<usize>>,
 }
 
 /// A `Service` middleware that validates the correctness of requests and
@@ -51,12 +99,39 @@ struct Validate<T> {
     inner: T,
 }
 
+/// Answers requests that were not valid UTF-8 on the wire with an error,
+/// rather than passing them to the inner service.
+struct AnswerInvalid<T> {
+    inner: T,
+}
+
 /// Our multiplexed line-based codec
 struct LineCodec;
 
+/// Substituted by the codec for frames that are not valid UTF-8.
+///
+/// Multiplexed transports cannot fail a single request, so the server answers
+/// the request with this line, and `Client` fails calls answered with it.
+const INVALID_UTF8: &'static str = "[error invalid utf-8]";
+
+/// Substituted by the client transport for the response of requests that
+/// timed out.
+const TIMED_OUT: &'static str = "[error timed out]";
+
+/// Substituted by the client transport for the response of requests whose
+/// call was dropped. As nobody waits for it, it is never seen by callers.
+const CANCELLED: &'static str = "[error cancelled]";
+
 /// Protocol definition
 struct LineProto;
 
+/// Client protocol definition, applying the request timeout of `Client`
+/// and releasing the ID of its dropped calls
+struct ClientLineProto {
+    timeout: Rc<RefCell<Option<(Timer, Duration)>>>,
+    calls: Rc<RefCell<Calls>>,
+}
+
 /// Start a server, listening for connections on `addr`.
 ///
 /// For each new connection, `new_service` will be used to build a `Service`
@@ -81,15 +156,96 @@ impl Client {
     /// Establish a connection to a multiplexed line-based server at the
     /// provided `addr`.
     pub fn connect(addr: &SocketAddr, handle: &Handle) -> Box<Future<Item = Client, Error = io::Error>> {
-        let ret = TcpClient::new(LineProto)
+        let timeout = Rc::new(RefCell::new(None));
+        let calls = Rc::new(RefCell::new(Calls::new()));
+
+        let proto = ClientLineProto {
+            timeout: timeout.clone(),
+            calls: calls.clone(),
+        };
+
+        let ret = TcpClient::new(proto)
             .connect(addr, handle)
-            .map(|client_service| {
-                let validate = Validate { inner: client_service};
-                Client { inner: validate }
+            .map(move |client_service| {
+                let validate = Validate { inner: Cancellable::new(client_service, calls) };
+                Client {
+                    inner: validate,
+                    in_flight: Rc::new(Cell::new(0)),
+                    timeout: timeout,
+                }
             });
 
         Box::new(ret)
     }
+
+    /// Send a request with the given priority.
+    ///
+    /// If the server is running the `priority::Prioritize` middleware, higher
+    /// priority requests are processed before lower priority ones that are
+    /// queued.
+    pub fn call_with_priority(&self, req: String, priority: u8) -> Box<Future<Item = String, Error = io::Error>> {
+        self.call(priority::encode(&req, priority))
+    }
+
+    /// Send a request tagged with the class of service `class`.
+    ///
+    /// If the server is running the `class_of_service::WeightedFairQueue`
+    /// middleware, queued requests are dispatched in proportion to the weight
+    /// of their class.
+    ///
+    /// The call fails with `InvalidInput` if `class` contains a space or a
+    /// ']'.
+    pub fn call_with_class(&self, req: String, class: &str) -> Box<Future<Item = String, Error = io::Error>> {
+        if !class_of_service::is_valid(class) {
+            return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput, "invalid class of service")));
+        }
+
+        self.call(class_of_service::encode(&req, class))
+    }
+
+    /// Returns the number of requests currently awaiting a response.
+    ///
+    /// Requests are counted from the moment `call` is invoked until the
+    /// response, or an error, is received. Dropping the response future also
+    /// stops counting the request.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.get()
+    }
+
+    /// Send a `ping` to the remote. The returned future resolves when the
+    /// remote has responded with a pong.
+    ///
+    /// Each ping is a request of its own, with its own request ID, so
+    /// concurrent pings do not interfere with each other.
+    pub fn ping(&self) -> Box<Future<Item = (), Error = io::Error>> {
+        let resp = self.call("[ping]".to_string())
+            .and_then(|resp| {
+                if resp != "[pong]" {
+                    Err(io::Error::new(io::ErrorKind::Other, "expected pong"))
+                } else {
+                    Ok(())
+                }
+            });
+
+        Box::new(resp)
+    }
+
+    /// Send a `ping` to the remote, resolving with the round trip time once
+    /// the remote has responded with a pong.
+    pub fn ping_timed(&self) -> Box<Future<Item = Duration, Error = io::Error>> {
+        let start = Instant::now();
+
+        Box::new(self.ping().map(move |_| start.elapsed()))
+    }
+
+    /// Fail calls that are not answered within `timeout` with `TimedOut`.
+    ///
+    /// This protects callers against a server that never responds to some
+    /// requests. The timeout applies to the requests sent from now on.
+    pub fn with_request_timeout(self, timer: Timer, timeout: Duration) -> Client {
+        *self.timeout.borrow_mut() = Some((timer, timeout));
+        self
+    }
 }
 
 impl Service for Client {
@@ -100,7 +256,32 @@ impl Service for Client {
     type Future = Box<Future<Item = String, Error = io::Error>>;
 
     fn call(&self, req: String) -> Self::Future {
-        self.inner.call(req)
+        self.in_flight.set(self.in_flight.get() + 1);
+
+        let guard = InFlight { count: self.in_flight.clone() };
+
+        let resp = self.inner.call(req)
+            .then(move |res| {
+                drop(guard);
+                res
+            })
+            .and_then(|resp| {
+                if resp == INVALID_UTF8 {
+                    Err(io::Error::new(io::ErrorKind::InvalidData, "invalid string"))
+                } else if resp == TIMED_OUT {
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "request timed out"))
+                } else {
+                    Ok(resp)
+                }
+            });
+
+        Box::new(resp)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.count.set(self.count.get() - 1);
     }
 }
 
@@ -140,55 +321,76 @@ impl<T> NewService for Validate<T>
     type Request = String;
     type Response = String;
     type Error = io::Error;
-    type Instance = Validate<T::Instance>;
+    type Instance = AnswerInvalid<Validate<T::Instance>>;
 
     fn new_service(&self) -> io::Result<Self::Instance> {
         let inner = try!(self.inner.new_service());
-        Ok(Validate { inner: inner })
+        Ok(AnswerInvalid { inner: Validate { inner: inner } })
+    }
+}
+
+impl<T> Service for AnswerInvalid<T>
+    where T: Service<Request = String, Response = String, Error = io::Error>,
+          T::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        if req == INVALID_UTF8 {
+            return Box::new(future::ok(req));
+        }
+
+        Box::new(self.inner.call(req))
     }
 }
 
 /// Implementation of the multiplexed line-based protocol.
 ///
-/// Frames begin with a 4 byte header, consisting of the numeric request ID
+/// Frames begin with an 8 byte header, consisting of the numeric request ID
 /// encoded in network order, followed by the frame payload encoded as a UTF-8
 /// string and terminated with a '\n' character:
 ///
 /// # An example frame:
 ///
-/// +-- request id --+------- frame payload --------+
-/// |                |                              |
-/// |   \x00000001   | This is the frame payload \n |
-/// |                |                              |
-/// +----------------+------------------------------+
+/// +------ request id ------+------- frame payload --------+
+/// |                        |                              |
+/// |   \x0000000000000001   | This is the frame payload \n |
+/// |                        |                              |
+/// +------------------------+------------------------------+
 ///
 impl Decoder for LineCodec {
     type Item = (RequestId, String);
     type Error = io::Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<(RequestId, String)>, io::Error> {
-        // At least 5 bytes are required for a frame: 4 byte head + one byte
+        // At least 9 bytes are required for a frame: 8 byte head + one byte
         // '\n'
-        if buf.len() < 5 {
+        if buf.len() < 9 {
             return Ok(None);
         }
 
-        // Check to see if the frame contains a new line, skipping the first 4
+        // Check to see if the frame contains a new line, skipping the first 8
         // bytes which is the request ID
-        if let Some(n) = buf.as_ref()[4..].iter().position(|b| *b == b'\n') {
+        if let Some(n) = buf.as_ref()[8..].iter().position(|b| *b == b'\n') {
             // remove the serialized frame from the buffer.
-            let line = buf.split_to(n + 4);
+            let line = buf.split_to(n + 8);
 
             // Also remove the '\n'
             buf.split_to(1);
 
             // Deserialize the request ID
-            let request_id = io::Cursor::new(&line[0..4]).get_u32::<BigEndian>();
+            let request_id = io::Cursor::new(&line[0..8]).get_u64::<BigEndian>();
 
             // Turn this data into a UTF string and return it in a Frame.
-            return match str::from_utf8(&line.as_ref()[4..]) {
-                Ok(s) => Ok(Some((request_id as RequestId, s.to_string()))),
-                Err(_) => Err(io::Error::new(io::ErrorKind::Other, "invalid string")),
+            return match str::from_utf8(&line.as_ref()[8..]) {
+                Ok(s) => Ok(Some((request_id, s.to_string()))),
+                // Fail the request with this ID rather than the connection,
+                // along with every other request in flight on it.
+                Err(_) => Ok(Some((request_id, INVALID_UTF8.to_string()))),
             }
         }
 
@@ -201,13 +403,14 @@ impl Encoder for LineCodec {
     type Error = io::Error;
 
     fn encode(&mut self, msg: (RequestId, String), buf: &mut BytesMut) -> io::Result<()> {
-        // Reserve enough space for the frame
-        let len = 4 + msg.1.len() + 1;
-        buf.reserve(len);
-
         let (request_id, msg) = msg;
 
-        buf.put_u32::<BigEndian>(request_id as u32);
+        // Reserve enough space for the frame. This is based on the size of
+        // this frame only, not on what is already buffered.
+        let len = 8 + msg.len() + 1;
+        buf.reserve(len);
+
+        buf.put_u64::<BigEndian>(request_id);
         buf.put_slice(msg.as_bytes());
         buf.put_u8(b'\n');
 
@@ -215,16 +418,18 @@ impl Encoder for LineCodec {
     }
 }
 
-impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for LineProto {
+impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for ClientLineProto {
     type Request = String;
     type Response = String;
 
-    /// `Framed<T, LineCodec>` is the return value of `io.framed(LineCodec)`
-    type Transport = Framed<T, LineCodec>;
+    /// `Framed<T, LineCodec>` is the return value of `io.framed(LineCodec)`.
+    /// It is wrapped so that requests that are not responded to in time are
+    /// failed, and that the ID of dropped calls is released.
+    type Transport = RequestTimeouts<Framed<T, LineCodec>>;
     type BindTransport = Result<Self::Transport, io::Error>;
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(LineCodec))
+        Ok(RequestTimeouts::new(io.framed(LineCodec), self.timeout.clone(), self.calls.clone()))
     }
 }
 
@@ -232,11 +437,297 @@ impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for LineProto {
     type Request = String;
     type Response = String;
 
-    /// `Framed<T, LineCodec>` is the return value of `io.framed(LineCodec)`
-    type Transport = Framed<T, LineCodec>;
+    /// `Framed<T, LineCodec>` is the return value of `io.framed(LineCodec)`.
+    /// It is wrapped so that requests reusing the ID of a request in flight
+    /// are rejected.
+    type Transport = request_ids::RejectDuplicateIds<Framed<T, LineCodec>>;
     type BindTransport = Result<Self::Transport, io::Error>;
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(LineCodec))
+        Ok(request_ids::RejectDuplicateIds::new(io.framed(LineCodec)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AnswerInvalid, Client, LineCodec, LineProto, INVALID_UTF8};
+
+    use futures::{future, Future, Stream};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_io::codec::{Encoder, Decoder};
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+    use tokio_timer::Timer;
+
+    use bytes::BytesMut;
+
+    use std::{io, iter, u32};
+    use std::time::Duration;
+
+    struct Served;
+
+    impl Service for Served {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, _: String) -> Self::Future {
+            future::ok("served".to_string())
+        }
+    }
+
+    /// Never responds to `never`, echoes everything else
+    struct Unresponsive;
+
+    impl Service for Unresponsive {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = Box<Future<Item = String, Error = io::Error>>;
+
+        fn call(&self, req: String) -> Self::Future {
+            if req == "never" {
+                return Box::new(future::empty());
+            }
+
+            Box::new(future::ok(req))
+        }
+    }
+
+    /// Responds to `slow` after 100ms, echoes everything else right away
+    struct Delayed {
+        timer: Timer,
+    }
+
+    impl Service for Delayed {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = Box<Future<Item = String, Error = io::Error>>;
+
+        fn call(&self, req: String) -> Self::Future {
+            if req == "slow" {
+                let resp = self.timer.sleep(Duration::from_millis(100))
+                    .map(move |_| req)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+
+                return Box::new(resp);
+            }
+
+            Box::new(future::ok(req))
+        }
+    }
+
+    /// Answers pings with a pong after 20ms
+    struct Pong {
+        timer: Timer,
+    }
+
+    impl Service for Pong {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = Box<Future<Item = String, Error = io::Error>>;
+
+        fn call(&self, req: String) -> Self::Future {
+            if req != "[ping]" {
+                return Box::new(future::ok(req));
+            }
+
+            let resp = self.timer.sleep(Duration::from_millis(20))
+                .map(|_| "[pong]".to_string())
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+
+            Box::new(resp)
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_fails_only_its_request() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x00\x00\x00\x00\x00\x00\x00\x01\xff\xfe\n");
+        buf.extend_from_slice(b"\x00\x00\x00\x00\x00\x00\x00\x02hello\n");
+
+        let mut codec = LineCodec;
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some((1, INVALID_UTF8.to_string())));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some((2, "hello".to_string())));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_requests_are_answered_with_an_error() {
+        let service = AnswerInvalid { inner: Served };
+
+        assert_eq!(service.call(INVALID_UTF8.to_string()).wait().unwrap(), INVALID_UTF8);
+        assert_eq!(service.call("hello".to_string()).wait().unwrap(), "served");
+    }
+
+    #[test]
+    fn unanswered_requests_time_out() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+
+        let server = listener.incoming().for_each(move |(socket, _)| {
+            LineProto.bind_server(&handle2, socket, Unresponsive);
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let client = core.run(Client::connect(&addr, &handle)).unwrap()
+            .with_request_timeout(Timer::default(), Duration::from_millis(200));
+
+        let never = client.call("never".to_string()).then(Ok::<_, io::Error>);
+        let hello = client.call("hello".to_string());
+
+        let (never, hello) = core.run(never.join(hello)).unwrap();
+
+        assert_eq!(hello, "hello");
+        assert_eq!(never.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(client.in_flight(), 0);
+
+        // The connection is still usable
+        assert_eq!(core.run(client.call("again".to_string())).unwrap(), "again");
+    }
+
+    #[test]
+    fn request_ids_above_u32_round_trip() {
+        let id = u32::MAX as u64 + 1;
+
+        let mut codec = LineCodec;
+        let mut buf = BytesMut::new();
+
+        codec.encode((id, "hello".to_string()), &mut buf).unwrap();
+        assert_eq!(&buf[..8], &b"\x00\x00\x00\x01\x00\x00\x00\x00"[..]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some((id, "hello".to_string())));
+    }
+
+    #[test]
+    fn encoding_reserves_for_the_frame_only() {
+        let payload: String = iter::repeat('a').take(100).collect();
+        let frames = 1000;
+        let total = frames * (8 + payload.len() + 1);
+
+        let mut codec = LineCodec;
+        let mut buf = BytesMut::with_capacity(total);
+
+        for id in 0..frames {
+            codec.encode((id as u64, payload.clone()), &mut buf).unwrap();
+        }
+
+        assert_eq!(buf.len(), total);
+
+        // Reserving for what is already buffered would double the buffer
+        // once half of the frames are encoded
+        assert!(buf.capacity() < 2 * total);
+    }
+
+    #[test]
+    fn dropped_calls_discard_their_late_response() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+        let timer = Timer::default();
+
+        let server = listener.incoming().for_each(move |(socket, _)| {
+            LineProto.bind_server(&handle2, socket, Delayed { timer: timer.clone() });
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        drop(client.call("slow".to_string()));
+        assert_eq!(client.in_flight(), 0);
+
+        assert_eq!(core.run(client.call("hello".to_string())).unwrap(), "hello");
+
+        // Let the late response arrive, it is not matched with another call
+        core.run(Timer::default().sleep(Duration::from_millis(200))).unwrap();
+
+        assert_eq!(core.run(client.call("again".to_string())).unwrap(), "again");
+    }
+
+    #[test]
+    fn in_flight_counts_concurrent_calls() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+        let timer = Timer::default();
+
+        let server = listener.incoming().for_each(move |(socket, _)| {
+            LineProto.bind_server(&handle2, socket, Delayed { timer: timer.clone() });
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+        assert_eq!(client.in_flight(), 0);
+
+        let slow: Vec<_> = (0..3).map(|_| client.call("slow".to_string())).collect();
+        assert_eq!(client.in_flight(), 3);
+
+        // The slow calls are still awaiting their response once a fast one
+        // has been answered
+        assert_eq!(core.run(client.call("hello".to_string())).unwrap(), "hello");
+        assert_eq!(client.in_flight(), 3);
+
+        let resps = core.run(future::join_all(slow)).unwrap();
+
+        assert_eq!(resps, vec!["slow", "slow", "slow"]);
+        assert_eq!(client.in_flight(), 0);
+    }
+
+    #[test]
+    fn concurrent_pings_resolve_independently() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+        let timer = Timer::default();
+
+        let server = listener.incoming().for_each(move |(socket, _)| {
+            LineProto.bind_server(&handle2, socket, Pong { timer: timer.clone() });
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        let pings: Vec<_> = (0..5).map(|_| client.ping_timed()).collect();
+        assert_eq!(client.in_flight(), 5);
+
+        let rtts = core.run(future::join_all(pings)).unwrap();
+
+        // Every ping got its own pong, after the server's delay
+        for rtt in rtts {
+            assert!(rtt >= Duration::from_millis(10), "round trip of {:?}", rtt);
+            assert!(rtt < Duration::from_secs(1), "round trip of {:?}", rtt);
+        }
+
+        assert_eq!(client.in_flight(), 0);
     }
 }
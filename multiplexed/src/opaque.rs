@@ -0,0 +1,499 @@
+//! A variant of the multiplexed protocol using opaque byte string request IDs.
+//!
+//! This is useful to correlate requests with IDs assigned by an external
+//! system, such as UUIDs. Frames begin with a 1 byte header holding the length
+//! of the request ID, followed by the request ID itself, followed by the frame
+//! payload encoded as a UTF-8 string and terminated with a '\n' character:
+//!
+//! # An example frame:
+//!
+//! +-- len --+-- request id --+------- frame payload --------+
+//! |         |                |                              |
+//! |  \x03   |      abc       | This is the frame payload \n |
+//! |         |                |                              |
+//! +---------+----------------+------------------------------+
+//!
+//! tokio-proto assigns numeric IDs to requests internally. The transports in
+//! this module translate between those and the opaque IDs found on the wire:
+//! the client keeps a map from the opaque ID of each in-flight request to its
+//! numeric ID, and the server does the reverse.
+//!
+//! An opaque ID can't be reused until the request using it is responded to,
+//! even if that request was cancelled by dropping its response future.
+//! `OpaqueClient` fails calls reusing such an ID, or using an ID longer than
+//! 255 bytes, with `InvalidInput`, without sending them.
+
+use Validate;
+
+use futures::{future, Future, Stream, Sink, Poll, Async, StartSend, AsyncSink};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::{Encoder, Decoder, Framed};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_proto::{TcpClient, TcpServer};
+use tokio_proto::multiplex::{RequestId, ServerProto, ClientProto, ClientService};
+use tokio_service::{Service, NewService};
+
+use bytes::{BytesMut, BufMut};
+
+use std::{io, str, u8};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+/// Client handle for the opaque ID variant of the protocol.
+///
+/// Requests are `(id, line)` pairs, and each response is returned along with
+/// the ID of the request it answers. Only one request with a given ID may be
+/// in flight at a time.
+pub struct OpaqueClient {
+    inner: ClientService<TcpStream, OpaqueClientProto>,
+    // IDs of the requests in flight, shared with the transport
+    in_flight: Rc<RefCell<HashSet<Vec<u8>>>>,
+}
+
+/// Codec for frames carrying an opaque request ID.
+pub struct OpaqueCodec;
+
+/// Client transport mapping numeric request IDs to opaque ones.
+pub struct ClientIds<T> {
+    upstream: T,
+    // Numeric ID of every in-flight request, keyed by opaque ID
+    in_flight: HashMap<Vec<u8>, RequestId>,
+    // Released once the response is received
+    reserved: Rc<RefCell<HashSet<Vec<u8>>>>,
+}
+
+/// Server transport mapping opaque request IDs to numeric ones.
+pub struct ServerIds<T> {
+    upstream: T,
+    next_id: RequestId,
+    // Opaque ID of every in-flight request, keyed by numeric ID
+    in_flight: HashMap<RequestId, Vec<u8>>,
+}
+
+/// Server protocol definition
+struct OpaqueProto;
+
+/// Client protocol definition, sharing the IDs in flight with `OpaqueClient`
+struct OpaqueClientProto {
+    in_flight: Rc<RefCell<HashSet<Vec<u8>>>>,
+}
+
+/// Start a server for the opaque ID variant of the protocol, listening for
+/// connections on `addr`.
+///
+/// Services are not exposed to request IDs, so any service usable with
+/// `serve` can be used here.
+pub fn serve_opaque<T>(addr: SocketAddr, new_service: T)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    let new_service = Validate { inner: new_service };
+
+    TcpServer::new(OpaqueProto, addr)
+        .serve(new_service);
+}
+
+impl OpaqueClient {
+    /// Establish a connection to a server started with `serve_opaque`.
+    pub fn connect(addr: &SocketAddr, handle: &Handle) -> Box<Future<Item = OpaqueClient, Error = io::Error>> {
+        let in_flight = Rc::new(RefCell::new(HashSet::new()));
+
+        let ret = TcpClient::new(OpaqueClientProto { in_flight: in_flight.clone() })
+            .connect(addr, handle)
+            .map(move |client_service| {
+                OpaqueClient {
+                    inner: client_service,
+                    in_flight: in_flight,
+                }
+            });
+
+        Box::new(ret)
+    }
+}
+
+impl Service for OpaqueClient {
+    type Request = (Vec<u8>, String);
+    type Response = (Vec<u8>, String);
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = (Vec<u8>, String), Error = io::Error>>;
+
+    fn call(&self, req: (Vec<u8>, String)) -> Self::Future {
+        if req.1.contains('\n') {
+            let err = io::Error::new(io::ErrorKind::InvalidInput, "message contained new line");
+            return Box::new(future::err(err));
+        }
+
+        // Sending an ID that is too long or a duplicate would fail the
+        // transport, and with it every request in flight
+        if req.0.len() > u8::MAX as usize {
+            let err = io::Error::new(io::ErrorKind::InvalidInput, "request id too long");
+            return Box::new(future::err(err));
+        }
+
+        if !self.in_flight.borrow_mut().insert(req.0.clone()) {
+            let err = io::Error::new(io::ErrorKind::InvalidInput, "request id already in flight");
+            return Box::new(future::err(err));
+        }
+
+        Box::new(self.inner.call(req))
+    }
+}
+
+impl Decoder for OpaqueCodec {
+    type Item = (Vec<u8>, String);
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<(Vec<u8>, String)>, io::Error> {
+        // Wait for the length of the request ID
+        if buf.len() < 1 {
+            return Ok(None);
+        }
+
+        let head = 1 + buf[0] as usize;
+
+        // Wait for the request ID, then check to see if the frame contains a
+        // new line, skipping the head
+        if buf.len() <= head {
+            return Ok(None);
+        }
+
+        if let Some(n) = buf.as_ref()[head..].iter().position(|b| *b == b'\n') {
+            // remove the serialized frame from the buffer.
+            let frame = buf.split_to(head + n);
+
+            // Also remove the '\n'
+            buf.split_to(1);
+
+            let request_id = frame[1..head].to_vec();
+
+            return match str::from_utf8(&frame.as_ref()[head..]) {
+                Ok(s) => Ok(Some((request_id, s.to_string()))),
+                Err(_) => Err(io::Error::new(io::ErrorKind::Other, "invalid string")),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Encoder for OpaqueCodec {
+    type Item = (Vec<u8>, String);
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: (Vec<u8>, String), buf: &mut BytesMut) -> io::Result<()> {
+        let (request_id, msg) = msg;
+
+        if request_id.len() > u8::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "request id too long"));
+        }
+
+        // Reserve enough space for the frame
+        buf.reserve(1 + request_id.len() + msg.len() + 1);
+
+        buf.put_u8(request_id.len() as u8);
+        buf.put_slice(&request_id);
+        buf.put_slice(msg.as_bytes());
+        buf.put_u8(b'\n');
+
+        Ok(())
+    }
+}
+
+impl<T> ClientIds<T> {
+    /// Wrap `upstream`
+    pub fn new(upstream: T) -> ClientIds<T> {
+        ClientIds::with_reserved(upstream, Rc::new(RefCell::new(HashSet::new())))
+    }
+
+    /// Wrap `upstream`, releasing IDs from `reserved` as their responses are
+    /// received
+    fn with_reserved(upstream: T, reserved: Rc<RefCell<HashSet<Vec<u8>>>>) -> ClientIds<T> {
+        ClientIds {
+            upstream: upstream,
+            in_flight: HashMap::new(),
+            reserved: reserved,
+        }
+    }
+}
+
+impl<T> Stream for ClientIds<T>
+    where T: Stream<Item = (Vec<u8>, String), Error = io::Error>,
+{
+    type Item = (RequestId, (Vec<u8>, String));
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        match try_ready!(self.upstream.poll()) {
+            Some((id, line)) => {
+                match self.in_flight.remove(&id) {
+                    Some(request_id) => {
+                        self.reserved.borrow_mut().remove(&id);
+                        Ok(Async::Ready(Some((request_id, (id, line)))))
+                    }
+                    None => Err(io::Error::new(io::ErrorKind::InvalidData, "response to unknown request id")),
+                }
+            }
+            None => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+impl<T> Sink for ClientIds<T>
+    where T: Sink<SinkItem = (Vec<u8>, String), SinkError = io::Error>,
+{
+    type SinkItem = (RequestId, (Vec<u8>, String));
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, io::Error> {
+        let (request_id, (id, line)) = item;
+
+        // `OpaqueClient` rejects duplicates before they get here, so this
+        // only fails transports used directly
+        if self.in_flight.contains_key(&id) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "request id already in flight"));
+        }
+
+        match try!(self.upstream.start_send((id.clone(), line))) {
+            AsyncSink::Ready => {
+                self.in_flight.insert(id, request_id);
+                Ok(AsyncSink::Ready)
+            }
+            AsyncSink::NotReady((id, line)) => Ok(AsyncSink::NotReady((request_id, (id, line)))),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.upstream.poll_complete()
+    }
+}
+
+impl<T> ServerIds<T> {
+    /// Wrap `upstream`
+    pub fn new(upstream: T) -> ServerIds<T> {
+        ServerIds {
+            upstream: upstream,
+            next_id: 0,
+            in_flight: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Stream for ServerIds<T>
+    where T: Stream<Item = (Vec<u8>, String), Error = io::Error>,
+{
+    type Item = (RequestId, String);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        match try_ready!(self.upstream.poll()) {
+            Some((id, line)) => {
+                let request_id = self.next_id;
+                self.next_id = self.next_id.wrapping_add(1);
+
+                self.in_flight.insert(request_id, id);
+                Ok(Async::Ready(Some((request_id, line))))
+            }
+            None => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+impl<T> Sink for ServerIds<T>
+    where T: Sink<SinkItem = (Vec<u8>, String), SinkError = io::Error>,
+{
+    type SinkItem = (RequestId, String);
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, io::Error> {
+        let (request_id, line) = item;
+
+        let id = match self.in_flight.remove(&request_id) {
+            Some(id) => id,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "response to unknown request id")),
+        };
+
+        match try!(self.upstream.start_send((id, line))) {
+            AsyncSink::Ready => Ok(AsyncSink::Ready),
+            AsyncSink::NotReady((id, line)) => {
+                // Keep the mapping around for when the response is retried
+                self.in_flight.insert(request_id, id);
+                Ok(AsyncSink::NotReady((request_id, line)))
+            }
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.upstream.poll_complete()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for OpaqueClientProto {
+    type Request = (Vec<u8>, String);
+    type Response = (Vec<u8>, String);
+
+    type Transport = ClientIds<Framed<T, OpaqueCodec>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(ClientIds::with_reserved(io.framed(OpaqueCodec), self.in_flight.clone()))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for OpaqueProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = ServerIds<Framed<T, OpaqueCodec>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(ServerIds::new(io.framed(OpaqueCodec)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{OpaqueClient, OpaqueProto};
+
+    use futures::{future, Future, Stream};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::{Core, Handle};
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+    use tokio_timer::Timer;
+
+    use std::io;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    /// Never responds to `never`, echoes everything else
+    struct Unresponsive;
+
+    impl Service for Unresponsive {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = Box<Future<Item = String, Error = io::Error>>;
+
+        fn call(&self, req: String) -> Self::Future {
+            if req == "never" {
+                return Box::new(future::empty());
+            }
+
+            Box::new(future::ok(req))
+        }
+    }
+
+    /// Echoes requests after the number of milliseconds they hold
+    struct Sleepy {
+        timer: Timer,
+    }
+
+    impl Service for Sleepy {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = Box<Future<Item = String, Error = io::Error>>;
+
+        fn call(&self, req: String) -> Self::Future {
+            let ms = req.parse().unwrap();
+
+            let resp = self.timer.sleep(Duration::from_millis(ms))
+                .map(move |_| req)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+
+            Box::new(resp)
+        }
+    }
+
+    /// Serve connections on an ephemeral port with `Unresponsive`, or with
+    /// `Sleepy` if `sleepy` is set
+    fn spawn_server(handle: &Handle, sleepy: bool) -> SocketAddr {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+        let timer = Timer::default();
+
+        let server = listener.incoming().for_each(move |(socket, _)| {
+            if sleepy {
+                OpaqueProto.bind_server(&handle2, socket, Sleepy { timer: timer.clone() });
+            } else {
+                OpaqueProto.bind_server(&handle2, socket, Unresponsive);
+            }
+
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+        addr
+    }
+
+    #[test]
+    fn duplicate_ids_fail_only_their_call() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let addr = spawn_server(&handle, false);
+
+        let client = core.run(OpaqueClient::connect(&addr, &handle)).unwrap();
+
+        let _pending = client.call((b"a".to_vec(), "never".to_string()));
+
+        let err = core.run(client.call((b"a".to_vec(), "again".to_string()))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // The connection is still usable
+        let resp = core.run(client.call((b"b".to_vec(), "hello".to_string()))).unwrap();
+        assert_eq!(resp, (b"b".to_vec(), "hello".to_string()));
+    }
+
+    #[test]
+    fn too_long_ids_fail_only_their_call() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let addr = spawn_server(&handle, false);
+
+        let client = core.run(OpaqueClient::connect(&addr, &handle)).unwrap();
+
+        let _pending = client.call((b"a".to_vec(), "never".to_string()));
+
+        let err = core.run(client.call((vec![b'x'; 256], "hello".to_string()))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // The ID was not reserved, and the connection is still usable
+        assert_eq!(client.in_flight.borrow().len(), 1);
+
+        let id = vec![b'x'; 255];
+        let resp = core.run(client.call((id.clone(), "hello".to_string()))).unwrap();
+        assert_eq!(resp, (id, "hello".to_string()));
+    }
+
+    #[test]
+    fn responses_are_correlated_by_uuid() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let addr = spawn_server(&handle, true);
+
+        let client = core.run(OpaqueClient::connect(&addr, &handle)).unwrap();
+
+        // 16 byte IDs, including one holding a '\n'. Later requests are
+        // answered first.
+        let requests: Vec<(Vec<u8>, String)> = (0..4u8)
+            .map(|i| {
+                let mut uuid = vec![0x5a; 16];
+                uuid[15] = i;
+                uuid[7] = 0x0a * (i % 2);
+                (uuid, ((4 - i as u64) * 30).to_string())
+            })
+            .collect();
+
+        let calls: Vec<_> = requests.iter().cloned().map(|req| client.call(req)).collect();
+        let responses = core.run(future::join_all(calls)).unwrap();
+
+        assert_eq!(responses, requests);
+        assert!(client.in_flight.borrow().is_empty());
+    }
+}
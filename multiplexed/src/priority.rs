@@ -0,0 +1,237 @@
+//! Request priorities honored by server-side scheduling.
+//!
+//! A request may carry a priority between 0 and 255, encoded as a
+//! `[prio=<n>] ` prefix on the line. Requests without a prefix have priority 0.
+//!
+//! The `Prioritize` middleware limits how many requests the inner service
+//! processes at once. When the service is busy, requests are queued, and once
+//! a slot frees up, the queued request with the highest priority is dispatched
+//! first. Requests with the same priority are dispatched in the order they were
+//! received.
+
+use semaphore::{Semaphore, Permit, Queue};
+
+use futures::Future;
+use futures::sync::oneshot;
+use tokio_service::{Service, NewService};
+
+use std::io;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::rc::Rc;
+
+const PREFIX: &'static str = "[prio=";
+
+/// A `Service` middleware dispatching queued requests by priority.
+pub struct Prioritize<T> {
+    inner: Rc<T>,
+    semaphore: Semaphore<Scheduler>,
+}
+
+/// Builds a `Prioritize` service for each new connection.
+pub struct NewPrioritize<T> {
+    inner: T,
+    max_concurrent: usize,
+}
+
+/// Queued requests, by priority
+struct Scheduler {
+    // Used to order requests with the same priority
+    next_seq: u64,
+    queue: BinaryHeap<Waiting>,
+}
+
+/// A queued request, handed a slot through `tx` once it may be processed
+struct Waiting {
+    priority: u8,
+    seq: u64,
+    tx: oneshot::Sender<Permit<Scheduler>>,
+}
+
+/// Prefix `req` with `priority`, to be decoded by the `Prioritize` middleware
+/// on the server.
+pub fn encode(req: &str, priority: u8) -> String {
+    format!("{}{}] {}", PREFIX, priority, req)
+}
+
+/// Split a request into its priority and the actual request line.
+///
+/// Lines without a well formed prefix have priority 0 and are returned
+/// unchanged.
+pub fn decode(req: String) -> (u8, String) {
+    if !req.starts_with(PREFIX) {
+        return (0, req);
+    }
+
+    let priority = req[PREFIX.len()..].find("] ").and_then(|end| {
+        let end = PREFIX.len() + end;
+
+        req[PREFIX.len()..end].parse::<u8>().ok()
+            .map(|priority| (priority, end + 2))
+    });
+
+    match priority {
+        Some((priority, start)) => (priority, req[start..].to_string()),
+        None => (0, req),
+    }
+}
+
+impl<T> Prioritize<T> {
+    /// Create a new `Prioritize`, processing at most `max_concurrent`
+    /// requests at once.
+    pub fn new(inner: T, max_concurrent: usize) -> Prioritize<T> {
+        assert!(max_concurrent > 0, "max_concurrent must be at least 1");
+
+        Prioritize {
+            inner: Rc::new(inner),
+            semaphore: Semaphore::new(max_concurrent, Scheduler {
+                next_seq: 0,
+                queue: BinaryHeap::new(),
+            }),
+        }
+    }
+}
+
+impl<T> Service for Prioritize<T>
+    where T: Service<Request = String, Response = String, Error = io::Error> + 'static,
+          T::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        let (priority, req) = decode(req);
+
+        let inner = self.inner.clone();
+
+        Box::new(self.semaphore.acquire(priority).and_then(move |slot| {
+            // The request now holds a slot, release it once done
+            inner.call(req)
+                .then(move |res| {
+                    drop(slot);
+                    res
+                })
+        }))
+    }
+}
+
+impl<T> NewPrioritize<T> {
+    /// Create a new `NewPrioritize`
+    pub fn new(inner: T, max_concurrent: usize) -> NewPrioritize<T> {
+        NewPrioritize {
+            inner: inner,
+            max_concurrent: max_concurrent,
+        }
+    }
+}
+
+impl<T> NewService for NewPrioritize<T>
+    where T: NewService<Request = String, Response = String, Error = io::Error>,
+          T::Instance: 'static,
+          <T::Instance as Service>::Future: 'static
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Instance = Prioritize<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = try!(self.inner.new_service());
+        Ok(Prioritize::new(inner, self.max_concurrent))
+    }
+}
+
+impl Queue for Scheduler {
+    type Key = u8;
+
+    fn push(&mut self, priority: u8, tx: oneshot::Sender<Permit<Scheduler>>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        self.queue.push(Waiting {
+            priority: priority,
+            seq: seq,
+            tx: tx,
+        });
+    }
+
+    fn pop(&mut self) -> Option<oneshot::Sender<Permit<Scheduler>>> {
+        // The highest priority request is handed the slot first
+        self.queue.pop().map(|waiting| waiting.tx)
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+impl Ord for Waiting {
+    fn cmp(&self, other: &Waiting) -> Ordering {
+        // Higher priorities first, then earlier requests first
+        self.priority.cmp(&other.priority)
+            .then(other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Waiting {
+    fn partial_cmp(&self, other: &Waiting) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Waiting {
+    fn eq(&self, other: &Waiting) -> bool {
+        self.priority == other.priority && self.seq == other.seq
+    }
+}
+
+impl Eq for Waiting {}
+
+#[cfg(test)]
+mod test {
+    use super::{encode, Prioritize};
+
+    use futures::future;
+    use tokio_service::Service;
+
+    use std::io;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn dropped_calls_free_up_their_slot() {
+        let service = Prioritize::new(Echo, 1);
+
+        let first = service.call("first".to_string());
+        let low = service.call(encode("low", 1));
+        let high = service.call(encode("high", 2));
+
+        assert_eq!(service.semaphore.active(), 1);
+        assert_eq!(service.semaphore.queued(), 2);
+
+        // The slot is handed over to the highest priority call
+        drop(first);
+        assert_eq!(service.semaphore.queued(), 1);
+
+        // Which frees it up for the other one without ever being polled
+        drop(high);
+        assert_eq!(service.semaphore.queued(), 0);
+
+        drop(low);
+        assert_eq!(service.semaphore.active(), 0);
+    }
+}
@@ -0,0 +1,156 @@
+//! Detection of request IDs reused while still in flight.
+//!
+//! Responses are matched with requests by their ID, so a client reusing the
+//! ID of a request that has not been responded to yet makes the two
+//! requests indistinguishable. The `RejectDuplicateIds` transport middleware,
+//! used by `serve`, tracks the IDs of the requests in flight and fails the
+//! connection with `InvalidData` when a duplicate is received. Multiplexed
+//! transports cannot fail a single request, and once a client is confused
+//! about its IDs, none of its responses can be trusted anyway.
+
+use futures::{Stream, Sink, Poll, Async, AsyncSink, StartSend};
+use tokio_proto::multiplex::RequestId;
+
+use std::io;
+use std::collections::HashSet;
+
+/// Server transport middleware rejecting duplicate request IDs.
+pub struct RejectDuplicateIds<T> {
+    // The upstream transport
+    upstream: T,
+    // IDs of the requests received and not yet responded to
+    active: HashSet<RequestId>,
+}
+
+impl<T> RejectDuplicateIds<T> {
+    /// Wrap `upstream`, tracking the IDs of the requests in flight
+    pub fn new(upstream: T) -> RejectDuplicateIds<T> {
+        RejectDuplicateIds {
+            upstream: upstream,
+            active: HashSet::new(),
+        }
+    }
+
+    /// Returns the number of requests received and not yet responded to
+    pub fn in_flight(&self) -> usize {
+        self.active.len()
+    }
+}
+
+impl<T> Stream for RejectDuplicateIds<T>
+    where T: Stream<Item = (RequestId, String), Error = io::Error>,
+{
+    type Item = (RequestId, String);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<(RequestId, String)>, io::Error> {
+        match try_ready!(self.upstream.poll()) {
+            Some((id, req)) => {
+                if !self.active.insert(id) {
+                    let msg = format!("duplicate request id {}", id);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+                }
+
+                Ok(Async::Ready(Some((id, req))))
+            }
+            None => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+impl<T> Sink for RejectDuplicateIds<T>
+    where T: Sink<SinkItem = (RequestId, String), SinkError = io::Error>,
+{
+    type SinkItem = (RequestId, String);
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: (RequestId, String)) -> StartSend<(RequestId, String), io::Error> {
+        let id = item.0;
+        let res = try!(self.upstream.start_send(item));
+
+        if let AsyncSink::Ready = res {
+            // The ID may be reused from now on
+            self.active.remove(&id);
+        }
+
+        Ok(res)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.upstream.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        self.upstream.close()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RejectDuplicateIds;
+
+    use futures::{Future, Stream, Sink, Poll, Async, AsyncSink, StartSend};
+    use tokio_proto::multiplex::RequestId;
+
+    use std::io;
+    use std::collections::VecDeque;
+
+    /// Yields the queued requests, and accepts every response
+    struct Mock {
+        requests: VecDeque<(RequestId, String)>,
+    }
+
+    impl Stream for Mock {
+        type Item = (RequestId, String);
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Option<(RequestId, String)>, io::Error> {
+            Ok(Async::Ready(self.requests.pop_front()))
+        }
+    }
+
+    impl Sink for Mock {
+        type SinkItem = (RequestId, String);
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, _: (RequestId, String)) -> StartSend<(RequestId, String), io::Error> {
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    fn transport(requests: Vec<(RequestId, &str)>) -> RejectDuplicateIds<Mock> {
+        let requests = requests.into_iter().map(|(id, req)| (id, req.to_string())).collect();
+        RejectDuplicateIds::new(Mock { requests: requests })
+    }
+
+    #[test]
+    fn duplicate_id_in_flight_is_rejected() {
+        let mut transport = transport(vec![(1, "first"), (2, "other"), (1, "duplicate")]);
+
+        assert_eq!(transport.poll().unwrap(), Async::Ready(Some((1, "first".to_string()))));
+        assert_eq!(transport.poll().unwrap(), Async::Ready(Some((2, "other".to_string()))));
+        assert_eq!(transport.in_flight(), 2);
+
+        let err = transport.poll().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("duplicate request id 1"));
+    }
+
+    #[test]
+    fn id_may_be_reused_once_responded_to() {
+        let transport = transport(vec![(1, "first"), (1, "again")]);
+
+        let (req, transport) = transport.into_future().map_err(|(e, _)| e).wait().unwrap();
+        assert_eq!(req, Some((1, "first".to_string())));
+
+        let transport = transport.send((1, "response".to_string())).wait().unwrap();
+        assert_eq!(transport.in_flight(), 0);
+
+        let (req, _) = transport.into_future().map_err(|(e, _)| e).wait().unwrap();
+        assert_eq!(req, Some((1, "again".to_string())));
+    }
+}
@@ -0,0 +1,474 @@
+//! Releasing the ID of requests that are not responded to in time, or whose
+//! call was dropped.
+//!
+//! tokio-proto matches responses with requests by their ID, and only releases
+//! the ID of a request once its response is received. Dropping the response
+//! future of a request that is never responded to does not release anything.
+//!
+//! The `RequestTimeouts` client transport middleware tracks the deadline of
+//! every request sent. Once a deadline passes, it answers the request itself
+//! with a `[error timed out]` response, so that tokio-proto releases the ID
+//! and `Client` fails the call with `TimedOut`.
+//!
+//! Calls are also registered with `Calls` by the `Cancellable` middleware of
+//! `Client`. When the response future of a call is dropped, the transport
+//! answers its request right away, which releases the ID.
+//!
+//! In both cases, the ID is remembered until the late response arrives, if
+//! ever, and that response is dropped.
+
+use {CANCELLED, TIMED_OUT};
+
+use futures::{Future, Stream, Sink, Poll, Async, AsyncSink, StartSend};
+use futures::task::{self, Task};
+use tokio_proto::multiplex::RequestId;
+use tokio_service::Service;
+use tokio_timer::{Timer, Sleep};
+
+use std::io;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Client transport middleware answering requests once their deadline passes
+/// or their call is dropped.
+pub struct RequestTimeouts<T> {
+    // The upstream transport
+    upstream: T,
+    // The timeout applied to requests being sent, shared with `Client`
+    config: Rc<RefCell<Option<(Timer, Duration)>>>,
+    // The calls issued by `Client`
+    calls: Rc<RefCell<Calls>>,
+    // Deadlines of the requests sent, in the order they were sent
+    deadlines: VecDeque<(RequestId, Instant)>,
+    // IDs of the requests sent and not yet responded to, with the token of
+    // their call
+    pending: HashMap<RequestId, Option<u64>>,
+    // IDs of the pending requests, by the token of their call
+    ids: HashMap<u64, RequestId>,
+    // IDs of the requests that were answered by this transport, whose late
+    // response is dropped
+    abandoned: HashSet<RequestId>,
+    // Fires at the deadline of the oldest pending request
+    sleep: Option<(Instant, Sleep)>,
+}
+
+/// The calls issued by `Client`, shared with its transport.
+///
+/// tokio-proto sends requests in the order they are issued, so the transport
+/// matches the requests it sends with the calls in that order.
+pub struct Calls {
+    // Token of the next call
+    next: u64,
+    // Tokens of the calls whose request was not sent yet
+    unsent: VecDeque<u64>,
+    // Tokens of the calls dropped before being answered
+    cancelled: HashSet<u64>,
+    // The transport task, notified when a call is dropped
+    task: Option<Task>,
+}
+
+/// Client service middleware registering every call with `Calls`, and
+/// cancelling it when its response future is dropped.
+pub struct Cancellable<T> {
+    inner: T,
+    calls: Rc<RefCell<Calls>>,
+}
+
+/// Cancels a call when dropped while still armed
+struct CancelOnDrop {
+    token: u64,
+    calls: Rc<RefCell<Calls>>,
+    armed: bool,
+}
+
+impl<T> RequestTimeouts<T> {
+    /// Wrap `upstream`, applying the timeout found in `config` when each
+    /// request is sent. No timeout is applied while it is `None`.
+    pub fn new(upstream: T,
+               config: Rc<RefCell<Option<(Timer, Duration)>>>,
+               calls: Rc<RefCell<Calls>>) -> RequestTimeouts<T> {
+        RequestTimeouts {
+            upstream: upstream,
+            config: config,
+            calls: calls,
+            deadlines: VecDeque::new(),
+            pending: HashMap::new(),
+            ids: HashMap::new(),
+            abandoned: HashSet::new(),
+            sleep: None,
+        }
+    }
+
+    /// Forget a pending request, which was answered
+    fn complete(&mut self, id: RequestId) {
+        if let Some(Some(token)) = self.pending.remove(&id) {
+            self.ids.remove(&token);
+        }
+    }
+
+    /// Returns the ID of a pending request whose call was dropped
+    fn poll_cancelled(&mut self) -> Option<RequestId> {
+        let token = {
+            let mut calls = self.calls.borrow_mut();
+            let calls = &mut *calls;
+            let ids = &self.ids;
+            let unsent = &calls.unsent;
+
+            // Forget the calls that were answered before being dropped
+            calls.cancelled.retain(|token| ids.contains_key(token) || unsent.contains(token));
+
+            match calls.cancelled.iter().cloned().find(|token| ids.contains_key(token)) {
+                Some(token) => {
+                    calls.cancelled.remove(&token);
+                    token
+                }
+                None => return None,
+            }
+        };
+
+        let id = self.ids[&token];
+        self.complete(id);
+
+        Some(id)
+    }
+
+    /// Returns the ID of the oldest pending request once its deadline has
+    /// passed
+    fn poll_expired(&mut self) -> Poll<RequestId, io::Error> {
+        // Skip the requests that were responded to
+        while let Some(&(id, _)) = self.deadlines.front() {
+            if self.pending.contains_key(&id) {
+                break;
+            }
+
+            self.deadlines.pop_front();
+        }
+
+        let (id, deadline) = match self.deadlines.front() {
+            Some(&front) => front,
+            None => {
+                self.sleep = None;
+                return Ok(Async::NotReady);
+            }
+        };
+
+        let now = Instant::now();
+
+        if deadline > now {
+            let armed = match self.sleep {
+                Some((at, _)) => at == deadline,
+                None => false,
+            };
+
+            if !armed {
+                let sleep = match *self.config.borrow() {
+                    Some((ref timer, _)) => timer.sleep(deadline - now),
+                    None => return Ok(Async::NotReady),
+                };
+
+                self.sleep = Some((deadline, sleep));
+            }
+
+            if let Some((_, ref mut sleep)) = self.sleep {
+                match sleep.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(())) => {}
+                    Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+                }
+            }
+        }
+
+        self.deadlines.pop_front();
+        self.sleep = None;
+
+        Ok(Async::Ready(id))
+    }
+}
+
+impl<T> Stream for RequestTimeouts<T>
+    where T: Stream<Item = (RequestId, String), Error = io::Error>,
+{
+    type Item = (RequestId, String);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<(RequestId, String)>, io::Error> {
+        self.calls.borrow_mut().task = Some(task::current());
+
+        loop {
+            match try!(self.upstream.poll()) {
+                Async::Ready(Some((id, resp))) => {
+                    if self.abandoned.remove(&id) {
+                        // The request was already answered
+                        continue;
+                    }
+
+                    self.complete(id);
+                    return Ok(Async::Ready(Some((id, resp))));
+                }
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => break,
+            }
+        }
+
+        // Answer the requests, which releases their ID
+        if let Some(id) = self.poll_cancelled() {
+            self.abandoned.insert(id);
+            return Ok(Async::Ready(Some((id, CANCELLED.to_string()))));
+        }
+
+        let id = try_ready!(self.poll_expired());
+
+        self.complete(id);
+        self.abandoned.insert(id);
+
+        Ok(Async::Ready(Some((id, TIMED_OUT.to_string()))))
+    }
+}
+
+impl<T> Sink for RequestTimeouts<T>
+    where T: Sink<SinkItem = (RequestId, String), SinkError = io::Error>,
+{
+    type SinkItem = (RequestId, String);
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: (RequestId, String)) -> StartSend<(RequestId, String), io::Error> {
+        let id = item.0;
+        let res = try!(self.upstream.start_send(item));
+
+        if let AsyncSink::Ready = res {
+            let token = {
+                let mut calls = self.calls.borrow_mut();
+                let token = calls.unsent.pop_front();
+
+                if let Some(token) = token {
+                    if calls.cancelled.contains(&token) {
+                        // The call was dropped before its request was sent
+                        task::current().notify();
+                    }
+                }
+
+                token
+            };
+
+            if let Some(token) = token {
+                self.ids.insert(token, id);
+            }
+
+            self.pending.insert(id, token);
+
+            if let Some((_, timeout)) = *self.config.borrow() {
+                self.deadlines.push_back((id, Instant::now() + timeout));
+
+                // The stream must be polled again for the deadline to be
+                // armed, even if nothing is read from the connection
+                task::current().notify();
+            }
+        }
+
+        Ok(res)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.upstream.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        self.upstream.close()
+    }
+}
+
+impl Calls {
+    /// Create a new, empty, `Calls`
+    pub fn new() -> Calls {
+        Calls {
+            next: 0,
+            unsent: VecDeque::new(),
+            cancelled: HashSet::new(),
+            task: None,
+        }
+    }
+
+    /// Register a call whose request is about to be sent, returning its token
+    fn issue(&mut self) -> u64 {
+        let token = self.next;
+        self.next += 1;
+        self.unsent.push_back(token);
+        token
+    }
+
+    /// Cancel the call with the given token
+    fn cancel(&mut self, token: u64) {
+        self.cancelled.insert(token);
+
+        if let Some(ref task) = self.task {
+            task.notify();
+        }
+    }
+}
+
+impl<T> Cancellable<T> {
+    /// Wrap `inner`, registering its calls with `calls`
+    pub fn new(inner: T, calls: Rc<RefCell<Calls>>) -> Cancellable<T> {
+        Cancellable {
+            inner: inner,
+            calls: calls,
+        }
+    }
+}
+
+impl<T> Service for Cancellable<T>
+    where T: Service<Request = String, Response = String, Error = io::Error>,
+          T::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        let token = self.calls.borrow_mut().issue();
+
+        let mut guard = CancelOnDrop {
+            token: token,
+            calls: self.calls.clone(),
+            armed: true,
+        };
+
+        // The guard is disarmed once the response is received, and cancels
+        // the call if the response future is dropped before that.
+        Box::new(self.inner.call(req)
+            .then(move |res| {
+                guard.armed = false;
+                res
+            }))
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if self.armed {
+            self.calls.borrow_mut().cancel(self.token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Calls, RequestTimeouts};
+    use {CANCELLED, TIMED_OUT};
+
+    use futures::{future, Future, Stream, Sink, Poll, Async, AsyncSink, StartSend};
+    use tokio_proto::multiplex::RequestId;
+    use tokio_timer::Timer;
+
+    use std::io;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    /// A transport reading the responses queued in `responses`
+    struct Mock {
+        responses: VecDeque<(RequestId, String)>,
+    }
+
+    impl Stream for Mock {
+        type Item = (RequestId, String);
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Option<(RequestId, String)>, io::Error> {
+            match self.responses.pop_front() {
+                Some(resp) => Ok(Async::Ready(Some(resp))),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    impl Sink for Mock {
+        type SinkItem = (RequestId, String);
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, _: (RequestId, String)) -> StartSend<(RequestId, String), io::Error> {
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn stale_entries_are_removed() {
+        let timeout = Duration::from_millis(200);
+        let config = Rc::new(RefCell::new(Some((Timer::default(), timeout))));
+
+        let calls = Rc::new(RefCell::new(Calls::new()));
+        let mut transport = RequestTimeouts::new(Mock { responses: VecDeque::new() }, config, calls);
+
+        let transport = future::lazy(move || {
+            try!(transport.start_send((1, "never".to_string())));
+            try!(transport.start_send((2, "hello".to_string())));
+
+            Ok::<_, io::Error>(transport)
+        }).wait().unwrap();
+
+        let mut transport = transport;
+        transport.upstream.responses.push_back((2, "hello".to_string()));
+
+        // The answered request goes through
+        let (resp, transport) = transport.into_future().wait().map_err(|(e, _)| e).unwrap();
+        assert_eq!(resp, Some((2, "hello".to_string())));
+
+        // The other one is answered once its deadline passes
+        let (resp, mut transport) = transport.into_future().wait().map_err(|(e, _)| e).unwrap();
+        assert_eq!(resp, Some((1, TIMED_OUT.to_string())));
+        assert!(transport.pending.is_empty());
+        assert!(transport.deadlines.is_empty());
+
+        // Its late response is dropped, and the ID forgotten
+        transport.upstream.responses.push_back((1, "late".to_string()));
+        transport.upstream.responses.push_back((3, "other".to_string()));
+
+        let (resp, transport) = transport.into_future().wait().map_err(|(e, _)| e).unwrap();
+        assert_eq!(resp, Some((3, "other".to_string())));
+        assert!(transport.abandoned.is_empty());
+    }
+
+    #[test]
+    fn dropped_calls_release_their_id() {
+        let config = Rc::new(RefCell::new(None));
+        let calls = Rc::new(RefCell::new(Calls::new()));
+
+        let mut transport = RequestTimeouts::new(Mock { responses: VecDeque::new() }, config, calls.clone());
+
+        let token = calls.borrow_mut().issue();
+
+        let transport = future::lazy(move || {
+            try!(transport.start_send((1, "slow".to_string())));
+            Ok::<_, io::Error>(transport)
+        }).wait().unwrap();
+
+        assert_eq!(transport.ids.len(), 1);
+
+        // The call is dropped, its request is answered right away
+        calls.borrow_mut().cancel(token);
+
+        let (resp, mut transport) = transport.into_future().wait().map_err(|(e, _)| e).unwrap();
+        assert_eq!(resp, Some((1, CANCELLED.to_string())));
+        assert!(transport.pending.is_empty());
+        assert!(transport.ids.is_empty());
+
+        // The late response is dropped, and nothing is left behind
+        transport.upstream.responses.push_back((1, "late".to_string()));
+        transport.upstream.responses.push_back((2, "other".to_string()));
+
+        let (resp, transport) = transport.into_future().wait().map_err(|(e, _)| e).unwrap();
+        assert_eq!(resp, Some((2, "other".to_string())));
+        assert!(transport.abandoned.is_empty());
+        assert!(calls.borrow().cancelled.is_empty());
+        assert!(calls.borrow().unsent.is_empty());
+    }
+}
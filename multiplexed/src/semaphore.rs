@@ -0,0 +1,115 @@
+//! A semaphore queueing the requests waiting for a permit.
+//!
+//! The `concurrency`, `priority` and `class_of_service` middlewares all cap
+//! the number of concurrent calls to the inner service, and only differ in the
+//! order they dispatch queued requests in. That order is given by the `Queue`
+//! the semaphore is built with.
+
+use futures::{future, Future};
+use futures::sync::oneshot;
+
+use std::io;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Orders the requests waiting for a permit
+pub trait Queue: Sized {
+    /// What a request is queued by, such as its priority
+    type Key;
+
+    /// Queue a request, to be handed a permit through `tx`
+    fn push(&mut self, key: Self::Key, tx: oneshot::Sender<Permit<Self>>);
+
+    /// Pop the next request to hand a permit to
+    fn pop(&mut self) -> Option<oneshot::Sender<Permit<Self>>>;
+
+    /// Returns the number of queued requests
+    fn len(&self) -> usize;
+}
+
+/// A semaphore handing over its permits in the order of `Q`
+pub struct Semaphore<Q> {
+    state: Rc<RefCell<State<Q>>>,
+}
+
+struct State<Q> {
+    // Number of permits held, including those being handed over
+    active: usize,
+    max: usize,
+    queue: Q,
+}
+
+/// Releases a permit when dropped
+pub struct Permit<Q: Queue> {
+    // `None` once the permit was handed over to another request
+    state: Option<Rc<RefCell<State<Q>>>>,
+}
+
+impl<Q: Queue + 'static> Semaphore<Q> {
+    /// Create a new `Semaphore` with `max` permits
+    pub fn new(max: usize, queue: Q) -> Semaphore<Q> {
+        Semaphore {
+            state: Rc::new(RefCell::new(State {
+                active: 0,
+                max: max,
+                queue: queue,
+            })),
+        }
+    }
+
+    /// Acquire a permit, waiting in the queue under `key` if none is
+    /// available.
+    ///
+    /// The permit is created as soon as it is acquired, so that it is
+    /// released even if the returned future is dropped before it is polled.
+    pub fn acquire(&self, key: Q::Key) -> Box<Future<Item = Permit<Q>, Error = io::Error>> {
+        let mut state = self.state.borrow_mut();
+
+        if state.active < state.max {
+            state.active += 1;
+            return Box::new(future::ok(Permit { state: Some(self.state.clone()) }));
+        }
+
+        // Wait for a permit to be handed over
+        let (tx, rx) = oneshot::channel();
+        state.queue.push(key, tx);
+
+        Box::new(rx.map_err(|_| io::Error::new(io::ErrorKind::Other, "semaphore dropped")))
+    }
+
+    /// Returns the number of permits currently held
+    pub fn active(&self) -> usize {
+        self.state.borrow().active
+    }
+
+    /// Returns the number of requests waiting for a permit
+    pub fn queued(&self) -> usize {
+        self.state.borrow().queue.len()
+    }
+}
+
+impl<Q: Queue> Drop for Permit<Q> {
+    fn drop(&mut self) {
+        let rc = match self.state.take() {
+            Some(rc) => rc,
+            None => return,
+        };
+
+        let mut state = rc.borrow_mut();
+
+        // Hand the permit to the next request that is still waiting. If that
+        // request is dropped before using it, the permit is released along
+        // with it.
+        while let Some(tx) = state.queue.pop() {
+            match tx.send(Permit { state: Some(rc.clone()) }) {
+                Ok(()) => return,
+                Err(mut permit) => {
+                    // The request is gone, don't release the permit twice
+                    permit.state = None;
+                }
+            }
+        }
+
+        state.active -= 1;
+    }
+}
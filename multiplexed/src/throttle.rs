@@ -0,0 +1,203 @@
+//! Capping the number of requests in flight on a connection.
+//!
+//! With a multiplexed protocol, a single connection may send thousands of
+//! requests without waiting for responses, and each of them is dispatched to
+//! the service right away. The `ConcurrencyLimitService` middleware of the
+//! `concurrency` module limits how many calls run at once, but still accepts
+//! every request, queueing them in memory.
+//!
+//! As services cannot refuse calls, backpressure has to be applied by the
+//! transport instead: the `Throttle` transport middleware stops reading
+//! requests once `max_in_flight` of them have been read and not yet responded
+//! to. Further requests stay in the socket buffers, until the client stops
+//! sending them.
+
+use {LineCodec, Validate};
+use request_ids::RejectDuplicateIds;
+
+use futures::{Stream, Sink, Poll, Async, AsyncSink, StartSend};
+use futures::task::{self, Task};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
+use tokio_proto::TcpServer;
+use tokio_proto::multiplex::{RequestId, ServerProto};
+use tokio_service::NewService;
+
+use std::io;
+use std::net::SocketAddr;
+
+/// Server transport middleware capping the number of requests in flight.
+pub struct Throttle<T> {
+    // The upstream transport
+    upstream: T,
+    in_flight: usize,
+    max_in_flight: usize,
+    // The task blocked on reading while at the limit
+    blocked: Option<Task>,
+}
+
+/// Protocol definition for a server throttling its connections
+struct ThrottleProto {
+    max_in_flight: usize,
+}
+
+/// Start a server reading at most `max_in_flight` requests ahead of the
+/// responses on each connection, listening for connections on `addr`.
+pub fn serve_throttled<T>(addr: SocketAddr, new_service: T, max_in_flight: usize)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    assert!(max_in_flight > 0, "max_in_flight must be at least 1");
+
+    let new_service = Validate { inner: new_service };
+
+    TcpServer::new(ThrottleProto { max_in_flight: max_in_flight }, addr)
+        .serve(new_service);
+}
+
+impl<T> Throttle<T> {
+    /// Wrap `upstream`, reading at most `max_in_flight` requests ahead of
+    /// the responses.
+    pub fn new(upstream: T, max_in_flight: usize) -> Throttle<T> {
+        Throttle {
+            upstream: upstream,
+            in_flight: 0,
+            max_in_flight: max_in_flight,
+            blocked: None,
+        }
+    }
+
+    /// Returns the number of requests read and not yet responded to
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+}
+
+impl<T> Stream for Throttle<T>
+    where T: Stream<Item = (RequestId, String), Error = io::Error>,
+{
+    type Item = (RequestId, String);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<(RequestId, String)>, io::Error> {
+        if self.in_flight >= self.max_in_flight {
+            // Stop reading until a response is sent
+            self.blocked = Some(task::current());
+            return Ok(Async::NotReady);
+        }
+
+        let req = try_ready!(self.upstream.poll());
+
+        if req.is_some() {
+            self.in_flight += 1;
+        }
+
+        Ok(Async::Ready(req))
+    }
+}
+
+impl<T> Sink for Throttle<T>
+    where T: Sink<SinkItem = (RequestId, String), SinkError = io::Error>,
+{
+    type SinkItem = (RequestId, String);
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: (RequestId, String)) -> StartSend<(RequestId, String), io::Error> {
+        let res = try!(self.upstream.start_send(item));
+
+        if let AsyncSink::Ready = res {
+            self.in_flight -= 1;
+
+            // Resume reading requests
+            if let Some(task) = self.blocked.take() {
+                task.notify();
+            }
+        }
+
+        Ok(res)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.upstream.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        self.upstream.close()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for ThrottleProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = Throttle<RejectDuplicateIds<Framed<T, LineCodec>>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        let transport = RejectDuplicateIds::new(io.framed(LineCodec));
+        Ok(Throttle::new(transport, self.max_in_flight))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Throttle;
+
+    use futures::{future, Future, Stream, Sink, Poll, Async, AsyncSink, StartSend};
+    use tokio_proto::multiplex::RequestId;
+
+    use std::io;
+
+    /// Yields requests with increasing IDs, and accepts every response
+    struct Mock {
+        next_id: RequestId,
+    }
+
+    impl Stream for Mock {
+        type Item = (RequestId, String);
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Option<(RequestId, String)>, io::Error> {
+            let id = self.next_id;
+            self.next_id += 1;
+
+            Ok(Async::Ready(Some((id, "hello".to_string()))))
+        }
+    }
+
+    impl Sink for Mock {
+        type SinkItem = (RequestId, String);
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, _: (RequestId, String)) -> StartSend<(RequestId, String), io::Error> {
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn request_over_the_limit_is_delayed() {
+        // Polling the transport requires a task
+        future::lazy(|| {
+            let mut transport = Throttle::new(Mock { next_id: 0 }, 2);
+
+            assert!(transport.poll().unwrap().is_ready());
+            assert!(transport.poll().unwrap().is_ready());
+            assert_eq!(transport.in_flight(), 2);
+
+            // The third request is not read while both are in flight
+            assert!(transport.poll().unwrap().is_not_ready());
+
+            assert!(transport.start_send((0, "response".to_string())).unwrap().is_ready());
+            assert_eq!(transport.in_flight(), 1);
+
+            // Responding made room for it
+            assert_eq!(transport.poll().unwrap(), Async::Ready(Some((2, "hello".to_string()))));
+            assert_eq!(transport.in_flight(), 2);
+
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+}
@@ -11,6 +11,10 @@
 //!
 //! To do this, we need to implement a `ClientLineProto` and a `ServerLineProto`
 //! that handle the handshakes on the client and server side respectively.
+//!
+//! A client that connects but never sends its handshake would tie up the
+//! connection forever, so the server gives up if the handshake does not
+//! complete within a timeout.
 
 extern crate tokio_line as line;
 
@@ -20,6 +24,7 @@ extern crate tokio_io;
 extern crate tokio_core;
 extern crate tokio_proto;
 extern crate tokio_service;
+extern crate tokio_timer;
 extern crate service_fn;
 
 use futures::future;
@@ -31,6 +36,7 @@ use tokio_core::reactor::Core;
 use tokio_proto::{TcpClient, TcpServer};
 use tokio_proto::pipeline::{ClientProto, ServerProto};
 use tokio_service::{Service, NewService};
+use tokio_timer::Timer;
 
 use service_fn::service_fn;
 
@@ -39,7 +45,22 @@ use std::net::SocketAddr;
 use std::time::Duration;
 
 struct ClientLineProto;
-struct ServerLineProto;
+
+struct ServerLineProto {
+    timer: Timer,
+    // How long the client has to complete the handshake
+    timeout: Duration,
+}
+
+impl ServerLineProto {
+    /// Drop connections that do not complete the handshake within `timeout`
+    fn with_timeout(timeout: Duration) -> ServerLineProto {
+        ServerLineProto {
+            timer: Timer::default(),
+            timeout: timeout,
+        }
+    }
+}
 
 /// Start a server, listening for connections on `addr`.
 ///
@@ -56,7 +77,7 @@ pub fn serve<T>(addr: SocketAddr, new_service: T)
 
     // Use the tokio-proto TCP server builder, this will handle creating a
     // reactor instance and other details needed to run a server.
-    TcpServer::new(ServerLineProto, addr)
+    TcpServer::new(ServerLineProto::with_timeout(Duration::from_secs(5)), addr)
         .serve(new_service);
 }
 
@@ -70,7 +91,7 @@ impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for ServerLineProto {
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
         // Construct the line-based transport
-        let transport = io.framed(line::LineCodec);
+        let transport = io.framed(line::LineCodec::new());
 
         // The handshake requires that the client sends `You ready?`, so wait to
         // receive that line. If anything else is sent, error out the connection
@@ -96,7 +117,8 @@ impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for ServerLineProto {
                 }
             });
 
-        Box::new(handshake)
+        // Error out the connection with `TimedOut` if the client is too slow
+        Box::new(self.timer.timeout(handshake, self.timeout))
     }
 }
 
@@ -110,7 +132,7 @@ impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for ClientLineProto {
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
         // Construct the line-based transport
-        let transport = io.framed(line::LineCodec);
+        let transport = io.framed(line::LineCodec::new());
 
         // Send the handshake frame to the server.
         let handshake = transport.send("You ready?".to_string())
@@ -186,3 +208,56 @@ pub fn main() {
             })
     ).unwrap();
 }
+
+#[cfg(test)]
+mod test {
+    use super::ServerLineProto;
+
+    use line::Validate;
+
+    use futures::{Future, Stream};
+    use tokio_io::io::read_to_end;
+    use tokio_core::net::{TcpListener, TcpStream};
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_timer::Timer;
+
+    use service_fn::service_fn;
+
+    use std::io;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn stalled_client_is_dropped() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            let service = service_fn(|msg: String| Ok::<_, io::Error>(msg));
+
+            ServerLineProto::with_timeout(Duration::from_millis(200))
+                .bind_server(&handle2, socket, Validate::new(service));
+
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let start = Instant::now();
+
+        // Connect, never send the handshake, and read until the server closes
+        // the connection. Give up if it takes far longer than the timeout.
+        let client = TcpStream::connect(&addr, &handle)
+            .and_then(|socket| read_to_end(socket, vec![]));
+
+        let (_, received) = core.run(Timer::default().timeout(client, Duration::from_secs(5))).unwrap();
+
+        assert!(received.is_empty());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
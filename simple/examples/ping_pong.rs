@@ -148,7 +148,7 @@ impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for LineProto {
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
         Ok(PingPong {
-            upstream: io.framed(line::LineCodec),
+            upstream: io.framed(line::LineCodec::new()),
             pongs_remaining: 0,
         })
     }
@@ -39,7 +39,7 @@ pub fn server() {
     let server = listener.incoming().for_each(move |(socket, _)| {
         // Use the `Io::framed` helper to get a transport from a socket. The
         // `LineCodec` handles encoding / decoding frames.
-        let transport = socket.framed(LineCodec);
+        let transport = socket.framed(LineCodec::new());
 
         // The transport is a `Stream<Item = String>`. So we can now operate at
         // at the frame level. For each received line, write the string to
@@ -79,7 +79,7 @@ pub fn main() {
         .and_then(|socket| {
             // Once the socket has been established, use the `framed` helper to
             // create a transport.
-            let transport = socket.framed(LineCodec);
+            let transport = socket.framed(LineCodec::new());
 
             // We're just going to send a few "log" messages to the remote
             let lines_to_send: Vec<Result<String, io::Error>> = vec![
@@ -0,0 +1,178 @@
+//! Limiting the rate at which new connections are accepted.
+//!
+//! This protects a server against connection floods, independently of how
+//! many connections are open at a time. The accept loop is paced by a token
+//! bucket holding up to one second worth of connections: each accepted
+//! connection takes a token, and tokens are refilled at `rate` per second.
+//! When the bucket is empty, accepting pauses until a token is available.
+//! Meanwhile, connection attempts are queued in the listen backlog of the OS.
+
+use {LineProto, Validate};
+
+use futures::{future, Future, Stream};
+use futures::future::Either;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+use tokio_proto::BindServer;
+use tokio_service::NewService;
+use tokio_timer::Timer;
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Token bucket pacing accepted connections
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    // May go negative, when connections are waiting for tokens
+    tokens: f64,
+    last: Instant,
+}
+
+/// Start a server accepting at most `rate` new connections per second,
+/// listening on `addr`.
+///
+/// This function will block as long as the server is running. Unlike `serve`,
+/// all connections are handled on the current thread.
+///
+/// # Panics
+///
+/// Panics if `rate` is not positive.
+pub fn serve_with_accept_rate<T>(addr: SocketAddr, new_service: T, rate: f64) -> io::Result<()>
+    where T: NewService<Request = String, Response = String, Error = io::Error> + 'static,
+          T::Instance: 'static,
+{
+    assert!(rate > 0.0, "rate must be positive");
+
+    let mut core = try!(Core::new());
+    let handle = core.handle();
+
+    let listener = try!(TcpListener::bind(&addr, &handle));
+    let new_service = Validate::new(new_service);
+
+    let timer = Timer::default();
+    let mut bucket = TokenBucket::new(rate);
+
+    let server = listener.incoming()
+        .and_then(move |(socket, _)| {
+            // Hold on to the connection until a token is available. The next
+            // connection is not accepted in the meantime.
+            let delay = bucket.take();
+
+            if delay == Duration::from_secs(0) {
+                return Either::A(future::ok(socket));
+            }
+
+            let sleep = timer.sleep(delay)
+                .map(move |_| socket)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+
+            Either::B(sleep)
+        })
+        .for_each(move |socket| {
+            let service = try!(new_service.new_service());
+
+            // Spawns a task on the reactor dedicated to processing the
+            // connection
+            LineProto::new().bind_server(&handle, socket, service);
+            Ok(())
+        });
+
+    core.run(server)
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> TokenBucket {
+        let capacity = rate.max(1.0);
+
+        TokenBucket {
+            rate: rate,
+            capacity: capacity,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    /// Take a token, returning how long to wait until it is available
+    fn take(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = now - self.last;
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.tokens -= 1.0;
+
+        if self.tokens >= 0.0 {
+            return Duration::from_secs(0);
+        }
+
+        let wait = -self.tokens / self.rate;
+        Duration::new(wait as u64, ((wait.fract()) * 1_000_000_000.0) as u32)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::serve_with_accept_rate;
+
+    use futures::future;
+    use tokio_service::Service;
+
+    use std::{io, net, thread};
+    use std::io::{BufRead, BufReader, Write};
+    use std::time::{Duration, Instant};
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn connection_flood_is_paced() {
+        // Pick a free port for the server
+        let addr = net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        thread::spawn(move || {
+            serve_with_accept_rate(addr, || Ok(Echo), 5.0).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let start = Instant::now();
+
+        // Connections are only answered once accepted
+        let clients: Vec<_> = (0..10).map(|_| {
+            thread::spawn(move || {
+                let mut socket = net::TcpStream::connect(&addr).unwrap();
+                socket.write_all(b"ping\n").unwrap();
+
+                let mut line = String::new();
+                BufReader::new(socket).read_line(&mut line).unwrap();
+                assert_eq!(line, "ping\n");
+
+                start.elapsed()
+            })
+        }).collect();
+
+        let mut answered: Vec<_> = clients.into_iter()
+            .map(|client| client.join().unwrap())
+            .collect();
+
+        answered.sort();
+
+        // The first second worth of connections is accepted right away, the
+        // rest at 5 per second.
+        assert!(answered[4] < Duration::from_millis(500), "burst took {:?}", answered[4]);
+        assert!(answered[9] >= Duration::from_millis(700), "flood took {:?}", answered[9]);
+    }
+}
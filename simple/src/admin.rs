@@ -0,0 +1,192 @@
+//! A built-in admin command for server introspection.
+//!
+//! The `AdminService` middleware answers a reserved command, `__STATS__` by
+//! default, with a line describing the server as a whole:
+//!
+//! ```text
+//! connections=3 requests=1024 uptime=3600
+//! ```
+//!
+//! where `connections` is the number of currently open connections, `requests`
+//! the total number of requests served, not counting admin commands, and
+//! `uptime` the number of seconds since the middleware was created. All other
+//! requests are passed to the inner service.
+
+use futures::{future, Future};
+use tokio_service::{Service, NewService};
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+/// The default admin command
+pub const STATS: &'static str = "__STATS__";
+
+/// A `Service` middleware answering the admin command.
+pub struct AdminService<T> {
+    inner: T,
+    metrics: Arc<Metrics>,
+    command: Arc<String>,
+}
+
+/// Builds an `AdminService` for each new connection, sharing metrics between
+/// all of them.
+pub struct NewAdminService<T> {
+    inner: T,
+    metrics: Arc<Metrics>,
+    command: Arc<String>,
+}
+
+/// Metrics shared by every connection of a server
+struct Metrics {
+    connections: AtomicUsize,
+    requests: AtomicUsize,
+    started: Instant,
+}
+
+impl<T> NewAdminService<T> {
+    /// Create a new `NewAdminService` answering `__STATS__`
+    pub fn new(inner: T) -> NewAdminService<T> {
+        NewAdminService::with_command(inner, STATS)
+    }
+
+    /// Create a new `NewAdminService` answering `command`, to avoid
+    /// collisions with requests of the inner service.
+    pub fn with_command(inner: T, command: &str) -> NewAdminService<T> {
+        NewAdminService {
+            inner: inner,
+            metrics: Arc::new(Metrics {
+                connections: AtomicUsize::new(0),
+                requests: AtomicUsize::new(0),
+                started: Instant::now(),
+            }),
+            command: Arc::new(command.to_string()),
+        }
+    }
+}
+
+impl Metrics {
+    fn stats(&self) -> String {
+        format!("connections={} requests={} uptime={}",
+                self.connections.load(Ordering::SeqCst),
+                self.requests.load(Ordering::SeqCst),
+                self.started.elapsed().as_secs())
+    }
+}
+
+impl<T> Service for AdminService<T>
+    where T: Service<Request = String, Response = String, Error = io::Error>,
+          T::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        if req == *self.command {
+            return Box::new(future::ok(self.metrics.stats()));
+        }
+
+        self.metrics.requests.fetch_add(1, Ordering::SeqCst);
+        Box::new(self.inner.call(req))
+    }
+}
+
+impl<T> Drop for AdminService<T> {
+    fn drop(&mut self) {
+        // The service is dropped along with its connection
+        self.metrics.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<T> NewService for NewAdminService<T>
+    where T: NewService<Request = String, Response = String, Error = io::Error>,
+          <T::Instance as Service>::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Instance = AdminService<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = try!(self.inner.new_service());
+        self.metrics.connections.fetch_add(1, Ordering::SeqCst);
+
+        Ok(AdminService {
+            inner: inner,
+            metrics: self.metrics.clone(),
+            command: self.command.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{NewAdminService, STATS};
+
+    use futures::{future, Future};
+    use tokio_service::{Service, NewService};
+
+    use std::io;
+    use std::collections::HashMap;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    /// Parse a stats line into its fields
+    fn parse(stats: &str) -> HashMap<String, u64> {
+        stats.split(' ')
+            .map(|field| {
+                let mut parts = field.splitn(2, '=');
+                let key = parts.next().unwrap().to_string();
+                (key, parts.next().expect("missing value").parse().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stats_command_is_answered_by_the_middleware() {
+        let new_service = NewAdminService::new(|| Ok(Echo));
+
+        let a = new_service.new_service().unwrap();
+        let b = new_service.new_service().unwrap();
+
+        assert_eq!(a.call("hello".to_string()).wait().unwrap(), "hello");
+        assert_eq!(b.call("world".to_string()).wait().unwrap(), "world");
+
+        let stats = parse(&a.call(STATS.to_string()).wait().unwrap());
+        assert_eq!(stats["connections"], 2);
+        assert_eq!(stats["requests"], 2);
+        assert!(stats.contains_key("uptime"));
+
+        // Closing a connection is reflected, and admin commands are not
+        // counted as requests
+        drop(b);
+        let stats = parse(&a.call(STATS.to_string()).wait().unwrap());
+        assert_eq!(stats["connections"], 1);
+        assert_eq!(stats["requests"], 2);
+    }
+
+    #[test]
+    fn command_name_is_configurable() {
+        let new_service = NewAdminService::with_command(|| Ok(Echo), "!stats");
+        let service = new_service.new_service().unwrap();
+
+        // The default command now reaches the inner service
+        assert_eq!(service.call(STATS.to_string()).wait().unwrap(), STATS);
+        assert!(service.call("!stats".to_string()).wait().unwrap().starts_with("connections=1 "));
+    }
+}
@@ -0,0 +1,155 @@
+//! Transports using a different codec for each direction.
+//!
+//! `LineCodec` is used symmetrically: requests and responses are both framed as
+//! lines. Some protocols frame each direction differently, for example text
+//! requests with length-prefixed responses. `AsymmetricCodec` combines a
+//! `Decoder`, used for inbound frames, with an unrelated `Encoder`, used for
+//! outbound frames.
+
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::{Framed, Encoder, Decoder};
+
+use bytes::BytesMut;
+
+/// A codec decoding inbound frames with `D` and encoding outbound frames with
+/// `E`.
+pub struct AsymmetricCodec<D, E> {
+    decoder: D,
+    encoder: E,
+}
+
+/// Create a transport from `io` that decodes inbound frames with `decoder` and
+/// encodes outbound frames with `encoder`.
+///
+/// For example, a server receiving lines and responding with length-prefixed
+/// strings would use:
+///
+/// ```ignore
+/// new_asymmetric_transport(socket, LineCodec::new(), ShortStringCodec)
+/// ```
+pub fn new_asymmetric_transport<T, D, E>(io: T, decoder: D, encoder: E) -> Framed<T, AsymmetricCodec<D, E>>
+    where T: AsyncRead + AsyncWrite,
+          D: Decoder,
+          E: Encoder,
+{
+    io.framed(AsymmetricCodec::new(decoder, encoder))
+}
+
+impl<D, E> AsymmetricCodec<D, E> {
+    /// Create a new `AsymmetricCodec`
+    pub fn new(decoder: D, encoder: E) -> AsymmetricCodec<D, E> {
+        AsymmetricCodec {
+            decoder: decoder,
+            encoder: encoder,
+        }
+    }
+
+    /// Returns a reference to the inbound decoder
+    pub fn decoder(&self) -> &D {
+        &self.decoder
+    }
+
+    /// Returns a reference to the outbound encoder
+    pub fn encoder(&self) -> &E {
+        &self.encoder
+    }
+}
+
+impl<D: Decoder, E> Decoder for AsymmetricCodec<D, E> {
+    type Item = D::Item;
+    type Error = D::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<D::Item>, D::Error> {
+        self.decoder.decode(buf)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<D::Item>, D::Error> {
+        self.decoder.decode_eof(buf)
+    }
+}
+
+impl<D, E: Encoder> Encoder for AsymmetricCodec<D, E> {
+    type Item = E::Item;
+    type Error = E::Error;
+
+    fn encode(&mut self, msg: E::Item, buf: &mut BytesMut) -> Result<(), E::Error> {
+        self.encoder.encode(msg, buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{new_asymmetric_transport, AsymmetricCodec};
+    use LineCodec;
+    use short_string::ShortStringCodec;
+
+    use futures::{future, Future, Stream, Sink};
+    use tokio_io::{AsyncRead, AsyncWrite};
+    use tokio_io::codec::Framed;
+    use tokio_core::net::{TcpListener, TcpStream};
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_proto::pipeline::ServerProto;
+    use tokio_service::Service;
+
+    use std::io;
+
+    /// Receives lines, responds with length-prefixed strings
+    struct TextInBinaryOut;
+
+    impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for TextInBinaryOut {
+        type Request = String;
+        type Response = String;
+
+        type Transport = Framed<T, AsymmetricCodec<LineCodec, ShortStringCodec>>;
+        type BindTransport = Result<Self::Transport, io::Error>;
+
+        fn bind_transport(&self, io: T) -> Self::BindTransport {
+            Ok(new_asymmetric_transport(io, LineCodec::new(), ShortStringCodec))
+        }
+    }
+
+    struct Upper;
+
+    impl Service for Upper {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req.to_uppercase())
+        }
+    }
+
+    #[test]
+    fn text_requests_get_length_prefixed_responses() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            TextInBinaryOut.bind_server(&handle2, socket, Upper);
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        // The client mirrors the server, encoding lines and decoding
+        // length-prefixed strings
+        let socket = core.run(TcpStream::connect(&addr, &handle)).unwrap();
+        let transport = new_asymmetric_transport(socket, ShortStringCodec, LineCodec::new());
+
+        let transport = core.run(transport.send("hello".to_string())).unwrap();
+        let transport = core.run(transport.send("".to_string())).unwrap();
+        let transport = core.run(transport.send("world".to_string())).unwrap();
+
+        let resps = core.run(transport.take(3).collect()).unwrap();
+
+        assert_eq!(resps, vec!["HELLO", "", "WORLD"]);
+    }
+}
@@ -0,0 +1,187 @@
+//! Audit logging of every frame exchanged on a connection.
+//!
+//! The `Audited` transport middleware sits beneath the service and above the
+//! codec, so it sees the plaintext of each frame: requests as they are decoded
+//! and responses before they are encoded. Every frame is passed to an audit
+//! hook, along with the direction it travels in, in the order in which frames
+//! are read or written.
+
+use {LineCodec, Validate};
+
+use futures::{Stream, Sink, Poll, Async, AsyncSink, StartSend};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
+use tokio_proto::TcpServer;
+use tokio_proto::pipeline::ServerProto;
+use tokio_service::NewService;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// The direction a frame travels in, from the point of view of the local end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// A frame read from the connection
+    Inbound,
+    /// A frame written to the connection
+    Outbound,
+}
+
+/// The audit hook, called with a copy of every frame
+pub type AuditHook = Arc<Fn(Direction, &str) + Send + Sync>;
+
+/// Transport middleware passing every frame to an audit hook.
+pub struct Audited<T> {
+    // The upstream transport
+    upstream: T,
+    hook: AuditHook,
+}
+
+/// Protocol definition for a server auditing its connections
+struct AuditProto {
+    hook: AuditHook,
+}
+
+/// Start a server passing every frame of every connection to `hook`.
+pub fn serve_with_audit<T>(addr: SocketAddr, new_service: T, hook: AuditHook)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    let new_service = Validate::new(new_service);
+
+    TcpServer::new(AuditProto { hook: hook }, addr)
+        .serve(new_service);
+}
+
+impl<T> Audited<T> {
+    /// Create a new `Audited` transport middleware
+    pub fn new(upstream: T, hook: AuditHook) -> Audited<T> {
+        Audited {
+            upstream: upstream,
+            hook: hook,
+        }
+    }
+}
+
+impl<T> Stream for Audited<T>
+    where T: Stream<Item = String, Error = io::Error>,
+{
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+        let frame = try_ready!(self.upstream.poll());
+
+        if let Some(ref frame) = frame {
+            (self.hook)(Direction::Inbound, frame);
+        }
+
+        Ok(Async::Ready(frame))
+    }
+}
+
+impl<T> Sink for Audited<T>
+    where T: Sink<SinkItem = String, SinkError = io::Error>,
+{
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: String) -> StartSend<String, io::Error> {
+        // Keep a copy, as the frame is gone once accepted by the upstream
+        let copy = item.clone();
+
+        let res = try!(self.upstream.start_send(item));
+
+        if let AsyncSink::Ready = res {
+            (self.hook)(Direction::Outbound, &copy);
+        }
+
+        Ok(res)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.upstream.poll_complete()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for AuditProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = Audited<Framed<T, LineCodec>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(Audited::new(io.framed(LineCodec::new()), self.hook.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AuditProto, Direction};
+
+    use {Client, Validate};
+
+    use futures::{future, Future, Stream};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    struct Upper;
+
+    impl Service for Upper {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req.to_uppercase())
+        }
+    }
+
+    #[test]
+    fn hook_sees_both_directions_in_order() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let log = Arc::new(Mutex::new(vec![]));
+        let log2 = log.clone();
+
+        let proto = AuditProto {
+            hook: Arc::new(move |direction: Direction, frame: &str| {
+                log2.lock().unwrap().push((direction, frame.to_string()));
+            }),
+        };
+
+        let handle2 = handle.clone();
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            proto.bind_server(&handle2, socket, Validate::new(Upper));
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        assert_eq!(core.run(client.call("hello".to_string())).unwrap(), "HELLO");
+        assert_eq!(core.run(client.call("world".to_string())).unwrap(), "WORLD");
+
+        let expected = vec![
+            (Direction::Inbound, "hello".to_string()),
+            (Direction::Outbound, "HELLO".to_string()),
+            (Direction::Inbound, "world".to_string()),
+            (Direction::Outbound, "WORLD".to_string()),
+        ];
+
+        assert_eq!(*log.lock().unwrap(), expected);
+    }
+}
@@ -0,0 +1,402 @@
+//! Batched requests acknowledged with a single response.
+//!
+//! A batch is framed as a header line, `[batch <n>]`, followed by `n` request
+//! lines. The server processes each request with the service as usual, but
+//! instead of sending `n` responses, it sends a single `[batch-ack <n>]` line
+//! once the last request in the batch has been processed.
+//!
+//! This is useful for fire-and-forget requests where the individual responses
+//! are not interesting and would only add overhead.
+//!
+//! On the client, `Client::call_batch` issues the whole batch as a single
+//! pipelined request, its lines joined with the codec delimiter, so that the ack is
+//! matched with it as its response. The `SplitBatches` transport middleware,
+//! used by `Client`, writes each line of such a request as a frame of its own.
+
+use {LineCodec, Validate};
+
+use futures::{Stream, Sink, Poll, Async, AsyncSink, StartSend};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
+use tokio_proto::TcpServer;
+use tokio_proto::pipeline::ServerProto;
+use tokio_service::NewService;
+
+use std::io;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+/// Build the header line for a batch of `n` requests.
+pub fn header(n: usize) -> String {
+    format!("[batch {}]", n)
+}
+
+/// Build the acknowledgement line for a batch of `n` requests.
+pub fn ack(n: usize) -> String {
+    format!("[batch-ack {}]", n)
+}
+
+/// Server transport middleware handling batch framing.
+pub struct BatchAck<T> {
+    // The upstream transport
+    upstream: T,
+    // Number of requests remaining in the batch currently being read
+    remaining: usize,
+    // Size of the batch currently being read
+    size: usize,
+    // What to do with each response, in request order
+    slots: VecDeque<Slot>,
+}
+
+/// Determines how the response to a request is handled
+#[derive(Clone, Copy)]
+enum Slot {
+    // Not part of a batch, send the response as is
+    Forward,
+    // Part of a batch, but not the last request, drop the response
+    Swallow,
+    // Last request of a batch of the given size, send an ack
+    Ack(usize),
+}
+
+/// Client transport middleware writing the lines of a batch as separate
+/// frames.
+pub struct SplitBatches<T> {
+    // The upstream transport
+    upstream: T,
+    // Lines of the current batch not yet accepted by the upstream
+    pending: VecDeque<String>,
+    // The byte batch lines are joined with
+    delimiter: u8,
+}
+
+/// Protocol definition for a server that accepts batches
+struct BatchProto;
+
+/// Start a server that accepts batched requests.
+///
+/// Requests sent outside of a batch are handled exactly like `serve`.
+pub fn serve_batched<T>(addr: SocketAddr, new_service: T)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    let new_service = Validate::new(new_service);
+
+    TcpServer::new(BatchProto, addr)
+        .serve(new_service);
+}
+
+fn parse_header(line: &str) -> Option<usize> {
+    if line.starts_with("[batch ") && line.ends_with(']') {
+        line[7..line.len() - 1].parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Split a request issued by `Client::call_batch` into its lines, joined with
+/// `delimiter`, or return `None` if it is not a batch.
+fn split_batch(req: &str, delimiter: u8) -> Option<VecDeque<String>> {
+    // Only ASCII delimiters can be told apart from the bytes of a character
+    if delimiter >= 0x80 || !req.as_bytes().contains(&delimiter) {
+        return None;
+    }
+
+    let lines: VecDeque<String> = req.split(delimiter as char).map(|line| line.to_string()).collect();
+
+    if parse_header(&lines[0]).is_none() {
+        return None;
+    }
+
+    Some(lines)
+}
+
+impl<T> BatchAck<T> {
+    /// Wrap `upstream` with batch handling.
+    pub fn new(upstream: T) -> BatchAck<T> {
+        BatchAck {
+            upstream: upstream,
+            remaining: 0,
+            size: 0,
+            slots: VecDeque::new(),
+        }
+    }
+}
+
+impl<T> Stream for BatchAck<T>
+    where T: Stream<Item = String, Error = io::Error>,
+{
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+        loop {
+            let line = match try_ready!(self.upstream.poll()) {
+                Some(line) => line,
+                None => return Ok(Async::Ready(None)),
+            };
+
+            if self.remaining == 0 {
+                if let Some(n) = parse_header(&line) {
+                    if n == 0 {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "empty batch"));
+                    }
+
+                    // Start of a batch, the header itself is not a request
+                    self.remaining = n;
+                    self.size = n;
+                    continue;
+                }
+
+                self.slots.push_back(Slot::Forward);
+            } else {
+                self.remaining -= 1;
+
+                if self.remaining == 0 {
+                    self.slots.push_back(Slot::Ack(self.size));
+                } else {
+                    self.slots.push_back(Slot::Swallow);
+                }
+            }
+
+            return Ok(Async::Ready(Some(line)));
+        }
+    }
+}
+
+impl<T> Sink for BatchAck<T>
+    where T: Sink<SinkItem = String, SinkError = io::Error>,
+{
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: String) -> StartSend<String, io::Error> {
+        // Responses are sent in the same order as requests are received, so
+        // the front slot belongs to this response.
+        let slot = self.slots.pop_front().unwrap_or(Slot::Forward);
+
+        let res = match slot {
+            Slot::Swallow => return Ok(AsyncSink::Ready),
+            Slot::Forward => try!(self.upstream.start_send(item)),
+            Slot::Ack(n) => {
+                match try!(self.upstream.start_send(ack(n))) {
+                    AsyncSink::Ready => AsyncSink::Ready,
+                    // Give back the original response, it will be retried
+                    AsyncSink::NotReady(_) => AsyncSink::NotReady(item),
+                }
+            }
+        };
+
+        if !res.is_ready() {
+            // The response will be sent again, so restore its slot
+            self.slots.push_front(slot);
+        }
+
+        Ok(res)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.upstream.poll_complete()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for BatchProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = BatchAck<Framed<T, LineCodec>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(BatchAck::new(io.framed(LineCodec::new())))
+    }
+}
+
+impl<T> SplitBatches<T> {
+    /// Wrap `upstream`, splitting batches joined with `delimiter` into
+    /// frames.
+    pub fn new(upstream: T, delimiter: u8) -> SplitBatches<T> {
+        SplitBatches {
+            upstream: upstream,
+            pending: VecDeque::new(),
+            delimiter: delimiter,
+        }
+    }
+}
+
+impl<T> SplitBatches<T>
+    where T: Sink<SinkItem = String, SinkError = io::Error>,
+{
+    /// Pass as many pending lines as possible to the upstream
+    fn send_pending(&mut self) -> io::Result<()> {
+        while let Some(line) = self.pending.pop_front() {
+            if let AsyncSink::NotReady(line) = try!(self.upstream.start_send(line)) {
+                self.pending.push_front(line);
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<T> Stream for SplitBatches<T>
+    where T: Stream<Item = String, Error = io::Error>,
+{
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+        self.upstream.poll()
+    }
+}
+
+impl<T> Sink for SplitBatches<T>
+    where T: Sink<SinkItem = String, SinkError = io::Error>,
+{
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: String) -> StartSend<String, io::Error> {
+        // Only accept the write once the previous batch has been sent
+        if !self.pending.is_empty() {
+            try!(self.send_pending());
+
+            if !self.pending.is_empty() {
+                return Ok(AsyncSink::NotReady(item));
+            }
+        }
+
+        match split_batch(&item, self.delimiter) {
+            Some(lines) => {
+                self.pending = lines;
+                try!(self.send_pending());
+
+                Ok(AsyncSink::Ready)
+            }
+            None => self.upstream.start_send(item),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        loop {
+            try!(self.send_pending());
+
+            if self.pending.is_empty() {
+                return self.upstream.poll_complete();
+            }
+
+            // Make room for the remaining lines
+            try_ready!(self.upstream.poll_complete());
+        }
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.poll_complete());
+        self.upstream.close()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split_batch, BatchProto};
+    use Client;
+
+    use futures::{future, Future, Stream};
+    use tokio_io::io::{read_exact, write_all};
+    use tokio_core::net::{TcpListener, TcpStream};
+    use tokio_core::reactor::{Core, Handle};
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+
+    use std::io;
+    use std::cell::Cell;
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+
+    /// Echoes requests, counting them
+    struct Count(Rc<Cell<usize>>);
+
+    impl Service for Count {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            self.0.set(self.0.get() + 1);
+            future::ok(req)
+        }
+    }
+
+    fn serve(handle: &Handle, count: Rc<Cell<usize>>) -> SocketAddr {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            BatchProto.bind_server(&handle2, socket, Count(count.clone()));
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        addr
+    }
+
+    #[test]
+    fn batch_of_five_gets_a_single_ack() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let count = Rc::new(Cell::new(0));
+        let addr = serve(&handle, count.clone());
+
+        // A request after the batch is answered right after the ack, so any
+        // extra response would show up in its place
+        let wire = "[batch 5]\na\nb\nc\nd\ne\nhello\n";
+        let expect = "[batch-ack 5]\nhello\n";
+
+        let client = TcpStream::connect(&addr, &handle)
+            .and_then(move |socket| write_all(socket, wire))
+            .and_then(move |(socket, _)| read_exact(socket, vec![0; expect.len()]));
+
+        let (_, received) = core.run(client).unwrap();
+
+        assert_eq!(received, expect.as_bytes());
+        assert_eq!(count.get(), 6);
+    }
+
+    #[test]
+    fn client_batch_is_acknowledged_once() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let count = Rc::new(Cell::new(0));
+        let addr = serve(&handle, count.clone());
+
+        let reqs = vec!["a", "b", "c", "d", "e"].into_iter().map(|req| req.to_string()).collect();
+
+        let client = Client::connect(&addr, &handle)
+            .and_then(move |client| {
+                client.call_batch(reqs)
+                    .and_then(move |()| client.call("hello".to_string()))
+            });
+
+        let resp = core.run(client).unwrap();
+
+        assert_eq!(resp, "hello");
+        assert_eq!(count.get(), 6);
+    }
+
+    #[test]
+    fn batches_are_split_on_the_delimiter() {
+        let lines = split_batch("[batch 2]\0a\nb\0c", b'\0').unwrap();
+        assert_eq!(lines, vec!["[batch 2]", "a\nb", "c"]);
+
+        // New lines do not split batches joined with another delimiter
+        assert!(split_batch("[batch 2]\na\nb", b'\0').is_none());
+
+        assert!(split_batch("hello", b'\n').is_none());
+    }
+}
@@ -0,0 +1,186 @@
+//! Load testing a line-based server.
+//!
+//! `benchmark` opens a number of connections to a server, and issues a number
+//! of requests on each of them. Requests on a connection are issued one after
+//! the other, while connections run concurrently. Once every request has
+//! completed, the connections are closed and a `BenchmarkReport` summarizing
+//! throughput and latencies is returned.
+
+use Client;
+use latency::LatencyHistogram;
+
+use futures::{future, stream, Future, Stream};
+use tokio_core::reactor::Handle;
+use tokio_service::Service;
+
+use std::io;
+use std::cell::Cell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Parameters of a benchmark run.
+#[derive(Debug, Clone)]
+pub struct BenchmarkConfig {
+    /// Number of connections opened concurrently
+    pub connections: usize,
+    /// Number of requests issued on each connection
+    pub requests_per_connection: usize,
+    /// The request line to send
+    pub request: String,
+}
+
+/// The results of a benchmark run.
+#[derive(Debug, Clone)]
+pub struct BenchmarkReport {
+    /// Number of requests issued
+    pub requests: u64,
+    /// Number of requests that failed
+    pub errors: u64,
+    /// Time taken by the whole run, including connecting
+    pub elapsed: Duration,
+    /// Median latency
+    pub p50: Duration,
+    /// 90th percentile latency
+    pub p90: Duration,
+    /// 99th percentile latency
+    pub p99: Duration,
+    /// Highest latency
+    pub max: Duration,
+}
+
+impl Default for BenchmarkConfig {
+    fn default() -> BenchmarkConfig {
+        BenchmarkConfig {
+            connections: 4,
+            requests_per_connection: 1_000,
+            request: "ping".to_string(),
+        }
+    }
+}
+
+impl BenchmarkReport {
+    /// Returns the number of requests completed per second
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs() as f64 + self.elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+
+        if secs == 0.0 {
+            return 0.0;
+        }
+
+        self.requests as f64 / secs
+    }
+}
+
+/// Run a benchmark against the server at `addr`.
+///
+/// The returned future fails if a connection cannot be established. Failed
+/// requests are counted in the report instead.
+pub fn benchmark(addr: &SocketAddr, handle: &Handle, config: BenchmarkConfig)
+    -> Box<Future<Item = BenchmarkReport, Error = io::Error>>
+{
+    let start = Instant::now();
+    let histogram = LatencyHistogram::new();
+    let errors = Rc::new(Cell::new(0));
+
+    let runs = (0..config.connections).map(|_| {
+        let histogram = histogram.clone();
+        let errors = errors.clone();
+        let request = config.request.clone();
+        let n = config.requests_per_connection;
+
+        Client::connect(addr, handle).and_then(move |client| {
+            stream::iter_ok::<_, io::Error>(0..n).for_each(move |_| {
+                let histogram = histogram.clone();
+                let errors = errors.clone();
+                let started = Instant::now();
+
+                client.call(request.clone()).then(move |res| {
+                    histogram.record(started.elapsed());
+
+                    if res.is_err() {
+                        errors.set(errors.get() + 1);
+                    }
+
+                    Ok(())
+                })
+            })
+            // The client is dropped here, closing the connection
+        })
+    }).collect::<Vec<_>>();
+
+    let report = future::join_all(runs).map(move |_| {
+        let zero = Duration::from_secs(0);
+
+        BenchmarkReport {
+            requests: histogram.count(),
+            errors: errors.get(),
+            elapsed: start.elapsed(),
+            p50: histogram.percentile(50.0).unwrap_or(zero),
+            p90: histogram.percentile(90.0).unwrap_or(zero),
+            p99: histogram.percentile(99.0).unwrap_or(zero),
+            max: histogram.max(),
+        }
+    });
+
+    Box::new(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{benchmark, BenchmarkConfig};
+
+    use {LineProto, Validate};
+
+    use futures::{future, Future, Stream};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+
+    use std::io;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn report_counts_every_request_issued() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let config = BenchmarkConfig {
+            connections: 3,
+            requests_per_connection: 20,
+            .. BenchmarkConfig::default()
+        };
+
+        let handle2 = handle.clone();
+
+        let server = listener.incoming().take(config.connections as u64).for_each(move |(socket, _)| {
+            LineProto::new().bind_server(&handle2, socket, Validate::new(Echo));
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let report = core.run(benchmark(&addr, &handle, config)).unwrap();
+
+        assert_eq!(report.requests, 60);
+        assert_eq!(report.errors, 0);
+        assert!(report.p50 <= report.p99);
+        assert!(report.throughput() > 0.0);
+    }
+}
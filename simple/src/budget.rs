@@ -0,0 +1,270 @@
+//! A memory budget shared by all connections of a server.
+//!
+//! Each connection accounts for the requests that it has read but not yet
+//! responded to. Once the bytes accounted across all connections reach the
+//! budget's ceiling, connections stop reading new requests until enough
+//! responses have been written to bring usage back under the ceiling. The
+//! unread data stays in the socket buffers, applying backpressure to clients.
+
+use {LineCodec, Validate};
+
+use futures::{Stream, Sink, Poll, Async, AsyncSink, StartSend};
+use futures::task::{self, Task};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
+use tokio_proto::TcpServer;
+use tokio_proto::pipeline::ServerProto;
+use tokio_service::NewService;
+
+use std::{io, mem};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Tracks the bytes buffered across all connections.
+///
+/// Budgets are cheap to clone, and all clones share the same accounting.
+#[derive(Clone)]
+pub struct MemoryBudget {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    used: AtomicUsize,
+    limit: usize,
+    // Connections waiting for usage to drop
+    waiters: Mutex<Vec<Task>>,
+}
+
+/// Transport middleware accounting for buffered requests in a
+/// `MemoryBudget`.
+pub struct Budgeted<T> {
+    // The upstream transport
+    upstream: T,
+    budget: MemoryBudget,
+    // Size of each request awaiting a response, in request order
+    pending: VecDeque<usize>,
+}
+
+/// Protocol definition for a server with a memory budget
+struct BudgetProto {
+    budget: MemoryBudget,
+}
+
+/// Start a server where all connections share `budget`.
+pub fn serve_with_memory_budget<T>(addr: SocketAddr, new_service: T, budget: MemoryBudget)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    let new_service = Validate::new(new_service);
+
+    TcpServer::new(BudgetProto { budget: budget }, addr)
+        .serve(new_service);
+}
+
+impl MemoryBudget {
+    /// Create a new budget allowing up to `limit` bytes to be buffered.
+    pub fn new(limit: usize) -> MemoryBudget {
+        MemoryBudget {
+            inner: Arc::new(Inner {
+                used: AtomicUsize::new(0),
+                limit: limit,
+                waiters: Mutex::new(vec![]),
+            }),
+        }
+    }
+
+    /// Returns the number of bytes currently accounted for
+    pub fn used(&self) -> usize {
+        self.inner.used.load(Ordering::SeqCst)
+    }
+
+    /// Returns true if the budget has been exhausted
+    pub fn is_exhausted(&self) -> bool {
+        self.used() >= self.inner.limit
+    }
+
+    fn acquire(&self, n: usize) {
+        self.inner.used.fetch_add(n, Ordering::SeqCst);
+    }
+
+    fn release(&self, n: usize) {
+        self.inner.used.fetch_sub(n, Ordering::SeqCst);
+
+        // Wake up all paused connections, they will check the budget again
+        let waiters = mem::replace(&mut *self.inner.waiters.lock().unwrap(), vec![]);
+
+        for task in waiters {
+            task.notify();
+        }
+    }
+
+    /// Returns true if there is room in the budget. Otherwise, the current
+    /// task is notified once bytes are released.
+    fn poll_ready(&self) -> bool {
+        if !self.is_exhausted() {
+            return true;
+        }
+
+        self.inner.waiters.lock().unwrap().push(task::current());
+
+        // Bytes may have been released before the task was registered
+        !self.is_exhausted()
+    }
+}
+
+impl<T> Budgeted<T> {
+    /// Wrap `upstream`, accounting for its buffered requests in `budget`
+    pub fn new(upstream: T, budget: MemoryBudget) -> Budgeted<T> {
+        Budgeted {
+            upstream: upstream,
+            budget: budget,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<T> Stream for Budgeted<T>
+    where T: Stream<Item = String, Error = io::Error>,
+{
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+        // Stop reading while the budget is exhausted
+        if !self.budget.poll_ready() {
+            return Ok(Async::NotReady);
+        }
+
+        match try_ready!(self.upstream.poll()) {
+            Some(req) => {
+                self.budget.acquire(req.len());
+                self.pending.push_back(req.len());
+                Ok(Async::Ready(Some(req)))
+            }
+            None => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+impl<T> Sink for Budgeted<T>
+    where T: Sink<SinkItem = String, SinkError = io::Error>,
+{
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: String) -> StartSend<String, io::Error> {
+        let res = try!(self.upstream.start_send(item));
+
+        if let AsyncSink::Ready = res {
+            // The request has been responded to, release its bytes
+            if let Some(n) = self.pending.pop_front() {
+                self.budget.release(n);
+            }
+        }
+
+        Ok(res)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.upstream.poll_complete()
+    }
+}
+
+impl<T> Drop for Budgeted<T> {
+    fn drop(&mut self) {
+        let n: usize = self.pending.iter().sum();
+
+        if n > 0 {
+            self.budget.release(n);
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for BudgetProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = Budgeted<Framed<T, LineCodec>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(Budgeted::new(io.framed(LineCodec::new()), self.budget.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Budgeted, MemoryBudget};
+
+    use futures::{future, Future, Stream, Sink, Poll, Async, AsyncSink, StartSend};
+
+    use std::io;
+    use std::collections::VecDeque;
+
+    /// A transport with requests ready to be read, accepting any response
+    struct Mock {
+        reqs: VecDeque<String>,
+    }
+
+    impl Mock {
+        fn new(reqs: &[&str]) -> Mock {
+            Mock { reqs: reqs.iter().map(|req| req.to_string()).collect() }
+        }
+    }
+
+    impl Stream for Mock {
+        type Item = String;
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+            Ok(Async::Ready(self.reqs.pop_front()))
+        }
+    }
+
+    impl Sink for Mock {
+        type SinkItem = String;
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, _: String) -> StartSend<String, io::Error> {
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn reads_pause_while_connections_exhaust_the_budget() {
+        let budget = MemoryBudget::new(10);
+
+        let mut a = Budgeted::new(Mock::new(&["hello", "again"]), budget.clone());
+        let mut b = Budgeted::new(Mock::new(&["world", "again"]), budget.clone());
+
+        future::lazy(move || {
+            assert_eq!(a.poll().unwrap(), Async::Ready(Some("hello".to_string())));
+            assert!(!budget.is_exhausted());
+
+            // Together, the two connections reach the ceiling
+            assert_eq!(b.poll().unwrap(), Async::Ready(Some("world".to_string())));
+            assert_eq!(budget.used(), 10);
+            assert!(budget.is_exhausted());
+
+            // Neither connection reads any further
+            assert_eq!(a.poll().unwrap(), Async::NotReady);
+            assert_eq!(b.poll().unwrap(), Async::NotReady);
+
+            // Responding on one connection lets the other read again
+            assert!(a.start_send("hello".to_string()).unwrap().is_ready());
+            assert_eq!(budget.used(), 5);
+            assert_eq!(b.poll().unwrap(), Async::Ready(Some("again".to_string())));
+
+            // Dropping a connection releases what it still buffers
+            drop(b);
+            assert_eq!(budget.used(), 0);
+
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+//! A line codec with optional per-frame checksums.
+//!
+//! A checksummed frame starts with a `\x01` flag byte, followed by the CRC-32
+//! of the payload as 8 lower case hex digits and a space, then the payload
+//! itself, terminated by a '\n' character:
+//!
+//! +- flag -+--- crc-32 ---+---- payload ----+
+//! |        |              |                 |
+//! |  \x01  |  3610a686 _  |    hello \n     |
+//! |        |              |                 |
+//! +--------+--------------+-----------------+
+//!
+//! Any frame that does not start with the flag byte is a plain line, exactly
+//! as sent by `LineCodec`. Checksums are verified frame by frame, so peers
+//! sending checksums and peers that do not can share a server, for example
+//! while rolling out checksums to clients.
+
+use tokio_io::codec::{Encoder, Decoder};
+
+use bytes::{BytesMut, BufMut};
+
+use std::{io, str};
+
+const FLAG: u8 = 0x01;
+
+/// Length of the flag, checksum and separating space
+const HEAD_LEN: usize = 1 + 8 + 1;
+
+/// Line codec accepting frames with or without a checksum.
+pub struct ChecksumCodec {
+    send_checksums: bool,
+}
+
+impl ChecksumCodec {
+    /// Create a codec adding a checksum to every frame it encodes
+    pub fn new() -> ChecksumCodec {
+        ChecksumCodec { send_checksums: true }
+    }
+
+    /// Create a codec encoding plain lines, while still accepting
+    /// checksummed frames
+    pub fn without_checksums() -> ChecksumCodec {
+        ChecksumCodec { send_checksums: false }
+    }
+}
+
+/// Returns the CRC-32 (IEEE) of `data`
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &byte in data {
+        crc ^= byte as u32;
+
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+
+    !crc
+}
+
+/// Verify and strip the head of a checksummed frame
+fn verify(frame: &[u8]) -> io::Result<&[u8]> {
+    if frame.len() < HEAD_LEN || frame[HEAD_LEN - 1] != b' ' {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed checksum"));
+    }
+
+    let expected = try!(str::from_utf8(&frame[1..HEAD_LEN - 1]).ok()
+        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed checksum")));
+
+    let payload = &frame[HEAD_LEN..];
+
+    if crc32(payload) != expected {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "checksum mismatch"));
+    }
+
+    Ok(payload)
+}
+
+impl Decoder for ChecksumCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<String>, io::Error> {
+        // Check to see if the frame contains a new line
+        if let Some(n) = buf.as_ref().iter().position(|b| *b == b'\n') {
+            // remove the serialized frame from the buffer.
+            let frame = buf.split_to(n);
+
+            // Also remove the '\n'
+            buf.split_to(1);
+
+            let payload = if frame.first() == Some(&FLAG) {
+                try!(verify(&frame))
+            } else {
+                &frame[..]
+            };
+
+            return match str::from_utf8(payload) {
+                Ok(s) => Ok(Some(s.to_string())),
+                Err(_) => Err(io::Error::new(io::ErrorKind::Other, "invalid string")),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Encoder for ChecksumCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: String, buf: &mut BytesMut) -> io::Result<()> {
+        if msg.as_bytes().first() == Some(&FLAG) {
+            // It would be mistaken for a checksummed frame
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "line starts with the checksum flag"));
+        }
+
+        if self.send_checksums {
+            let head = format!("{:08x} ", crc32(msg.as_bytes()));
+
+            buf.reserve(1 + head.len() + msg.len() + 1);
+            buf.put_u8(FLAG);
+            buf.extend(head.as_bytes());
+        } else {
+            buf.reserve(msg.len() + 1);
+        }
+
+        buf.extend(msg.as_bytes());
+        buf.put_u8(b'\n');
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{crc32, ChecksumCodec};
+
+    use tokio_io::codec::{Encoder, Decoder};
+
+    use bytes::BytesMut;
+
+    #[test]
+    fn checksummed_and_plain_frames_share_a_connection() {
+        assert_eq!(crc32(b"hello"), 0x3610a686);
+
+        let mut codec = ChecksumCodec::without_checksums();
+        let mut buf = BytesMut::from(&b"\x013610a686 hello\nworld\n"[..]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("hello".to_string()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("world".to_string()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        // Frames encoded with checksums decode back to the line
+        let mut codec = ChecksumCodec::new();
+        codec.encode("again".to_string(), &mut buf).unwrap();
+        assert_eq!(buf[0], 0x01);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("again".to_string()));
+    }
+
+    #[test]
+    fn corrupted_frame_is_rejected() {
+        let mut codec = ChecksumCodec::new();
+        let mut buf = BytesMut::from(&b"\x013610a686 jello\n"[..]);
+
+        assert!(codec.decode(&mut buf).is_err());
+    }
+}
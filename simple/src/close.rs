@@ -0,0 +1,465 @@
+//! Graceful close handshake.
+//!
+//! Instead of closing the socket abruptly, a client may send a `CLOSE` line.
+//! The server stops reading requests, finishes writing the responses to the
+//! requests received before `CLOSE`, and then acknowledges with a `CLOSE_ACK`
+//! line before shutting the connection down. As responses are written in
+//! order, receiving the acknowledgement guarantees that no response was lost.
+//!
+//! The handshake is initiated with `Client::graceful_close`, on a connection
+//! to a server started with `serve_with_close_handshake`. On such a server,
+//! services never see `CLOSE` requests.
+//!
+//! The server may initiate the handshake as well, when started with
+//! `serve_with_close_handshake_until`. Once shutdown is signalled, it sends a
+//! `CLOSE` line on every connection, and keeps answering requests until the
+//! client acknowledges with `CLOSE_ACK`. A client connected with
+//! `Client::connect_with_close_handshake` acknowledges right after the
+//! requests it already sent, so the server answers every one of them before
+//! closing the connection. Requests issued after the acknowledgement fail
+//! once the connection is closed.
+//!
+//! As `CLOSE` is sent outside of any response, services of such a server must
+//! not respond with `CLOSE` themselves.
+
+use {serve_until_with, Client, LineCodec, Validate};
+
+use futures::{Future, Stream, Sink, Poll, Async, AsyncSink, StartSend};
+use futures::future::Shared;
+use futures::sync::oneshot;
+use futures::task;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_proto::{TcpClient, TcpServer};
+use tokio_proto::pipeline::{ServerProto, ClientProto, ClientService};
+use tokio_service::{Service, NewService};
+
+use std::io;
+use std::net::SocketAddr;
+
+/// The line sent to initiate the handshake
+pub const CLOSE: &'static str = "CLOSE";
+
+/// The line acknowledging the handshake
+pub const CLOSE_ACK: &'static str = "CLOSE_ACK";
+
+/// State of the handshake on the server side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // Requests are being processed normally
+    Open,
+    // Shutdown was signalled, `CLOSE` is being sent
+    Closing,
+    // `CLOSE` was sent, requests are read until the client acknowledges
+    AwaitingAck,
+    // The client acknowledged, the remaining responses are being written
+    Acked,
+    // `CLOSE` was received, waiting for in-flight responses to be written
+    Draining,
+    // Every response was written, the acknowledgement is being sent
+    Acking,
+    // The acknowledgement was sent
+    Closed,
+}
+
+/// Server transport middleware answering the close handshake.
+pub struct CloseHandshake<T> {
+    // The upstream transport
+    upstream: T,
+    state: State,
+    // Number of requests read and responses written
+    read: usize,
+    written: usize,
+    // Completes, or fails, once the server should initiate the handshake
+    shutdown: Option<Shared<oneshot::Receiver<()>>>,
+}
+
+/// Client transport middleware acknowledging a close initiated by the server
+struct ClientCloseHandshake<T> {
+    // The upstream transport
+    upstream: T,
+    // Set once `CLOSE` was received, until `CLOSE_ACK` is buffered
+    ack_pending: bool,
+    // Set once `CLOSE_ACK` was buffered
+    acked: bool,
+}
+
+/// Protocol definition for a server supporting the close handshake
+struct CloseProto {
+    shutdown: Option<Shared<oneshot::Receiver<()>>>,
+}
+
+/// Protocol definition for a client supporting the close handshake
+struct ClientCloseProto;
+
+/// Start a server supporting the close handshake, listening for connections
+/// on `addr`.
+pub fn serve_with_close_handshake<T>(addr: SocketAddr, new_service: T)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    let new_service = Validate::new(new_service);
+
+    TcpServer::new(CloseProto { shutdown: None }, addr)
+        .serve(new_service);
+}
+
+/// Start a server supporting the close handshake, listening for connections
+/// on `addr`, until `shutdown` completes.
+///
+/// Once `shutdown` completes, successfully or not, the listener stops
+/// accepting connections and the server initiates the handshake on every open
+/// connection. This function returns once every one of them has been closed.
+///
+/// Unlike `serve_with_close_handshake`, all connections are handled on the
+/// current thread.
+pub fn serve_with_close_handshake_until<T, F>(addr: SocketAddr, new_service: T, shutdown: F) -> io::Result<()>
+    where T: NewService<Request = String, Response = String, Error = io::Error>,
+          T::Instance: 'static,
+          F: Future<Item = (), Error = ()>,
+{
+    serve_until_with(addr, new_service, shutdown, |shutdown| CloseProto { shutdown: Some(shutdown) })
+}
+
+impl Client {
+    /// Establish a connection to a server started with
+    /// `serve_with_close_handshake` or `serve_with_close_handshake_until`,
+    /// acknowledging the handshake when the server initiates it.
+    pub fn connect_with_close_handshake(addr: &SocketAddr, handle: &Handle) -> Box<Future<Item = Client, Error = io::Error>> {
+        let handle = handle.clone();
+
+        let ret = TcpClient::new(ClientCloseProto)
+            .connect(addr, &handle)
+            .map(move |client_service: ClientService<TcpStream, ClientCloseProto>| {
+                Client::new(client_service, handle)
+            });
+
+        Box::new(ret)
+    }
+
+    /// Close the connection with the close handshake.
+    ///
+    /// The returned future completes once the server acknowledged the close,
+    /// at which point the responses to every request sent before are
+    /// received.
+    pub fn graceful_close(self) -> Box<Future<Item = (), Error = io::Error>> {
+        let resp = self.call(CLOSE.to_string())
+            .and_then(|resp| {
+                if resp != CLOSE_ACK {
+                    Err(io::Error::new(io::ErrorKind::Other, "expected close acknowledgement"))
+                } else {
+                    Ok(())
+                }
+            });
+
+        Box::new(resp)
+    }
+}
+
+impl<T> CloseHandshake<T> {
+    /// Wrap `upstream` with close handshake handling.
+    pub fn new(upstream: T) -> CloseHandshake<T> {
+        CloseHandshake {
+            upstream: upstream,
+            state: State::Open,
+            read: 0,
+            written: 0,
+            shutdown: None,
+        }
+    }
+
+    /// Wrap `upstream` with close handshake handling, initiating the
+    /// handshake once `shutdown` completes.
+    pub fn with_shutdown(upstream: T, shutdown: Shared<oneshot::Receiver<()>>) -> CloseHandshake<T> {
+        CloseHandshake {
+            shutdown: Some(shutdown),
+            .. CloseHandshake::new(upstream)
+        }
+    }
+
+    /// Returns true once either side initiated the handshake
+    pub fn is_closing(&self) -> bool {
+        self.state != State::Open
+    }
+}
+
+impl<T> Stream for CloseHandshake<T>
+    where T: Stream<Item = String, Error = io::Error>,
+{
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+        match self.state {
+            State::Open | State::Closing | State::AwaitingAck => {}
+            // No more requests are read once `CLOSE` or `CLOSE_ACK` is
+            // received
+            _ => return Ok(Async::Ready(None)),
+        }
+
+        match try!(self.upstream.poll()) {
+            Async::Ready(Some(ref line)) if line == CLOSE => {
+                // Both sides may initiate the handshake at once, in which case
+                // the client waits for the acknowledgement of its own `CLOSE`
+                self.state = State::Draining;
+                return Ok(Async::Ready(None));
+            }
+            Async::Ready(Some(ref line)) if line == CLOSE_ACK && self.state == State::AwaitingAck => {
+                self.state = State::Acked;
+                return Ok(Async::Ready(None));
+            }
+            Async::Ready(Some(line)) => {
+                self.read += 1;
+                return Ok(Async::Ready(Some(line)));
+            }
+            Async::Ready(None) => return Ok(Async::Ready(None)),
+            Async::NotReady => {}
+        }
+
+        if self.state == State::Open {
+            // Polling the signal also ensures the task is notified once it
+            // completes
+            let signalled = match self.shutdown {
+                Some(ref mut shutdown) => {
+                    match shutdown.poll() {
+                        Ok(Async::NotReady) => false,
+                        _ => true,
+                    }
+                }
+                None => false,
+            };
+
+            if signalled {
+                // `CLOSE` is sent by `poll_complete`
+                self.state = State::Closing;
+                task::current().notify();
+            }
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+impl<T> Sink for CloseHandshake<T>
+    where T: Sink<SinkItem = String, SinkError = io::Error>,
+{
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: String) -> StartSend<String, io::Error> {
+        if self.state == State::Acking || self.state == State::Closed {
+            return Err(io::Error::new(io::ErrorKind::Other, "connection already closed"));
+        }
+
+        let res = try!(self.upstream.start_send(item));
+
+        if res.is_ready() {
+            self.written += 1;
+        }
+
+        Ok(res)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        // The acknowledgement is the last frame, once every request read
+        // before `CLOSE` has been responded to.
+        if self.state == State::Draining && self.written == self.read {
+            self.state = State::Acking;
+        }
+
+        if self.state == State::Acking {
+            if let AsyncSink::Ready = try!(self.upstream.start_send(CLOSE_ACK.to_string())) {
+                self.state = State::Closed;
+            }
+        }
+
+        // Initiated by the server, `CLOSE` does not wait for the responses in
+        // flight, as the client acknowledges after the requests it sent.
+        if self.state == State::Closing {
+            if let AsyncSink::Ready = try!(self.upstream.start_send(CLOSE.to_string())) {
+                self.state = State::AwaitingAck;
+            }
+        }
+
+        try_ready!(self.upstream.poll_complete());
+
+        if self.state == State::Acking || self.state == State::Closing {
+            // The handshake line could not be buffered yet, `poll_complete`
+            // freed up space so try again.
+            return self.poll_complete();
+        }
+
+        Ok(Async::Ready(()))
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.poll_complete());
+        self.upstream.close()
+    }
+}
+
+impl<T> Stream for ClientCloseHandshake<T>
+    where T: Stream<Item = String, Error = io::Error>,
+{
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+        loop {
+            match try_ready!(self.upstream.poll()) {
+                Some(ref line) if line == CLOSE && !self.ack_pending && !self.acked => {
+                    // Not a response, acknowledge it instead. `CLOSE_ACK` is
+                    // sent by `poll_complete`.
+                    self.ack_pending = true;
+                    task::current().notify();
+                }
+                line => return Ok(Async::Ready(line)),
+            }
+        }
+    }
+}
+
+impl<T> Sink for ClientCloseHandshake<T>
+    where T: Sink<SinkItem = String, SinkError = io::Error>,
+{
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: String) -> StartSend<String, io::Error> {
+        self.upstream.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        // The acknowledgement follows every request already sent, which the
+        // server answers before closing the connection.
+        if self.ack_pending {
+            if let AsyncSink::Ready = try!(self.upstream.start_send(CLOSE_ACK.to_string())) {
+                self.ack_pending = false;
+                self.acked = true;
+            }
+        }
+
+        try_ready!(self.upstream.poll_complete());
+
+        if self.ack_pending {
+            // The acknowledgement could not be buffered yet, `poll_complete`
+            // freed up space so try again.
+            return self.poll_complete();
+        }
+
+        Ok(Async::Ready(()))
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.poll_complete());
+        self.upstream.close()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for CloseProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = CloseHandshake<Framed<T, LineCodec>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        let transport = io.framed(LineCodec::new());
+
+        match self.shutdown {
+            Some(ref shutdown) => Ok(CloseHandshake::with_shutdown(transport, shutdown.clone())),
+            None => Ok(CloseHandshake::new(transport)),
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for ClientCloseProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = ClientCloseHandshake<Framed<T, LineCodec>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(ClientCloseHandshake {
+            upstream: io.framed(LineCodec::new()),
+            ack_pending: false,
+            acked: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::serve_with_close_handshake_until;
+
+    use futures::{future, Future};
+    use futures::sync::oneshot;
+    use tokio_service::Service;
+
+    use std::{io, net, thread};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn server_initiated_close_answers_requests_in_flight() {
+        let addr = net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let (done_tx, done_rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            serve_with_close_handshake_until(addr, || Ok(Echo), shutdown_rx.map_err(|_| ())).unwrap();
+            done_tx.send(()).unwrap();
+        });
+
+        let socket = loop {
+            match net::TcpStream::connect(addr) {
+                Ok(socket) => break socket,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+
+        let mut reader = BufReader::new(socket.try_clone().unwrap());
+        let mut writer = socket;
+        let mut line = String::new();
+
+        writer.write_all(b"hello\n").unwrap();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "hello\n");
+
+        shutdown_tx.send(()).unwrap();
+
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "CLOSE\n");
+
+        // A request sent before the acknowledgement is still answered
+        writer.write_all(b"in flight\nCLOSE_ACK\n").unwrap();
+
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "in flight\n");
+
+        done_rx.recv_timeout(Duration::from_secs(5)).expect("the connection was not closed");
+
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+}
@@ -0,0 +1,399 @@
+//! Coalescing small responses into a single frame.
+//!
+//! When a client pipelines many requests, the server often has several
+//! responses ready to be written at once. A server started with
+//! `serve_with_coalescing` packs the responses that are ready together into a
+//! single line, within the limits of a `CoalescePolicy`:
+//!
+//! ```text
+//! [coalesced <n>]<RS><response 1><RS>...<RS><response n>
+//! ```
+//!
+//! where `<RS>` is the ASCII record separator, `SUB_RESPONSE_DELIMITER`.
+//! Clients connected with `Client::connect_coalescing` split these lines back
+//! into the individual responses, so services and callers never see them.
+//!
+//! Responses containing the record separator are sent on their own. Responses
+//! that look like a coalesced frame are wrapped in a frame of their own so
+//! that they are not mistaken for one.
+
+use {Client, LineCodec, Validate};
+
+use futures::{Future, Stream, Sink, Poll, Async, AsyncSink, StartSend};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_proto::{TcpClient, TcpServer};
+use tokio_proto::pipeline::{ServerProto, ClientProto, ClientService};
+use tokio_service::NewService;
+
+use std::{io, mem};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+/// Separates the responses packed in a coalesced frame
+pub const SUB_RESPONSE_DELIMITER: char = '\x1e';
+
+const PREFIX: &'static str = "[coalesced ";
+
+/// Limits on the responses packed into a single frame
+#[derive(Debug, Clone, Copy)]
+pub struct CoalescePolicy {
+    max_responses: usize,
+    max_bytes: usize,
+}
+
+/// Server transport middleware coalescing responses.
+pub struct Coalesce<T> {
+    // The upstream transport
+    upstream: T,
+    policy: CoalescePolicy,
+    // Responses waiting to be packed
+    buf: Vec<String>,
+    // Combined length of the responses in `buf`
+    bytes: usize,
+    // A frame that the upstream was not ready to accept
+    pending: Option<String>,
+}
+
+/// Client transport middleware splitting coalesced frames.
+pub struct Split<T> {
+    // The upstream transport
+    upstream: T,
+    // Responses split off a coalesced frame and not yet returned
+    ready: VecDeque<String>,
+}
+
+/// Protocol definition for a server coalescing responses
+struct CoalesceServerProto {
+    policy: CoalescePolicy,
+}
+
+/// Protocol definition for a client of a server coalescing responses
+struct CoalesceClientProto;
+
+/// Start a server coalescing the responses ready to be written at once,
+/// within the limits of `policy`, listening for connections on `addr`.
+///
+/// Clients must connect with `Client::connect_coalescing`.
+pub fn serve_with_coalescing<T>(addr: SocketAddr, new_service: T, policy: CoalescePolicy)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    let new_service = Validate::new(new_service);
+
+    TcpServer::new(CoalesceServerProto { policy: policy }, addr)
+        .serve(new_service);
+}
+
+/// Pack `responses` into a coalesced frame
+pub fn encode(responses: &[String]) -> String {
+    let mut frame = format!("{}{}]", PREFIX, responses.len());
+
+    for resp in responses {
+        frame.push(SUB_RESPONSE_DELIMITER);
+        frame.push_str(resp);
+    }
+
+    frame
+}
+
+/// Split a coalesced frame into its responses.
+///
+/// Returns `None` if `line` is not a coalesced frame, and fails with
+/// `InvalidData` if it is a malformed one.
+pub fn decode(line: &str) -> Option<io::Result<Vec<String>>> {
+    if !line.starts_with(PREFIX) {
+        return None;
+    }
+
+    let end = match line.find(']') {
+        Some(end) => end,
+        None => return Some(Err(invalid())),
+    };
+
+    let n = match line[PREFIX.len()..end].parse::<usize>() {
+        Ok(n) => n,
+        Err(_) => return Some(Err(invalid())),
+    };
+
+    let rest = &line[end + 1..];
+
+    if !rest.starts_with(SUB_RESPONSE_DELIMITER) {
+        return Some(Err(invalid()));
+    }
+
+    let responses = rest[SUB_RESPONSE_DELIMITER.len_utf8()..]
+        .split(SUB_RESPONSE_DELIMITER)
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+
+    if responses.len() != n {
+        return Some(Err(invalid()));
+    }
+
+    Some(Ok(responses))
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed coalesced frame")
+}
+
+impl Client {
+    /// Establish a connection to a server started with
+    /// `serve_with_coalescing` at the provided `addr`.
+    pub fn connect_coalescing(addr: &SocketAddr, handle: &Handle) -> Box<Future<Item = Client, Error = io::Error>> {
+        let handle = handle.clone();
+
+        let ret = TcpClient::new(CoalesceClientProto)
+            .connect(addr, &handle)
+            .map(move |client_service: ClientService<TcpStream, CoalesceClientProto>| {
+                Client::new(client_service, handle)
+            });
+
+        Box::new(ret)
+    }
+}
+
+impl CoalescePolicy {
+    /// Pack at most `max_responses` responses, adding up to at most
+    /// `max_bytes` bytes, into a frame.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_responses` is zero.
+    pub fn new(max_responses: usize, max_bytes: usize) -> CoalescePolicy {
+        assert!(max_responses > 0, "max_responses must be at least 1");
+
+        CoalescePolicy {
+            max_responses: max_responses,
+            max_bytes: max_bytes,
+        }
+    }
+}
+
+impl<T> Coalesce<T> {
+    /// Wrap `upstream`, coalescing responses within the limits of `policy`
+    pub fn new(upstream: T, policy: CoalescePolicy) -> Coalesce<T> {
+        Coalesce {
+            upstream: upstream,
+            policy: policy,
+            buf: vec![],
+            bytes: 0,
+            pending: None,
+        }
+    }
+}
+
+impl<T> Coalesce<T>
+    where T: Sink<SinkItem = String, SinkError = io::Error>,
+{
+    /// Send the pending frame, if any. Returns true once there is none.
+    fn send_pending(&mut self) -> io::Result<bool> {
+        if let Some(frame) = self.pending.take() {
+            if let AsyncSink::NotReady(frame) = try!(self.upstream.start_send(frame)) {
+                self.pending = Some(frame);
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Pack the buffered responses into a frame, and send it. Returns true
+    /// once everything was accepted by the upstream.
+    fn send_buf(&mut self) -> io::Result<bool> {
+        if !try!(self.send_pending()) {
+            return Ok(false);
+        }
+
+        let buf = mem::replace(&mut self.buf, vec![]);
+        self.bytes = 0;
+
+        self.pending = match buf.len() {
+            0 => None,
+            // A single response is sent as is, unless it could be mistaken
+            // for a coalesced frame
+            1 if !buf[0].starts_with(PREFIX) => buf.into_iter().next(),
+            _ => Some(encode(&buf)),
+        };
+
+        self.send_pending()
+    }
+}
+
+impl<T> Stream for Coalesce<T>
+    where T: Stream<Item = String, Error = io::Error>,
+{
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+        self.upstream.poll()
+    }
+}
+
+impl<T> Sink for Coalesce<T>
+    where T: Sink<SinkItem = String, SinkError = io::Error>,
+{
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: String) -> StartSend<String, io::Error> {
+        let packable = !item.contains(SUB_RESPONSE_DELIMITER);
+
+        if !packable && item.starts_with(PREFIX) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "response cannot be framed"));
+        }
+
+        let fits = packable &&
+            self.buf.len() < self.policy.max_responses &&
+            self.bytes + item.len() <= self.policy.max_bytes;
+
+        if !fits && !try!(self.send_buf()) {
+            return Ok(AsyncSink::NotReady(item));
+        }
+
+        if !packable {
+            // Sent on its own, once the previous responses are
+            return self.upstream.start_send(item);
+        }
+
+        self.bytes += item.len();
+        self.buf.push(item);
+
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        // Every response ready at this point is packed together
+        if !try!(self.send_buf()) {
+            // The upstream may need to be flushed to accept the frame
+            try_ready!(self.upstream.poll_complete());
+            return self.poll_complete();
+        }
+
+        self.upstream.poll_complete()
+    }
+}
+
+impl<T> Split<T> {
+    /// Wrap `upstream`, splitting the coalesced frames it receives
+    pub fn new(upstream: T) -> Split<T> {
+        Split {
+            upstream: upstream,
+            ready: VecDeque::new(),
+        }
+    }
+}
+
+impl<T> Stream for Split<T>
+    where T: Stream<Item = String, Error = io::Error>,
+{
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+        loop {
+            if let Some(resp) = self.ready.pop_front() {
+                return Ok(Async::Ready(Some(resp)));
+            }
+
+            let line = match try_ready!(self.upstream.poll()) {
+                Some(line) => line,
+                None => return Ok(Async::Ready(None)),
+            };
+
+            match decode(&line) {
+                Some(responses) => self.ready.extend(try!(responses)),
+                None => return Ok(Async::Ready(Some(line))),
+            }
+        }
+    }
+}
+
+impl<T> Sink for Split<T>
+    where T: Sink<SinkItem = String, SinkError = io::Error>,
+{
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: String) -> StartSend<String, io::Error> {
+        self.upstream.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.upstream.poll_complete()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for CoalesceServerProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = Coalesce<Framed<T, LineCodec>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(Coalesce::new(io.framed(LineCodec::new()), self.policy))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for CoalesceClientProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = Split<Framed<T, LineCodec>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(Split::new(io.framed(LineCodec::new())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Coalesce, CoalescePolicy, Split};
+
+    use futures::{stream, Future, Stream, Sink, Poll, Async, AsyncSink, StartSend};
+
+    use std::io;
+
+    /// Records the frames written to it
+    struct Frames(Vec<String>);
+
+    impl Sink for Frames {
+        type SinkItem = String;
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, item: String) -> StartSend<String, io::Error> {
+            self.0.push(item);
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn small_responses_take_fewer_frames() {
+        let responses: Vec<String> = (0..5).map(|i| format!("resp {}", i)).collect();
+
+        let mut coalesce = Coalesce::new(Frames(vec![]), CoalescePolicy::new(3, 1024));
+
+        // All the responses are ready at once
+        for resp in &responses {
+            assert!(coalesce.start_send(resp.clone()).unwrap().is_ready());
+        }
+
+        assert!(coalesce.poll_complete().unwrap().is_ready());
+
+        // At most three responses fit in a frame
+        let frames = coalesce.upstream.0;
+        assert_eq!(frames.len(), 2);
+
+        let split = Split::new(stream::iter_ok::<_, io::Error>(frames));
+        assert_eq!(split.collect().wait().unwrap(), responses);
+    }
+}
@@ -0,0 +1,338 @@
+//! Compressing the line protocol.
+//!
+//! `Compressed` wraps a socket, deflating the bytes written to it and
+//! inflating the bytes read from it. Compression happens below framing, on
+//! the raw byte stream: `LineCodec` frames the inflated bytes exactly as it
+//! would frame those of a plain socket, and a compressed chunk on the wire may
+//! hold several lines, or part of one.
+//!
+//! As deflate buffers its input to compress it better, the compressor is only
+//! flushed when the transport is, so a burst of responses written together is
+//! compressed as a whole.
+//!
+//! This module is only available with the `compression` feature enabled.
+
+pub use flate2::Compression;
+
+use {Client, LineCodec, Validate};
+
+use futures::{Future, Poll, Async};
+use flate2::{Compress, Decompress, FlushCompress, FlushDecompress, Status};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_proto::{TcpClient, TcpServer};
+use tokio_proto::pipeline::{ServerProto, ClientProto, ClientService};
+use tokio_service::NewService;
+
+use bytes::BytesMut;
+
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+
+// Size of the chunks read from the underlying socket
+const READ_CAPACITY: usize = 8 * 1024;
+
+/// A socket deflating the bytes written to it, and inflating the bytes read
+/// from it.
+pub struct Compressed<T> {
+    io: T,
+    compress: Compress,
+    decompress: Decompress,
+    // Deflated bytes read from `io`, not inflated yet
+    rd: BytesMut,
+    // Deflated bytes not written to `io` yet
+    wr: Vec<u8>,
+    // Whether bytes were compressed since the last flush
+    dirty: bool,
+    // Whether the deflate stream was finished
+    finished: bool,
+}
+
+/// Protocol definition for lines over a compressed stream
+#[derive(Debug, Clone, Copy)]
+struct CompressedProto {
+    level: Compression,
+}
+
+/// Start a server compressing its connections, listening for connections on
+/// `addr`.
+///
+/// Clients must connect with `Client::connect_compressed`.
+pub fn serve_compressed<T>(addr: SocketAddr, new_service: T, level: Compression)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    let new_service = Validate::new(new_service);
+
+    TcpServer::new(CompressedProto { level: level }, addr)
+        .serve(new_service);
+}
+
+impl Client {
+    /// Establish a compressed connection to a line-based server at the
+    /// provided `addr`.
+    pub fn connect_compressed(addr: &SocketAddr, handle: &Handle, level: Compression) -> Box<Future<Item = Client, Error = io::Error>> {
+        let handle = handle.clone();
+
+        let ret = TcpClient::new(CompressedProto { level: level })
+            .connect(addr, &handle)
+            .map(move |client_service: ClientService<TcpStream, CompressedProto>| {
+                Client::new(client_service, handle)
+            });
+
+        // For simplicity, box the future.
+        Box::new(ret)
+    }
+}
+
+impl<T> Compressed<T> {
+    /// Wrap `io`, compressing the written bytes at the given `level`
+    pub fn new(io: T, level: Compression) -> Compressed<T> {
+        Compressed {
+            io: io,
+            // Raw deflate streams, without zlib headers
+            compress: Compress::new(level, false),
+            decompress: Decompress::new(false),
+            rd: BytesMut::new(),
+            wr: Vec::new(),
+            dirty: false,
+            finished: false,
+        }
+    }
+
+    /// Returns the number of bytes written, before compression
+    pub fn total_in(&self) -> u64 {
+        self.compress.total_in()
+    }
+
+    /// Returns the number of bytes written, after compression
+    pub fn total_out(&self) -> u64 {
+        self.compress.total_out()
+    }
+
+    /// Returns a reference to the underlying I/O object
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    /// Compress everything buffered by the compressor into `wr`, with `flush`
+    fn compress_pending(&mut self, flush: FlushCompress) -> io::Result<Status> {
+        loop {
+            self.wr.reserve(READ_CAPACITY);
+
+            let status = try!(self.compress.compress_vec(&[], &mut self.wr, flush)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+
+            // The output did not fill the buffer, so nothing is left
+            if status == Status::StreamEnd || self.wr.len() < self.wr.capacity() {
+                return Ok(status);
+            }
+        }
+    }
+}
+
+impl<T: Write> Compressed<T> {
+    /// Write the deflated bytes to `io`
+    fn write_pending(&mut self) -> io::Result<()> {
+        while !self.wr.is_empty() {
+            let n = try!(self.io.write(&self.wr));
+
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write compressed bytes"));
+            }
+
+            self.wr.drain(..n);
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: Read> Read for Compressed<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            let (in_before, out_before) = (self.decompress.total_in(), self.decompress.total_out());
+
+            let status = try!(self.decompress.decompress(&self.rd, buf, FlushDecompress::None)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)));
+
+            let consumed = (self.decompress.total_in() - in_before) as usize;
+            let produced = (self.decompress.total_out() - out_before) as usize;
+
+            self.rd.split_to(consumed);
+
+            if produced > 0 || status == Status::StreamEnd {
+                return Ok(produced);
+            }
+
+            // More deflated bytes are needed
+            let mut chunk = [0; READ_CAPACITY];
+            let n = try!(self.io.read(&mut chunk));
+
+            if n == 0 {
+                if self.rd.is_empty() {
+                    return Ok(0);
+                }
+
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "compressed stream truncated"));
+            }
+
+            self.rd.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl<T: Write> Write for Compressed<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Make room before compressing more
+        try!(self.write_pending());
+
+        let before = self.compress.total_in();
+
+        self.wr.reserve(buf.len() + READ_CAPACITY);
+
+        try!(self.compress.compress_vec(buf, &mut self.wr, FlushCompress::None)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+
+        let n = (self.compress.total_in() - before) as usize;
+
+        if n > 0 {
+            self.dirty = true;
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.dirty {
+            // Emit everything written so far, so that the peer can inflate
+            // it without waiting for more
+            try!(self.compress_pending(FlushCompress::Sync));
+            self.dirty = false;
+        }
+
+        try!(self.write_pending());
+        self.io.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Compressed<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for Compressed<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        if !self.finished {
+            try!(self.compress_pending(FlushCompress::Finish));
+            self.finished = true;
+        }
+
+        match self.write_pending().and_then(|_| self.io.flush()) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            Err(e) => return Err(e),
+        }
+
+        self.io.shutdown()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for CompressedProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = Framed<Compressed<T>, LineCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(Compressed::new(io, self.level).framed(LineCodec::new()))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for CompressedProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = Framed<Compressed<T>, LineCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(Compressed::new(io, self.level).framed(LineCodec::new()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Compressed, CompressedProto, Compression};
+
+    use {Client, Validate};
+
+    use futures::{future, Future, Stream};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+
+    use std::io::{self, Cursor, Read, Write};
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn wire_bytes_are_smaller_than_the_plaintext() {
+        let plaintext = "all work and no play\n".repeat(500);
+
+        let mut compressed = Compressed::new(vec![], Compression::default());
+        compressed.write_all(plaintext.as_bytes()).unwrap();
+        compressed.flush().unwrap();
+
+        let wire = compressed.get_ref().clone();
+        assert!(wire.len() < plaintext.len() / 10, "{} bytes on the wire", wire.len());
+        assert_eq!(compressed.total_in(), plaintext.len() as u64);
+
+        // The other end inflates it back
+        let mut inflated = String::new();
+        Compressed::new(Cursor::new(wire), Compression::default())
+            .read_to_string(&mut inflated).unwrap();
+
+        assert_eq!(inflated, plaintext);
+    }
+
+    #[test]
+    fn line_round_trips_over_a_compressed_connection() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+        let proto = CompressedProto { level: Compression::default() };
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            proto.bind_server(&handle2, socket, Validate::new(Echo));
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let client = core.run(Client::connect_compressed(&addr, &handle, Compression::default())).unwrap();
+
+        let req = "z".repeat(10_000);
+        assert_eq!(core.run(client.call(req.clone())).unwrap(), req);
+        assert_eq!(core.run(client.call("short".to_string())).unwrap(), "short");
+    }
+}
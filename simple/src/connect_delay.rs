@@ -0,0 +1,110 @@
+//! Delaying the processing of newly accepted connections.
+//!
+//! With `serve_with_connect_delay`, each accepted connection waits for a
+//! fixed duration before its requests are read. The accept loop keeps
+//! running meanwhile, so a delayed connection does not hold up the others.
+//! This is useful to exercise the slow-start behavior of clients, or as a
+//! deliberate throttling policy.
+
+use {LineProto, Validate};
+
+use futures::{Future, Stream};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+use tokio_proto::BindServer;
+use tokio_service::NewService;
+use tokio_timer::Timer;
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Start a server waiting `delay` after accepting each connection before
+/// processing its requests, listening for connections on `addr`.
+///
+/// Requests sent by a client during the delay are buffered by the OS, and
+/// processed once it elapses.
+///
+/// This function will block as long as the server is running. Unlike `serve`,
+/// all connections are handled on the current thread.
+pub fn serve_with_connect_delay<T>(addr: SocketAddr, new_service: T, delay: Duration) -> io::Result<()>
+    where T: NewService<Request = String, Response = String, Error = io::Error> + 'static,
+          T::Instance: 'static,
+{
+    let mut core = try!(Core::new());
+    let handle = core.handle();
+
+    let listener = try!(TcpListener::bind(&addr, &handle));
+    let new_service = Validate::new(new_service);
+
+    let timer = Timer::default();
+
+    let server = listener.incoming().for_each(move |(socket, _)| {
+        let service = try!(new_service.new_service());
+        let handle2 = handle.clone();
+
+        // Wait on a task of its own, so that accepting goes on
+        let delayed = timer.sleep(delay)
+            .map(move |_| {
+                // Spawns a task on the reactor dedicated to processing the
+                // connection
+                LineProto::new().bind_server(&handle2, socket, service);
+            })
+            .map_err(|_| ());
+
+        handle.spawn(delayed);
+        Ok(())
+    });
+
+    core.run(server)
+}
+
+#[cfg(test)]
+mod test {
+    use super::serve_with_connect_delay;
+
+    use futures::future;
+    use tokio_service::Service;
+
+    use std::{io, net, thread};
+    use std::io::{BufRead, BufReader, Write};
+    use std::time::{Duration, Instant};
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn first_request_waits_for_the_delay() {
+        // Pick a free port for the server
+        let addr = net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        thread::spawn(move || {
+            serve_with_connect_delay(addr, || Ok(Echo), Duration::from_millis(500)).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let start = Instant::now();
+
+        let mut socket = net::TcpStream::connect(&addr).unwrap();
+        socket.write_all(b"hello\n").unwrap();
+
+        let mut line = String::new();
+        BufReader::new(socket).read_line(&mut line).unwrap();
+
+        assert_eq!(line, "hello\n");
+
+        // Allow for the 100ms resolution of the default timer
+        assert!(start.elapsed() >= Duration::from_millis(400), "answered after {:?}", start.elapsed());
+    }
+}
@@ -0,0 +1,215 @@
+//! Unique connection IDs, for correlating client and server logs.
+//!
+//! A server started with `serve_with_connection_ids` numbers its connections,
+//! starting at 1. The ID is passed to the function building the service for
+//! the connection, and sent to the client as part of a handshake: right after
+//! accepting the connection, the server writes a `[connection-id=<n>]` line
+//! before any response. A client connected with
+//! `Client::connect_with_connection_id` reads that line before issuing
+//! requests, and exposes the ID through `Client::connection_id`.
+
+use {Client, LineCodec, Validate};
+
+use futures::{future, Future, Stream, Sink};
+use futures::sync::oneshot;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_proto::{BindServer, TcpClient};
+use tokio_proto::pipeline::{ServerProto, ClientProto, ClientService};
+use tokio_service::Service;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+const PREFIX: &'static str = "[connection-id=";
+
+/// Server protocol, sending the ID of the connection
+struct ServerIdProto {
+    id: u64,
+}
+
+/// Client protocol, receiving the ID of the connection
+struct ClientIdProto {
+    // Completed once the handshake line is read
+    id: Mutex<Option<oneshot::Sender<u64>>>,
+}
+
+/// Start a server, listening for connections on `addr`, assigning each
+/// connection a unique ID.
+///
+/// For each new connection, `new_service` is called with the ID of the
+/// connection to build a `Service` instance processing its requests.
+///
+/// This function will block as long as the server is running. Unlike `serve`,
+/// all connections are handled on the current thread.
+pub fn serve_with_connection_ids<F, S>(addr: SocketAddr, new_service: F) -> io::Result<()>
+    where F: Fn(u64) -> io::Result<S> + 'static,
+          S: Service<Request = String, Response = String, Error = io::Error> + 'static,
+          S::Future: 'static,
+{
+    let mut core = try!(Core::new());
+    let handle = core.handle();
+
+    let listener = try!(TcpListener::bind(&addr, &handle));
+    let mut next_id = 1;
+
+    let server = listener.incoming().for_each(move |(socket, _)| {
+        let id = next_id;
+        next_id += 1;
+
+        let service = try!(new_service(id));
+
+        // Spawns a task on the reactor dedicated to processing the connection
+        ServerIdProto { id: id }.bind_server(&handle, socket, Validate::new(service));
+        Ok(())
+    });
+
+    core.run(server)
+}
+
+impl Client {
+    /// Establish a connection to a server started with
+    /// `serve_with_connection_ids`.
+    pub fn connect_with_connection_id(addr: &SocketAddr, handle: &Handle) -> Box<Future<Item = Client, Error = io::Error>> {
+        let (tx, rx) = oneshot::channel();
+        let handle = handle.clone();
+
+        // The client service is returned as soon as the connection is
+        // established, before the handshake line is read, so wait for the ID
+        // as well. The sender is dropped if the handshake fails.
+        let id = rx.map_err(|_| io::Error::new(io::ErrorKind::Other, "connection id handshake failed"));
+
+        let ret = TcpClient::new(ClientIdProto { id: Mutex::new(Some(tx)) })
+            .connect(addr, &handle)
+            .join(id)
+            .map(move |(client_service, id): (ClientService<TcpStream, ClientIdProto>, u64)| {
+                let mut client = Client::new(client_service, handle);
+                client.connection_id = Some(id);
+                client
+            });
+
+        Box::new(ret)
+    }
+
+    /// Returns the ID assigned to this connection by the server.
+    ///
+    /// This is only available for clients connected with
+    /// `connect_with_connection_id`.
+    pub fn connection_id(&self) -> Option<u64> {
+        self.connection_id
+    }
+}
+
+/// Parse the handshake line sent by the server
+fn parse(line: &str) -> Option<u64> {
+    if !line.starts_with(PREFIX) || !line.ends_with("]") {
+        return None;
+    }
+
+    line[PREFIX.len()..line.len() - 1].parse().ok()
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for ServerIdProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = Framed<T, LineCodec>;
+    type BindTransport = Box<Future<Item = Self::Transport, Error = io::Error>>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        let line = format!("{}{}]", PREFIX, self.id);
+
+        Box::new(io.framed(LineCodec::new()).send(line))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for ClientIdProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = Framed<T, LineCodec>;
+    type BindTransport = Box<Future<Item = Self::Transport, Error = io::Error>>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        let id = self.id.lock().unwrap().take();
+
+        let handshake = io.framed(LineCodec::new()).into_future()
+            .map_err(|(e, _)| e)
+            .and_then(move |(line, transport)| {
+                match line.as_ref().and_then(|line| parse(line)) {
+                    Some(n) => {
+                        if let Some(id) = id {
+                            let _ = id.send(n);
+                        }
+
+                        future::ok(transport)
+                    }
+                    None => {
+                        let err = io::Error::new(io::ErrorKind::Other, "invalid connection id handshake");
+                        future::err(err)
+                    }
+                }
+            });
+
+        Box::new(handshake)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ServerIdProto;
+    use {Client, Validate};
+
+    use futures::{future, Future, Stream};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+
+    use std::io;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn connections_get_distinct_ids() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+        let mut next_id = 1;
+
+        let server = listener.incoming().for_each(move |(socket, _)| {
+            ServerIdProto { id: next_id }.bind_server(&handle2, socket, Validate::new(Echo));
+            next_id += 1;
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let clients = Client::connect_with_connection_id(&addr, &handle)
+            .join(Client::connect_with_connection_id(&addr, &handle));
+
+        let (a, b) = core.run(clients).unwrap();
+
+        assert!(a.connection_id().is_some());
+        assert!(b.connection_id().is_some());
+        assert!(a.connection_id() != b.connection_id());
+    }
+}
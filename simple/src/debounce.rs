@@ -0,0 +1,230 @@
+//! Collapsing rapid successive requests.
+//!
+//! The `DebounceService` middleware holds each request back for a quiet
+//! period. If another request with the same key arrives during that period,
+//! the earlier request is superseded: it never reaches the inner service and
+//! is answered with a `DEBOUNCED` line. Only a request that is followed by a
+//! full quiet period without a newer request for its key is forwarded.
+//!
+//! Keys are computed from the request line by a user supplied function, so
+//! that, for example, successive updates to the same record are collapsed
+//! while updates to different records are not.
+
+use futures::{Future, IntoFuture};
+use futures::future::Either;
+use futures::sync::oneshot;
+use tokio_service::{Service, NewService};
+use tokio_timer::Timer;
+
+use std::io;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Response line sent for requests superseded by a newer request.
+pub const DEBOUNCED: &'static str = "DEBOUNCED";
+
+/// A `Service` middleware only forwarding the last of several rapid requests
+/// with the same key.
+pub struct DebounceService<T, F, K> {
+    inner: Rc<T>,
+    key: Arc<F>,
+    quiet: Duration,
+    timer: Timer,
+    pending: Rc<RefCell<Pending<K>>>,
+}
+
+/// Builds a `DebounceService` for each new connection.
+pub struct NewDebounce<T, F> {
+    inner: T,
+    key: Arc<F>,
+    quiet: Duration,
+    timer: Timer,
+}
+
+/// The requests waiting for their quiet period to elapse, by key
+struct Pending<K> {
+    next_id: u64,
+    waiting: HashMap<K, (u64, oneshot::Sender<()>)>,
+}
+
+impl<T, F, K> DebounceService<T, F, K>
+    where F: Fn(&str) -> K,
+          K: Hash + Eq,
+{
+    /// Create a new `DebounceService`, forwarding requests once no request
+    /// with the same key has arrived for `quiet`.
+    pub fn new(inner: T, key: F, quiet: Duration, timer: Timer) -> DebounceService<T, F, K> {
+        DebounceService::with_shared_key(inner, Arc::new(key), quiet, timer)
+    }
+
+    fn with_shared_key(inner: T, key: Arc<F>, quiet: Duration, timer: Timer) -> DebounceService<T, F, K> {
+        DebounceService {
+            inner: Rc::new(inner),
+            key: key,
+            quiet: quiet,
+            timer: timer,
+            pending: Rc::new(RefCell::new(Pending {
+                next_id: 0,
+                waiting: HashMap::new(),
+            })),
+        }
+    }
+}
+
+impl<T, F, K> Service for DebounceService<T, F, K>
+    where T: Service<Request = String, Response = String, Error = io::Error> + 'static,
+          T::Future: 'static,
+          F: Fn(&str) -> K + 'static,
+          K: Hash + Eq + 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        let key = (*self.key)(&req);
+        let (tx, superseded) = oneshot::channel();
+
+        let id = {
+            let mut pending = self.pending.borrow_mut();
+            let id = pending.next_id;
+            pending.next_id += 1;
+
+            // Supersede the previous request with the same key, if any. It may
+            // already be gone, in which case there is nothing to do.
+            if let Some((_, prev)) = pending.waiting.insert(key, (id, tx)) {
+                let _ = prev.send(());
+            }
+
+            id
+        };
+
+        let sleep = self.timer.sleep(self.quiet)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+
+        let inner = self.inner.clone();
+        let pending = self.pending.clone();
+        let key = self.key.clone();
+
+        let resp = sleep.select2(superseded)
+            .then(move |res| -> Box<Future<Item = String, Error = io::Error>> {
+                match res {
+                    Ok(Either::A(_)) => {
+                        // The quiet period elapsed, the request is no longer
+                        // pending
+                        let mut pending = pending.borrow_mut();
+                        let key = (*key)(&req);
+
+                        if pending.waiting.get(&key).map_or(false, |&(current, _)| current == id) {
+                            pending.waiting.remove(&key);
+                        }
+
+                        Box::new(inner.call(req))
+                    }
+                    Err(Either::A((e, _))) => Box::new(Err(e).into_future()),
+                    // Superseded by a newer request
+                    Ok(Either::B(_)) | Err(Either::B(_)) => {
+                        Box::new(Ok(DEBOUNCED.to_string()).into_future())
+                    }
+                }
+            });
+
+        Box::new(resp)
+    }
+}
+
+impl<T, F> NewDebounce<T, F> {
+    /// Create a new `NewDebounce`
+    pub fn new(inner: T, key: F, quiet: Duration, timer: Timer) -> NewDebounce<T, F> {
+        NewDebounce {
+            inner: inner,
+            key: Arc::new(key),
+            quiet: quiet,
+            timer: timer,
+        }
+    }
+}
+
+impl<T, F, K> NewService for NewDebounce<T, F>
+    where T: NewService<Request = String, Response = String, Error = io::Error>,
+          T::Instance: 'static,
+          <T::Instance as Service>::Future: 'static,
+          F: Fn(&str) -> K + 'static,
+          K: Hash + Eq + 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Instance = DebounceService<T::Instance, F, K>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = try!(self.inner.new_service());
+        Ok(DebounceService::with_shared_key(inner, self.key.clone(), self.quiet, self.timer.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DebounceService, DEBOUNCED};
+
+    use futures::{future, Future};
+    use tokio_service::Service;
+    use tokio_timer::Timer;
+
+    use std::io;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    /// Records the requests reaching it
+    struct Record(Rc<RefCell<Vec<String>>>);
+
+    impl Service for Record {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            self.0.borrow_mut().push(req.clone());
+            future::ok(req)
+        }
+    }
+
+    /// Requests are keyed by the record they update, before the `:`
+    fn record_key(req: &str) -> String {
+        req.split(':').next().unwrap().to_string()
+    }
+
+    #[test]
+    fn only_the_last_of_rapid_requests_is_forwarded() {
+        let seen = Rc::new(RefCell::new(vec![]));
+
+        let service = DebounceService::new(Record(seen.clone()),
+                                           record_key,
+                                           Duration::from_millis(200),
+                                           Timer::default());
+
+        let calls = vec![
+            service.call("a:1".to_string()),
+            service.call("a:2".to_string()),
+            service.call("b:1".to_string()),
+            service.call("a:3".to_string()),
+        ];
+
+        let resps = future::join_all(calls).wait().unwrap();
+
+        assert_eq!(resps, [DEBOUNCED, DEBOUNCED, "b:1", "a:3"]);
+
+        // Other keys are not collapsed
+        let mut seen = seen.borrow().clone();
+        seen.sort();
+        assert_eq!(seen, ["a:3", "b:1"]);
+    }
+}
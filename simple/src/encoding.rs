@@ -0,0 +1,124 @@
+//! A line codec transcoding to and from a legacy wire encoding.
+//!
+//! Lines are `String`s on the Rust side, as with `LineCodec`, but are written
+//! to and read from the socket in the configured encoding, for example
+//! ISO-8859-1 when talking to systems that predate UTF-8.
+//!
+//! Only ASCII compatible encodings are supported, as lines are still delimited
+//! by a single `\n` byte.
+//!
+//! This module is only available with the `encoding` feature enabled.
+
+use tokio_io::codec::{Encoder, Decoder};
+use encoding_rs::Encoding;
+
+use bytes::{BytesMut, BufMut};
+
+use std::io;
+
+/// Line codec using `encoding` on the wire.
+pub struct EncodingCodec {
+    encoding: &'static Encoding,
+}
+
+impl EncodingCodec {
+    /// Create a new `EncodingCodec` for the given wire encoding, for example
+    /// `encoding_rs::WINDOWS_1252` for Latin-1.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `encoding` is not ASCII compatible.
+    pub fn new(encoding: &'static Encoding) -> EncodingCodec {
+        assert!(encoding.is_ascii_compatible(), "wire encoding must be ASCII compatible");
+
+        EncodingCodec { encoding: encoding }
+    }
+
+    /// Returns the wire encoding
+    pub fn encoding(&self) -> &'static Encoding {
+        self.encoding
+    }
+}
+
+impl Decoder for EncodingCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<String>, io::Error> {
+        // Check to see if the frame contains a new line
+        if let Some(n) = buf.as_ref().iter().position(|b| *b == b'\n') {
+            // remove the serialized frame from the buffer.
+            let line = buf.split_to(n);
+
+            // Also remove the '\n'
+            buf.split_to(1);
+
+            // Transcode the line from the wire encoding
+            return match self.encoding.decode_without_bom_handling_and_without_replacement(&line) {
+                Some(s) => Ok(Some(s.into_owned())),
+                None => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid string")),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Encoder for EncodingCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: String, buf: &mut BytesMut) -> io::Result<()> {
+        let (bytes, _, unmappable) = self.encoding.encode(&msg);
+
+        if unmappable {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("line is not representable in {}", self.encoding.name())));
+        }
+
+        // Reserve enough space for the line
+        buf.reserve(bytes.len() + 1);
+
+        buf.extend(&bytes[..]);
+        buf.put_u8(b'\n');
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::EncodingCodec;
+
+    use tokio_io::codec::{Encoder, Decoder};
+    use encoding_rs::WINDOWS_1252;
+
+    use bytes::BytesMut;
+
+    use std::io;
+
+    #[test]
+    fn latin1_text_round_trips() {
+        let mut codec = EncodingCodec::new(WINDOWS_1252);
+        let mut buf = BytesMut::new();
+
+        codec.encode("café".to_string(), &mut buf).unwrap();
+
+        // A single byte is used for the accented character
+        assert_eq!(&buf[..], b"caf\xe9\n");
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("café".to_string()));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn unrepresentable_character_is_an_error() {
+        let mut codec = EncodingCodec::new(WINDOWS_1252);
+        let mut buf = BytesMut::new();
+
+        let err = codec.encode("snow \u{2603}".to_string(), &mut buf).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(buf.is_empty());
+    }
+}
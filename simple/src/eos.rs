@@ -0,0 +1,406 @@
+//! Explicit end-of-stream markers.
+//!
+//! Relying on the socket closing makes it impossible to tell a clean end of
+//! data apart from a connection that dropped. With this middleware, the
+//! sending side writes a marker line (`__EOS__` by default) as its final frame.
+//! The receiving side ends the stream cleanly when it sees the marker, and
+//! errors with `UnexpectedEof` if the socket closes without it.
+//!
+//! A client connected with `Client::connect_with_eos` recognizes the marker
+//! sent by a server started with `serve_with_eos`. `Client::end_of_stream`
+//! then tells whether the server ended the connection cleanly.
+
+use {Client, LineCodec, Validate};
+
+use futures::{future, Future, Stream, Sink, Poll, Async, AsyncSink, StartSend};
+use futures::sync::oneshot;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_proto::{TcpClient, TcpServer};
+use tokio_proto::pipeline::{ServerProto, ClientProto, ClientService};
+use tokio_service::NewService;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+/// The default end-of-stream marker
+pub const DEFAULT_MARKER: &'static str = "__EOS__";
+
+/// Transport middleware that sends and recognizes end-of-stream markers.
+pub struct EndOfStream<T> {
+    // The upstream transport
+    upstream: T,
+    marker: String,
+    // When true, the marker is sent automatically once the peer is done
+    // sending requests and every response has been written.
+    server: bool,
+    // Number of frames read and written
+    read: usize,
+    written: usize,
+    // True once the remote is done sending frames
+    read_done: bool,
+    // True once the marker has been received
+    eos_received: bool,
+    // True when the marker needs to be sent upstream
+    eos_pending: bool,
+    // True once the marker has been sent upstream
+    eos_sent: bool,
+}
+
+/// Client transport middleware reporting how the stream ended
+struct NotifyEos<T> {
+    // The upstream transport
+    upstream: EndOfStream<T>,
+    // Notified with whether the marker was received once the stream ends
+    tx: Option<oneshot::Sender<bool>>,
+}
+
+/// Protocol definition for a server that emits end-of-stream markers
+struct EosProto {
+    marker: String,
+}
+
+/// Protocol definition for a client that recognizes end-of-stream markers
+struct ClientEosProto {
+    marker: String,
+    // Taken by the single connection established with this protocol
+    tx: Mutex<Option<oneshot::Sender<bool>>>,
+}
+
+/// Start a server that sends `marker` as the final frame on each connection.
+///
+/// Once the client is done sending requests, the server finishes writing the
+/// responses, followed by the marker, before closing the socket.
+pub fn serve_with_eos<T>(addr: SocketAddr, new_service: T, marker: &str)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    let new_service = Validate::new(new_service);
+    let proto = EosProto { marker: marker.to_string() };
+
+    TcpServer::new(proto, addr)
+        .serve(new_service);
+}
+
+impl Client {
+    /// Establish a connection to a server started with `serve_with_eos`,
+    /// recognizing `marker` as the end of the stream.
+    pub fn connect_with_eos(addr: &SocketAddr, handle: &Handle, marker: &str) -> Box<Future<Item = Client, Error = io::Error>> {
+        let (tx, rx) = oneshot::channel();
+        let handle = handle.clone();
+
+        let proto = ClientEosProto {
+            marker: marker.to_string(),
+            tx: Mutex::new(Some(tx)),
+        };
+
+        let ret = TcpClient::new(proto)
+            .connect(addr, &handle)
+            .map(move |client_service: ClientService<TcpStream, ClientEosProto>| {
+                let mut client = Client::new(client_service, handle);
+                client.eos = Some(rx.shared());
+                client
+            });
+
+        Box::new(ret)
+    }
+
+    /// Returns a future that completes once the server ends the connection.
+    ///
+    /// The future resolves if the server sent the end-of-stream marker before
+    /// closing the connection, and fails with `UnexpectedEof` if the
+    /// connection was dropped without it. This is only available for clients
+    /// connected with `connect_with_eos`.
+    pub fn end_of_stream(&self) -> Box<Future<Item = (), Error = io::Error>> {
+        let eos = match self.eos {
+            Some(ref eos) => eos.clone(),
+            None => {
+                let err = io::Error::new(io::ErrorKind::Other, "not connected with end-of-stream markers");
+                return Box::new(future::err(err));
+            }
+        };
+
+        // The sender is dropped without a value if the transport is dropped
+        // before its stream ends
+        let ret = eos.then(|res| {
+            match res {
+                Ok(ref received) if **received => Ok(()),
+                _ => Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                        "connection closed without end-of-stream marker")),
+            }
+        });
+
+        Box::new(ret)
+    }
+}
+
+impl<T> EndOfStream<T> {
+    /// Server side middleware, sending `marker` once all responses have been
+    /// written after the client has stopped sending requests.
+    ///
+    /// A client that closes the socket without sending a marker is not
+    /// considered an error.
+    pub fn server(upstream: T, marker: &str) -> EndOfStream<T> {
+        EndOfStream::new(upstream, marker, true)
+    }
+
+    /// Client side middleware. The stream ends cleanly when `marker` is
+    /// received, and errors with `UnexpectedEof` if the connection is dropped
+    /// before that.
+    ///
+    /// Calling `close` on the sink sends the marker to the remote.
+    pub fn client(upstream: T, marker: &str) -> EndOfStream<T> {
+        EndOfStream::new(upstream, marker, false)
+    }
+
+    fn new(upstream: T, marker: &str, server: bool) -> EndOfStream<T> {
+        EndOfStream {
+            upstream: upstream,
+            marker: marker.to_string(),
+            server: server,
+            read: 0,
+            written: 0,
+            read_done: false,
+            eos_received: false,
+            eos_pending: false,
+            eos_sent: false,
+        }
+    }
+
+    /// Returns true if the remote ended the stream with a marker
+    pub fn eos_received(&self) -> bool {
+        self.eos_received
+    }
+}
+
+impl<T> Stream for EndOfStream<T>
+    where T: Stream<Item = String, Error = io::Error>,
+{
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+        if self.read_done {
+            return Ok(Async::Ready(None));
+        }
+
+        match try_ready!(self.upstream.poll()) {
+            Some(line) => {
+                if line == self.marker {
+                    // Clean end of stream
+                    self.read_done = true;
+                    self.eos_received = true;
+                    return Ok(Async::Ready(None));
+                }
+
+                self.read += 1;
+                Ok(Async::Ready(Some(line)))
+            }
+            None => {
+                self.read_done = true;
+
+                if self.server {
+                    Ok(Async::Ready(None))
+                } else {
+                    Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                       "connection closed without end-of-stream marker"))
+                }
+            }
+        }
+    }
+}
+
+impl<T> Sink for EndOfStream<T>
+    where T: Sink<SinkItem = String, SinkError = io::Error>,
+{
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: String) -> StartSend<String, io::Error> {
+        if self.eos_pending || self.eos_sent {
+            return Err(io::Error::new(io::ErrorKind::Other, "end-of-stream already sent"));
+        }
+
+        let res = try!(self.upstream.start_send(item));
+
+        if res.is_ready() {
+            self.written += 1;
+        }
+
+        Ok(res)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        // Once the client is done and every request has been responded to,
+        // the marker is the last frame to send.
+        if self.server && self.read_done && !self.eos_sent && self.written == self.read {
+            self.eos_pending = true;
+        }
+
+        if self.eos_pending {
+            if let AsyncSink::Ready = try!(self.upstream.start_send(self.marker.clone())) {
+                self.eos_pending = false;
+                self.eos_sent = true;
+            }
+        }
+
+        try_ready!(self.upstream.poll_complete());
+
+        if self.eos_pending {
+            // The marker could not be buffered yet, `poll_complete` freed up
+            // space so try again.
+            return self.poll_complete();
+        }
+
+        Ok(Async::Ready(()))
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        if !self.eos_sent {
+            self.eos_pending = true;
+        }
+
+        self.poll_complete()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for EosProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = EndOfStream<Framed<T, LineCodec>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(EndOfStream::server(io.framed(LineCodec::new()), &self.marker))
+    }
+}
+
+impl<T> NotifyEos<T> {
+    fn notify(&mut self) {
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send(self.upstream.eos_received());
+        }
+    }
+}
+
+impl<T> Stream for NotifyEos<T>
+    where T: Stream<Item = String, Error = io::Error>,
+{
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+        match self.upstream.poll() {
+            Ok(Async::Ready(None)) => {
+                self.notify();
+                Ok(Async::Ready(None))
+            }
+            Err(e) => {
+                self.notify();
+                Err(e)
+            }
+            res => res,
+        }
+    }
+}
+
+impl<T> Sink for NotifyEos<T>
+    where T: Sink<SinkItem = String, SinkError = io::Error>,
+{
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: String) -> StartSend<String, io::Error> {
+        self.upstream.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.upstream.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        self.upstream.close()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for ClientEosProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = NotifyEos<Framed<T, LineCodec>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(NotifyEos {
+            upstream: EndOfStream::client(io.framed(LineCodec::new()), &self.marker),
+            tx: self.tx.lock().unwrap().take(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use Client;
+
+    use futures::Future;
+    use tokio_core::reactor::Core;
+    use tokio_service::Service;
+
+    use std::{io, net, thread};
+    use std::io::{BufRead, BufReader, Write};
+
+    /// Answers a single request with `resp`, then closes the connection
+    fn respond_once(resp: &'static str) -> net::SocketAddr {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+
+            let mut line = String::new();
+            BufReader::new(socket.try_clone().unwrap()).read_line(&mut line).unwrap();
+
+            let mut socket = socket;
+            socket.write_all(resp.as_bytes()).unwrap();
+        });
+
+        addr
+    }
+
+    fn end_of_stream(addr: net::SocketAddr) -> io::Result<()> {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let client = Client::connect_with_eos(&addr, &handle, "__EOS__")
+            .and_then(|client| {
+                client.call("hello".to_string())
+                    .and_then(move |resp| {
+                        assert_eq!(resp, "hello");
+
+                        // Keep the client alive, dropping it would close the
+                        // connection from this side
+                        client.end_of_stream().then(move |res| {
+                            drop(client);
+                            res
+                        })
+                    })
+            });
+
+        core.run(client)
+    }
+
+    #[test]
+    fn client_tells_marker_from_dropped_connection() {
+        // The marker ends the stream cleanly
+        let addr = respond_once("hello\n__EOS__\n");
+        end_of_stream(addr).unwrap();
+
+        // Without the marker, the close is unexpected
+        let addr = respond_once("hello\n");
+        let err = end_of_stream(addr).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,278 @@
+//! Line framing with an escaped delimiter.
+//!
+//! `EscapedLineCodec` frames messages as lines, like `LineCodec`, but allows
+//! them to contain new lines: on the wire, a backslash followed by '\n' is a
+//! literal new line within the message rather than the end of the frame, and
+//! two backslashes are a literal backslash.
+//!
+//! Unlike `Validate::escaping`, which escapes '\n' as the two characters
+//! "\\n" at the service level, escaping is done by the codec, so services
+//! and clients see the unescaped messages.
+//!
+//! Lines are unbounded by default. Use `EscapedLineCodec::with_max_length` and
+//! `EscapedLineProto::with_max_length` to bound the memory buffered while
+//! waiting for the end of a line.
+
+use {Client, Newlines};
+
+use futures::Future;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::{Framed, Encoder, Decoder};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_proto::{TcpClient, TcpServer};
+use tokio_proto::pipeline::{ServerProto, ClientProto, ClientService};
+use tokio_service::NewService;
+
+use bytes::{BytesMut, BufMut};
+
+use std::{io, mem, usize};
+use std::net::SocketAddr;
+
+/// Codec for lines in which new lines and backslashes are escaped
+#[derive(Debug, Clone)]
+pub struct EscapedLineCodec {
+    // Unescaped bytes of the line being decoded
+    line: Vec<u8>,
+    // Number of bytes at the start of the buffer already unescaped into
+    // `line`, so that they are not scanned again once more data is read
+    scanned: usize,
+    // Whether the last byte scanned starts an escape sequence
+    escaped: bool,
+    // Maximum length of a line, once unescaped, excluding the delimiter
+    max_length: usize,
+}
+
+/// Protocol definition for lines with escaped new lines
+#[derive(Debug, Clone, Copy)]
+pub struct EscapedLineProto {
+    max_length: usize,
+}
+
+/// Start a server framing lines with `EscapedLineCodec`, listening for
+/// connections on `addr`.
+///
+/// Unlike `serve`, requests and responses may contain new lines.
+pub fn serve_escaped<T>(addr: SocketAddr, new_service: T)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    TcpServer::new(EscapedLineProto::new(), addr)
+        .serve(new_service);
+}
+
+impl Client {
+    /// Establish a connection to a server started with `serve_escaped` at the
+    /// provided `addr`.
+    ///
+    /// Requests and responses may contain new lines.
+    pub fn connect_escaped(addr: &SocketAddr, handle: &Handle) -> Box<Future<Item = Client, Error = io::Error>> {
+        let handle = handle.clone();
+
+        let ret = TcpClient::new(EscapedLineProto::new())
+            .connect(addr, &handle)
+            .map(move |client_service: ClientService<TcpStream, EscapedLineProto>| {
+                let mut client = Client::new(client_service, handle);
+                client.inner.newlines = Newlines::Allow;
+                client
+            });
+
+        Box::new(ret)
+    }
+}
+
+impl EscapedLineCodec {
+    /// Create a new `EscapedLineCodec`, for lines of any length
+    pub fn new() -> EscapedLineCodec {
+        EscapedLineCodec {
+            line: Vec::new(),
+            scanned: 0,
+            escaped: false,
+            max_length: usize::MAX,
+        }
+    }
+
+    /// Create a codec for lines of at most `max_length` bytes once
+    /// unescaped, excluding the delimiter.
+    ///
+    /// Decoding fails with `InvalidData` once more than `max_length` bytes
+    /// are received without the line being terminated.
+    pub fn with_max_length(max_length: usize) -> EscapedLineCodec {
+        EscapedLineCodec {
+            max_length: max_length,
+            .. EscapedLineCodec::new()
+        }
+    }
+}
+
+impl Default for EscapedLineCodec {
+    fn default() -> EscapedLineCodec {
+        EscapedLineCodec::new()
+    }
+}
+
+impl EscapedLineProto {
+    /// Create a new `EscapedLineProto`, for lines of any length
+    pub fn new() -> EscapedLineProto {
+        EscapedLineProto::with_max_length(usize::MAX)
+    }
+
+    /// Create a new `EscapedLineProto`, framing lines with
+    /// `EscapedLineCodec::with_max_length`
+    pub fn with_max_length(max_length: usize) -> EscapedLineProto {
+        EscapedLineProto {
+            max_length: max_length,
+        }
+    }
+}
+
+impl Default for EscapedLineProto {
+    fn default() -> EscapedLineProto {
+        EscapedLineProto::new()
+    }
+}
+
+impl Decoder for EscapedLineCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<String>, io::Error> {
+        let mut end = false;
+
+        // Look for the first unescaped '\n', resuming where the previous call
+        // stopped. If the buffer ends in the middle of an escape sequence,
+        // the sequence is completed once more data is read.
+        while self.scanned < buf.len() {
+            let b = buf[self.scanned];
+            self.scanned += 1;
+
+            if self.escaped {
+                if b != b'\n' && b != b'\\' {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid escape sequence"));
+                }
+
+                self.line.push(b);
+                self.escaped = false;
+            } else if b == b'\\' {
+                self.escaped = true;
+            } else if b == b'\n' {
+                end = true;
+                break;
+            } else {
+                self.line.push(b);
+            }
+
+            if self.line.len() > self.max_length {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+            }
+        }
+
+        if !end {
+            return Ok(None);
+        }
+
+        // Remove the frame, including the delimiter, from the buffer
+        buf.split_to(self.scanned);
+        self.scanned = 0;
+
+        let line = mem::replace(&mut self.line, Vec::new());
+
+        match String::from_utf8(line) {
+            Ok(s) => Ok(Some(s)),
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "invalid string")),
+        }
+    }
+}
+
+impl Encoder for EscapedLineCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: String, buf: &mut BytesMut) -> io::Result<()> {
+        let escapes = msg.bytes().filter(|&b| b == b'\n' || b == b'\\').count();
+
+        buf.reserve(msg.len() + escapes + 1);
+
+        for b in msg.bytes() {
+            if b == b'\n' || b == b'\\' {
+                buf.put_u8(b'\\');
+            }
+
+            buf.put_u8(b);
+        }
+
+        buf.put_u8(b'\n');
+
+        Ok(())
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for EscapedLineProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = Framed<T, EscapedLineCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(EscapedLineCodec::with_max_length(self.max_length)))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for EscapedLineProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = Framed<T, EscapedLineCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(EscapedLineCodec::with_max_length(self.max_length)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::EscapedLineCodec;
+
+    use tokio_io::codec::{Encoder, Decoder};
+
+    use bytes::BytesMut;
+
+    #[test]
+    fn escape_split_across_reads() {
+        let mut codec = EscapedLineCodec::new();
+        let mut buf = BytesMut::from(&b"one\\"[..]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(b"ntwo\n");
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("one\ntwo".to_string()));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut codec = EscapedLineCodec::new();
+        let mut buf = BytesMut::new();
+
+        codec.encode("multi\nline \\ with backslash".to_string(), &mut buf).unwrap();
+        codec.encode("".to_string(), &mut buf).unwrap();
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("multi\nline \\ with backslash".to_string()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("".to_string()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn lines_over_max_length_fail() {
+        let mut codec = EscapedLineCodec::with_max_length(5);
+        let mut buf = BytesMut::from(&b"ab\\\ncd"[..]);
+
+        // Escaped bytes count once unescaped
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        // The line is failed before it is terminated
+        buf.extend_from_slice(b"e");
+        assert!(codec.decode(&mut buf).is_err());
+    }
+}
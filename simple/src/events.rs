@@ -0,0 +1,306 @@
+//! Monitoring the connections of a server as a stream of events.
+//!
+//! `serve_with_event_stream` runs a server on a background thread and returns
+//! a `Stream` of `ConnEvent`s, emitted as connections are accepted, fail, and
+//! close, so that a monitoring dashboard can consume them asynchronously.
+//!
+//! Events are observed at the socket level: a connection is reported closed
+//! when its socket is dropped, with the reason being an EOF sent by the peer,
+//! an I/O error, or the server closing the connection on its own.
+
+use {LineProto, Validate};
+
+use futures::{future, Future, Stream, Poll};
+use futures::future::Either;
+use futures::sync::{mpsc, oneshot};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Core;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_proto::BindServer;
+use tokio_service::NewService;
+
+use std::{io, thread};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::mpsc as std_mpsc;
+
+/// A connection lifecycle event
+#[derive(Debug)]
+pub enum ConnEvent {
+    /// A connection was accepted
+    Accepted {
+        /// Address of the client
+        peer: SocketAddr,
+    },
+    /// A connection was closed
+    Closed {
+        /// Address of the client
+        peer: SocketAddr,
+        /// Why the connection was closed
+        reason: CloseReason,
+    },
+    /// Reading from or writing to a connection failed
+    Error {
+        /// Address of the client
+        peer: SocketAddr,
+        /// The I/O error
+        err: io::Error,
+    },
+}
+
+/// Why a connection was closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The client closed its end of the connection
+    Eof,
+    /// The connection failed with an I/O error
+    Error,
+    /// The server closed the connection
+    Server,
+}
+
+/// Handle to a server started with `serve_with_event_stream`.
+///
+/// Dropping the handle leaves the server running.
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+}
+
+/// The `Stream` of events of a server started with `serve_with_event_stream`.
+///
+/// The stream ends once the server has shut down and every connection is
+/// closed.
+pub struct EventStream {
+    inner: mpsc::UnboundedReceiver<ConnEvent>,
+}
+
+/// A socket reporting its lifecycle events
+struct Monitored {
+    socket: TcpStream,
+    peer: SocketAddr,
+    events: mpsc::UnboundedSender<ConnEvent>,
+    // Set once the reason of the eventual close is known
+    reason: Option<CloseReason>,
+}
+
+/// Start a server on a background thread, listening for connections on
+/// `addr`, and return a handle to it along with the stream of its connection
+/// events.
+///
+/// This returns once the server is bound, so that binding errors are
+/// reported to the caller.
+pub fn serve_with_event_stream<T>(addr: SocketAddr, new_service: T) -> io::Result<(ServerHandle, EventStream)>
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + 'static,
+          T::Instance: 'static,
+{
+    let (events_tx, events_rx) = mpsc::unbounded();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let (bound_tx, bound_rx) = std_mpsc::channel();
+
+    thread::spawn(move || {
+        let mut core = match Core::new() {
+            Ok(core) => core,
+            Err(e) => {
+                let _ = bound_tx.send(Err(e));
+                return;
+            }
+        };
+
+        let handle = core.handle();
+
+        let listener = match TcpListener::bind(&addr, &handle) {
+            Ok(listener) => listener,
+            Err(e) => {
+                let _ = bound_tx.send(Err(e));
+                return;
+            }
+        };
+
+        let _ = bound_tx.send(listener.local_addr());
+
+        let new_service = Validate::new(new_service);
+
+        let server = listener.incoming().for_each(move |(socket, peer)| {
+            let _ = events_tx.unbounded_send(ConnEvent::Accepted { peer: peer });
+
+            let service = try!(new_service.new_service());
+            let socket = Monitored {
+                socket: socket,
+                peer: peer,
+                events: events_tx.clone(),
+                reason: None,
+            };
+
+            // Spawns a task on the reactor dedicated to processing the
+            // connection
+            LineProto::new().bind_server(&handle, socket, service);
+            Ok(())
+        });
+
+        // Keep running if the handle is dropped without shutting down
+        let shutdown = shutdown_rx.then(|res| {
+            match res {
+                Ok(()) => Either::A(future::ok(())),
+                Err(_) => Either::B(future::empty()),
+            }
+        });
+
+        // Dropping the reactor closes the remaining connections
+        let _ = core.run(server.select(shutdown));
+    });
+
+    let local_addr = match bound_rx.recv() {
+        Ok(res) => try!(res),
+        Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "server thread exited")),
+    };
+
+    let handle = ServerHandle {
+        local_addr: local_addr,
+        shutdown: shutdown_tx,
+    };
+
+    Ok((handle, EventStream { inner: events_rx }))
+}
+
+impl ServerHandle {
+    /// Returns the address the server is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stop the server, closing every connection
+    pub fn shutdown(self) {
+        let _ = self.shutdown.send(());
+    }
+}
+
+impl Stream for EventStream {
+    type Item = ConnEvent;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<ConnEvent>, ()> {
+        self.inner.poll()
+    }
+}
+
+impl Monitored {
+    /// Report a failed I/O operation
+    fn check<T>(&mut self, res: io::Result<T>) -> io::Result<T> {
+        if let Err(ref e) = res {
+            if e.kind() != io::ErrorKind::WouldBlock && self.reason.is_none() {
+                self.reason = Some(CloseReason::Error);
+
+                let err = io::Error::new(e.kind(), e.to_string());
+                let _ = self.events.unbounded_send(ConnEvent::Error { peer: self.peer, err: err });
+            }
+        }
+
+        res
+    }
+}
+
+impl Read for Monitored {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let res = self.socket.read(buf);
+
+        if let Ok(0) = res {
+            if !buf.is_empty() && self.reason.is_none() {
+                self.reason = Some(CloseReason::Eof);
+            }
+        }
+
+        self.check(res)
+    }
+}
+
+impl Write for Monitored {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let res = self.socket.write(buf);
+        self.check(res)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let res = self.socket.flush();
+        self.check(res)
+    }
+}
+
+impl AsyncRead for Monitored {}
+
+impl AsyncWrite for Monitored {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        let res = AsyncWrite::shutdown(&mut self.socket);
+        self.check(res)
+    }
+}
+
+impl Drop for Monitored {
+    fn drop(&mut self) {
+        let event = ConnEvent::Closed {
+            peer: self.peer,
+            reason: self.reason.unwrap_or(CloseReason::Server),
+        };
+
+        let _ = self.events.unbounded_send(event);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{serve_with_event_stream, CloseReason, ConnEvent};
+
+    use futures::{future, Stream};
+    use tokio_service::Service;
+
+    use std::{io, net};
+    use std::io::{BufRead, BufReader, Write};
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn connecting_and_disconnecting_yields_events() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let (server, events) = serve_with_event_stream(addr, || Ok(Echo)).unwrap();
+
+        let client_addr = {
+            let mut socket = net::TcpStream::connect(&server.local_addr()).unwrap();
+            socket.write_all(b"hello\n").unwrap();
+
+            let mut line = String::new();
+            BufReader::new(socket.try_clone().unwrap()).read_line(&mut line).unwrap();
+            assert_eq!(line, "hello\n");
+
+            socket.local_addr().unwrap()
+            // The client disconnects here
+        };
+
+        let mut events = events.wait();
+
+        match events.next() {
+            Some(Ok(ConnEvent::Accepted { peer })) => assert_eq!(peer, client_addr),
+            event => panic!("unexpected event: {:?}", event),
+        }
+
+        match events.next() {
+            Some(Ok(ConnEvent::Closed { peer, reason })) => {
+                assert_eq!(peer, client_addr);
+                assert_eq!(reason, CloseReason::Eof);
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+
+        server.shutdown();
+    }
+}
@@ -0,0 +1,161 @@
+//! Scatter / gather requests across multiple backends.
+
+use futures::{future, Future};
+use tokio_service::Service;
+
+use std::io;
+use std::sync::Arc;
+
+/// How `FanoutService` handles backends that error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Error the request as soon as any backend errors.
+    FailFast,
+    /// Merge the responses of the backends that succeeded. The request only
+    /// errors if every backend errored.
+    BestEffort,
+}
+
+/// A `Service` that sends each request to all backends concurrently and
+/// merges their responses into a single response line.
+///
+/// The responses are passed to `merge` in the same order as the backends were
+/// given to `FanoutService::new`.
+pub struct FanoutService<S, F> {
+    backends: Vec<S>,
+    merge: Arc<F>,
+    failure: Failure,
+}
+
+impl<S, F> FanoutService<S, F>
+    where F: Fn(Vec<String>) -> String,
+{
+    /// Create a new `FanoutService` that fails fast.
+    pub fn new(backends: Vec<S>, merge: F) -> FanoutService<S, F> {
+        FanoutService {
+            backends: backends,
+            merge: Arc::new(merge),
+            failure: Failure::FailFast,
+        }
+    }
+
+    /// Set how backend errors are handled
+    pub fn failure(mut self, failure: Failure) -> FanoutService<S, F> {
+        self.failure = failure;
+        self
+    }
+}
+
+impl<S, F> Service for FanoutService<S, F>
+    where S: Service<Request = String, Response = String, Error = io::Error>,
+          S::Future: 'static,
+          F: Fn(Vec<String>) -> String + 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        let merge = self.merge.clone();
+
+        let calls: Vec<_> = self.backends.iter()
+            .map(|backend| backend.call(req.clone()))
+            .collect();
+
+        match self.failure {
+            Failure::FailFast => {
+                // `join_all` errors as soon as any of the calls errors
+                Box::new(future::join_all(calls)
+                    .map(move |resps| merge(resps)))
+            }
+            Failure::BestEffort => {
+                // Turn each call into a future that always succeeds with the
+                // call's result, so that every backend is waited on.
+                let calls: Vec<_> = calls.into_iter()
+                    .map(|call| call.then(|res| Ok::<_, io::Error>(res)))
+                    .collect();
+
+                Box::new(future::join_all(calls)
+                    .and_then(move |results| {
+                        let mut resps = vec![];
+                        let mut first_err = None;
+
+                        for res in results {
+                            match res {
+                                Ok(resp) => resps.push(resp),
+                                Err(e) => {
+                                    if first_err.is_none() {
+                                        first_err = Some(e);
+                                    }
+                                }
+                            }
+                        }
+
+                        match first_err {
+                            Some(e) if resps.is_empty() => Err(e),
+                            _ => Ok(merge(resps)),
+                        }
+                    }))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FanoutService, Failure};
+
+    use futures::{future, Future};
+    use tokio_service::Service;
+
+    use std::io;
+
+    /// A backend that always responds with the same string, or always errors
+    struct Backend(Option<&'static str>);
+
+    impl Service for Backend {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            match self.0 {
+                Some(resp) => future::ok(format!("{}:{}", req, resp)),
+                None => future::err(io::Error::new(io::ErrorKind::Other, "backend down")),
+            }
+        }
+    }
+
+    fn concat(resps: Vec<String>) -> String {
+        resps.join(",")
+    }
+
+    #[test]
+    fn responses_are_merged_in_backend_order() {
+        let service = FanoutService::new(vec![Backend(Some("a")), Backend(Some("b"))], concat);
+
+        assert_eq!(service.call("q".to_string()).wait().unwrap(), "q:a,q:b");
+    }
+
+    #[test]
+    fn best_effort_merges_the_successes() {
+        let backends = || vec![Backend(Some("a")), Backend(None)];
+
+        let service = FanoutService::new(backends(), concat)
+            .failure(Failure::BestEffort);
+        assert_eq!(service.call("q".to_string()).wait().unwrap(), "q:a");
+
+        // Failing fast, the same error fails the whole request
+        let service = FanoutService::new(backends(), concat);
+        let err = service.call("q".to_string()).wait().unwrap_err();
+        assert_eq!(err.to_string(), "backend down");
+
+        // With no successes to merge, best effort errors too
+        let service = FanoutService::new(vec![Backend(None), Backend(None)], concat)
+            .failure(Failure::BestEffort);
+        assert!(service.call("q".to_string()).wait().is_err());
+    }
+}
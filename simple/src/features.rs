@@ -0,0 +1,209 @@
+//! Advertising server capabilities during the connection handshake.
+//!
+//! A server started with `serve_with_features` writes a line listing the
+//! features it supports, separated by spaces, right after accepting a
+//! connection and before any response:
+//!
+//! ```text
+//! FEATURES streaming compression ping
+//! ```
+//!
+//! A client connected with `Client::connect_with_features` reads that line
+//! before issuing requests, and exposes the advertised features through
+//! `Client::supports`, so that it can adapt to the server it is talking to.
+
+use {Client, LineCodec, Validate};
+
+use futures::{future, Future, Stream, Sink};
+use futures::sync::oneshot;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_proto::{TcpClient, TcpServer};
+use tokio_proto::pipeline::{ServerProto, ClientProto, ClientService};
+use tokio_service::NewService;
+
+use std::io;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+const PREFIX: &'static str = "FEATURES";
+
+/// Server protocol, advertising `features`
+struct AdvertiseProto {
+    line: String,
+}
+
+/// Client protocol, receiving the advertised features
+struct DiscoverProto {
+    // Completed once the handshake line is read
+    features: Mutex<Option<oneshot::Sender<HashSet<String>>>>,
+}
+
+/// Start a server advertising `features` to every client, listening for
+/// connections on `addr`.
+///
+/// # Panics
+///
+/// Panics if a feature is empty or contains whitespace.
+pub fn serve_with_features<T>(addr: SocketAddr, new_service: T, features: &[&str])
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    for feature in features {
+        assert!(!feature.is_empty() && !feature.contains(char::is_whitespace),
+                "invalid feature name: {:?}", feature);
+    }
+
+    let mut line = PREFIX.to_string();
+
+    for feature in features {
+        line.push(' ');
+        line.push_str(feature);
+    }
+
+    let new_service = Validate::new(new_service);
+
+    TcpServer::new(AdvertiseProto { line: line }, addr)
+        .serve(new_service);
+}
+
+impl Client {
+    /// Establish a connection to a server started with `serve_with_features`.
+    pub fn connect_with_features(addr: &SocketAddr, handle: &Handle) -> Box<Future<Item = Client, Error = io::Error>> {
+        let (tx, rx) = oneshot::channel();
+        let handle = handle.clone();
+
+        // The client service is returned as soon as the connection is
+        // established, before the handshake line is read, so wait for the
+        // features as well. The sender is dropped if the handshake fails.
+        let features = rx.map_err(|_| io::Error::new(io::ErrorKind::Other, "features handshake failed"));
+
+        let ret = TcpClient::new(DiscoverProto { features: Mutex::new(Some(tx)) })
+            .connect(addr, &handle)
+            .join(features)
+            .map(move |(client_service, features): (ClientService<TcpStream, DiscoverProto>, HashSet<String>)| {
+                let mut client = Client::new(client_service, handle);
+                client.features = features;
+                client
+            });
+
+        Box::new(ret)
+    }
+
+    /// Returns `true` if the server advertised support for `feature`.
+    ///
+    /// This always returns `false` for clients not connected with
+    /// `connect_with_features`.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.contains(feature)
+    }
+}
+
+/// Parse the handshake line sent by the server
+fn parse(line: &str) -> Option<HashSet<String>> {
+    let mut words = line.split(' ');
+
+    if words.next() != Some(PREFIX) {
+        return None;
+    }
+
+    Some(words.filter(|w| !w.is_empty()).map(|w| w.to_string()).collect())
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for AdvertiseProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = Framed<T, LineCodec>;
+    type BindTransport = Box<Future<Item = Self::Transport, Error = io::Error>>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Box::new(io.framed(LineCodec::new()).send(self.line.clone()))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for DiscoverProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = Framed<T, LineCodec>;
+    type BindTransport = Box<Future<Item = Self::Transport, Error = io::Error>>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        let features = self.features.lock().unwrap().take();
+
+        let handshake = io.framed(LineCodec::new()).into_future()
+            .map_err(|(e, _)| e)
+            .and_then(move |(line, transport)| {
+                match line.as_ref().and_then(|line| parse(line)) {
+                    Some(advertised) => {
+                        if let Some(features) = features {
+                            let _ = features.send(advertised);
+                        }
+
+                        future::ok(transport)
+                    }
+                    None => {
+                        let err = io::Error::new(io::ErrorKind::Other, "invalid features handshake");
+                        future::err(err)
+                    }
+                }
+            });
+
+        Box::new(handshake)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AdvertiseProto;
+    use {Client, Validate};
+
+    use futures::{future, Future, Stream};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+
+    use std::io;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn client_supports_advertised_features() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+
+        let server = listener.incoming().for_each(move |(socket, _)| {
+            let proto = AdvertiseProto { line: "FEATURES streaming ping".to_string() };
+            proto.bind_server(&handle2, socket, Validate::new(Echo));
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let client = core.run(Client::connect_with_features(&addr, &handle)).unwrap();
+
+        assert!(client.supports("streaming"));
+        assert!(client.supports("ping"));
+        assert!(!client.supports("compression"));
+    }
+}
@@ -0,0 +1,194 @@
+//! Hedged requests across two connections.
+//!
+//! To cut tail latency, a `HedgedClient` sends each request on its primary
+//! connection first. If no response is received within the hedge delay, the
+//! same request is also sent on the secondary connection, and whichever
+//! response arrives first is used.
+//!
+//! Because the protocol is pipelined, the losing connection still receives a
+//! response for the request. It is driven to completion in the background and
+//! discarded, so that it does not get matched with a later request. Requests
+//! must be idempotent, as they may be processed twice.
+
+use Client;
+
+use futures::{Future, IntoFuture};
+use futures::future::Either;
+use tokio_service::Service;
+use tokio_timer::Timer;
+
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// A client sending requests on a secondary connection when the primary one
+/// is slow to respond.
+pub struct HedgedClient {
+    primary: Client,
+    // Shared with the response futures, which only call it once the hedge
+    // delay elapses
+    secondary: Rc<Client>,
+    hedge_delay: Duration,
+    timer: Timer,
+}
+
+impl HedgedClient {
+    /// Create a new `HedgedClient` from a primary and a secondary client.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `clients` does not contain exactly two clients.
+    pub fn new(clients: Vec<Client>, hedge_delay: Duration) -> HedgedClient {
+        assert_eq!(clients.len(), 2, "hedging requires exactly two clients");
+
+        let mut clients = clients.into_iter();
+
+        HedgedClient {
+            primary: clients.next().unwrap(),
+            secondary: Rc::new(clients.next().unwrap()),
+            hedge_delay: hedge_delay,
+            timer: Timer::default(),
+        }
+    }
+}
+
+impl Service for HedgedClient {
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        let handle = self.primary.handle.clone();
+
+        let sleep = self.timer.sleep(self.hedge_delay)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+
+        let secondary = self.secondary.clone();
+        let hedge_req = req.clone();
+
+        let resp = self.primary.call(req)
+            .select2(sleep)
+            .then(move |res| -> Box<Future<Item = String, Error = io::Error>> {
+                let primary = match res {
+                    // The primary responded in time
+                    Ok(Either::A((resp, _))) => return Box::new(Ok(resp).into_future()),
+                    Err(Either::A((e, _))) => return Box::new(Err(e).into_future()),
+                    // The hedge delay elapsed, race both connections
+                    Ok(Either::B((_, primary))) => primary,
+                    Err(Either::B((_, primary))) => primary,
+                };
+
+                let resp = primary.select(secondary.call(hedge_req))
+                    .then(move |res| {
+                        match res {
+                            Ok((resp, loser)) => {
+                                // Discard the losing response once it arrives
+                                handle.spawn(loser.then(|_| Ok(())));
+                                Box::new(Ok(resp).into_future()) as Box<Future<Item = String, Error = io::Error>>
+                            }
+                            // One connection failed, use the other one
+                            Err((_, other)) => Box::new(other),
+                        }
+                    });
+
+                Box::new(resp)
+            });
+
+        Box::new(resp)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HedgedClient;
+
+    use {Client, LineProto, Validate};
+
+    use futures::{future, Future, Stream};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::{Core, Handle};
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+    use tokio_timer::Timer;
+
+    use std::io;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    /// Responds with the request, tagged with `name`, after `delay`
+    struct Delayed {
+        name: &'static str,
+        delay: Duration,
+        timer: Timer,
+    }
+
+    impl Service for Delayed {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = Box<Future<Item = String, Error = io::Error>>;
+
+        fn call(&self, req: String) -> Self::Future {
+            let resp = format!("{}: {}", self.name, req);
+
+            Box::new(self.timer.sleep(self.delay)
+                .map(move |_| resp)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e)))
+        }
+    }
+
+    fn serve(handle: &Handle, service: Delayed) -> SocketAddr {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+        let mut service = Some(service);
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            let service = Validate::new(service.take().unwrap());
+            LineProto::new().bind_server(&handle2, socket, service);
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        addr
+    }
+
+    #[test]
+    fn hedge_wins_over_slow_primary() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let timer = Timer::default();
+
+        let slow = serve(&handle, Delayed {
+            name: "primary",
+            delay: Duration::from_millis(1_000),
+            timer: timer.clone(),
+        });
+
+        let fast = serve(&handle, Delayed {
+            name: "secondary",
+            delay: Duration::from_millis(0),
+            timer: timer.clone(),
+        });
+
+        let clients = future::join_all(vec![
+            Client::connect(&slow, &handle),
+            Client::connect(&fast, &handle),
+        ]);
+
+        let clients = core.run(clients).unwrap();
+        let client = HedgedClient::new(clients, Duration::from_millis(100));
+
+        let start = Instant::now();
+        let resp = core.run(client.call("hello".to_string())).unwrap();
+
+        assert_eq!(resp, "secondary: hello");
+
+        // The response did not wait for the primary
+        assert!(start.elapsed() < Duration::from_millis(1_000));
+    }
+}
@@ -0,0 +1,241 @@
+//! Closing idle connections.
+//!
+//! The `IdleTimeout` transport middleware closes connections on which no
+//! frame was read or written for a given duration, failing the transport with
+//! `TimedOut`. The timer is restarted by every frame, so a connection that
+//! keeps exchanging frames never times out, however long it lives.
+//!
+//! A connection waiting for the service to respond is not idle: the timer
+//! only runs while every request read has been responded to.
+
+use {LineCodec, Validate};
+
+use futures::{Future, Stream, Sink, Poll, Async, AsyncSink, StartSend};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
+use tokio_proto::TcpServer;
+use tokio_proto::pipeline::ServerProto;
+use tokio_service::NewService;
+use tokio_timer::{Timer, Sleep};
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Server transport middleware closing the connection once it is idle.
+pub struct IdleTimeout<T> {
+    // The upstream transport
+    upstream: T,
+    timer: Timer,
+    timeout: Duration,
+    // Fires once the connection has been idle for `timeout`
+    sleep: Sleep,
+    // Requests read and not yet responded to
+    in_flight: usize,
+}
+
+/// Protocol definition for a server closing idle connections
+struct IdleTimeoutProto {
+    timer: Timer,
+    timeout: Duration,
+}
+
+/// Start a server closing connections on which no frame was read or written
+/// for `timeout`, listening for connections on `addr`.
+pub fn serve_with_idle_timeout<T>(addr: SocketAddr, new_service: T, timer: Timer, timeout: Duration)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    let new_service = Validate::new(new_service);
+
+    let proto = IdleTimeoutProto {
+        timer: timer,
+        timeout: timeout,
+    };
+
+    TcpServer::new(proto, addr)
+        .serve(new_service);
+}
+
+impl<T> IdleTimeout<T> {
+    /// Wrap `upstream`, failing it once idle for `timeout`.
+    pub fn new(upstream: T, timer: Timer, timeout: Duration) -> IdleTimeout<T> {
+        let sleep = timer.sleep(timeout);
+
+        IdleTimeout {
+            upstream: upstream,
+            timer: timer,
+            timeout: timeout,
+            sleep: sleep,
+            in_flight: 0,
+        }
+    }
+
+    /// A frame went through, so restart the timer
+    fn reset(&mut self) {
+        self.sleep = self.timer.sleep(self.timeout);
+    }
+}
+
+impl<T> Stream for IdleTimeout<T>
+    where T: Stream<Item = String, Error = io::Error>,
+{
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+        match try!(self.upstream.poll()) {
+            Async::Ready(Some(msg)) => {
+                self.reset();
+                self.in_flight += 1;
+
+                return Ok(Async::Ready(Some(msg)));
+            }
+            Async::Ready(None) => return Ok(Async::Ready(None)),
+            Async::NotReady => {}
+        }
+
+        if self.in_flight > 0 {
+            // Waiting on the service, not on the client
+            return Ok(Async::NotReady);
+        }
+
+        // Nothing to read, check the timer. Polling the sleep also ensures the
+        // task is notified once it fires.
+        match self.sleep.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(())) => Err(io::Error::new(io::ErrorKind::TimedOut, "connection idle for too long")),
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+        }
+    }
+}
+
+impl<T> Sink for IdleTimeout<T>
+    where T: Sink<SinkItem = String, SinkError = io::Error>,
+{
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: String) -> StartSend<String, io::Error> {
+        let res = try!(self.upstream.start_send(item));
+
+        if let AsyncSink::Ready = res {
+            self.reset();
+            self.in_flight = self.in_flight.saturating_sub(1);
+        }
+
+        Ok(res)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.upstream.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        self.upstream.close()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for IdleTimeoutProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = IdleTimeout<Framed<T, LineCodec>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(IdleTimeout::new(
+            io.framed(LineCodec::new()),
+            self.timer.clone(),
+            self.timeout))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::IdleTimeoutProto;
+
+    use {Client, Validate};
+
+    use futures::{future, Future, Stream};
+    use tokio_io::io::read_to_end;
+    use tokio_core::net::{TcpListener, TcpStream};
+    use tokio_core::reactor::{Core, Handle};
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+    use tokio_timer::Timer;
+
+    use std::io;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    fn serve(handle: &Handle, timer: &Timer) -> SocketAddr {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+        let proto = IdleTimeoutProto {
+            timer: timer.clone(),
+            timeout: Duration::from_millis(300),
+        };
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            proto.bind_server(&handle2, socket, Validate::new(Echo));
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        addr
+    }
+
+    #[test]
+    fn silent_client_is_disconnected() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let timer = Timer::default();
+
+        let addr = serve(&handle, &timer);
+        let start = Instant::now();
+
+        // Never send anything, and read until the server closes the
+        // connection. Give up if it takes far longer than the timeout.
+        let client = TcpStream::connect(&addr, &handle)
+            .and_then(|socket| read_to_end(socket, vec![]));
+
+        let (_, received) = core.run(timer.timeout(client, Duration::from_secs(5))).unwrap();
+
+        assert!(received.is_empty());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn active_client_outlives_the_timeout() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let timer = Timer::default();
+
+        let addr = serve(&handle, &timer);
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        // Well past the timeout in total, but never idle for that long
+        for i in 0..8 {
+            core.run(timer.sleep(Duration::from_millis(100))).unwrap();
+
+            let req = format!("ping {}", i);
+            assert_eq!(core.run(client.call(req.clone())).unwrap(), req);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Running a server and a client in the same process, without any socket.
+//!
+//! `serve_in_process` connects a client to a server over a pair of in-memory
+//! pipes, which is useful to embed a line-based service in an application,
+//! for example as a plugin or in tests. The server runs as a task on the
+//! provided reactor, just like connections accepted by `serve` do.
+//!
+//! The pipes are `MemoryStream`s, which can also be used directly with any
+//! protocol. They only work within a single reactor, and buffer writes
+//! without bound.
+
+use {Client, LineProto, Validate};
+
+use futures::{Async, Poll};
+use futures::task::{self, Task};
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_proto::{BindClient, BindServer};
+use tokio_service::{Service, NewService};
+
+use std::{cmp, io};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+/// One end of an in-memory, bidirectional byte stream.
+pub struct MemoryStream {
+    read: Rc<RefCell<Pipe>>,
+    write: Rc<RefCell<Pipe>>,
+}
+
+/// A single direction of a `MemoryStream` pair
+struct Pipe {
+    buf: VecDeque<u8>,
+    // Set once the writing end is shut down or dropped
+    closed: bool,
+    // The task waiting for data to read
+    reader: Option<Task>,
+}
+
+/// Returns a pair of connected `MemoryStream`s. Bytes written to one end are
+/// read from the other.
+pub fn pair() -> (MemoryStream, MemoryStream) {
+    let a = Rc::new(RefCell::new(Pipe::new()));
+    let b = Rc::new(RefCell::new(Pipe::new()));
+
+    let first = MemoryStream {
+        read: a.clone(),
+        write: b.clone(),
+    };
+
+    let second = MemoryStream {
+        read: b,
+        write: a,
+    };
+
+    (first, second)
+}
+
+/// Start a server on `handle` and return a client connected to it through
+/// in-memory pipes.
+///
+/// The server processes requests with a service built from `new_service`, and
+/// shuts down once the client is dropped.
+pub fn serve_in_process<T>(new_service: T, handle: &Handle) -> io::Result<Client>
+    where T: NewService<Request = String, Response = String, Error = io::Error>,
+          T::Instance: 'static,
+          <T::Instance as Service>::Future: 'static,
+{
+    let (server_io, client_io) = pair();
+
+    // Wrap the service with `Validate`, just like `serve` does
+    let service = try!(Validate::new(new_service).new_service());
+    LineProto::new().bind_server(handle, server_io, service);
+
+    let client_service = LineProto::new().bind_client(handle, client_io);
+    Ok(Client::new(client_service, handle.clone()))
+}
+
+impl Pipe {
+    fn new() -> Pipe {
+        Pipe {
+            buf: VecDeque::new(),
+            closed: false,
+            reader: None,
+        }
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+
+        if let Some(task) = self.reader.take() {
+            task.notify();
+        }
+    }
+}
+
+impl io::Read for MemoryStream {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        let mut pipe = self.read.borrow_mut();
+
+        if pipe.buf.is_empty() {
+            if pipe.closed {
+                return Ok(0);
+            }
+
+            // Wait to be notified by the writing end
+            pipe.reader = Some(task::current());
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        let n = cmp::min(dst.len(), pipe.buf.len());
+
+        for (dst, src) in dst.iter_mut().zip(pipe.buf.drain(..n)) {
+            *dst = src;
+        }
+
+        Ok(n)
+    }
+}
+
+impl io::Write for MemoryStream {
+    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        let mut pipe = self.write.borrow_mut();
+
+        if pipe.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        pipe.buf.extend(src);
+
+        if let Some(task) = pipe.reader.take() {
+            task.notify();
+        }
+
+        Ok(src.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for MemoryStream {}
+
+impl AsyncWrite for MemoryStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.write.borrow_mut().close();
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        // Signal EOF to the other end, and stop accepting its writes
+        self.write.borrow_mut().close();
+        self.read.borrow_mut().closed = true;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::serve_in_process;
+
+    use futures::{future, Future};
+    use tokio_core::reactor::Core;
+    use tokio_service::Service;
+
+    use std::io;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// Upper cases requests, counting the calls
+    struct Upper(Rc<Cell<usize>>);
+
+    impl Service for Upper {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            self.0.set(self.0.get() + 1);
+            future::ok(req.to_uppercase())
+        }
+    }
+
+    #[test]
+    fn call_reaches_the_in_process_service() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let calls = Rc::new(Cell::new(0));
+        let calls2 = calls.clone();
+
+        let client = serve_in_process(move || Ok(Upper(calls2.clone())), &handle).unwrap();
+
+        assert_eq!(core.run(client.call("hello".to_string())).unwrap(), "HELLO");
+        assert_eq!(core.run(client.call("world".to_string())).unwrap(), "WORLD");
+        assert_eq!(calls.get(), 2);
+    }
+}
@@ -0,0 +1,125 @@
+//! Newline-delimited JSON.
+//!
+//! `JsonLineCodec` frames values as lines, like `LineCodec`, with each line
+//! holding a single JSON value. As JSON strings escape new lines, any value
+//! can be framed this way.
+//!
+//! This module is only available with the `json` feature enabled.
+
+use LineCodec;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+use tokio_io::codec::{Encoder, Decoder};
+
+use bytes::BytesMut;
+
+use std::io;
+use std::marker::PhantomData;
+
+/// Codec for lines holding JSON values of type `T`
+pub struct JsonLineCodec<T> {
+    lines: LineCodec,
+    _marker: PhantomData<fn(T) -> T>,
+}
+
+impl<T> JsonLineCodec<T> {
+    /// Create a codec for JSON values on lines terminated by '\n'
+    pub fn new() -> JsonLineCodec<T> {
+        JsonLineCodec::with_codec(LineCodec::new())
+    }
+
+    /// Create a codec for JSON values on lines framed by `lines`
+    pub fn with_codec(lines: LineCodec) -> JsonLineCodec<T> {
+        JsonLineCodec {
+            lines: lines,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Clone for JsonLineCodec<T> {
+    fn clone(&self) -> JsonLineCodec<T> {
+        JsonLineCodec::with_codec(self.lines)
+    }
+}
+
+impl<T> Default for JsonLineCodec<T> {
+    fn default() -> JsonLineCodec<T> {
+        JsonLineCodec::new()
+    }
+}
+
+impl<T: DeserializeOwned> Decoder for JsonLineCodec<T> {
+    type Item = T;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<T>, io::Error> {
+        let line = match try!(self.lines.decode(buf)) {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+
+        match serde_json::from_str(&line) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+impl<T: Serialize> Encoder for JsonLineCodec<T> {
+    type Item = T;
+    type Error = io::Error;
+
+    fn encode(&mut self, value: T, buf: &mut BytesMut) -> io::Result<()> {
+        let line = match serde_json::to_string(&value) {
+            Ok(line) => line,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+        };
+
+        self.lines.encode(line, buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::JsonLineCodec;
+
+    use tokio_io::codec::{Encoder, Decoder};
+
+    use bytes::BytesMut;
+
+    use std::io;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Point {
+        x: i32,
+        y: i32,
+        label: String,
+    }
+
+    #[test]
+    fn struct_round_trips() {
+        let mut codec = JsonLineCodec::<Point>::new();
+        let mut buf = BytesMut::new();
+
+        let point = Point { x: 1, y: -2, label: "multi\nline".to_string() };
+        codec.encode(point, &mut buf).unwrap();
+
+        // The embedded new line is escaped, the frame ends with the only one
+        assert_eq!(buf.iter().filter(|&&b| b == b'\n').count(), 1);
+
+        let decoded = codec.decode(&mut buf).unwrap();
+        assert_eq!(decoded, Some(Point { x: 1, y: -2, label: "multi\nline".to_string() }));
+    }
+
+    #[test]
+    fn malformed_line_is_an_error() {
+        let mut codec = JsonLineCodec::<Point>::new();
+        let mut buf = BytesMut::from(&b"{\"x\": 1\n"[..]);
+
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,416 @@
+//! Server initiated keep alive.
+//!
+//! If the server has not received anything from a client within an idle
+//! window, it sends a `[ping]` line. The client is expected to respond with
+//! `[pong]` before a deadline, otherwise the connection is closed.
+//!
+//! Similar to the `ping_pong` example, this is handled at the transport layer
+//! so that the service never sees the keep alive frames.
+//!
+//! Clients must connect with `Client::connect_with_keepalive` to answer the
+//! pings. Other clients would see them as responses to their requests.
+//!
+//! As a consequence, the `[ping]` and `[pong]` lines are reserved: on both
+//! sides, `Validate` fails requests and responses equal to either of them,
+//! which would otherwise be swallowed by the keep alive handling.
+
+use {Client, LineCodec, Validate};
+
+use futures::{Future, Stream, Sink, Poll, Async, AsyncSink, StartSend};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_proto::{TcpClient, TcpServer};
+use tokio_proto::pipeline::{ServerProto, ClientProto, ClientService};
+use tokio_service::NewService;
+use tokio_timer::{Timer, Sleep};
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const PING: &'static str = "[ping]";
+const PONG: &'static str = "[pong]";
+
+/// Lines reserved for the keep alive handling
+const RESERVED: &'static [&'static str] = &[PING, PONG];
+
+/// Server transport middleware that probes idle clients with a `[ping]`.
+pub struct ServerKeepalive<T> {
+    // The upstream transport
+    upstream: T,
+    timer: Timer,
+    // How long the connection may be silent before a ping is sent
+    idle: Duration,
+    // How long to wait for the pong once the ping is sent
+    deadline: Duration,
+    // Fires when the current idle window or pong deadline elapses
+    sleep: Sleep,
+    // True once the ping has been sent and a pong is expected
+    awaiting_pong: bool,
+    // True if a ping needs to be sent upstream
+    ping_pending: bool,
+}
+
+/// Client transport middleware that responds to `[ping]` lines sent by the
+/// server with a `[pong]`.
+pub struct AnswerPings<T> {
+    // The upstream transport
+    upstream: T,
+    // Number of remaining pongs to send
+    pongs_remaining: usize,
+}
+
+/// Protocol definition for a client answering keep alive pings
+struct AnswerPingsProto;
+
+/// Protocol definition for a server with keep alive enabled
+struct KeepaliveProto {
+    timer: Timer,
+    idle: Duration,
+    deadline: Duration,
+}
+
+/// Start a server that pings clients after `idle` without receiving any data.
+///
+/// Clients that fail to respond with a pong within `deadline` are
+/// disconnected. Otherwise, this behaves exactly like `serve`.
+pub fn serve_with_keepalive<T>(addr: SocketAddr, new_service: T, idle: Duration, deadline: Duration)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    let new_service = Validate::new(new_service).reserved(RESERVED);
+
+    let proto = KeepaliveProto {
+        timer: Timer::default(),
+        idle: idle,
+        deadline: deadline,
+    };
+
+    TcpServer::new(proto, addr)
+        .serve(new_service);
+}
+
+impl Client {
+    /// Establish a connection to a server started with
+    /// `serve_with_keepalive` at the provided `addr`, answering its pings.
+    pub fn connect_with_keepalive(addr: &SocketAddr, handle: &Handle) -> Box<Future<Item = Client, Error = io::Error>> {
+        let handle = handle.clone();
+
+        let ret = TcpClient::new(AnswerPingsProto)
+            .connect(addr, &handle)
+            .map(move |client_service: ClientService<TcpStream, AnswerPingsProto>| {
+                let mut client = Client::new(client_service, handle);
+                client.inner.reserved = RESERVED;
+                client
+            });
+
+        Box::new(ret)
+    }
+}
+
+impl<T> ServerKeepalive<T> {
+    /// Wrap `upstream` with keep alive handling.
+    pub fn new(upstream: T, timer: Timer, idle: Duration, deadline: Duration) -> ServerKeepalive<T> {
+        let sleep = timer.sleep(idle);
+
+        ServerKeepalive {
+            upstream: upstream,
+            timer: timer,
+            idle: idle,
+            deadline: deadline,
+            sleep: sleep,
+            awaiting_pong: false,
+            ping_pending: false,
+        }
+    }
+
+    /// Some data has been received from the client, so restart the idle
+    /// window.
+    fn reset(&mut self) {
+        self.awaiting_pong = false;
+        self.sleep = self.timer.sleep(self.idle);
+    }
+}
+
+impl<T> Stream for ServerKeepalive<T>
+    where T: Stream<Item = String, Error = io::Error>,
+          T: Sink<SinkItem = String, SinkError = io::Error>,
+{
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+        loop {
+            match try!(self.upstream.poll()) {
+                Async::Ready(Some(msg)) => {
+                    // Any frame proves that the client is alive
+                    self.reset();
+
+                    // Intercept the pong, it is not a request
+                    if msg != PONG {
+                        return Ok(Async::Ready(Some(msg)));
+                    }
+                }
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => break,
+            }
+        }
+
+        // Nothing to read, check the timer. Polling the sleep also ensures the
+        // task is notified once it fires.
+        loop {
+            match self.sleep.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(())) => {}
+                Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+            }
+
+            if self.awaiting_pong {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "keep alive ping timed out"));
+            }
+
+            // The connection has been idle for too long, ping the client.
+            self.awaiting_pong = true;
+            self.ping_pending = true;
+            self.sleep = self.timer.sleep(self.deadline);
+
+            // Try flushing the ping, only bubble up errors
+            try!(self.poll_complete());
+        }
+    }
+}
+
+impl<T> Sink for ServerKeepalive<T>
+    where T: Sink<SinkItem = String, SinkError = io::Error>,
+{
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: String) -> StartSend<String, io::Error> {
+        // Only accept the write if the ping has been sent
+        if self.ping_pending {
+            return Ok(AsyncSink::NotReady(item));
+        }
+
+        self.upstream.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        if self.ping_pending {
+            if try!(self.upstream.start_send(PING.to_string())).is_ready() {
+                self.ping_pending = false;
+            }
+        }
+
+        self.upstream.poll_complete()
+    }
+}
+
+impl<T> AnswerPings<T> {
+    /// Wrap `upstream`, responding to pings sent by the remote.
+    pub fn new(upstream: T) -> AnswerPings<T> {
+        AnswerPings {
+            upstream: upstream,
+            pongs_remaining: 0,
+        }
+    }
+}
+
+impl<T> Stream for AnswerPings<T>
+    where T: Stream<Item = String, Error = io::Error>,
+          T: Sink<SinkItem = String, SinkError = io::Error>,
+{
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+        loop {
+            match try_ready!(self.upstream.poll()) {
+                Some(ref msg) if msg == PING => {
+                    // Intercept [ping] messages
+                    self.pongs_remaining += 1;
+
+                    // Try flushing the pong, only bubble up errors
+                    try!(self.poll_complete());
+                }
+                m => return Ok(Async::Ready(m)),
+            }
+        }
+    }
+}
+
+impl<T> Sink for AnswerPings<T>
+    where T: Sink<SinkItem = String, SinkError = io::Error>,
+{
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: String) -> StartSend<String, io::Error> {
+        // Only accept the write if there are no pending pongs
+        if self.pongs_remaining > 0 {
+            return Ok(AsyncSink::NotReady(item));
+        }
+
+        self.upstream.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        while self.pongs_remaining > 0 {
+            let res = try!(self.upstream.start_send(PONG.to_string()));
+
+            if !res.is_ready() {
+                // The upstream is not ready to accept new items
+                break;
+            }
+
+            self.pongs_remaining -= 1;
+        }
+
+        self.upstream.poll_complete()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for KeepaliveProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = ServerKeepalive<Framed<T, LineCodec>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(ServerKeepalive::new(
+            io.framed(LineCodec::new()),
+            self.timer.clone(),
+            self.idle,
+            self.deadline))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for AnswerPingsProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = AnswerPings<Framed<T, LineCodec>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(AnswerPings::new(io.framed(LineCodec::new())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{KeepaliveProto, RESERVED};
+    use {Client, Validate};
+
+    use futures::{future, Future, Stream};
+    use tokio_io::io::read_to_end;
+    use tokio_core::net::{TcpListener, TcpStream};
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+    use tokio_timer::Timer;
+
+    use std::io;
+    use std::time::Duration;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn silent_client_is_disconnected() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let proto = KeepaliveProto {
+            timer: Timer::default(),
+            idle: Duration::from_millis(50),
+            deadline: Duration::from_millis(50),
+        };
+
+        let handle2 = handle.clone();
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            proto.bind_server(&handle2, socket, Echo);
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        // Never send anything, and read until the server closes the
+        // connection
+        let client = TcpStream::connect(&addr, &handle)
+            .and_then(|socket| read_to_end(socket, vec![]));
+
+        let (_, received) = core.run(client).unwrap();
+
+        assert_eq!(received, b"[ping]\n");
+    }
+
+    /// Responds to every request with a ping
+    struct PingResponder;
+
+    impl Service for PingResponder {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, _: String) -> Self::Future {
+            future::ok("[ping]".to_string())
+        }
+    }
+
+    #[test]
+    fn control_lines_are_reserved() {
+        // A service may not respond with a ping, the client would swallow it
+        let server = Validate::new(PingResponder).reserved(RESERVED);
+        let err = server.call("hello".to_string()).wait().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let proto = KeepaliveProto {
+            timer: Timer::default(),
+            idle: Duration::from_secs(60),
+            deadline: Duration::from_secs(60),
+        };
+
+        let handle2 = handle.clone();
+
+        let server = listener.incoming().for_each(move |(socket, _)| {
+            proto.bind_server(&handle2, socket, Validate::new(Echo).reserved(RESERVED));
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let client = core.run(Client::connect_with_keepalive(&addr, &handle)).unwrap();
+
+        // A pong request would be swallowed by the server, leaving the call
+        // hanging, so it fails right away
+        let err = core.run(client.call("[pong]".to_string())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // The connection is still usable
+        assert_eq!(core.run(client.call("hello".to_string())).unwrap(), "hello");
+    }
+}
@@ -0,0 +1,247 @@
+//! Request latency histograms.
+//!
+//! The `LatencyHistogramService` middleware measures the time between a
+//! request being passed to the inner service and its response future
+//! completing, and records it in a `LatencyHistogram`. The histogram can be
+//! shared by every connection to get global figures, or created per
+//! connection.
+//!
+//! To avoid pulling in a dedicated histogram crate, latencies are counted in a
+//! fixed set of buckets. Percentiles are therefore reported as the upper bound
+//! of the bucket they fall in, which is precise enough for SLO monitoring.
+
+use futures::Future;
+use tokio_service::{Service, NewService};
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the buckets, in microseconds. Latencies above the last
+/// bound are counted in an additional overflow bucket.
+const BOUNDS: &'static [u64] = &[
+    100, 250, 500,
+    1_000, 2_500, 5_000,
+    10_000, 25_000, 50_000,
+    100_000, 250_000, 500_000,
+    1_000_000, 2_500_000, 5_000_000,
+    10_000_000,
+];
+
+/// Latencies of completed requests, counted in buckets.
+///
+/// Handles are cheap to clone, and all clones record into the same histogram.
+#[derive(Clone)]
+pub struct LatencyHistogram {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    // One counter per bound, plus the overflow bucket
+    counts: Vec<u64>,
+    total: u64,
+    max: Duration,
+}
+
+/// A `Service` middleware recording the latency of every request.
+pub struct LatencyHistogramService<T> {
+    inner: T,
+    histogram: LatencyHistogram,
+}
+
+impl LatencyHistogram {
+    /// Create a new, empty `LatencyHistogram`
+    pub fn new() -> LatencyHistogram {
+        LatencyHistogram {
+            inner: Arc::new(Mutex::new(Inner {
+                counts: vec![0; BOUNDS.len() + 1],
+                total: 0,
+                max: Duration::from_secs(0),
+            })),
+        }
+    }
+
+    /// Record a single latency
+    pub fn record(&self, latency: Duration) {
+        let micros = latency.as_secs() * 1_000_000 + (latency.subsec_nanos() / 1_000) as u64;
+        let bucket = BOUNDS.iter().position(|&bound| micros <= bound).unwrap_or(BOUNDS.len());
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.counts[bucket] += 1;
+        inner.total += 1;
+
+        if latency > inner.max {
+            inner.max = latency;
+        }
+    }
+
+    /// Returns the number of recorded latencies
+    pub fn count(&self) -> u64 {
+        self.inner.lock().unwrap().total
+    }
+
+    /// Returns the highest recorded latency
+    pub fn max(&self) -> Duration {
+        self.inner.lock().unwrap().max
+    }
+
+    /// Returns the latency below which `percentile` percent of the recorded
+    /// latencies fall, for example `percentile(99.0)`.
+    ///
+    /// The value is the upper bound of the bucket containing the percentile,
+    /// or the maximum recorded latency for the overflow bucket. Returns `None`
+    /// if nothing was recorded yet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `percentile` is not within `0.0..=100.0`.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        assert!(percentile >= 0.0 && percentile <= 100.0, "percentile out of range");
+
+        let inner = self.inner.lock().unwrap();
+
+        if inner.total == 0 {
+            return None;
+        }
+
+        // The rank of the sample we are looking for, starting at 1
+        let rank = ((percentile / 100.0) * inner.total as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+
+        for (i, &count) in inner.counts.iter().enumerate() {
+            seen += count;
+
+            if seen >= rank {
+                return Some(match BOUNDS.get(i) {
+                    Some(&bound) => from_micros(bound),
+                    None => inner.max,
+                });
+            }
+        }
+
+        Some(inner.max)
+    }
+
+    /// Returns the number of latencies recorded in each bucket, along with the
+    /// bucket's upper bound. The overflow bucket has no upper bound.
+    pub fn buckets(&self) -> Vec<(Option<Duration>, u64)> {
+        let inner = self.inner.lock().unwrap();
+
+        inner.counts.iter().enumerate()
+            .map(|(i, &count)| {
+                (BOUNDS.get(i).map(|&bound| from_micros(bound)), count)
+            })
+            .collect()
+    }
+}
+
+fn from_micros(micros: u64) -> Duration {
+    Duration::new(micros / 1_000_000, ((micros % 1_000_000) * 1_000) as u32)
+}
+
+impl<T> LatencyHistogramService<T> {
+    /// Create a new `LatencyHistogramService` recording into `histogram`
+    pub fn new(inner: T, histogram: LatencyHistogram) -> LatencyHistogramService<T> {
+        LatencyHistogramService {
+            inner: inner,
+            histogram: histogram,
+        }
+    }
+
+    /// Returns the histogram latencies are recorded into
+    pub fn histogram(&self) -> &LatencyHistogram {
+        &self.histogram
+    }
+}
+
+impl<T> Service for LatencyHistogramService<T>
+    where T: Service<Request = String, Response = String, Error = io::Error>,
+          T::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        let histogram = self.histogram.clone();
+        let start = Instant::now();
+
+        Box::new(self.inner.call(req)
+            .then(move |res| {
+                histogram.record(start.elapsed());
+                res
+            }))
+    }
+}
+
+impl<T> NewService for LatencyHistogramService<T>
+    where T: NewService<Request = String, Response = String, Error = io::Error>,
+          <T::Instance as Service>::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Instance = LatencyHistogramService<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = try!(self.inner.new_service());
+        Ok(LatencyHistogramService::new(inner, self.histogram.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LatencyHistogram, LatencyHistogramService};
+
+    use futures::{future, Future};
+    use tokio_service::Service;
+
+    use std::{io, thread};
+    use std::time::Duration;
+
+    /// Takes as many milliseconds as the request says to respond
+    struct Sleep;
+
+    impl Service for Sleep {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            thread::sleep(Duration::from_millis(req.parse().unwrap()));
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn latencies_are_recorded_in_their_buckets() {
+        let histogram = LatencyHistogram::new();
+        let service = LatencyHistogramService::new(Sleep, histogram.clone());
+
+        assert_eq!(histogram.percentile(50.0), None);
+
+        for delay in &["0", "30", "120"] {
+            service.call(delay.to_string()).wait().unwrap();
+        }
+
+        assert_eq!(histogram.count(), 3);
+        assert!(histogram.max() >= Duration::from_millis(120));
+
+        let count_in = |bound: u64| {
+            histogram.buckets().iter()
+                .find(|&&(b, _)| b == Some(Duration::from_millis(bound)))
+                .map(|&(_, count)| count)
+                .unwrap()
+        };
+
+        // 30ms falls in the (25ms, 50ms] bucket, 120ms in (100ms, 250ms]
+        assert_eq!(count_in(50), 1);
+        assert_eq!(count_in(250), 1);
+
+        assert_eq!(histogram.percentile(50.0), Some(Duration::from_millis(50)));
+        assert_eq!(histogram.percentile(100.0), Some(Duration::from_millis(250)));
+    }
+}
@@ -0,0 +1,291 @@
+//! Length-prefixed framing.
+//!
+//! `LineCodec` terminates frames with a delimiter, so payloads cannot contain
+//! it. `LengthCodec` instead frames each message as a 4 byte big-endian
+//! length followed by exactly that many bytes of UTF-8, so that messages may
+//! contain new lines.
+//!
+//! Servers started with `serve_length_prefixed` do not reject messages
+//! containing new lines, and neither do clients connected with
+//! `Client::connect_length_prefixed`.
+
+use {Client, Newlines};
+
+use futures::Future;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::{Framed, Encoder, Decoder};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_proto::{TcpClient, TcpServer};
+use tokio_proto::pipeline::{ServerProto, ClientProto, ClientService};
+use tokio_service::NewService;
+
+use bytes::{BytesMut, Buf, BufMut, BigEndian};
+
+use std::{io, str, u32};
+use std::net::SocketAddr;
+
+/// Length of the header preceding each payload
+const HEADER_LEN: usize = 4;
+
+/// Codec framing messages with a 4 byte big-endian length prefix
+///
+/// Payloads are limited to 8MB by default. Use `with_max_length` to change
+/// the limit, which is checked as soon as the header is received, before
+/// buffering the payload.
+#[derive(Debug, Clone, Copy)]
+pub struct LengthCodec {
+    max_length: usize,
+}
+
+/// Protocol definition for length-prefixed framing
+#[derive(Debug, Clone, Copy)]
+pub struct LengthProto {
+    codec: LengthCodec,
+}
+
+/// Frame `io` with a `LengthCodec` with the default length limit
+pub fn new_length_transport<T>(io: T) -> Framed<T, LengthCodec>
+    where T: AsyncRead + AsyncWrite,
+{
+    io.framed(LengthCodec::new())
+}
+
+/// Start a server framing messages with a length prefix, listening for
+/// connections on `addr`.
+///
+/// Unlike `serve`, requests and responses may contain new lines.
+pub fn serve_length_prefixed<T>(addr: SocketAddr, new_service: T)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    TcpServer::new(LengthProto::new(), addr)
+        .serve(new_service);
+}
+
+impl Client {
+    /// Establish a connection to a server started with
+    /// `serve_length_prefixed` at the provided `addr`.
+    ///
+    /// Requests and responses may contain new lines.
+    pub fn connect_length_prefixed(addr: &SocketAddr, handle: &Handle) -> Box<Future<Item = Client, Error = io::Error>> {
+        let handle = handle.clone();
+
+        let ret = TcpClient::new(LengthProto::new())
+            .connect(addr, &handle)
+            .map(move |client_service: ClientService<TcpStream, LengthProto>| {
+                let mut client = Client::new(client_service, handle);
+                client.inner.newlines = Newlines::Allow;
+                client
+            });
+
+        Box::new(ret)
+    }
+}
+
+impl LengthCodec {
+    /// Create a codec limiting payloads to 8MB
+    pub fn new() -> LengthCodec {
+        LengthCodec::with_max_length(8 * 1024 * 1024)
+    }
+
+    /// Create a codec limiting payloads to `max_length` bytes.
+    ///
+    /// Decoding fails with `InvalidData` when a header announces a longer
+    /// payload, and encoding fails with `InvalidInput` for longer messages.
+    pub fn with_max_length(max_length: usize) -> LengthCodec {
+        LengthCodec { max_length: max_length }
+    }
+
+    /// Returns the maximum length of a payload
+    pub fn max_length(&self) -> usize {
+        self.max_length
+    }
+}
+
+impl LengthProto {
+    /// Create a protocol framing messages with the default `LengthCodec`
+    pub fn new() -> LengthProto {
+        LengthProto::with_codec(LengthCodec::new())
+    }
+
+    /// Create a protocol framing messages with `codec`
+    pub fn with_codec(codec: LengthCodec) -> LengthProto {
+        LengthProto { codec: codec }
+    }
+}
+
+impl Default for LengthCodec {
+    fn default() -> LengthCodec {
+        LengthCodec::new()
+    }
+}
+
+impl Default for LengthProto {
+    fn default() -> LengthProto {
+        LengthProto::new()
+    }
+}
+
+impl Decoder for LengthCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<String>, io::Error> {
+        // Wait for the full header
+        if buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+
+        let len = io::Cursor::new(&buf[0..HEADER_LEN]).get_u32::<BigEndian>() as usize;
+
+        if len > self.max_length {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too long"));
+        }
+
+        // Wait for the full payload, leaving the header in the buffer
+        if buf.len() < HEADER_LEN + len {
+            buf.reserve(HEADER_LEN + len - buf.len());
+            return Ok(None);
+        }
+
+        buf.split_to(HEADER_LEN);
+        let payload = buf.split_to(len);
+
+        match str::from_utf8(&payload.as_ref()) {
+            Ok(s) => Ok(Some(s.to_string())),
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "invalid string")),
+        }
+    }
+}
+
+impl Encoder for LengthCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: String, buf: &mut BytesMut) -> io::Result<()> {
+        if msg.len() > self.max_length || msg.len() > u32::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "message too long"));
+        }
+
+        buf.reserve(HEADER_LEN + msg.len());
+
+        buf.put_u32::<BigEndian>(msg.len() as u32);
+        buf.extend(msg.as_bytes());
+
+        Ok(())
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for LengthProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = Framed<T, LengthCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(self.codec))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for LengthProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = Framed<T, LengthCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(self.codec))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LengthCodec, LengthProto};
+
+    use Client;
+
+    use futures::{future, Future, Stream};
+    use tokio_io::codec::{Encoder, Decoder};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+
+    use bytes::BytesMut;
+
+    use std::io;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn embedded_new_line_is_decoded_across_partial_reads() {
+        let mut codec = LengthCodec::new();
+        let mut encoded = BytesMut::new();
+
+        codec.encode("hello\nworld".to_string(), &mut encoded).unwrap();
+        assert_eq!(&encoded[..4], &[0, 0, 0, 11]);
+
+        // Feed the frame in pieces: part of the header, then part of the
+        // payload, then the rest.
+        let mut buf = BytesMut::new();
+
+        buf.extend(&encoded[..2]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.extend(&encoded[2..8]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.extend(&encoded[8..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("hello\nworld".to_string()));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn lengths_over_the_cap_are_rejected() {
+        let mut codec = LengthCodec::with_max_length(4);
+
+        let err = codec.encode("hello".to_string(), &mut BytesMut::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // Rejected as soon as the header is received
+        let mut buf = BytesMut::from(&[0, 0, 0, 5][..]);
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn new_lines_round_trip_through_the_client() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            LengthProto::new().bind_server(&handle2, socket, Echo);
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let client = core.run(Client::connect_length_prefixed(&addr, &handle)).unwrap();
+
+        let resp = core.run(client.call("multi\nline\n".to_string())).unwrap();
+        assert_eq!(resp, "multi\nline\n");
+    }
+}
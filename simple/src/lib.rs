@@ -2,27 +2,118 @@
 
 #![deny(warnings, missing_docs)]
 
+#[macro_use]
 extern crate futures;
 extern crate tokio_io;
 extern crate tokio_core;
 extern crate tokio_proto;
 extern crate tokio_service;
+extern crate tokio_timer;
 extern crate bytes;
+extern crate futures_cpupool;
 
-use futures::{future, Future};
+#[cfg(feature = "encoding")]
+extern crate encoding_rs;
+#[cfg(feature = "compression")]
+extern crate flate2;
+#[cfg(feature = "tls")]
+extern crate native_tls;
+#[cfg(feature = "json")]
+extern crate serde;
+#[cfg(feature = "json")]
+extern crate serde_json;
+#[cfg(all(test, feature = "json"))]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "tls")]
+extern crate tokio_tls;
+#[cfg(feature = "websocket")]
+extern crate tokio_tungstenite;
+#[cfg(feature = "websocket")]
+extern crate tungstenite;
+#[cfg(feature = "unicode")]
+extern crate unicode_normalization;
+#[cfg(feature = "websocket")]
+extern crate url;
+
+use futures::{future, Future, Stream, Sink, Poll, Async, AsyncSink, StartSend};
+use futures::future::{Either, Shared};
+use futures::sync::oneshot;
+use futures::task::{self, Task};
 
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::codec::{Framed, Encoder, Decoder};
-use tokio_core::net::TcpStream;
-use tokio_core::reactor::Handle;
-use tokio_proto::{TcpClient, TcpServer};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_proto::{BindServer, TcpClient, TcpServer};
 use tokio_proto::pipeline::{ServerProto, ClientProto, ClientService};
 use tokio_service::{Service, NewService};
+use tokio_timer::Timer;
 
 use bytes::{BytesMut, BufMut};
 
-use std::{io, str};
+use std::{io, str, usize};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashSet};
 use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+pub mod accept_rate;
+pub mod admin;
+pub mod asymmetric;
+pub mod audit;
+pub mod batch;
+pub mod benchmark;
+pub mod budget;
+pub mod checksum;
+pub mod close;
+pub mod coalesce;
+#[cfg(feature = "compression")]
+pub mod compression;
+pub mod connect_delay;
+pub mod connection_id;
+pub mod debounce;
+#[cfg(feature = "encoding")]
+pub mod encoding;
+pub mod eos;
+pub mod escaped;
+pub mod events;
+pub mod fanout;
+pub mod features;
+pub mod hedge;
+pub mod idle_timeout;
+pub mod in_process;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod keepalive;
+pub mod latency;
+pub mod length;
+pub mod min_gap;
+pub mod multi_protocol;
+pub mod peer_addr;
+pub mod pool;
+pub mod prometheus;
+pub mod reconnect;
+pub mod reverse;
+pub mod schema;
+pub mod sequenced;
+pub mod setup;
+pub mod short_string;
+pub mod sliding_window;
+pub mod slowloris;
+pub mod swap;
+pub mod testing;
+pub mod time_budget;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod trace;
+pub mod ttl;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+pub mod write_buffer;
 
 /// Line-based client handle
 ///
@@ -36,25 +127,189 @@ use std::net::SocketAddr;
 /// specific. For example, our line client has a `ping()` function, which sends
 /// a "ping" request.
 pub struct Client {
-    inner: Validate<ClientService<TcpStream, LineProto>>,
+    inner: Validate<BoxService>,
+    acks: Rc<RefCell<Acks>>,
+    handle: Handle,
+    // Assigned by servers started with `connection_id::serve_with_connection_ids`
+    connection_id: Option<u64>,
+    // Advertised by servers started with `features::serve_with_features`
+    features: HashSet<String>,
+    // Completes with whether the end-of-stream marker was received, for
+    // clients connected with `Client::connect_with_eos`
+    eos: Option<Shared<oneshot::Receiver<bool>>>,
+}
+
+/// Erases the type of the underlying client service.
+///
+/// This allows `Client` to be used with protocols other than `LineProto`, for
+/// example when running the line protocol over a WebSocket.
+struct BoxService {
+    inner: Box<Service<Request = String,
+                       Response = String,
+                       Error = io::Error,
+                       Future = Box<Future<Item = String, Error = io::Error>>>>,
+}
+
+/// Boxes the response futures of the inner service
+struct BoxFutures<T> {
+    inner: T,
+}
+
+/// Tracks requests that have been issued but not yet answered by the server.
+///
+/// Every call is assigned a sequence number. A `flush_and_confirm` waiter
+/// records the sequence number that was next at the time it was created and is
+/// notified once every request issued before it has completed.
+struct Acks {
+    next: u64,
+    outstanding: BTreeSet<u64>,
+    waiters: Vec<(u64, oneshot::Sender<()>)>,
+}
+
+/// Removes a request from `Acks` once its response future completes or is
+/// dropped.
+struct AckGuard {
+    acks: Rc<RefCell<Acks>>,
+    seq: u64,
 }
 
 /// A `Service` middleware that validates the correctness of requests and
 /// responses.
 ///
-/// Our line protocol does not support escaping '\n' in strings, this means that
-/// requests and responses cannot contain new lines. The `Validate` middleware
-/// will check the messages for new lines and error the request if one is
-/// detected.
+/// Our line protocol does not support new lines in frames, this means that
+/// requests and responses cannot contain new lines. By default, the `Validate`
+/// middleware will check the messages for new lines and error the request if
+/// one is detected.
+///
+/// Alternatively, `Validate::escaping` transparently escapes '\n' as "\\n",
+/// and '\\' as "\\\\", so that services can process arbitrary strings.
+///
+/// For peers splitting lines on "\r\n", `Validate::strict` also rejects
+/// messages containing carriage returns.
+///
+/// When lines are framed with another delimiter, `delimiter` makes `Validate`
+/// reject messages containing that delimiter instead of new lines, so that
+/// only the offending call fails rather than the whole connection.
+///
+/// Protocols sending control lines of their own, such as keep alive pings,
+/// use `reserved` to reject messages that would be mistaken for them.
 pub struct Validate<T> {
     inner: T,
+    newlines: Newlines,
+    // The byte terminating lines on the connection
+    delimiter: u8,
+    // Lines used by the protocol itself, which messages must not be
+    reserved: &'static [&'static str],
+}
+
+/// How `Validate` handles new lines
+#[derive(Debug, Clone, Copy)]
+enum Newlines {
+    // Error messages containing the delimiter
+    Reject,
+    // Error messages containing the delimiter or carriage returns
+    RejectStrict,
+    // The inner service is the application: unescape requests, escape
+    // responses
+    Unescape,
+    // The inner service is the connection: escape requests, unescape
+    // responses
+    Escape,
+    // Pass messages through, for framings that can carry new lines
+    Allow,
+}
+
+/// Counts the open connections of `serve_until`
+struct Connections {
+    open: usize,
+    // Notified once the last connection closes
+    drained: Option<Task>,
+}
+
+/// A service instance counted in `Connections` for as long as its connection
+/// is open
+struct Tracked<T> {
+    inner: T,
+    connections: Rc<RefCell<Connections>>,
+}
+
+/// Server transport middleware of `serve_until`, ending the stream of
+/// requests once shutdown is signalled and no request is in flight, which
+/// closes the connection.
+struct Drain<T> {
+    // The upstream transport
+    upstream: T,
+    // Completes, or fails, once shutdown is signalled
+    shutdown: Shared<oneshot::Receiver<()>>,
+    // Set once shutdown was signalled
+    closing: bool,
+    // Requests read and not yet responded to
+    in_flight: usize,
+}
+
+/// Protocol definition of `serve_until`
+struct DrainProto {
+    shutdown: Shared<oneshot::Receiver<()>>,
+}
+
+/// Transport middleware counting the frames and bytes passing through it.
+///
+/// Frames are passed through untouched. Bytes are counted as the length of
+/// the lines, excluding delimiters. The counters can be read from any thread
+/// through the `Metrics` handle returned by `metrics`.
+pub struct Metered<T> {
+    // The upstream transport
+    upstream: T,
+    metrics: Metrics,
+}
+
+/// Handle to the counters of a `Metered` transport
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    inner: Arc<Counters>,
+}
+
+#[derive(Debug)]
+struct Counters {
+    frames_in: AtomicUsize,
+    frames_out: AtomicUsize,
+    bytes_in: AtomicUsize,
+    bytes_out: AtomicUsize,
 }
 
 /// Our line-based codec
-pub struct LineCodec;
+///
+/// Lines are terminated by '\n' by default. Use `with_delimiter` to talk to
+/// peers terminating frames with another byte, and `crlf` to talk to peers
+/// terminating lines with "\r\n".
+///
+/// Lines are unbounded by default. Use `with_max_length` to bound the memory
+/// buffered while waiting for the end of a line, and `min_length` to reject
+/// lines that are too short to be valid.
+#[derive(Debug, Clone, Copy)]
+pub struct LineCodec {
+    delimiter: u8,
+    // Whether the delimiter is preceded by '\r'
+    crlf: bool,
+    // Minimum and maximum length of a line, excluding the delimiter
+    min_length: usize,
+    max_length: usize,
+    // Whether invalid UTF-8 is replaced rather than failing the connection
+    lossy: bool,
+    // Whether decoded lines are normalized to NFC
+    #[cfg(feature = "unicode")]
+    nfc: bool,
+}
 
 /// Protocol definition
-struct LineProto;
+///
+/// This is the protocol used by `serve` and `Client`. It is exposed so that it
+/// can be used with the tokio-proto builders directly, for example when
+/// wrapping it with `setup::with_connection_setup`.
+#[derive(Debug, Clone, Copy)]
+pub struct LineProto {
+    codec: LineCodec,
+}
 
 /// Start a server, listening for connections on `addr`.
 ///
@@ -68,27 +323,353 @@ pub fn serve<T>(addr: SocketAddr, new_service: T)
     // We want responses returned from the provided request handler to be well
     // formed. The `Validate` wrapper ensures that all service instances are
     // also wrapped with `Validate`.
-    let new_service = Validate { inner: new_service };
+    let new_service = Validate::new(new_service);
 
     // Use the tokio-proto TCP server builder, this will handle creating a
     // reactor instance and other details needed to run a server.
-    TcpServer::new(LineProto, addr)
+    TcpServer::new(LineProto::new(), addr)
         .serve(new_service);
 }
 
+/// Start a server framing lines with `codec`, listening for connections on
+/// `addr`.
+///
+/// This is otherwise the same as `serve`.
+pub fn serve_with_codec<T>(addr: SocketAddr, new_service: T, codec: LineCodec)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    let new_service = Validate::new(new_service).delimiter(codec.delimiter());
+
+    TcpServer::new(LineProto::with_codec(codec), addr)
+        .serve(new_service);
+}
+
+/// Start a server, listening for connections on `addr`, until `shutdown`
+/// completes.
+///
+/// Once `shutdown` completes, successfully or not, the listener stops
+/// accepting connections. Connections that are already open are allowed to
+/// finish the requests they sent: each of them is closed as soon as it has no
+/// request in flight, and this function returns once every one of them has
+/// been closed.
+///
+/// Unlike `serve`, all connections are handled on the current thread.
+pub fn serve_until<T, F>(addr: SocketAddr, new_service: T, shutdown: F) -> io::Result<()>
+    where T: NewService<Request = String, Response = String, Error = io::Error>,
+          T::Instance: 'static,
+          F: Future<Item = (), Error = ()>,
+{
+    serve_until_with(addr, new_service, shutdown, |shutdown| DrainProto { shutdown: shutdown })
+}
+
+/// Implements `serve_until`, binding every connection with the protocol
+/// returned by `proto`.
+///
+/// The protocol is handed the shutdown signal, and must close its connection
+/// once it completes.
+fn serve_until_with<T, F, P, G>(addr: SocketAddr, new_service: T, shutdown: F, proto: G) -> io::Result<()>
+    where T: NewService<Request = String, Response = String, Error = io::Error>,
+          T::Instance: 'static,
+          F: Future<Item = (), Error = ()>,
+          P: ServerProto<TcpStream, Request = String, Response = String>,
+          G: Fn(Shared<oneshot::Receiver<()>>) -> P,
+{
+    let mut core = try!(Core::new());
+    let handle = core.handle();
+
+    let listener = try!(TcpListener::bind(&addr, &handle));
+    let new_service = Validate::new(new_service);
+
+    let connections = Rc::new(RefCell::new(Connections {
+        open: 0,
+        drained: None,
+    }));
+
+    // Signals shutdown to the open connections. Dropping the sender signals
+    // it as well.
+    let (tx, rx) = oneshot::channel();
+    let rx = rx.shared();
+
+    let server = {
+        let connections = connections.clone();
+
+        listener.incoming().for_each(move |(socket, _)| {
+            let service = try!(new_service.new_service());
+
+            connections.borrow_mut().open += 1;
+
+            let service = Tracked {
+                inner: service,
+                connections: connections.clone(),
+            };
+
+            // Spawns a task on the reactor dedicated to processing the
+            // connection
+            proto(rx.clone()).bind_server(&handle, socket, service);
+            Ok(())
+        })
+    };
+
+    let shutdown = shutdown.then(|_| Ok(()));
+
+    // Dropping the accept loop closes the listener
+    let res = core.run(server.select(shutdown).map(|_| ()).map_err(|(e, _)| e));
+
+    // Close the connections once idle
+    drop(tx);
+    try!(res);
+
+    // Wait for the open connections to be closed
+    core.run(future::poll_fn(move || {
+        let mut connections = connections.borrow_mut();
+
+        if connections.open == 0 {
+            return Ok(Async::Ready(()));
+        }
+
+        connections.drained = Some(task::current());
+        Ok(Async::NotReady)
+    }))
+}
+
 impl Client {
     /// Establish a connection to a line-based server at the provided `addr`.
     pub fn connect(addr: &SocketAddr, handle: &Handle) -> Box<Future<Item = Client, Error = io::Error>> {
-        let ret = TcpClient::new(LineProto)
-            .connect(addr, handle)
-            .map(|client_service| {
-                let validate = Validate { inner: client_service};
-                Client { inner: validate }
+        Client::connect_with_codec(addr, handle, LineCodec::new())
+    }
+
+    /// Establish a connection to a line-based server at the provided `addr`,
+    /// framing lines with `codec`.
+    pub fn connect_with_codec(addr: &SocketAddr, handle: &Handle, codec: LineCodec) -> Box<Future<Item = Client, Error = io::Error>> {
+        let handle = handle.clone();
+
+        let delimiter = codec.delimiter();
+
+        let ret = TcpClient::new(LineProto::with_codec(codec))
+            .connect(addr, &handle)
+            .map(move |client_service: ClientService<TcpStream, LineProto>| {
+                let mut client = Client::new(client_service, handle);
+                client.inner.delimiter = delimiter;
+                client
+            });
+
+        Box::new(ret)
+    }
+
+    /// Establish a connection to a line-based server at the provided `addr`,
+    /// failing with `TimedOut` if it is not established within `dur`.
+    ///
+    /// Without a timeout, connecting to an unreachable address may not fail
+    /// until the operating system gives up, which can take minutes.
+    pub fn connect_timeout(addr: &SocketAddr, handle: &Handle, timer: &Timer, dur: Duration) -> Box<Future<Item = Client, Error = io::Error>> {
+        let sleep = timer.sleep(dur)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+
+        let ret = Client::connect(addr, handle)
+            .select2(sleep)
+            .then(|res| {
+                // Dropping the pending connection aborts it
+                match res {
+                    Ok(Either::A((client, _))) => Ok(client),
+                    Ok(Either::B(_)) => Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out")),
+                    Err(Either::A((e, _))) => Err(e),
+                    Err(Either::B((e, _))) => Err(e),
+                }
             });
 
         Box::new(ret)
     }
 
+    /// Wrap a connected client service
+    fn new<T>(service: T, handle: Handle) -> Client
+        where T: Service<Request = String, Response = String, Error = io::Error> + 'static,
+              T::Future: 'static,
+    {
+        let service = BoxService {
+            inner: Box::new(BoxFutures { inner: service }),
+        };
+
+        Client {
+            inner: Validate::new(service),
+            acks: Rc::new(RefCell::new(Acks::new())),
+            handle: handle,
+            connection_id: None,
+            features: HashSet::new(),
+            eos: None,
+        }
+    }
+
+    /// Returns a future that resolves once the server has responded to every
+    /// request issued on this client so far.
+    ///
+    /// This is stronger than flushing the socket: the future only completes
+    /// once the responses have been received, confirming end-to-end delivery.
+    /// Requests issued after calling this function are not waited on.
+    pub fn flush_and_confirm(&self) -> Box<Future<Item = (), Error = io::Error>> {
+        let mut acks = self.acks.borrow_mut();
+        let seq = acks.next;
+
+        if acks.is_confirmed(seq) {
+            return Box::new(future::ok(()));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        acks.waiters.push((seq, tx));
+
+        Box::new(rx.map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "client dropped")
+        }))
+    }
+
+    /// Send a request that the server should drop if it has not started
+    /// processing it within `ttl`.
+    ///
+    /// The server must be running the `ttl::Expire` middleware. Requests that
+    /// expire resolve with an error of kind `TimedOut`.
+    pub fn call_with_ttl(&self, req: String, ttl: Duration) -> Box<Future<Item = String, Error = io::Error>> {
+        let resp = self.call(ttl::encode(&req, ttl))
+            .and_then(|resp| {
+                if resp == ttl::EXPIRED {
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "request expired"))
+                } else {
+                    Ok(resp)
+                }
+            });
+
+        Box::new(resp)
+    }
+
+    /// Send a request carrying the W3C trace context `traceparent`.
+    ///
+    /// The server must be running the `trace::ExtractTrace` middleware. A
+    /// malformed `traceparent` fails with `InvalidInput` without being sent.
+    pub fn call_traced(&self, req: String, traceparent: String) -> Box<Future<Item = String, Error = io::Error>> {
+        match trace::TraceParent::parse(&traceparent) {
+            Ok(traceparent) => self.call(trace::encode(&req, &traceparent)),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /// Send a request, failing with `TimedOut` if no response is received
+    /// within `dur`.
+    ///
+    /// Because the protocol is pipelined, the server will still send the
+    /// response once it is done processing the request. When the deadline
+    /// elapses, the call keeps being driven in the background so that the late
+    /// response is consumed and discarded in its slot of the pipeline, instead
+    /// of being matched with a later request.
+    pub fn call_deadline(&self, req: String, timer: &Timer, dur: Duration) -> Box<Future<Item = String, Error = io::Error>> {
+        let handle = self.handle.clone();
+
+        let sleep = timer.sleep(dur)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+
+        let resp = self.call(req)
+            .select2(sleep)
+            .then(move |res| {
+                match res {
+                    Ok(Either::A((resp, _))) => Ok(resp),
+                    Ok(Either::B((_, resp))) => {
+                        // The deadline elapsed first, discard the response
+                        // once it arrives.
+                        handle.spawn(resp.then(|_| Ok(())));
+                        Err(io::Error::new(io::ErrorKind::TimedOut, "request timed out"))
+                    }
+                    Err(Either::A((e, _))) => Err(e),
+                    Err(Either::B((e, resp))) => {
+                        handle.spawn(resp.then(|_| Ok(())));
+                        Err(e)
+                    }
+                }
+            });
+
+        Box::new(resp)
+    }
+
+    /// Send `reqs` to the server as a single batch.
+    ///
+    /// The returned future resolves once the server has acknowledged that
+    /// every request in the batch has been processed. The individual responses
+    /// are not sent back. The server must be started with
+    /// `batch::serve_batched`.
+    pub fn call_batch(&self, reqs: Vec<String>) -> Box<Future<Item = (), Error = io::Error>> {
+        let n = reqs.len();
+
+        if n == 0 {
+            return Box::new(future::ok(()));
+        }
+
+        // Batch lines are joined with the delimiter, which must be a
+        // character of its own
+        let delimiter = self.inner.delimiter;
+
+        if delimiter >= 0x80 {
+            let err = io::Error::new(io::ErrorKind::InvalidInput, "batches require an ASCII delimiter");
+            return Box::new(future::err(err));
+        }
+
+        let mut lines = vec![batch::header(n)];
+
+        // `Validate` is bypassed below, as it would reject the delimiters, so
+        // validate each request on its own
+        for req in reqs {
+            match self.inner.validate_request(req) {
+                Ok(req) => lines.push(req),
+                Err(e) => return Box::new(future::err(e)),
+            }
+        }
+
+        // The header and all of the requests are joined with the delimiter
+        // and sent as a single pipelined request, so that the single ack is
+        // matched with it as its response. The `SplitBatches` transport writes
+        // each line as a frame of its own, so on the wire this is exactly the
+        // batch framing.
+        let guard = AckGuard::new(&self.acks);
+
+        let resp = self.inner.inner.call(lines.join(&(delimiter as char).to_string()))
+            .then(move |res| {
+                drop(guard);
+                res
+            })
+            .and_then(move |resp| {
+                if resp == batch::ack(n) {
+                    Ok(())
+                } else {
+                    Err(io::Error::new(io::ErrorKind::Other, "expected batch ack"))
+                }
+            });
+
+        Box::new(resp)
+    }
+
+    /// Send `reqs` as a group, collecting the result of each request.
+    ///
+    /// All requests of the group are queued at once, so they are written to
+    /// the connection back to back. The returned future resolves once every
+    /// request has completed, with the results in request order. A failed
+    /// request does not fail the group, instead its error is reported in its
+    /// slot.
+    pub fn call_group(&self, reqs: Vec<String>) -> Box<Future<Item = Vec<Result<String, io::Error>>, Error = io::Error>> {
+        let calls = reqs.into_iter()
+            .map(|req| self.call(req).then(|res| Ok::<_, io::Error>(res)))
+            .collect::<Vec<_>>();
+
+        Box::new(future::join_all(calls))
+    }
+
+    /// Send all of `reqs`, collecting the responses in request order.
+    ///
+    /// The requests are pipelined: they are all queued at once rather than
+    /// waiting for each response before sending the next request. The
+    /// returned future fails with the first error, if any request fails.
+    pub fn call_all(&self, reqs: Vec<String>) -> Box<Future<Item = Vec<String>, Error = io::Error>> {
+        let calls = reqs.into_iter()
+            .map(|req| self.call(req))
+            .collect::<Vec<_>>();
+
+        Box::new(future::join_all(calls))
+    }
+
     /// Send a `ping` to the remote. The returned future resolves when the
     /// remote has responded with a pong.
     ///
@@ -110,6 +691,15 @@ impl Client {
         // a new future type and `impl T` isn't stable yet...
         Box::new(resp)
     }
+
+    /// Escape new lines in requests, and unescape them in responses.
+    ///
+    /// This allows sending arbitrary strings to a server wrapping its service
+    /// with `Validate::escaping`.
+    pub fn escaping(mut self) -> Client {
+        self.inner.newlines = Newlines::Escape;
+        self
+    }
 }
 
 impl Service for Client {
@@ -119,16 +709,233 @@ impl Service for Client {
     // For simplicity, box the future.
     type Future = Box<Future<Item = String, Error = io::Error>>;
 
+    fn call(&self, req: String) -> Self::Future {
+        let guard = AckGuard::new(&self.acks);
+
+        // The guard is dropped once the response is received, or when the
+        // response future is dropped, whichever happens first.
+        Box::new(self.inner.call(req)
+            .then(move |res| {
+                drop(guard);
+                res
+            }))
+    }
+}
+
+impl Service for BoxService {
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
     fn call(&self, req: String) -> Self::Future {
         self.inner.call(req)
     }
 }
 
+impl<T> Service for BoxFutures<T>
+    where T: Service<Request = String, Response = String, Error = io::Error>,
+          T::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        Box::new(self.inner.call(req))
+    }
+}
+
+impl Acks {
+    fn new() -> Acks {
+        Acks {
+            next: 0,
+            outstanding: BTreeSet::new(),
+            waiters: vec![],
+        }
+    }
+
+    /// Returns true if all requests with a sequence number lower than `seq`
+    /// have completed.
+    fn is_confirmed(&self, seq: u64) -> bool {
+        self.outstanding.iter().next().map(|&min| min >= seq).unwrap_or(true)
+    }
+}
+
+impl AckGuard {
+    fn new(acks: &Rc<RefCell<Acks>>) -> AckGuard {
+        let seq = {
+            let mut a = acks.borrow_mut();
+            let seq = a.next;
+            a.next += 1;
+            a.outstanding.insert(seq);
+            seq
+        };
+
+        AckGuard {
+            acks: acks.clone(),
+            seq: seq,
+        }
+    }
+}
+
+impl Drop for AckGuard {
+    fn drop(&mut self) {
+        let mut acks = self.acks.borrow_mut();
+        acks.outstanding.remove(&self.seq);
+
+        // Notify any waiters whose requests have now all been acknowledged
+        let waiters = ::std::mem::replace(&mut acks.waiters, vec![]);
+
+        for (seq, tx) in waiters {
+            if acks.is_confirmed(seq) {
+                let _ = tx.send(());
+            } else {
+                acks.waiters.push((seq, tx));
+            }
+        }
+    }
+}
+
 impl<T> Validate<T> {
 
-    /// Create a new `Validate`
+    /// Create a new `Validate`, rejecting messages containing new lines
     pub fn new(inner: T) -> Validate<T> {
-        Validate { inner: inner }
+        Validate {
+            inner: inner,
+            newlines: Newlines::Reject,
+            delimiter: b'\n',
+            reserved: &[],
+        }
+    }
+
+    /// Create a new `Validate`, rejecting messages containing new lines or
+    /// carriage returns.
+    ///
+    /// A carriage return does not end a frame of `LineCodec`, but peers
+    /// treating "\r\n" as the delimiter may split messages on it.
+    pub fn strict(inner: T) -> Validate<T> {
+        Validate {
+            inner: inner,
+            newlines: Newlines::RejectStrict,
+            delimiter: b'\n',
+            reserved: &[],
+        }
+    }
+
+    /// Create a new `Validate`, escaping new lines.
+    ///
+    /// Requests are unescaped before being passed to `inner`, and responses
+    /// are escaped, so this is meant to wrap a service processing requests.
+    /// Requests that are not validly escaped are rejected with `InvalidData`.
+    ///
+    /// Clients opt into escaping with `Client::escaping`.
+    pub fn escaping(inner: T) -> Validate<T> {
+        Validate {
+            inner: inner,
+            newlines: Newlines::Unescape,
+            delimiter: b'\n',
+            reserved: &[],
+        }
+    }
+
+    /// Reject messages containing `delimiter` rather than new lines, for
+    /// connections framing lines with `LineCodec::with_delimiter`.
+    pub fn delimiter(self, delimiter: u8) -> Validate<T> {
+        Validate {
+            delimiter: delimiter,
+            .. self
+        }
+    }
+
+    /// Validate `req`, returning it as it is to be passed to the inner service
+    fn validate_request(&self, req: String) -> io::Result<String> {
+        let req = try!(reject_reserved(req, self.reserved));
+
+        // Make sure that the request does not include any new lines
+        match self.newlines {
+            Newlines::Reject => reject_newlines(req, self.delimiter),
+            Newlines::RejectStrict => reject_newlines_strict(req, self.delimiter),
+            Newlines::Unescape => unescape(&req),
+            Newlines::Escape => Ok(escape(&req)),
+            Newlines::Allow => Ok(req),
+        }
+    }
+
+    /// Reject messages equal to one of `reserved`, which the protocol sends
+    /// as control lines.
+    pub fn reserved(self, reserved: &'static [&'static str]) -> Validate<T> {
+        Validate {
+            reserved: reserved,
+            .. self
+        }
+    }
+}
+
+/// Escape '\n' as "\\n", and '\\' as "\\\\"
+pub fn escape(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '\n' => ret.push_str("\\n"),
+            '\\' => ret.push_str("\\\\"),
+            c => ret.push(c),
+        }
+    }
+
+    ret
+}
+
+/// Reverse `escape`, failing with `InvalidData` on any other escape sequence
+pub fn unescape(s: &str) -> io::Result<String> {
+    let mut ret = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => ret.push('\n'),
+            Some('\\') => ret.push('\\'),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid escape sequence")),
+        }
+    }
+
+    Ok(ret)
+}
+
+/// Reject `msg` if it contains the delimiter
+fn reject_newlines(msg: String, delimiter: u8) -> io::Result<String> {
+    if !msg.as_bytes().contains(&delimiter) {
+        return Ok(msg);
+    }
+
+    if delimiter == b'\n' {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "message contained new line"))
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "message contained the delimiter"))
+    }
+}
+
+/// Reject `msg` if it is one of the `reserved` control lines
+fn reject_reserved(msg: String, reserved: &[&str]) -> io::Result<String> {
+    if reserved.iter().any(|&line| line == msg) {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "message is a reserved control line"))
+    } else {
+        Ok(msg)
+    }
+}
+
+fn reject_newlines_strict(msg: String, delimiter: u8) -> io::Result<String> {
+    if msg.chars().find(|&c| c == '\r').is_some() {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "message contained carriage return"))
+    } else {
+        reject_newlines(msg, delimiter)
     }
 }
 
@@ -143,19 +950,25 @@ impl<T> Service for Validate<T>
     type Future = Box<Future<Item = String, Error = io::Error>>;
 
     fn call(&self, req: String) -> Self::Future {
-        // Make sure that the request does not include any new lines
-        if req.chars().find(|&c| c == '\n').is_some() {
-            let err = io::Error::new(io::ErrorKind::InvalidInput, "message contained new line");
-            return Box::new(future::done(Err(err)))
-        }
+        let req = match self.validate_request(req) {
+            Ok(req) => req,
+            Err(e) => return Box::new(future::done(Err(e))),
+        };
+
+        let newlines = self.newlines;
+        let delimiter = self.delimiter;
+        let reserved = self.reserved;
 
         // Call the upstream service and validate the response
         Box::new(self.inner.call(req)
-            .and_then(|resp| {
-                if resp.chars().find(|&c| c == '\n').is_some() {
-                    Err(io::Error::new(io::ErrorKind::InvalidInput, "message contained new line"))
-                } else {
-                    Ok(resp)
+            .and_then(move |resp| reject_reserved(resp, reserved))
+            .and_then(move |resp| {
+                match newlines {
+                    Newlines::Reject => reject_newlines(resp, delimiter),
+                    Newlines::RejectStrict => reject_newlines_strict(resp, delimiter),
+                    Newlines::Unescape => Ok(escape(&resp)),
+                    Newlines::Escape => unescape(&resp),
+                    Newlines::Allow => Ok(resp),
                 }
             }))
     }
@@ -172,33 +985,377 @@ impl<T> NewService for Validate<T>
 
     fn new_service(&self) -> io::Result<Self::Instance> {
         let inner = try!(self.inner.new_service());
-        Ok(Validate { inner: inner })
+
+        Ok(Validate {
+            inner: inner,
+            newlines: self.newlines,
+            delimiter: self.delimiter,
+            reserved: self.reserved,
+        })
     }
 }
 
-/// Implementation of the simple line-based protocol.
-///
-/// Frames consist of a UTF-8 encoded string, terminated by a '\n' character.
-impl Decoder for LineCodec {
-    type Item = String;
-    type Error = io::Error;
+impl<T: Service> Service for Tracked<T> {
+    type Request = T::Request;
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = T::Future;
 
-    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<String>, io::Error> {
-        // Check to see if the frame contains a new line
-        if let Some(n) = buf.as_ref().iter().position(|b| *b == b'\n') {
-            // remove the serialized frame from the buffer.
-            let line = buf.split_to(n);
+    fn call(&self, req: T::Request) -> T::Future {
+        self.inner.call(req)
+    }
+}
 
-            // Also remove the '\n'
-            buf.split_to(1);
+impl<T> Drop for Tracked<T> {
+    fn drop(&mut self) {
+        let mut connections = self.connections.borrow_mut();
+        connections.open -= 1;
+
+        if connections.open == 0 {
+            if let Some(task) = connections.drained.take() {
+                task.notify();
+            }
+        }
+    }
+}
+
+impl<T> Stream for Drain<T>
+    where T: Stream<Item = String, Error = io::Error>,
+{
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+        match try!(self.upstream.poll()) {
+            Async::Ready(Some(req)) => {
+                self.in_flight += 1;
+                return Ok(Async::Ready(Some(req)));
+            }
+            Async::Ready(None) => return Ok(Async::Ready(None)),
+            Async::NotReady => {}
+        }
+
+        // Polling the signal also ensures the task is notified once it
+        // completes
+        if !self.closing {
+            match self.shutdown.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                _ => self.closing = true,
+            }
+        }
+
+        if self.in_flight > 0 {
+            // Let the requests in flight finish
+            return Ok(Async::NotReady);
+        }
+
+        Ok(Async::Ready(None))
+    }
+}
+
+impl<T> Sink for Drain<T>
+    where T: Sink<SinkItem = String, SinkError = io::Error>,
+{
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: String) -> StartSend<String, io::Error> {
+        let res = try!(self.upstream.start_send(item));
+
+        if let AsyncSink::Ready = res {
+            self.in_flight = self.in_flight.saturating_sub(1);
+
+            if self.closing && self.in_flight == 0 {
+                // The stream must be polled again to end it
+                task::current().notify();
+            }
+        }
+
+        Ok(res)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.upstream.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        self.upstream.close()
+    }
+}
+
+impl<T> Metered<T> {
+    /// Wrap `upstream`, counting the frames passing through it
+    pub fn new(upstream: T) -> Metered<T> {
+        Metered {
+            upstream: upstream,
+            metrics: Metrics {
+                inner: Arc::new(Counters {
+                    frames_in: AtomicUsize::new(0),
+                    frames_out: AtomicUsize::new(0),
+                    bytes_in: AtomicUsize::new(0),
+                    bytes_out: AtomicUsize::new(0),
+                }),
+            },
+        }
+    }
+
+    /// Returns a handle to the counters, which may be sent to another thread
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    /// Returns the number of frames read
+    pub fn frames_in(&self) -> u64 {
+        self.metrics.frames_in()
+    }
+
+    /// Returns the number of frames written
+    pub fn frames_out(&self) -> u64 {
+        self.metrics.frames_out()
+    }
+
+    /// Returns the number of bytes read
+    pub fn bytes_in(&self) -> u64 {
+        self.metrics.bytes_in()
+    }
+
+    /// Returns the number of bytes written
+    pub fn bytes_out(&self) -> u64 {
+        self.metrics.bytes_out()
+    }
+}
+
+impl Metrics {
+    /// Returns the number of frames read
+    pub fn frames_in(&self) -> u64 {
+        self.inner.frames_in.load(Ordering::Relaxed) as u64
+    }
+
+    /// Returns the number of frames written
+    pub fn frames_out(&self) -> u64 {
+        self.inner.frames_out.load(Ordering::Relaxed) as u64
+    }
+
+    /// Returns the number of bytes read
+    pub fn bytes_in(&self) -> u64 {
+        self.inner.bytes_in.load(Ordering::Relaxed) as u64
+    }
+
+    /// Returns the number of bytes written
+    pub fn bytes_out(&self) -> u64 {
+        self.inner.bytes_out.load(Ordering::Relaxed) as u64
+    }
+}
+
+impl<T> Stream for Metered<T>
+    where T: Stream<Item = String, Error = io::Error>,
+{
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+        let frame = try_ready!(self.upstream.poll());
+
+        if let Some(ref line) = frame {
+            self.metrics.inner.frames_in.fetch_add(1, Ordering::Relaxed);
+            self.metrics.inner.bytes_in.fetch_add(line.len(), Ordering::Relaxed);
+        }
+
+        Ok(Async::Ready(frame))
+    }
+}
+
+impl<T> Sink for Metered<T>
+    where T: Sink<SinkItem = String, SinkError = io::Error>,
+{
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: String) -> StartSend<String, io::Error> {
+        let len = item.len();
+        let res = try!(self.upstream.start_send(item));
+
+        if let AsyncSink::Ready = res {
+            self.metrics.inner.frames_out.fetch_add(1, Ordering::Relaxed);
+            self.metrics.inner.bytes_out.fetch_add(len, Ordering::Relaxed);
+        }
+
+        Ok(res)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.upstream.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        self.upstream.close()
+    }
+}
+
+impl LineCodec {
+    /// Create a codec for lines terminated by '\n'
+    pub fn new() -> LineCodec {
+        LineCodec::with_delimiter(b'\n')
+    }
+
+    /// Create a codec for lines terminated by `delimiter`
+    pub fn with_delimiter(delimiter: u8) -> LineCodec {
+        LineCodec {
+            delimiter: delimiter,
+            crlf: false,
+            min_length: 0,
+            max_length: usize::MAX,
+            lossy: false,
+            #[cfg(feature = "unicode")]
+            nfc: false,
+        }
+    }
+
+    /// Create a codec for lines terminated by '\n', of at most `max_length`
+    /// bytes
+    pub fn with_max_length(max_length: usize) -> LineCodec {
+        LineCodec::new().max_length(max_length)
+    }
+
+    /// Require lines to be at least `min_length` bytes, excluding the
+    /// delimiter.
+    ///
+    /// Decoding fails with `InvalidData` when a shorter line is received.
+    pub fn min_length(self, min_length: usize) -> LineCodec {
+        LineCodec {
+            min_length: min_length,
+            .. self
+        }
+    }
+
+    /// Limit lines to `max_length` bytes, excluding the delimiter.
+    ///
+    /// Decoding fails with `InvalidData` once more than `max_length` bytes
+    /// are buffered without a delimiter.
+    pub fn max_length(self, max_length: usize) -> LineCodec {
+        LineCodec {
+            max_length: max_length,
+            .. self
+        }
+    }
+
+    /// Replace invalid UTF-8 sequences in decoded lines with U+FFFD when
+    /// `lossy` is true.
+    ///
+    /// By default, a line that is not valid UTF-8 fails decoding, closing the
+    /// connection along with every request in flight on it.
+    pub fn lossy(self, lossy: bool) -> LineCodec {
+        LineCodec {
+            lossy: lossy,
+            .. self
+        }
+    }
+
+    /// Create a codec for lines terminated by "\r\n"
+    ///
+    /// Lines terminated by a bare '\n' are decoded as well, to tolerate
+    /// sloppy peers.
+    pub fn crlf() -> LineCodec {
+        LineCodec {
+            crlf: true,
+            .. LineCodec::new()
+        }
+    }
+
+    /// Normalize decoded lines to Unicode Normalization Form C when `nfc` is
+    /// true, so that canonically equivalent strings compare equal.
+    ///
+    /// This is only available with the `unicode` feature enabled.
+    #[cfg(feature = "unicode")]
+    pub fn normalize_nfc(self, nfc: bool) -> LineCodec {
+        LineCodec {
+            nfc: nfc,
+            .. self
+        }
+    }
+
+    /// Returns the byte terminating lines
+    pub fn delimiter(&self) -> u8 {
+        self.delimiter
+    }
+}
+
+impl LineProto {
+    /// Create a protocol framing lines with the default `LineCodec`
+    pub fn new() -> LineProto {
+        LineProto::with_codec(LineCodec::new())
+    }
+
+    /// Create a protocol framing lines with `codec`
+    pub fn with_codec(codec: LineCodec) -> LineProto {
+        LineProto { codec: codec }
+    }
+}
+
+impl Default for LineCodec {
+    fn default() -> LineCodec {
+        LineCodec::new()
+    }
+}
+
+impl Default for LineProto {
+    fn default() -> LineProto {
+        LineProto::new()
+    }
+}
+
+/// Implementation of the simple line-based protocol.
+///
+/// Frames consist of a UTF-8 encoded string, terminated by the delimiter,
+/// which is a '\n' character by default.
+impl Decoder for LineCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<String>, io::Error> {
+        let delimiter = self.delimiter;
+
+        // Check to see if the frame contains a delimiter
+        if let Some(n) = buf.as_ref().iter().position(|b| *b == delimiter) {
+            // remove the serialized frame from the buffer.
+            let mut line = buf.split_to(n);
+
+            // Also remove the delimiter
+            buf.split_to(1);
+
+            // In CRLF mode, strip the '\r' preceding the delimiter, if any
+            if self.crlf && line.as_ref().last() == Some(&b'\r') {
+                line.truncate(n - 1);
+            }
+
+            if line.len() > self.max_length {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+            }
+
+            if line.len() < self.min_length {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "line too short"));
+            }
 
             // Turn this data into a UTF string and return it in a Frame.
             return match str::from_utf8(&line.as_ref()) {
+                #[cfg(feature = "unicode")]
+                Ok(s) if self.nfc => {
+                    use unicode_normalization::UnicodeNormalization;
+                    Ok(Some(s.nfc().collect()))
+                }
                 Ok(s) => Ok(Some(s.to_string())),
+                Err(_) if self.lossy => Ok(Some(String::from_utf8_lossy(&line.as_ref()).into_owned())),
                 Err(_) => Err(io::Error::new(io::ErrorKind::Other, "invalid string")),
             }
         }
 
+        // In CRLF mode, the '\r' of an incomplete line may already be buffered
+        let limit = if self.crlf { self.max_length.saturating_add(1) } else { self.max_length };
+
+        if buf.len() > limit {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+        }
+
         Ok(None)
     }
 }
@@ -208,11 +1365,20 @@ impl Encoder for LineCodec {
     type Error = io::Error;
 
     fn encode(&mut self, msg: String, buf: &mut BytesMut) -> io::Result<()> {
+        if msg.as_bytes().contains(&self.delimiter) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "message contained the delimiter"));
+        }
+
         // Reserve enough space for the line
-        buf.reserve(msg.len() + 1);
+        buf.reserve(msg.len() + 2);
 
         buf.extend(msg.as_bytes());
-        buf.put_u8(b'\n');
+
+        if self.crlf {
+            buf.put_u8(b'\r');
+        }
+
+        buf.put_u8(self.delimiter);
 
         Ok(())
     }
@@ -222,12 +1388,14 @@ impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for LineProto {
     type Request = String;
     type Response = String;
 
-    /// `Framed<T, LineCodec>` is the return value of `io.framed(LineCodec)`
-    type Transport = Framed<T, LineCodec>;
+    /// `Framed<T, LineCodec>` is the return value of `io.framed(codec)`.
+    /// It is wrapped so that batches are written one line per frame.
+    type Transport = batch::SplitBatches<Framed<T, LineCodec>>;
     type BindTransport = Result<Self::Transport, io::Error>;
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(LineCodec))
+        let delimiter = self.codec.delimiter();
+        Ok(batch::SplitBatches::new(io.framed(self.codec), delimiter))
     }
 }
 
@@ -235,11 +1403,486 @@ impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for LineProto {
     type Request = String;
     type Response = String;
 
-    /// `Framed<T, LineCodec>` is the return value of `io.framed(LineCodec)`
+    /// `Framed<T, LineCodec>` is the return value of `io.framed(codec)`
     type Transport = Framed<T, LineCodec>;
     type BindTransport = Result<Self::Transport, io::Error>;
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(LineCodec))
+        Ok(io.framed(self.codec))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for DrainProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = Drain<Framed<T, LineCodec>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(Drain {
+            upstream: io.framed(LineCodec::new()),
+            shutdown: self.shutdown.clone(),
+            closing: false,
+            in_flight: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{escape, serve_until, unescape, Client, LineCodec, LineProto, Metered, Newlines, Validate};
+
+    use futures::{future, Future, Stream, Sink};
+    use futures::sync::oneshot;
+    use tokio_io::AsyncRead;
+    use tokio_io::codec::{Encoder, Decoder};
+    use tokio_core::net::{TcpListener, TcpStream};
+    use tokio_core::reactor::{Core, Handle};
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+    use tokio_timer::Timer;
+
+    use bytes::BytesMut;
+
+    use std::{io, net, thread};
+    use std::cell::RefCell;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::rc::Rc;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    /// Responds to `slow` after 300ms, echoes everything else right away
+    struct Delayed {
+        timer: Timer,
+    }
+
+    impl Service for Delayed {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = Box<Future<Item = String, Error = io::Error>>;
+
+        fn call(&self, req: String) -> Self::Future {
+            if req == "slow" {
+                let resp = self.timer.sleep(Duration::from_millis(300))
+                    .map(move |_| req)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+
+                return Box::new(resp);
+            }
+
+            Box::new(future::ok(req))
+        }
+    }
+
+    /// Fails `fail`, echoes everything else
+    struct FailOne;
+
+    impl Service for FailOne {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            if req == "fail" {
+                return future::err(io::Error::new(io::ErrorKind::Other, "failed"));
+            }
+
+            future::ok(req)
+        }
+    }
+
+    /// Serve a single connection with `service` on the reactor of `handle`
+    fn serve_one<T>(handle: &Handle, service: T) -> net::SocketAddr
+        where T: Service<Request = String, Response = String, Error = io::Error> + 'static,
+              T::Future: 'static,
+    {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+        let mut service = Some(service);
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            let service = Validate::new(service.take().unwrap());
+            LineProto::new().bind_server(&handle2, socket, service);
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        addr
+    }
+
+    #[test]
+    fn round_trip_with_nul_delimiter() {
+        let mut codec = LineCodec::with_delimiter(b'\0');
+        let mut buf = BytesMut::new();
+
+        codec.encode("hello".to_string(), &mut buf).unwrap();
+        codec.encode("multi\nline".to_string(), &mut buf).unwrap();
+
+        assert_eq!(&buf[..], &b"hello\0multi\nline\0"[..]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("hello".to_string()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("multi\nline".to_string()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_utf8_fails_decoding() {
+        let mut codec = LineCodec::new();
+        let mut buf = BytesMut::from(&b"\xff\xfe\n"[..]);
+
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced_when_lossy() {
+        let mut codec = LineCodec::new().lossy(true);
+        let mut buf = BytesMut::from(&b"a\xffb\nok\n"[..]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("a\u{fffd}b".to_string()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("ok".to_string()));
+    }
+
+    #[test]
+    fn encode_rejects_delimiter() {
+        let mut codec = LineCodec::with_delimiter(b'\0');
+        let mut buf = BytesMut::new();
+
+        assert!(codec.encode("nul\0byte".to_string(), &mut buf).is_err());
+    }
+
+    #[test]
+    fn shutdown_closes_idle_connections() {
+        let addr = net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let (done_tx, done_rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            serve_until(addr, || Ok(Echo), shutdown_rx.map_err(|_| ())).unwrap();
+            done_tx.send(()).unwrap();
+        });
+
+        let mut socket = loop {
+            match net::TcpStream::connect(addr) {
+                Ok(socket) => break socket,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+
+        // Make sure the connection is being served
+        socket.write_all(b"hello\n").unwrap();
+
+        let mut buf = [0; 6];
+        socket.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello\n");
+
+        // The client stays connected, but is idle
+        shutdown_tx.send(()).unwrap();
+        done_rx.recv_timeout(Duration::from_secs(5)).expect("shutdown waited on an idle connection");
+
+        assert_eq!(socket.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn crlf_lines_are_stripped() {
+        let mut codec = LineCodec::crlf();
+        let mut buf = BytesMut::from(&b"hi\r\n"[..]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("hi".to_string()));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn crlf_mode_accepts_bare_new_lines() {
+        let mut codec = LineCodec::crlf();
+        let mut buf = BytesMut::from(&b"hi\n"[..]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("hi".to_string()));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn crlf_empty_line() {
+        let mut codec = LineCodec::crlf();
+        let mut buf = BytesMut::from(&b"\r\n"[..]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("".to_string()));
+
+        codec.encode("hi".to_string(), &mut buf).unwrap();
+        assert_eq!(&buf[..], &b"hi\r\n"[..]);
+    }
+
+    #[test]
+    fn lines_over_max_length_fail() {
+        let mut codec = LineCodec::with_max_length(4);
+
+        // Exactly `max_length` bytes are still waited on
+        let mut buf = BytesMut::from(&b"abcd"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        // One more byte without a delimiter fails
+        buf.extend_from_slice(b"e");
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn lines_at_max_length_are_decoded() {
+        let mut codec = LineCodec::with_max_length(4);
+        let mut buf = BytesMut::from(&b"abcd\n"[..]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("abcd".to_string()));
+    }
+
+    #[test]
+    fn lines_under_min_length_fail() {
+        let mut codec = LineCodec::new().min_length(3);
+        let mut buf = BytesMut::from(&b"ab\n\n"[..]);
+
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Empty lines are too short as well
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn lines_at_min_length_are_decoded() {
+        let mut codec = LineCodec::new().min_length(3);
+        let mut buf = BytesMut::from(&b"abc\n"[..]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("abc".to_string()));
+    }
+
+    #[test]
+    fn carriage_returns_are_only_rejected_when_strict() {
+        let lenient = Validate::new(Echo);
+        assert_eq!(lenient.call("a\rb".to_string()).wait().unwrap(), "a\rb");
+
+        let strict = Validate::strict(Echo);
+        let err = strict.call("a\rb".to_string()).wait().unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("carriage return"));
+
+        // New lines are rejected in both modes
+        let err = strict.call("a\nb".to_string()).wait().unwrap_err();
+        assert!(err.to_string().contains("new line"));
+        assert!(lenient.call("a\nb".to_string()).wait().is_err());
+    }
+
+    #[test]
+    fn validation_follows_the_delimiter() {
+        let nul = Validate::new(Echo).delimiter(b'\0');
+
+        // New lines are harmless when lines end with NUL
+        assert_eq!(nul.call("a\nb".to_string()).wait().unwrap(), "a\nb");
+
+        let err = nul.call("a\0b".to_string()).wait().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("delimiter"));
+
+        // The call is failed before reaching the codec, which would fail the
+        // whole connection
+        let mut codec = LineCodec::with_delimiter(b'\0');
+        let mut buf = BytesMut::new();
+        assert!(codec.encode("a\0b".to_string(), &mut buf).is_err());
+    }
+
+    #[test]
+    fn escaping_round_trips_new_lines_and_backslashes() {
+        let msg = "one\ntwo \\ three\\n\n";
+
+        let escaped = escape(msg);
+        assert!(!escaped.contains('\n'));
+        assert_eq!(unescape(&escaped).unwrap(), msg);
+
+        // The server side unescapes requests and escapes responses, so it
+        // echoes the escaped line as is
+        let server = Validate::escaping(Echo);
+        assert_eq!(server.call(escaped.clone()).wait().unwrap(), escaped);
+
+        // The client side escapes requests and unescapes responses
+        let client = Validate { newlines: Newlines::Escape, .. Validate::new(Validate::escaping(Echo)) };
+        assert_eq!(client.call(msg.to_string()).wait().unwrap(), msg);
+
+        let err = server.call("bad \\escape".to_string()).wait().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn flush_and_confirm_waits_for_every_response() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let addr = serve_one(&handle, Delayed { timer: Timer::default() });
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        let responses = Rc::new(RefCell::new(vec![]));
+
+        for req in &["slow", "fast", "slow"] {
+            let responses = responses.clone();
+
+            handle.spawn(client.call(req.to_string())
+                .map(move |resp| responses.borrow_mut().push(resp))
+                .map_err(|_| ()));
+        }
+
+        let responded = responses.clone();
+        let confirmed = client.flush_and_confirm().map(move |()| responded.borrow().len());
+
+        assert_eq!(core.run(confirmed).unwrap(), 3);
+        assert_eq!(*responses.borrow(), vec!["slow", "fast", "slow"]);
+
+        // Nothing is outstanding anymore
+        assert!(client.flush_and_confirm().wait().is_ok());
+    }
+
+    #[test]
+    fn late_response_is_discarded_after_the_deadline() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let timer = Timer::default();
+
+        let addr = serve_one(&handle, Delayed { timer: timer.clone() });
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        let slow = client.call_deadline("slow".to_string(), &timer, Duration::from_millis(50));
+        let err = core.run(slow).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // The late response to `slow` is still on its way, and is not taken
+        // for the response to the next request
+        assert_eq!(core.run(client.call("hello".to_string())).unwrap(), "hello");
+        assert_eq!(core.run(client.call("again".to_string())).unwrap(), "again");
+    }
+
+    #[test]
+    fn group_reports_the_result_of_each_request() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let addr = serve_one(&handle, FailOne);
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        // A failed request ends the pipelined connection, so it is sent last
+        let reqs = vec!["one".to_string(), "two".to_string(), "fail".to_string()];
+        let results = core.run(client.call_group(reqs)).unwrap();
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), "one");
+        assert_eq!(results[1].as_ref().unwrap(), "two");
+        assert!(results[2].is_err());
+    }
+
+    #[test]
+    fn call_all_preserves_request_order() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let addr = serve_one(&handle, Echo);
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        let reqs: Vec<String> = (0..10).map(|i| format!("line {}", i)).collect();
+        let resps = core.run(client.call_all(reqs.clone())).unwrap();
+
+        assert_eq!(resps, reqs);
+    }
+
+    #[test]
+    #[cfg(feature = "unicode")]
+    fn decomposed_lines_are_normalized_to_nfc() {
+        let decomposed = "e\u{301}";
+
+        let mut buf = BytesMut::from(format!("{}\n{}\n", decomposed, decomposed).as_bytes());
+
+        let mut codec = LineCodec::new().normalize_nfc(true);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("\u{e9}".to_string()));
+
+        // Normalization is off by default
+        let mut codec = LineCodec::new();
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(decomposed.to_string()));
+    }
+
+    #[test]
+    fn metered_counts_frames_and_bytes() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+
+            let mut line = String::new();
+            BufReader::new(socket.try_clone().unwrap()).read_line(&mut line).unwrap();
+            assert_eq!(line, "abc\n");
+
+            socket.write_all(b"hello\nworld\n").unwrap();
+        });
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let socket = core.run(TcpStream::connect(&addr, &handle)).unwrap();
+        let transport = Metered::new(socket.framed(LineCodec::new()));
+        let metrics = transport.metrics();
+
+        let transport = core.run(transport.send("abc".to_string())).unwrap();
+        assert_eq!(transport.frames_out(), 1);
+        assert_eq!(transport.bytes_out(), 3);
+
+        // Frames are passed through untouched
+        let frames = core.run(transport.take(2).collect()).unwrap();
+        assert_eq!(frames, vec!["hello", "world"]);
+
+        // The counters can be read from another thread
+        let counts = thread::spawn(move || {
+            (metrics.frames_in(), metrics.bytes_in(), metrics.frames_out(), metrics.bytes_out())
+        }).join().unwrap();
+
+        assert_eq!(counts, (2, 10, 1, 3));
+    }
+
+    #[test]
+    fn connect_to_blackholed_address_times_out() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let timer = Timer::default();
+
+        // Nothing answers on this non-routable address, so the SYN is dropped
+        let addr = "10.255.255.1:9".parse().unwrap();
+
+        let err = core.run(Client::connect_timeout(&addr, &handle, &timer, Duration::from_millis(200)))
+            .err()
+            .expect("connected to a blackholed address");
+
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // A reachable server is connected to within the timeout
+        let addr = serve_one(&handle, Echo);
+        let client = core.run(Client::connect_timeout(&addr, &handle, &timer, Duration::from_secs(5))).unwrap();
+
+        assert_eq!(core.run(client.call("hello".to_string())).unwrap(), "hello");
     }
 }
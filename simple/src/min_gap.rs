@@ -0,0 +1,161 @@
+//! Enforcing a minimum gap between requests.
+//!
+//! Some backends, such as hardware with a cooldown, cannot handle requests in
+//! quick succession. The `MinGapService` middleware dispatches requests to the
+//! inner service at least `min_gap` apart. A request arriving too soon after
+//! the previous one is delayed until the gap has elapsed.
+//!
+//! Unlike rate limiting, requests are never rejected, only delayed. Each
+//! request reserves its dispatch time when it is received, so requests are
+//! dispatched in the order they arrive.
+
+use futures::Future;
+use tokio_service::{Service, NewService};
+use tokio_timer::Timer;
+
+use std::io;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// A `Service` middleware spacing out requests by at least a minimum gap.
+pub struct MinGapService<T> {
+    inner: Rc<T>,
+    min_gap: Duration,
+    timer: Timer,
+    // Dispatch time reserved by the most recent request
+    last_dispatch: Cell<Option<Instant>>,
+}
+
+/// Builds a `MinGapService` for each new connection.
+pub struct NewMinGap<T> {
+    inner: T,
+    min_gap: Duration,
+    timer: Timer,
+}
+
+impl<T> MinGapService<T> {
+    /// Create a new `MinGapService`
+    pub fn new(inner: T, min_gap: Duration, timer: Timer) -> MinGapService<T> {
+        MinGapService {
+            inner: Rc::new(inner),
+            min_gap: min_gap,
+            timer: timer,
+            last_dispatch: Cell::new(None),
+        }
+    }
+}
+
+impl<T> Service for MinGapService<T>
+    where T: Service<Request = String, Response = String, Error = io::Error> + 'static,
+          T::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        let now = Instant::now();
+
+        let dispatch = match self.last_dispatch.get() {
+            Some(last) if last + self.min_gap > now => last + self.min_gap,
+            _ => now,
+        };
+
+        self.last_dispatch.set(Some(dispatch));
+
+        if dispatch == now {
+            return Box::new(self.inner.call(req));
+        }
+
+        let inner = self.inner.clone();
+
+        let resp = self.timer.sleep(dispatch - now)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            .and_then(move |_| inner.call(req));
+
+        Box::new(resp)
+    }
+}
+
+impl<T> NewMinGap<T> {
+    /// Create a new `NewMinGap`
+    pub fn new(inner: T, min_gap: Duration, timer: Timer) -> NewMinGap<T> {
+        NewMinGap {
+            inner: inner,
+            min_gap: min_gap,
+            timer: timer,
+        }
+    }
+}
+
+impl<T> NewService for NewMinGap<T>
+    where T: NewService<Request = String, Response = String, Error = io::Error>,
+          T::Instance: 'static,
+          <T::Instance as Service>::Future: 'static
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Instance = MinGapService<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = try!(self.inner.new_service());
+        Ok(MinGapService::new(inner, self.min_gap, self.timer.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MinGapService;
+
+    use futures::{future, Future};
+    use tokio_service::Service;
+    use tokio_timer::Timer;
+
+    use std::io;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    /// Records when each request is dispatched to it
+    struct Record(Rc<RefCell<Vec<(String, Instant)>>>);
+
+    impl Service for Record {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            self.0.borrow_mut().push((req.clone(), Instant::now()));
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn back_to_back_requests_are_spaced_apart() {
+        let dispatched = Rc::new(RefCell::new(vec![]));
+        let min_gap = Duration::from_millis(300);
+
+        let service = MinGapService::new(Record(dispatched.clone()), min_gap, Timer::default());
+
+        let calls = vec![
+            service.call("first".to_string()),
+            service.call("second".to_string()),
+        ];
+
+        let resps = future::join_all(calls).wait().unwrap();
+        assert_eq!(resps, ["first", "second"]);
+
+        let dispatched = dispatched.borrow();
+        assert_eq!(dispatched[0].0, "first");
+        assert_eq!(dispatched[1].0, "second");
+
+        // Allow for the 100ms resolution of the default timer
+        let gap = dispatched[1].1.duration_since(dispatched[0].1);
+        assert!(gap >= min_gap - Duration::from_millis(100), "gap was {:?}", gap);
+    }
+}
@@ -0,0 +1,253 @@
+//! Serving several protocols on the same port.
+//!
+//! During a migration, old clients may still speak the newline protocol while
+//! new ones use length-prefixed framing. `serve_multi_protocol` reads the
+//! first bytes of each connection, and hands them to a list of
+//! `ProtocolDetector`s, in order. The first detector claiming the connection
+//! serves it with its protocol, starting with the bytes that were sniffed, so
+//! detection does not consume any data.
+//!
+//! `ProtocolDetector::length_prefixed` claims connections starting with a
+//! zero byte, which is the first byte of the length prefix of any frame
+//! shorter than 16MB. `ProtocolDetector::line` claims any other connection.
+
+use {BoxService, LineProto, Validate};
+use length::LengthProto;
+
+use futures::{Future, Stream, Poll, Async};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_proto::BindServer;
+use tokio_proto::pipeline::ServerProto;
+use tokio_service::{Service, NewService};
+
+use bytes::BytesMut;
+
+use std::{cmp, io};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+// Space reserved in the sniffing buffer before each read
+const SNIFF_CAPACITY: usize = 64;
+
+/// Claims connections for a protocol, based on their first bytes.
+pub struct ProtocolDetector {
+    detect: Box<Fn(&[u8]) -> Option<bool>>,
+    bind: Box<Fn(&Handle, Sniffed<TcpStream>, BoxService)>,
+}
+
+/// A socket replaying the bytes read while sniffing its protocol
+pub struct Sniffed<T> {
+    io: T,
+    // Sniffed bytes that were not read yet
+    prefix: BytesMut,
+}
+
+/// Reads the first bytes of a connection until a detector claims it
+struct Sniff {
+    io: Option<TcpStream>,
+    buf: BytesMut,
+    detectors: Rc<Vec<ProtocolDetector>>,
+}
+
+/// Start a server, listening for connections on `addr`, serving each
+/// connection with the protocol of the first detector claiming it.
+///
+/// Connections that no detector claims are closed.
+///
+/// This function will block as long as the server is running. Unlike `serve`,
+/// all connections are handled on the current thread.
+pub fn serve_multi_protocol<T>(addr: SocketAddr, new_service: T, detectors: Vec<ProtocolDetector>) -> io::Result<()>
+    where T: NewService<Request = String, Response = String, Error = io::Error> + 'static,
+          T::Instance: 'static,
+          <T::Instance as Service>::Future: 'static,
+{
+    let mut core = try!(Core::new());
+    let handle = core.handle();
+
+    let listener = try!(TcpListener::bind(&addr, &handle));
+    let new_service = Rc::new(Validate::new(new_service));
+    let detectors = Rc::new(detectors);
+
+    let server = listener.incoming().for_each(move |(socket, _)| {
+        let handle2 = handle.clone();
+        let new_service = new_service.clone();
+
+        let sniff = Sniff {
+            io: Some(socket),
+            buf: BytesMut::new(),
+            detectors: detectors.clone(),
+        };
+
+        // Sniff on a task of its own, so that accepting goes on
+        handle.spawn(sniff
+            .and_then(move |(i, socket, detectors)| {
+                let service = try!(new_service.new_service());
+                let service = BoxService { inner: Box::new(service) };
+
+                (detectors[i].bind)(&handle2, socket, service);
+                Ok(())
+            })
+            .map_err(|_| ()));
+
+        Ok(())
+    });
+
+    core.run(server)
+}
+
+impl ProtocolDetector {
+    /// Create a detector serving the connections for which `detect` returns
+    /// true with `proto`.
+    ///
+    /// `detect` is called with the bytes received so far, and returns `None`
+    /// when it needs more of them to decide.
+    pub fn new<F, P>(detect: F, proto: P) -> ProtocolDetector
+        where F: Fn(&[u8]) -> Option<bool> + 'static,
+              P: ServerProto<Sniffed<TcpStream>, Request = String, Response = String> + 'static,
+    {
+        ProtocolDetector {
+            detect: Box::new(detect),
+            bind: Box::new(move |handle, io, service| {
+                proto.bind_server(handle, io, service);
+            }),
+        }
+    }
+
+    /// Detect the newline protocol, claiming connections that do not start
+    /// with a zero byte
+    pub fn line() -> ProtocolDetector {
+        ProtocolDetector::new(|buf| buf.first().map(|&b| b != 0), LineProto::new())
+    }
+
+    /// Detect length-prefixed framing, claiming connections that start with a
+    /// zero byte
+    pub fn length_prefixed() -> ProtocolDetector {
+        ProtocolDetector::new(|buf| buf.first().map(|&b| b == 0), LengthProto::new())
+    }
+}
+
+impl Future for Sniff {
+    type Item = (usize, Sniffed<TcpStream>, Rc<Vec<ProtocolDetector>>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        loop {
+            let mut undecided = false;
+
+            for (i, detector) in self.detectors.iter().enumerate() {
+                match (detector.detect)(&self.buf[..]) {
+                    Some(true) => {
+                        let sniffed = Sniffed {
+                            io: self.io.take().expect("polled after completion"),
+                            prefix: self.buf.take(),
+                        };
+
+                        return Ok(Async::Ready((i, sniffed, self.detectors.clone())));
+                    }
+                    Some(false) => {}
+                    None => undecided = true,
+                }
+            }
+
+            if !undecided {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown protocol"));
+            }
+
+            self.buf.reserve(SNIFF_CAPACITY);
+
+            let n = try_ready!(self.io.as_mut().expect("polled after completion").read_buf(&mut self.buf));
+
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before its protocol was detected"));
+            }
+        }
+    }
+}
+
+impl<T: Read> Read for Sniffed<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.prefix.is_empty() {
+            return self.io.read(buf);
+        }
+
+        let n = cmp::min(buf.len(), self.prefix.len());
+        buf[..n].copy_from_slice(&self.prefix.split_to(n));
+
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Sniffed<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Sniffed<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for Sniffed<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{serve_multi_protocol, ProtocolDetector};
+
+    use futures::future;
+    use tokio_service::Service;
+
+    use std::{io, net, thread};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::time::Duration;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn each_client_is_served_with_its_protocol() {
+        // Pick a free port for the server
+        let addr = net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        thread::spawn(move || {
+            let detectors = vec![ProtocolDetector::length_prefixed(), ProtocolDetector::line()];
+            serve_multi_protocol(addr, || Ok(Echo), detectors).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        // A newline client
+        let mut line_client = net::TcpStream::connect(&addr).unwrap();
+        line_client.write_all(b"hello\n").unwrap();
+
+        // A length-prefixed client, connected at the same time
+        let mut length_client = net::TcpStream::connect(&addr).unwrap();
+        length_client.write_all(b"\x00\x00\x00\x05world").unwrap();
+
+        let mut line = String::new();
+        BufReader::new(line_client).read_line(&mut line).unwrap();
+        assert_eq!(line, "hello\n");
+
+        let mut frame = [0; 9];
+        length_client.read_exact(&mut frame).unwrap();
+        assert_eq!(&frame, b"\x00\x00\x00\x05world");
+    }
+}
@@ -0,0 +1,92 @@
+//! Passing the address of the client to the service of each connection.
+//!
+//! `serve` builds services with `NewService::new_service`, which receives no
+//! information about the connection. With `serve_with_addr`, services are
+//! built by a function receiving the remote address of the connection, which
+//! is useful for logging and rate limiting per client.
+
+use {LineProto, Validate};
+
+use futures::Stream;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+use tokio_proto::BindServer;
+use tokio_service::Service;
+
+use std::io;
+use std::net::SocketAddr;
+
+/// Start a server, listening for connections on `addr`.
+///
+/// For each new connection, `new_service` is called with the address of the
+/// client to build a `Service` instance processing its requests.
+///
+/// This function will block as long as the server is running. Unlike `serve`,
+/// all connections are handled on the current thread.
+pub fn serve_with_addr<F, S>(addr: SocketAddr, new_service: F) -> io::Result<()>
+    where F: Fn(SocketAddr) -> io::Result<S> + 'static,
+          S: Service<Request = String, Response = String, Error = io::Error> + 'static,
+          S::Future: 'static,
+{
+    let mut core = try!(Core::new());
+    let handle = core.handle();
+
+    let listener = try!(TcpListener::bind(&addr, &handle));
+
+    let server = listener.incoming().for_each(move |(socket, peer)| {
+        let service = try!(new_service(peer));
+
+        // Spawns a task on the reactor dedicated to processing the connection
+        LineProto::new().bind_server(&handle, socket, Validate::new(service));
+        Ok(())
+    });
+
+    core.run(server)
+}
+
+#[cfg(test)]
+mod test {
+    use super::serve_with_addr;
+
+    use futures::future;
+    use tokio_service::Service;
+
+    use std::{io, net, thread};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    /// Responds with the address of the client
+    struct Peer(SocketAddr);
+
+    impl Service for Peer {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, _: String) -> Self::Future {
+            future::ok(self.0.to_string())
+        }
+    }
+
+    #[test]
+    fn service_sees_the_client_address() {
+        // Pick a free port for the server
+        let addr = net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        thread::spawn(move || {
+            serve_with_addr(addr, |peer| Ok(Peer(peer))).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let mut socket = net::TcpStream::connect(&addr).unwrap();
+        socket.write_all(b"who am i\n").unwrap();
+
+        let mut line = String::new();
+        BufReader::new(socket.try_clone().unwrap()).read_line(&mut line).unwrap();
+
+        assert_eq!(line, format!("{}\n", socket.local_addr().unwrap()));
+    }
+}
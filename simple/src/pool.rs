@@ -0,0 +1,174 @@
+//! Processing requests on a thread pool.
+//!
+//! By default, services are called on the reactor thread, so a service that
+//! blocks or performs CPU heavy work stalls IO for every connection handled by
+//! that reactor. `serve_on_pool` instead runs every `Service::call`, and the
+//! returned future, on a `CpuPool`. The reactor only waits for the result,
+//! which is then written back to the connection as usual.
+
+use {LineProto, Validate};
+
+use futures::Future;
+use futures_cpupool::CpuPool;
+use tokio_proto::TcpServer;
+use tokio_service::{Service, NewService};
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// A `Service` middleware calling the inner service on a `CpuPool`.
+pub struct PoolService<T> {
+    inner: Arc<T>,
+    pool: CpuPool,
+}
+
+/// Builds a `PoolService` for each new connection.
+pub struct NewPoolService<T> {
+    inner: T,
+    pool: CpuPool,
+}
+
+/// Start a server, listening for connections on `addr`, running all service
+/// calls on `pool`.
+///
+/// The service instances are shared with the pool threads, so they must be
+/// `Send` and `Sync`.
+pub fn serve_on_pool<T>(addr: SocketAddr, new_service: T, pool: CpuPool)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+          T::Instance: Send + Sync + 'static,
+          <T::Instance as Service>::Future: Send + 'static,
+{
+    let new_service = Validate::new(NewPoolService::new(new_service, pool));
+
+    TcpServer::new(LineProto::new(), addr)
+        .serve(new_service);
+}
+
+impl<T> PoolService<T> {
+    /// Create a new `PoolService`
+    pub fn new(inner: T, pool: CpuPool) -> PoolService<T> {
+        PoolService {
+            inner: Arc::new(inner),
+            pool: pool,
+        }
+    }
+}
+
+impl<T> Service for PoolService<T>
+    where T: Service<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+          T::Future: Send + 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        let inner = self.inner.clone();
+
+        Box::new(self.pool.spawn_fn(move || inner.call(req)))
+    }
+}
+
+impl<T> NewPoolService<T> {
+    /// Create a new `NewPoolService`
+    pub fn new(inner: T, pool: CpuPool) -> NewPoolService<T> {
+        NewPoolService {
+            inner: inner,
+            pool: pool,
+        }
+    }
+}
+
+impl<T> NewService for NewPoolService<T>
+    where T: NewService<Request = String, Response = String, Error = io::Error>,
+          T::Instance: Send + Sync + 'static,
+          <T::Instance as Service>::Future: Send + 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Instance = PoolService<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = try!(self.inner.new_service());
+        Ok(PoolService::new(inner, self.pool.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PoolService;
+
+    use {Client, LineProto, Validate};
+
+    use futures::{future, Future, Stream};
+    use futures_cpupool::CpuPool;
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+
+    use std::{io, thread};
+    use std::time::{Duration, Instant};
+
+    /// Blocks the calling thread on "block" requests
+    struct Blocking;
+
+    impl Service for Blocking {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            if req == "block" {
+                thread::sleep(Duration::from_millis(500));
+            }
+
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn blocking_call_does_not_stall_other_connections() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let pool = CpuPool::new(2);
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+
+        let server = listener.incoming().take(2).for_each(move |(socket, _)| {
+            let service = Validate::new(PoolService::new(Blocking, pool.clone()));
+            LineProto::new().bind_server(&handle2, socket, service);
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let a = core.run(Client::connect(&addr, &handle)).unwrap();
+        let b = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        let start = Instant::now();
+
+        let blocked = a.call("block".to_string())
+            .map(move |resp| (resp, start.elapsed()));
+
+        let ping = b.call("ping".to_string())
+            .map(move |resp| (resp, start.elapsed()));
+
+        let ((blocked, blocked_at), (ping, ping_at)) = core.run(blocked.join(ping)).unwrap();
+
+        assert_eq!(blocked, "block");
+        assert_eq!(ping, "ping");
+
+        // The ping was answered while the other call was still blocking
+        assert!(ping_at < Duration::from_millis(400), "ping took {:?}", ping_at);
+        assert!(blocked_at >= Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,265 @@
+//! Server metrics in the Prometheus text format.
+//!
+//! The `PrometheusMetricsService` middleware answers a reserved command,
+//! `__METRICS__` by default, with the metrics of the server as a whole, in
+//! the Prometheus text exposition format:
+//!
+//! ```text
+//! # HELP line_requests_total Requests served.
+//! # TYPE line_requests_total counter
+//! line_requests_total 1024
+//! ...
+//! ```
+//!
+//! The following metrics are exposed:
+//!
+//! * `line_requests_total`, the number of requests served, not counting
+//!   metrics commands,
+//! * `line_request_errors_total`, the number of those requests that failed,
+//! * `line_connections`, the number of currently open connections,
+//! * `line_request_duration_seconds`, a histogram of the time taken by the
+//!   inner service to respond.
+//!
+//! All other requests are passed to the inner service.
+//!
+//! The exposition format spans several lines, so the server must be able to
+//! send new lines, for example by being started with `escaped::serve_escaped`
+//! and queried with `Client::connect_escaped`. With `serve`, the response is
+//! rejected by `Validate`.
+
+use futures::{future, Future};
+use tokio_service::{Service, NewService};
+
+use std::io;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// The default metrics command
+pub const METRICS: &'static str = "__METRICS__";
+
+/// Upper bounds of the latency buckets, in seconds. Latencies above the last
+/// bound are only counted in the `+Inf` bucket.
+const BUCKETS: &'static [f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// A `Service` middleware answering the metrics command.
+pub struct PrometheusMetricsService<T> {
+    inner: T,
+    metrics: Arc<Metrics>,
+    command: Arc<String>,
+}
+
+/// Builds a `PrometheusMetricsService` for each new connection, sharing
+/// metrics between all of them.
+pub struct NewPrometheusMetricsService<T> {
+    inner: T,
+    metrics: Arc<Metrics>,
+    command: Arc<String>,
+}
+
+/// Metrics shared by every connection of a server
+struct Metrics {
+    connections: AtomicUsize,
+    requests: AtomicUsize,
+    errors: AtomicUsize,
+    // One counter per bound, plus the overflow bucket
+    buckets: Vec<AtomicUsize>,
+    // Total latency, in microseconds
+    latency_sum: AtomicUsize,
+}
+
+impl<T> NewPrometheusMetricsService<T> {
+    /// Create a new `NewPrometheusMetricsService` answering `__METRICS__`
+    pub fn new(inner: T) -> NewPrometheusMetricsService<T> {
+        NewPrometheusMetricsService::with_command(inner, METRICS)
+    }
+
+    /// Create a new `NewPrometheusMetricsService` answering `command`, to
+    /// avoid collisions with requests of the inner service.
+    pub fn with_command(inner: T, command: &str) -> NewPrometheusMetricsService<T> {
+        NewPrometheusMetricsService {
+            inner: inner,
+            metrics: Arc::new(Metrics {
+                connections: AtomicUsize::new(0),
+                requests: AtomicUsize::new(0),
+                errors: AtomicUsize::new(0),
+                buckets: (0..BUCKETS.len() + 1).map(|_| AtomicUsize::new(0)).collect(),
+                latency_sum: AtomicUsize::new(0),
+            }),
+            command: Arc::new(command.to_string()),
+        }
+    }
+}
+
+impl Metrics {
+    fn record(&self, latency: Duration, ok: bool) {
+        if !ok {
+            self.errors.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let secs = latency.as_secs() as f64 + latency.subsec_nanos() as f64 / 1e9;
+        let i = BUCKETS.iter().position(|&bound| secs <= bound).unwrap_or(BUCKETS.len());
+
+        self.buckets[i].fetch_add(1, Ordering::SeqCst);
+
+        let micros = latency.as_secs() as usize * 1_000_000 + latency.subsec_nanos() as usize / 1_000;
+        self.latency_sum.fetch_add(micros, Ordering::SeqCst);
+    }
+
+    fn exposition(&self) -> String {
+        let mut ret = String::new();
+
+        metric(&mut ret, "line_requests_total", "counter", "Requests served.",
+               self.requests.load(Ordering::SeqCst));
+        metric(&mut ret, "line_request_errors_total", "counter", "Requests that failed.",
+               self.errors.load(Ordering::SeqCst));
+        metric(&mut ret, "line_connections", "gauge", "Open connections.",
+               self.connections.load(Ordering::SeqCst));
+
+        let name = "line_request_duration_seconds";
+
+        writeln!(ret, "# HELP {} Time taken to respond to requests.", name).unwrap();
+        writeln!(ret, "# TYPE {} histogram", name).unwrap();
+
+        // Buckets are cumulative
+        let mut count = 0;
+
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            count += bucket.load(Ordering::SeqCst);
+            writeln!(ret, "{}_bucket{{le=\"{}\"}} {}", name, bound, count).unwrap();
+        }
+
+        count += self.buckets[BUCKETS.len()].load(Ordering::SeqCst);
+
+        let sum = self.latency_sum.load(Ordering::SeqCst) as f64 / 1e6;
+
+        writeln!(ret, "{}_bucket{{le=\"+Inf\"}} {}", name, count).unwrap();
+        writeln!(ret, "{}_sum {}", name, sum).unwrap();
+        writeln!(ret, "{}_count {}", name, count).unwrap();
+
+        ret
+    }
+}
+
+/// Append a single-valued metric to `buf`
+fn metric(buf: &mut String, name: &str, kind: &str, help: &str, value: usize) {
+    writeln!(buf, "# HELP {} {}", name, help).unwrap();
+    writeln!(buf, "# TYPE {} {}", name, kind).unwrap();
+    writeln!(buf, "{} {}", name, value).unwrap();
+}
+
+impl<T> Service for PrometheusMetricsService<T>
+    where T: Service<Request = String, Response = String, Error = io::Error>,
+          T::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        if req == *self.command {
+            return Box::new(future::ok(self.metrics.exposition()));
+        }
+
+        self.metrics.requests.fetch_add(1, Ordering::SeqCst);
+
+        let metrics = self.metrics.clone();
+        let start = Instant::now();
+
+        Box::new(self.inner.call(req).then(move |res| {
+            metrics.record(start.elapsed(), res.is_ok());
+            res
+        }))
+    }
+}
+
+impl<T> Drop for PrometheusMetricsService<T> {
+    fn drop(&mut self) {
+        // The service is dropped along with its connection
+        self.metrics.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<T> NewService for NewPrometheusMetricsService<T>
+    where T: NewService<Request = String, Response = String, Error = io::Error>,
+          <T::Instance as Service>::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Instance = PrometheusMetricsService<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = try!(self.inner.new_service());
+        self.metrics.connections.fetch_add(1, Ordering::SeqCst);
+
+        Ok(PrometheusMetricsService {
+            inner: inner,
+            metrics: self.metrics.clone(),
+            command: self.command.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{NewPrometheusMetricsService, METRICS};
+
+    use futures::{future, Future};
+    use tokio_service::{Service, NewService};
+
+    use std::io;
+
+    /// Echoes requests, failing on "fail"
+    struct FailOne;
+
+    impl Service for FailOne {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            if req == "fail" {
+                future::err(io::Error::new(io::ErrorKind::Other, "failed"))
+            } else {
+                future::ok(req)
+            }
+        }
+    }
+
+    #[test]
+    fn metrics_reflect_prior_requests() {
+        let new_service = NewPrometheusMetricsService::new(|| Ok(FailOne));
+        let service = new_service.new_service().unwrap();
+
+        assert_eq!(service.call("a".to_string()).wait().unwrap(), "a");
+        assert_eq!(service.call("b".to_string()).wait().unwrap(), "b");
+        assert!(service.call("fail".to_string()).wait().is_err());
+
+        let exposition = service.call(METRICS.to_string()).wait().unwrap();
+
+        // Every line is a comment or a sample with a numeric value
+        for line in exposition.lines() {
+            if line.starts_with("# HELP ") || line.starts_with("# TYPE ") {
+                continue;
+            }
+
+            let value = line.rsplit(' ').next().unwrap();
+            assert!(value.parse::<f64>().is_ok(), "malformed sample: {:?}", line);
+        }
+
+        let samples: Vec<&str> = exposition.lines().collect();
+
+        assert!(samples.contains(&"line_requests_total 3"));
+        assert!(samples.contains(&"line_request_errors_total 1"));
+        assert!(samples.contains(&"line_connections 1"));
+        assert!(samples.contains(&"line_request_duration_seconds_bucket{le=\"+Inf\"} 3"));
+        assert!(samples.contains(&"line_request_duration_seconds_count 3"));
+    }
+}
@@ -0,0 +1,189 @@
+//! A client that survives its connection being dropped.
+//!
+//! A `Client` is dead once its connection drops. `ReconnectingClient` keeps
+//! the address of the server, and when a call fails because the connection
+//! was lost, it establishes a new connection and replays the request, up to a
+//! bounded number of times. Calls made while disconnected wait for the new
+//! connection.
+//!
+//! As a request may have been processed by the server before the connection
+//! dropped, replaying is only safe for idempotent requests.
+
+use Client;
+
+use futures::{future, Future};
+use tokio_core::reactor::Handle;
+use tokio_service::Service;
+
+use std::io;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+/// Client handle reconnecting to the server when the connection drops.
+pub struct ReconnectingClient {
+    shared: Rc<Shared>,
+    max_retries: usize,
+}
+
+struct Shared {
+    addr: SocketAddr,
+    handle: Handle,
+    // `None` once the connection is known to be lost
+    client: RefCell<Option<Rc<Client>>>,
+}
+
+impl ReconnectingClient {
+    /// Establish a connection to a line-based server at the provided `addr`.
+    ///
+    /// A request failing because the connection dropped is replayed on a new
+    /// connection, at most `max_retries` times.
+    pub fn connect(addr: &SocketAddr, handle: &Handle, max_retries: usize) -> Box<Future<Item = ReconnectingClient, Error = io::Error>> {
+        let addr = *addr;
+        let handle = handle.clone();
+
+        let ret = Client::connect(&addr, &handle)
+            .map(move |client| {
+                ReconnectingClient {
+                    shared: Rc::new(Shared {
+                        addr: addr,
+                        handle: handle,
+                        client: RefCell::new(Some(Rc::new(client))),
+                    }),
+                    max_retries: max_retries,
+                }
+            });
+
+        Box::new(ret)
+    }
+}
+
+impl Service for ReconnectingClient {
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        attempt(self.shared.clone(), req, self.max_retries)
+    }
+}
+
+/// Returns true if `err` means that the connection is lost
+fn is_disconnect(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::BrokenPipe |
+        io::ErrorKind::NotConnected |
+        io::ErrorKind::UnexpectedEof => true,
+        _ => false,
+    }
+}
+
+/// Send `req`, reconnecting first if needed, and retrying up to `retries`
+/// more times if the connection is lost.
+fn attempt(shared: Rc<Shared>, req: String, retries: usize) -> Box<Future<Item = String, Error = io::Error>> {
+    let current = shared.client.borrow().clone();
+
+    let client: Box<Future<Item = Rc<Client>, Error = io::Error>> = match current {
+        Some(client) => Box::new(future::ok(client)),
+        None => {
+            let shared = shared.clone();
+
+            Box::new(Client::connect(&shared.addr, &shared.handle)
+                .map(move |client| {
+                    let client = Rc::new(client);
+                    *shared.client.borrow_mut() = Some(client.clone());
+                    client
+                }))
+        }
+    };
+
+    let resp = {
+        let shared = shared.clone();
+        let req = req.clone();
+
+        client.and_then(move |client| {
+            client.call(req)
+                .map_err(move |e| {
+                    if is_disconnect(&e) {
+                        // Forget the dead connection, unless another call
+                        // already replaced it.
+                        let mut current = shared.client.borrow_mut();
+
+                        if current.as_ref().map_or(false, |c| Rc::ptr_eq(c, &client)) {
+                            *current = None;
+                        }
+                    }
+
+                    e
+                })
+        })
+    };
+
+    Box::new(resp.or_else(move |e| {
+        if retries == 0 || !(is_disconnect(&e) || e.kind() == io::ErrorKind::ConnectionRefused) {
+            return Box::new(future::err(e)) as Box<Future<Item = String, Error = io::Error>>;
+        }
+
+        attempt(shared, req, retries - 1)
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::ReconnectingClient;
+
+    use tokio_core::reactor::Core;
+    use tokio_service::Service;
+
+    use std::{net, thread};
+    use std::io::{BufRead, BufReader, Write};
+    use std::sync::mpsc;
+
+    /// Echo lines on a single connection, answering at most `n` requests
+    /// before closing it.
+    fn echo_lines(listener: net::TcpListener, n: usize) {
+        let (mut socket, _) = listener.accept().unwrap();
+        let reader = BufReader::new(socket.try_clone().unwrap());
+
+        for line in reader.lines().take(n) {
+            socket.write_all(format!("{}\n", line.unwrap()).as_bytes()).unwrap();
+        }
+    }
+
+    #[test]
+    fn call_succeeds_after_the_server_restarts() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // The first server answers one request, then goes away along with
+        // its listener.
+        let first = thread::spawn(move || echo_lines(listener, 1));
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let client = core.run(ReconnectingClient::connect(&addr, &handle, 3)).unwrap();
+
+        assert_eq!(core.run(client.call("one".to_string())).unwrap(), "one");
+
+        first.join().unwrap();
+
+        // Restart the server on the same address
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let listener = net::TcpListener::bind(&addr).unwrap();
+            tx.send(()).unwrap();
+            echo_lines(listener, usize::max_value());
+        });
+
+        rx.recv().unwrap();
+
+        assert_eq!(core.run(client.call("two".to_string())).unwrap(), "two");
+        assert_eq!(core.run(client.call("three".to_string())).unwrap(), "three");
+    }
+}
@@ -0,0 +1,55 @@
+//! A service responding with each request line reversed.
+//!
+//! This is mostly useful as a demo of a transform that is more interesting
+//! than echoing. Characters are reversed, not bytes, so multi-byte UTF-8
+//! characters remain intact. Grapheme clusters made of several characters
+//! (such as flags or characters with combining accents) are not kept together.
+
+use futures::future::{self, FutureResult};
+use tokio_service::Service;
+
+use std::io;
+
+/// A `Service` that reverses the characters of each request.
+#[derive(Debug, Clone, Copy)]
+pub struct ReverseService;
+
+impl Service for ReverseService {
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Future = FutureResult<String, io::Error>;
+
+    fn call(&self, req: String) -> Self::Future {
+        future::ok(req.chars().rev().collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ReverseService;
+
+    use futures::Future;
+    use tokio_service::Service;
+
+    fn reverse(line: &str) -> String {
+        ReverseService.call(line.to_string()).wait().unwrap()
+    }
+
+    #[test]
+    fn ascii_is_reversed() {
+        assert_eq!(reverse("hello world"), "dlrow olleh");
+    }
+
+    #[test]
+    fn emoji_are_kept_intact() {
+        // Each of these is a single, 4 byte character
+        assert_eq!(reverse("a\u{1f600}b\u{1f680}"), "\u{1f680}b\u{1f600}a");
+        assert_eq!(reverse("\u{e9}t\u{e9}"), "\u{e9}t\u{e9}");
+    }
+
+    #[test]
+    fn empty_line_is_empty() {
+        assert_eq!(reverse(""), "");
+    }
+}
@@ -0,0 +1,121 @@
+//! Request validation with a user supplied closure.
+
+use Validate;
+
+use futures::{future, Future};
+use tokio_service::{Service, NewService};
+
+use std::io;
+use std::sync::Arc;
+
+/// A `Service` middleware that validates requests with a user supplied
+/// closure.
+///
+/// This generalizes `Validate`: in addition to the new line check performed by
+/// `Validate`, which is always applied, each request is passed to `validator`.
+/// If the closure returns an error, the request is rejected with an error of
+/// kind `InvalidInput` carrying the returned message, and the inner service is
+/// never called.
+pub struct SchemaValidate<T, F> {
+    inner: Validate<T>,
+    validator: Arc<F>,
+}
+
+impl<T, F> SchemaValidate<T, F>
+    where F: Fn(&str) -> Result<(), String>,
+{
+    /// Create a new `SchemaValidate`
+    pub fn new(inner: T, validator: F) -> SchemaValidate<T, F> {
+        SchemaValidate {
+            inner: Validate::new(inner),
+            validator: Arc::new(validator),
+        }
+    }
+}
+
+impl<T, F> Service for SchemaValidate<T, F>
+    where T: Service<Request = String, Response = String, Error = io::Error>,
+          T::Future: 'static,
+          F: Fn(&str) -> Result<(), String>,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        if let Err(msg) = (self.validator)(&req) {
+            let err = io::Error::new(io::ErrorKind::InvalidInput, msg);
+            return Box::new(future::err(err));
+        }
+
+        self.inner.call(req)
+    }
+}
+
+impl<T, F> NewService for SchemaValidate<T, F>
+    where T: NewService<Request = String, Response = String, Error = io::Error>,
+          <T::Instance as Service>::Future: 'static,
+          F: Fn(&str) -> Result<(), String>,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Instance = SchemaValidate<T::Instance, F>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = try!(self.inner.new_service());
+
+        Ok(SchemaValidate {
+            inner: inner,
+            validator: self.validator.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SchemaValidate;
+
+    use futures::{future, Future};
+    use tokio_service::Service;
+
+    use std::io;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    fn commands_only(line: &str) -> Result<(), String> {
+        if line.starts_with("CMD:") {
+            Ok(())
+        } else {
+            Err(format!("not a command: {:?}", line))
+        }
+    }
+
+    #[test]
+    fn lines_without_the_prefix_are_rejected() {
+        let service = SchemaValidate::new(Echo, commands_only);
+
+        assert_eq!(service.call("CMD:ping".to_string()).wait().unwrap(), "CMD:ping");
+
+        let err = service.call("ping".to_string()).wait().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "not a command: \"ping\"");
+
+        // The new line check still applies to lines passing the validator
+        let err = service.call("CMD:a\nb".to_string()).wait().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,135 @@
+//! A line codec that numbers each frame, allowing the receiver to detect
+//! dropped frames.
+//!
+//! Frames begin with a 4 byte header, consisting of the sequence number
+//! encoded in network order, followed by the frame payload encoded as a UTF-8
+//! string and terminated with a '\n' character. The first frame sent has the
+//! sequence number 1.
+
+use tokio_io::codec::{Encoder, Decoder};
+
+use bytes::{BytesMut, Buf, BufMut, BigEndian};
+
+use std::{io, str};
+
+/// Line codec that prepends a sequence number to each frame.
+///
+/// Whenever a decoded frame's sequence number is not the one that was
+/// expected, `on_gap` is called with the expected and received sequence
+/// numbers. The frame itself is still yielded, so it is up to the callback to
+/// decide how to handle the gap.
+pub struct SequencedCodec<F> {
+    // Sequence number of the next frame to encode
+    next_send: u32,
+    // Sequence number that the next decoded frame should have
+    next_recv: u32,
+    on_gap: F,
+}
+
+impl<F> SequencedCodec<F>
+    where F: FnMut(u32, u32),
+{
+    /// Create a new `SequencedCodec`, reporting gaps to `on_gap`.
+    pub fn new(on_gap: F) -> SequencedCodec<F> {
+        SequencedCodec {
+            next_send: 1,
+            next_recv: 1,
+            on_gap: on_gap,
+        }
+    }
+}
+
+impl<F> Decoder for SequencedCodec<F>
+    where F: FnMut(u32, u32),
+{
+    type Item = String;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<String>, io::Error> {
+        // At least 5 bytes are required for a frame: 4 byte head + one byte
+        // '\n'
+        if buf.len() < 5 {
+            return Ok(None);
+        }
+
+        // Check to see if the frame contains a new line, skipping the first 4
+        // bytes which is the sequence number
+        if let Some(n) = buf.as_ref()[4..].iter().position(|b| *b == b'\n') {
+            // remove the serialized frame from the buffer.
+            let line = buf.split_to(n + 4);
+
+            // Also remove the '\n'
+            buf.split_to(1);
+
+            let seq = io::Cursor::new(&line[0..4]).get_u32::<BigEndian>();
+
+            if seq != self.next_recv {
+                (self.on_gap)(self.next_recv, seq);
+            }
+
+            self.next_recv = seq.wrapping_add(1);
+
+            return match str::from_utf8(&line.as_ref()[4..]) {
+                Ok(s) => Ok(Some(s.to_string())),
+                Err(_) => Err(io::Error::new(io::ErrorKind::Other, "invalid string")),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl<F> Encoder for SequencedCodec<F>
+    where F: FnMut(u32, u32),
+{
+    type Item = String;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: String, buf: &mut BytesMut) -> io::Result<()> {
+        buf.reserve(4 + msg.len() + 1);
+
+        buf.put_u32::<BigEndian>(self.next_send);
+        buf.put_slice(msg.as_bytes());
+        buf.put_u8(b'\n');
+
+        self.next_send = self.next_send.wrapping_add(1);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SequencedCodec;
+
+    use tokio_io::codec::Decoder;
+
+    use bytes::{BytesMut, BufMut, BigEndian};
+
+    use std::cell::RefCell;
+
+    #[test]
+    fn gaps_are_reported_and_frames_delivered() {
+        let gaps = RefCell::new(vec![]);
+        let mut codec = SequencedCodec::new(|expected, received| {
+            gaps.borrow_mut().push((expected, received));
+        });
+
+        let mut buf = BytesMut::new();
+
+        for &(seq, msg) in &[(1, "one"), (2, "two"), (4, "four")] {
+            buf.reserve(4 + msg.len() + 1);
+            buf.put_u32::<BigEndian>(seq);
+            buf.put_slice(msg.as_bytes());
+            buf.put_u8(b'\n');
+        }
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("one".to_string()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("two".to_string()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("four".to_string()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        drop(codec);
+        assert_eq!(gaps.into_inner(), vec![(3, 4)]);
+    }
+}
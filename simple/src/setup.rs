@@ -0,0 +1,167 @@
+//! Per-connection setup before request processing starts.
+//!
+//! This generalizes the pattern shown in the `handshake` example. Once a
+//! protocol has bound its transport, a user supplied `setup` function is given
+//! direct access to it. `setup` may send and receive any number of lines (a
+//! banner, a negotiation, ...) and then hands the transport back, at which
+//! point tokio-proto starts dispatching requests as usual.
+
+use futures::{Future, IntoFuture};
+use tokio_proto::pipeline::{ServerProto, ClientProto};
+
+use std::io;
+use std::sync::Arc;
+
+/// A protocol wrapper that runs `setup` on each newly bound transport.
+///
+/// Created by `with_connection_setup`.
+pub struct WithSetup<P, F> {
+    proto: P,
+    setup: Arc<F>,
+}
+
+/// Wrap `proto` so that `setup` runs on every transport it binds, before any
+/// request is processed.
+///
+/// `setup` receives the transport and returns a future resolving to the same
+/// transport once setup has completed. If the future errors, the connection is
+/// closed.
+///
+/// The returned value may be used both with `TcpServer` and `TcpClient`
+/// depending on whether `proto` is a server or client protocol.
+pub fn with_connection_setup<P, F>(proto: P, setup: F) -> WithSetup<P, F> {
+    WithSetup {
+        proto: proto,
+        setup: Arc::new(setup),
+    }
+}
+
+impl<T, P, F, R> ServerProto<T> for WithSetup<P, F>
+    where T: 'static,
+          P: ServerProto<T>,
+          <P::BindTransport as IntoFuture>::Future: 'static,
+          F: Fn(P::Transport) -> R + 'static,
+          R: IntoFuture<Item = P::Transport, Error = io::Error>,
+          R::Future: 'static,
+{
+    type Request = P::Request;
+    type Response = P::Response;
+    type Transport = P::Transport;
+    type BindTransport = Box<Future<Item = Self::Transport, Error = io::Error>>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        let transport = self.proto.bind_transport(io).into_future();
+        let setup = self.setup.clone();
+
+        // Run the setup with the transport once it has been bound
+        let setup = transport.and_then(move |transport| (*setup)(transport));
+
+        Box::new(setup)
+    }
+}
+
+impl<T, P, F, R> ClientProto<T> for WithSetup<P, F>
+    where T: 'static,
+          P: ClientProto<T>,
+          <P::BindTransport as IntoFuture>::Future: 'static,
+          F: Fn(P::Transport) -> R + 'static,
+          R: IntoFuture<Item = P::Transport, Error = io::Error>,
+          R::Future: 'static,
+{
+    type Request = P::Request;
+    type Response = P::Response;
+    type Transport = P::Transport;
+    type BindTransport = Box<Future<Item = Self::Transport, Error = io::Error>>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        let transport = self.proto.bind_transport(io).into_future();
+        let setup = self.setup.clone();
+
+        // Run the setup with the transport once it has been bound
+        let setup = transport.and_then(move |transport| (*setup)(transport));
+
+        Box::new(setup)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::with_connection_setup;
+    use {LineCodec, LineProto, Validate};
+    use batch::SplitBatches;
+
+    use futures::{future, Future, Stream, Sink};
+    use tokio_io::codec::Framed;
+    use tokio_core::net::{TcpListener, TcpStream};
+    use tokio_core::reactor::Core;
+    use tokio_proto::{BindServer, TcpClient};
+    use tokio_service::Service;
+
+    use std::io;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    /// Check that the next line received is `expected`
+    fn expect<T>(transport: T, expected: &'static str) -> Box<Future<Item = T, Error = io::Error>>
+        where T: Stream<Item = String, Error = io::Error> + 'static,
+    {
+        let ret = transport.into_future()
+            .map_err(|(e, _)| e)
+            .and_then(move |(line, transport)| {
+                if line.as_ref().map(|line| &line[..]) == Some(expected) {
+                    Ok(transport)
+                } else {
+                    Err(io::Error::new(io::ErrorKind::Other, "unexpected setup line"))
+                }
+            });
+
+        Box::new(ret)
+    }
+
+    #[test]
+    fn setup_runs_before_requests() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // The server sends a banner, and waits for the client to be ready
+        let server_proto = with_connection_setup(LineProto::new(), |transport: Framed<TcpStream, LineCodec>| {
+            transport.send("HELLO".to_string())
+                .and_then(|transport| expect(transport, "READY"))
+        });
+
+        let handle2 = handle.clone();
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            server_proto.bind_server(&handle2, socket, Validate::new(Echo));
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let client_proto = with_connection_setup(LineProto::new(), |transport: SplitBatches<Framed<TcpStream, LineCodec>>| {
+            expect(transport, "HELLO")
+                .and_then(|transport| transport.send("READY".to_string()))
+        });
+
+        let client = core.run(TcpClient::new(client_proto).connect(&addr, &handle)).unwrap();
+        let client = Validate::new(client);
+
+        // The setup lines are not taken for requests or responses
+        assert_eq!(core.run(client.call("hello".to_string())).unwrap(), "hello");
+        assert_eq!(core.run(client.call("again".to_string())).unwrap(), "again");
+    }
+}
@@ -0,0 +1,123 @@
+//! A codec for length-prefixed UTF-8 strings.
+//!
+//! Unlike `LineCodec`, frames are not delimited. Instead, each frame begins
+//! with a 2 byte header, consisting of the payload length encoded in network
+//! order, followed by exactly that many bytes of UTF-8 encoded payload:
+//!
+//! +-- length --+---- payload ----+
+//! |            |                 |
+//! |  \x000005  |      Hello      |
+//! |            |                 |
+//! +------------+-----------------+
+
+use tokio_io::codec::{Encoder, Decoder};
+
+use bytes::{BytesMut, Buf, BufMut, BigEndian};
+
+use std::{io, str, u16};
+
+/// Codec for `[u16 length][utf8 bytes]` frames.
+pub struct ShortStringCodec;
+
+impl Decoder for ShortStringCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<String>, io::Error> {
+        // Wait for the full header
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+
+        let len = io::Cursor::new(&buf[0..2]).get_u16::<BigEndian>() as usize;
+
+        // Wait for the full payload. Reserving the remaining space avoids
+        // growing the buffer repeatedly as the payload trickles in.
+        if buf.len() < 2 + len {
+            let remaining = 2 + len - buf.len();
+            buf.reserve(remaining);
+            return Ok(None);
+        }
+
+        // Remove the header, then the payload
+        buf.split_to(2);
+        let payload = buf.split_to(len);
+
+        match str::from_utf8(&payload) {
+            Ok(s) => Ok(Some(s.to_string())),
+            Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "payload is not valid UTF-8")),
+        }
+    }
+}
+
+impl Encoder for ShortStringCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: String, buf: &mut BytesMut) -> io::Result<()> {
+        if msg.len() > u16::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "string too long"));
+        }
+
+        buf.reserve(2 + msg.len());
+
+        buf.put_u16::<BigEndian>(msg.len() as u16);
+        buf.put_slice(msg.as_bytes());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ShortStringCodec;
+
+    use tokio_io::codec::{Encoder, Decoder};
+
+    use bytes::BytesMut;
+
+    use std::iter;
+
+    #[test]
+    fn round_trip() {
+        let mut codec = ShortStringCodec;
+        let mut buf = BytesMut::new();
+
+        codec.encode("Hello".to_string(), &mut buf).unwrap();
+        assert_eq!(&buf[..], &b"\x00\x05Hello"[..]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("Hello".to_string()));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn length_split_across_reads() {
+        let mut codec = ShortStringCodec;
+
+        // Only the first byte of the length is received
+        let mut buf = BytesMut::from(&b"\x00"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(b"\x05Hel");
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(b"lo");
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("Hello".to_string()));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn over_length_strings_are_rejected() {
+        let mut codec = ShortStringCodec;
+        let mut buf = BytesMut::new();
+
+        let max: String = iter::repeat('a').take(65535).collect();
+        codec.encode(max, &mut buf).unwrap();
+
+        buf.clear();
+
+        let too_long: String = iter::repeat('a').take(65536).collect();
+        assert!(codec.encode(too_long, &mut buf).is_err());
+        assert!(buf.is_empty());
+    }
+}
@@ -0,0 +1,297 @@
+//! Per-connection rate limiting over a sliding window.
+//!
+//! The `SlidingWindowLimitService` middleware accepts at most `max_requests`
+//! requests within any rolling window of `window` duration. The arrival time
+//! of each accepted request is recorded, and entries older than the window are
+//! evicted as new requests come in. Requests in excess of the limit are never
+//! passed to the inner service and are answered with a `RATE_LIMITED` line
+//! instead, carrying a hint of how long to wait before retrying:
+//!
+//! ```text
+//! RATE_LIMITED retry_after=250
+//! ```
+//!
+//! The hint is the number of milliseconds until the oldest request in the
+//! window falls out of it. `Client::call_rate_limited` turns these lines into
+//! errors, from which the hint is extracted with `retry_after`.
+//!
+//! Unlike a token bucket, there is no burst allowance carried over between
+//! windows: a request is accepted only if fewer than `max_requests` requests
+//! were accepted in the preceding `window`.
+
+use Client;
+
+use futures::{future, Future};
+use tokio_service::{Service, NewService};
+
+use std::{error, fmt, io};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Prefix of the response line sent for requests rejected by the rate
+/// limiter.
+pub const RATE_LIMITED: &'static str = "RATE_LIMITED";
+
+const RETRY_AFTER: &'static str = " retry_after=";
+
+/// The error returned by `Client::call_rate_limited` for rejected requests
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimited {
+    retry_after: Duration,
+}
+
+/// A `Service` middleware limiting the number of requests accepted within a
+/// rolling window.
+pub struct SlidingWindowLimitService<T> {
+    inner: T,
+    max_requests: usize,
+    window: Duration,
+    // Arrival times of the requests accepted within the current window,
+    // oldest first
+    accepted: RefCell<VecDeque<Instant>>,
+}
+
+/// Builds a `SlidingWindowLimitService` for each new connection.
+pub struct NewSlidingWindowLimit<T> {
+    inner: T,
+    max_requests: usize,
+    window: Duration,
+}
+
+impl<T> SlidingWindowLimitService<T> {
+    /// Create a new `SlidingWindowLimitService`, accepting at most
+    /// `max_requests` requests per `window`.
+    pub fn new(inner: T, max_requests: usize, window: Duration) -> SlidingWindowLimitService<T> {
+        SlidingWindowLimitService {
+            inner: inner,
+            max_requests: max_requests,
+            window: window,
+            accepted: RefCell::new(VecDeque::with_capacity(max_requests)),
+        }
+    }
+
+    /// Records a request arriving at `now`. If it exceeds the limit, returns
+    /// how long until a request would be accepted.
+    fn acquire(&self, now: Instant) -> Result<(), Duration> {
+        let mut accepted = self.accepted.borrow_mut();
+
+        // Evict the requests that have fallen out of the window
+        while accepted.front().map_or(false, |&t| now.duration_since(t) >= self.window) {
+            accepted.pop_front();
+        }
+
+        if accepted.len() >= self.max_requests {
+            let wait = match accepted.front() {
+                Some(&oldest) => self.window - now.duration_since(oldest),
+                // Nothing is ever accepted with a limit of zero
+                None => self.window,
+            };
+
+            return Err(wait);
+        }
+
+        accepted.push_back(now);
+        Ok(())
+    }
+}
+
+impl<T> Service for SlidingWindowLimitService<T>
+    where T: Service<Request = String, Response = String, Error = io::Error>,
+          T::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        if let Err(wait) = self.acquire(Instant::now()) {
+            return Box::new(future::ok(encode(wait)));
+        }
+
+        Box::new(self.inner.call(req))
+    }
+}
+
+impl<T> NewSlidingWindowLimit<T> {
+    /// Create a new `NewSlidingWindowLimit`
+    pub fn new(inner: T, max_requests: usize, window: Duration) -> NewSlidingWindowLimit<T> {
+        NewSlidingWindowLimit {
+            inner: inner,
+            max_requests: max_requests,
+            window: window,
+        }
+    }
+}
+
+impl<T> NewService for NewSlidingWindowLimit<T>
+    where T: NewService<Request = String, Response = String, Error = io::Error>,
+          <T::Instance as Service>::Future: 'static
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Instance = SlidingWindowLimitService<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = try!(self.inner.new_service());
+        Ok(SlidingWindowLimitService::new(inner, self.max_requests, self.window))
+    }
+}
+
+/// Format the rejection line of a request that may be retried after `wait`.
+///
+/// The hint is rounded up to the next millisecond, so that it is never zero.
+pub fn encode(wait: Duration) -> String {
+    let millis = wait.as_secs() * 1_000 + ((wait.subsec_nanos() + 999_999) / 1_000_000) as u64;
+    format!("{}{}{}", RATE_LIMITED, RETRY_AFTER, millis.max(1))
+}
+
+/// Returns the retry-after hint of a rejection line, or `None` if `resp` is
+/// not a rejection.
+///
+/// A bare `RATE_LIMITED` line is a rejection without a hint, returned as a
+/// zero duration.
+pub fn decode(resp: &str) -> Option<Duration> {
+    if resp == RATE_LIMITED {
+        return Some(Duration::from_secs(0));
+    }
+
+    if !resp.starts_with(RATE_LIMITED) || !resp[RATE_LIMITED.len()..].starts_with(RETRY_AFTER) {
+        return None;
+    }
+
+    resp[RATE_LIMITED.len() + RETRY_AFTER.len()..].parse::<u64>().ok()
+        .map(Duration::from_millis)
+}
+
+/// Returns the retry-after hint carried by an error returned by
+/// `Client::call_rate_limited`, or `None` for any other error.
+pub fn retry_after(err: &io::Error) -> Option<Duration> {
+    err.get_ref()
+        .and_then(|e| e.downcast_ref::<RateLimited>())
+        .map(|e| e.retry_after)
+}
+
+impl Client {
+    /// Send a request to a rate limited server, failing with a `RateLimited`
+    /// error if the request is rejected.
+    ///
+    /// Use `retry_after` to extract how long to back off from the error.
+    pub fn call_rate_limited(&self, req: String) -> Box<Future<Item = String, Error = io::Error>> {
+        let resp = self.call(req)
+            .and_then(|resp| {
+                match decode(&resp) {
+                    Some(wait) => {
+                        let err = RateLimited { retry_after: wait };
+                        Err(io::Error::new(io::ErrorKind::Other, err))
+                    }
+                    None => Ok(resp),
+                }
+            });
+
+        Box::new(resp)
+    }
+}
+
+impl RateLimited {
+    /// Returns how long the server asked to wait before retrying
+    pub fn retry_after(&self) -> Duration {
+        self.retry_after
+    }
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let d = self.retry_after;
+        write!(fmt, "rate limited, retry after {}ms", d.as_secs() * 1_000 + (d.subsec_nanos() / 1_000_000) as u64)
+    }
+}
+
+impl error::Error for RateLimited {
+    fn description(&self) -> &str {
+        "rate limited"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, retry_after, SlidingWindowLimitService};
+
+    use {Client, LineProto, Validate};
+
+    use futures::{future, Future, Stream};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+
+    use std::{io, thread};
+    use std::time::Duration;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn requests_over_the_limit_are_rejected_until_the_window_slides() {
+        let service = SlidingWindowLimitService::new(Echo, 3, Duration::from_millis(200));
+
+        for i in 0..3 {
+            let req = format!("req {}", i);
+            assert_eq!(service.call(req.clone()).wait().unwrap(), req);
+        }
+
+        let resp = service.call("one too many".to_string()).wait().unwrap();
+        assert!(decode(&resp).is_some(), "not rejected: {:?}", resp);
+
+        thread::sleep(Duration::from_millis(250));
+
+        assert_eq!(service.call("later".to_string()).wait().unwrap(), "later");
+    }
+
+    #[test]
+    fn client_extracts_the_retry_after_hint() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            let service = SlidingWindowLimitService::new(Echo, 1, Duration::from_secs(1));
+            LineProto::new().bind_server(&handle2, socket, Validate::new(service));
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        assert_eq!(core.run(client.call_rate_limited("first".to_string())).unwrap(), "first");
+
+        let err = core.run(client.call_rate_limited("second".to_string())).unwrap_err();
+        let wait = retry_after(&err).expect("no retry-after hint");
+
+        // The first request was accepted moments ago, so most of the window
+        // is left to wait out.
+        assert!(wait > Duration::from_millis(0));
+        assert!(wait <= Duration::from_secs(1));
+
+        // Errors other than rate limiting carry no hint
+        assert!(retry_after(&io::Error::new(io::ErrorKind::Other, "boom")).is_none());
+    }
+}
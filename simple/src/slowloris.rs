@@ -0,0 +1,270 @@
+//! Protection against slow-loris style clients.
+//!
+//! A slow-loris client holds connections open by dribbling out the bytes of a
+//! frame, one at a time, without ever completing it. To guard against this, a
+//! frame must be fully received within a configurable duration of its first
+//! byte arriving, otherwise the connection is closed with `TimedOut`. The timer
+//! is reset each time a complete frame is decoded.
+//!
+//! Whether a partial frame is buffered is only known to the codec, so the
+//! codec is wrapped with `TrackPartial`, which shares that information with
+//! the `AssemblyTimeout` transport middleware that owns the timer.
+
+use {LineCodec, Validate};
+
+use futures::{Future, Stream, Sink, Poll, Async, StartSend};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::{Framed, Encoder, Decoder};
+use tokio_proto::TcpServer;
+use tokio_proto::pipeline::ServerProto;
+use tokio_service::NewService;
+use tokio_timer::{Timer, Sleep};
+
+use bytes::BytesMut;
+
+use std::io;
+use std::cell::Cell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Codec wrapper recording whether a partial frame is left in the buffer.
+pub struct TrackPartial<C> {
+    inner: C,
+    partial: Rc<Cell<bool>>,
+}
+
+/// Transport middleware closing connections that take too long to send a
+/// complete frame.
+pub struct AssemblyTimeout<T> {
+    // The upstream transport
+    upstream: T,
+    // Shared with the `TrackPartial` codec
+    partial: Rc<Cell<bool>>,
+    timer: Timer,
+    timeout: Duration,
+    // Armed while a partial frame is buffered
+    sleep: Option<Sleep>,
+}
+
+/// Protocol definition for a server enforcing a frame assembly timeout
+struct AssemblyTimeoutProto {
+    timer: Timer,
+    timeout: Duration,
+}
+
+/// Start a server that closes connections failing to complete a frame within
+/// `timeout` of its first byte.
+pub fn serve_with_frame_timeout<T>(addr: SocketAddr, new_service: T, timeout: Duration)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    let new_service = Validate::new(new_service);
+
+    let proto = AssemblyTimeoutProto {
+        timer: Timer::default(),
+        timeout: timeout,
+    };
+
+    TcpServer::new(proto, addr)
+        .serve(new_service);
+}
+
+impl<T> AssemblyTimeout<Framed<T, TrackPartial<LineCodec>>> {
+    /// Create a line transport from `io`, enforcing the frame assembly
+    /// timeout.
+    pub fn new(io: T, timer: Timer, timeout: Duration) -> Self
+        where T: AsyncRead + AsyncWrite,
+    {
+        AssemblyTimeout::with_codec(io, LineCodec::new(), timer, timeout)
+    }
+}
+
+impl<T, C> AssemblyTimeout<Framed<T, TrackPartial<C>>> {
+    /// Create a transport from `io` using `codec`, enforcing the frame
+    /// assembly timeout.
+    pub fn with_codec(io: T, codec: C, timer: Timer, timeout: Duration) -> Self
+        where T: AsyncRead + AsyncWrite,
+              C: Encoder + Decoder,
+    {
+        let partial = Rc::new(Cell::new(false));
+
+        let codec = TrackPartial {
+            inner: codec,
+            partial: partial.clone(),
+        };
+
+        AssemblyTimeout {
+            upstream: io.framed(codec),
+            partial: partial,
+            timer: timer,
+            timeout: timeout,
+            sleep: None,
+        }
+    }
+}
+
+impl<C: Decoder> Decoder for TrackPartial<C> {
+    type Item = C::Item;
+    type Error = C::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<C::Item>, C::Error> {
+        let res = self.inner.decode(buf);
+
+        // Any bytes left in the buffer belong to a frame that is incomplete
+        self.partial.set(!buf.is_empty());
+
+        res
+    }
+}
+
+impl<C: Encoder> Encoder for TrackPartial<C> {
+    type Item = C::Item;
+    type Error = C::Error;
+
+    fn encode(&mut self, msg: C::Item, buf: &mut BytesMut) -> Result<(), C::Error> {
+        self.inner.encode(msg, buf)
+    }
+}
+
+impl<T> Stream for AssemblyTimeout<T>
+    where T: Stream<Error = io::Error>,
+{
+    type Item = T::Item;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<T::Item>, io::Error> {
+        if let Async::Ready(frame) = try!(self.upstream.poll()) {
+            // A complete frame has been decoded, reset the timer
+            self.sleep = None;
+            return Ok(Async::Ready(frame));
+        }
+
+        if !self.partial.get() {
+            // Nothing buffered, the connection is simply idle
+            self.sleep = None;
+            return Ok(Async::NotReady);
+        }
+
+        // The first bytes of a frame have arrived, start the timer
+        if self.sleep.is_none() {
+            self.sleep = Some(self.timer.sleep(self.timeout));
+        }
+
+        match self.sleep.as_mut().unwrap().poll() {
+            Ok(Async::Ready(())) => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for a complete frame"))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+        }
+    }
+}
+
+impl<T> Sink for AssemblyTimeout<T>
+    where T: Sink<SinkError = io::Error>,
+{
+    type SinkItem = T::SinkItem;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: T::SinkItem) -> StartSend<T::SinkItem, io::Error> {
+        self.upstream.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.upstream.poll_complete()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for AssemblyTimeoutProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = AssemblyTimeout<Framed<T, TrackPartial<LineCodec>>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(AssemblyTimeout::new(io, self.timer.clone(), self.timeout))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AssemblyTimeoutProto;
+
+    use Validate;
+
+    use futures::{future, stream, Future, Stream};
+    use tokio_io::io::{read_exact, read_to_end, write_all};
+    use tokio_core::net::{TcpListener, TcpStream};
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+    use tokio_timer::Timer;
+
+    use std::io;
+    use std::time::{Duration, Instant};
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn dribbling_client_is_dropped() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let timer = Timer::default();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+        let proto = AssemblyTimeoutProto {
+            timer: timer.clone(),
+            timeout: Duration::from_millis(200),
+        };
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            proto.bind_server(&handle2, socket, Validate::new(Echo));
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let start = Instant::now();
+        let timer2 = timer.clone();
+
+        let client = TcpStream::connect(&addr, &handle)
+            // A complete frame is answered as usual
+            .and_then(|socket| write_all(socket, b"ok\n"))
+            .and_then(|(socket, _)| read_exact(socket, [0; 3]))
+            .and_then(move |(socket, resp)| {
+                assert_eq!(&resp, b"ok\n");
+
+                // Then dribble out a frame, slower than the timeout allows
+                stream::iter_ok(b"he".to_vec()).fold(socket, move |socket, byte| {
+                    write_all(socket, [byte])
+                        .and_then(|(socket, _)| {
+                            timer2.sleep(Duration::from_millis(150))
+                                .map(|_| socket)
+                                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                        })
+                })
+            })
+            .and_then(|socket| read_to_end(socket, vec![]));
+
+        let (_, received) = core.run(timer.timeout(client, Duration::from_secs(5))).unwrap();
+
+        // The server closed the connection without answering
+        assert!(received.is_empty());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,204 @@
+//! Replacing the request handler of a running server.
+//!
+//! For hot-reloading, the implementation behind a server can be swapped at
+//! runtime through a `SwapHandle`, without dropping any connection:
+//!
+//! * `SwappableNewService` wraps a `NewService`. After a swap, new connections
+//!   are handled by services built from the new `NewService`, while existing
+//!   connections keep their current service.
+//! * `SwappableService` wraps a single `Service`, shared by every connection.
+//!   Each call is routed to the implementation current at the time of the
+//!   call, so a swap applies to existing connections too. Calls that are
+//!   already in flight complete with the old implementation.
+
+use futures::Future;
+use tokio_service::{Service, NewService};
+
+use std::io;
+use std::sync::{Arc, RwLock};
+
+/// A `Service` whose implementation can be swapped through a `SwapHandle`.
+///
+/// It also implements `NewService`, handing out a `SwappableService` sharing
+/// the same implementation to every connection.
+pub struct SwappableService<T> {
+    current: Arc<RwLock<Arc<T>>>,
+}
+
+/// A `NewService` whose implementation can be swapped through a `SwapHandle`.
+pub struct SwappableNewService<T> {
+    current: Arc<RwLock<Arc<T>>>,
+}
+
+/// Handle used to swap the implementation of a `SwappableService` or a
+/// `SwappableNewService`.
+pub struct SwapHandle<T> {
+    current: Arc<RwLock<Arc<T>>>,
+}
+
+impl<T> SwappableService<T> {
+    /// Create a new `SwappableService`, initially calling `inner`
+    pub fn new(inner: T) -> SwappableService<T> {
+        SwappableService {
+            current: Arc::new(RwLock::new(Arc::new(inner))),
+        }
+    }
+
+    /// Returns a handle to swap the implementation
+    pub fn handle(&self) -> SwapHandle<T> {
+        SwapHandle { current: self.current.clone() }
+    }
+}
+
+impl<T> SwappableNewService<T> {
+    /// Create a new `SwappableNewService`, initially building services with
+    /// `inner`
+    pub fn new(inner: T) -> SwappableNewService<T> {
+        SwappableNewService {
+            current: Arc::new(RwLock::new(Arc::new(inner))),
+        }
+    }
+
+    /// Returns a handle to swap the implementation
+    pub fn handle(&self) -> SwapHandle<T> {
+        SwapHandle { current: self.current.clone() }
+    }
+}
+
+impl<T> SwapHandle<T> {
+    /// Replace the implementation with `inner`, returning the previous one.
+    pub fn swap(&self, inner: T) -> Arc<T> {
+        let mut current = self.current.write().unwrap();
+        ::std::mem::replace(&mut *current, Arc::new(inner))
+    }
+
+    /// Returns the current implementation
+    pub fn current(&self) -> Arc<T> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+impl<T> Clone for SwappableService<T> {
+    fn clone(&self) -> SwappableService<T> {
+        SwappableService { current: self.current.clone() }
+    }
+}
+
+impl<T> Clone for SwapHandle<T> {
+    fn clone(&self) -> SwapHandle<T> {
+        SwapHandle { current: self.current.clone() }
+    }
+}
+
+impl<T> Service for SwappableService<T>
+    where T: Service<Request = String, Response = String, Error = io::Error>,
+          T::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        // Only hold the lock long enough to grab the current implementation.
+        // The returned future does not borrow it, so a swap does not affect
+        // calls in flight.
+        let current = self.current.read().unwrap().clone();
+        Box::new(current.call(req))
+    }
+}
+
+impl<T> NewService for SwappableService<T>
+    where T: Service<Request = String, Response = String, Error = io::Error>,
+          T::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Instance = SwappableService<T>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+impl<T> NewService for SwappableNewService<T>
+    where T: NewService<Request = String, Response = String, Error = io::Error>,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Instance = T::Instance;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let current = self.current.read().unwrap().clone();
+        current.new_service()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SwappableService, SwappableNewService};
+
+    use futures::{future, Future};
+    use tokio_service::{Service, NewService};
+
+    use std::io;
+
+    /// Prefixes requests with its version, once the response is polled
+    struct Version(&'static str);
+
+    impl Service for Version {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = Box<Future<Item = String, Error = io::Error>>;
+
+        fn call(&self, req: String) -> Self::Future {
+            let version = self.0;
+            Box::new(future::lazy(move || Ok(format!("{} {}", version, req))))
+        }
+    }
+
+    #[test]
+    fn swap_applies_to_later_calls_only() {
+        let service = SwappableService::new(Version("v1"));
+        let handle = service.handle();
+
+        // An existing connection sharing the implementation
+        let conn = service.new_service().unwrap();
+
+        let in_flight = conn.call("before".to_string());
+
+        let old = handle.swap(Version("v2"));
+        assert_eq!(old.0, "v1");
+
+        assert_eq!(in_flight.wait().unwrap(), "v1 before");
+        assert_eq!(conn.call("after".to_string()).wait().unwrap(), "v2 after");
+        assert_eq!(service.call("after".to_string()).wait().unwrap(), "v2 after");
+    }
+
+    fn v1() -> io::Result<Version> {
+        Ok(Version("v1"))
+    }
+
+    fn v2() -> io::Result<Version> {
+        Ok(Version("v2"))
+    }
+
+    #[test]
+    fn swapped_new_service_applies_to_new_connections() {
+        let new_service = SwappableNewService::new(v1 as fn() -> io::Result<Version>);
+        let handle = new_service.handle();
+
+        let existing = new_service.new_service().unwrap();
+
+        handle.swap(v2);
+
+        let new = new_service.new_service().unwrap();
+
+        assert_eq!(existing.call("req".to_string()).wait().unwrap(), "v1 req");
+        assert_eq!(new.call("req".to_string()).wait().unwrap(), "v2 req");
+    }
+}
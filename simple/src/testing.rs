@@ -0,0 +1,297 @@
+//! Helpers for testing line-based services and clients.
+
+use {Client, LineProto, Validate};
+
+use futures::{future, Future, Stream, IntoFuture};
+use tokio_io::codec::{Encoder, Decoder};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+use tokio_proto::BindServer;
+use tokio_service::{Service, NewService};
+
+use bytes::BytesMut;
+
+use std::{fmt, io};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+
+/// A server answering requests from a recorded transcript.
+///
+/// Each incoming request is matched against the requests of the transcript,
+/// and answered with the recorded response. If the same request was recorded
+/// several times, its responses are replayed in the order they were recorded.
+/// Every connection replays the transcript from the start.
+///
+/// Requests that are not in the transcript, or whose recorded responses have
+/// all been replayed, are handled by the fallback service. By default, this
+/// fails them with `InvalidInput`. Use `passthrough` to forward them to a real
+/// service instead.
+#[derive(Clone)]
+pub struct MockServer<T = Reject> {
+    responses: HashMap<String, VecDeque<String>>,
+    fallback: T,
+}
+
+/// The `Service` instance of a `MockServer`, created for each connection.
+pub struct MockService<T> {
+    responses: RefCell<HashMap<String, VecDeque<String>>>,
+    fallback: T,
+}
+
+/// Fallback service of a `MockServer`, failing every request.
+#[derive(Debug, Clone, Copy)]
+pub struct Reject;
+
+/// Run a server and a client cooperatively on a single reactor.
+///
+/// A server is bound to an ephemeral loopback port and uses `new_service` to
+/// process requests received on each connection. A `Client` is then connected
+/// to it and handed to `client_logic`, and the returned future is driven to
+/// completion. Once it completes, the reactor is dropped, shutting down the
+/// server along with it.
+///
+/// Unlike the examples, no thread is spawned for the server, so tests built on
+/// this helper are single-threaded and deterministic.
+pub fn run_server_and_client<T, F, R>(new_service: T, client_logic: F) -> Result<R::Item, io::Error>
+    where T: NewService<Request = String, Response = String, Error = io::Error> + 'static,
+          T::Instance: 'static,
+          <T::Instance as Service>::Future: 'static,
+          F: FnOnce(Client) -> R,
+          R: IntoFuture<Error = io::Error>,
+{
+    let mut core = try!(Core::new());
+    let handle = core.handle();
+
+    // Bind to port 0 so that the OS picks an available port
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let listener = try!(TcpListener::bind(&addr, &handle));
+    let addr = try!(listener.local_addr());
+
+    // Wrap the service with `Validate`, just like `serve` does
+    let new_service = Validate::new(new_service);
+    let server_handle = handle.clone();
+
+    let server = listener.incoming().for_each(move |(socket, _)| {
+        let service = try!(new_service.new_service());
+
+        // Spawns a task on the reactor dedicated to processing the connection
+        LineProto::new().bind_server(&server_handle, socket, service);
+        Ok(())
+    });
+
+    handle.spawn(server.map_err(|_| ()));
+
+    core.run(Client::connect(&addr, &handle).and_then(client_logic))
+}
+
+/// Assert that `codec` decodes each of `inputs` back after encoding it.
+///
+/// Each input is encoded on its own, then decoded from a buffer holding the
+/// whole frame, and again from a buffer filled one byte at a time, checking
+/// that nothing is decoded before the last byte of the frame. Each step uses
+/// a fresh clone of `codec`.
+///
+/// # Panics
+///
+/// Panics if encoding or decoding fails, or if a frame does not round-trip.
+pub fn assert_codec_roundtrip<C, T>(codec: C, inputs: Vec<T>)
+    where C: Encoder<Item = T> + Decoder<Item = T> + Clone,
+          T: Clone + PartialEq + fmt::Debug,
+          <C as Encoder>::Error: fmt::Debug,
+          <C as Decoder>::Error: fmt::Debug,
+{
+    for input in inputs {
+        let mut buf = BytesMut::new();
+        codec.clone().encode(input.clone(), &mut buf).expect("encoding failed");
+
+        let encoded = buf.clone();
+
+        // Decode the whole frame at once
+        let decoded = codec.clone().decode(&mut buf).expect("decoding failed");
+
+        assert_eq!(decoded.as_ref(), Some(&input), "frame did not round-trip");
+        assert!(buf.is_empty(), "{} bytes left after decoding {:?}", buf.len(), input);
+
+        // Decode the frame as it trickles in, one byte at a time
+        let mut decoder = codec.clone();
+        let mut buf = BytesMut::new();
+
+        for (i, &b) in encoded.iter().enumerate() {
+            buf.extend_from_slice(&[b]);
+
+            let decoded = decoder.decode(&mut buf).expect("decoding failed");
+
+            if i + 1 < encoded.len() {
+                assert!(decoded.is_none(), "{:?} decoded from a partial frame", decoded);
+            } else {
+                assert_eq!(decoded.as_ref(), Some(&input), "partial frames did not round-trip");
+            }
+        }
+    }
+}
+
+impl MockServer {
+    /// Create a `MockServer` replaying `transcript`, a sequence of recorded
+    /// `(request, response)` pairs.
+    pub fn from_transcript<I>(transcript: I) -> MockServer
+        where I: IntoIterator<Item = (String, String)>,
+    {
+        let mut responses = HashMap::new();
+
+        for (req, resp) in transcript {
+            responses.entry(req).or_insert_with(VecDeque::new).push_back(resp);
+        }
+
+        MockServer {
+            responses: responses,
+            fallback: Reject,
+        }
+    }
+}
+
+impl<T> MockServer<T> {
+    /// Forward requests that do not match the transcript to `new_service`.
+    pub fn passthrough<U>(self, new_service: U) -> MockServer<U> {
+        MockServer {
+            responses: self.responses,
+            fallback: new_service,
+        }
+    }
+}
+
+impl<T> NewService for MockServer<T>
+    where T: NewService<Request = String, Response = String, Error = io::Error>,
+          <T::Instance as Service>::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Instance = MockService<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let fallback = try!(self.fallback.new_service());
+
+        Ok(MockService {
+            responses: RefCell::new(self.responses.clone()),
+            fallback: fallback,
+        })
+    }
+}
+
+impl<T> Service for MockService<T>
+    where T: Service<Request = String, Response = String, Error = io::Error>,
+          T::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        let recorded = self.responses.borrow_mut()
+            .get_mut(&req)
+            .and_then(|responses| responses.pop_front());
+
+        match recorded {
+            Some(resp) => Box::new(future::ok(resp)),
+            None => Box::new(self.fallback.call(req)),
+        }
+    }
+}
+
+impl NewService for Reject {
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Instance = Reject;
+
+    fn new_service(&self) -> io::Result<Reject> {
+        Ok(Reject)
+    }
+}
+
+impl Service for Reject {
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Future = future::FutureResult<String, io::Error>;
+
+    fn call(&self, req: String) -> Self::Future {
+        let msg = format!("unexpected request: {}", req);
+        future::err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{assert_codec_roundtrip, run_server_and_client, MockServer};
+    use LineCodec;
+
+    use futures::{future, Future};
+    use tokio_service::Service;
+
+    use std::io;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn line_codec_round_trips() {
+        let inputs = vec![
+            "hello".to_string(),
+            "".to_string(),
+            "with spaces and \u{e9}".to_string(),
+        ];
+
+        assert_codec_roundtrip(LineCodec::new(), inputs.clone());
+        assert_codec_roundtrip(LineCodec::crlf(), inputs);
+    }
+
+    #[test]
+    fn server_and_client_share_a_reactor() {
+        let resp = run_server_and_client(|| Ok(Echo), |client| client.call("hello".to_string()));
+
+        assert_eq!(resp.unwrap(), "hello");
+    }
+
+    fn transcript() -> MockServer {
+        let transcript = vec![("a", "1"), ("b", "2"), ("a", "3")];
+        MockServer::from_transcript(transcript.into_iter().map(|(req, resp)| (req.to_string(), resp.to_string())))
+    }
+
+    #[test]
+    fn mock_server_replays_the_transcript() {
+        let resps = run_server_and_client(transcript(), |client| {
+            let reqs = vec!["a".to_string(), "b".to_string(), "a".to_string()];
+
+            client.call_all(reqs)
+                .join(client.call("c".to_string()).then(Ok::<_, io::Error>))
+        });
+
+        let (resps, unexpected) = resps.unwrap();
+
+        assert_eq!(resps, vec!["1", "2", "3"]);
+        assert!(unexpected.is_err());
+    }
+
+    #[test]
+    fn mock_server_passes_unexpected_requests_through() {
+        let resps = run_server_and_client(transcript().passthrough(|| Ok(Echo)), |client| {
+            client.call_all(vec!["a".to_string(), "a".to_string(), "a".to_string()])
+        });
+
+        // The second `a` was recorded, but the third one was not
+        assert_eq!(resps.unwrap(), vec!["1", "3", "a"]);
+    }
+}
@@ -0,0 +1,277 @@
+//! End-to-end timeout budgets across chains of services.
+//!
+//! When a service calls downstream services to process a request, per-call
+//! timeouts do not bound the total time spent. Instead, a request may carry
+//! the time remaining for the whole chain, encoded as a
+//! `[budget=<millis>] ` prefix on the line.
+//!
+//! The `EnforceBudget` middleware strips the prefix and passes the request to
+//! the inner service along with a `TimeBudget`, which counts down from the
+//! moment the request was received. The inner service passes the remaining
+//! time downstream with `Client::call_with_budget`. Any hop finding the budget
+//! exhausted fails with `TimedOut`.
+
+use Client;
+
+use futures::{future, Future};
+use tokio_service::{Service, NewService};
+use tokio_timer::Timer;
+
+use std::io;
+use std::time::{Duration, Instant};
+
+const PREFIX: &'static str = "[budget=";
+
+/// The time left to process a request
+#[derive(Debug, Clone, Copy)]
+pub struct TimeBudget {
+    deadline: Instant,
+}
+
+/// A `Service` middleware passing the time budget of each request to the
+/// inner service.
+///
+/// Requests without a budget prefix are passed to the inner service with
+/// `None`.
+pub struct EnforceBudget<T> {
+    inner: T,
+}
+
+/// Builds an `EnforceBudget` service for each new connection.
+pub struct NewEnforceBudget<T> {
+    inner: T,
+}
+
+/// Prefix `req` with `budget`, to be decoded by the `EnforceBudget`
+/// middleware on the server.
+pub fn encode(req: &str, budget: Duration) -> String {
+    let millis = budget.as_secs() * 1_000 + (budget.subsec_nanos() / 1_000_000) as u64;
+    format!("{}{}] {}", PREFIX, millis, req)
+}
+
+/// Split a request into its budget (if any) and the actual request line.
+///
+/// Lines with a malformed prefix are returned unchanged.
+pub fn decode(req: String) -> (Option<Duration>, String) {
+    if !req.starts_with(PREFIX) {
+        return (None, req);
+    }
+
+    let budget = req[PREFIX.len()..].find("] ").and_then(|end| {
+        let end = PREFIX.len() + end;
+
+        req[PREFIX.len()..end].parse::<u64>().ok()
+            .map(|millis| (Duration::from_millis(millis), end + 2))
+    });
+
+    match budget {
+        Some((budget, start)) => (Some(budget), req[start..].to_string()),
+        None => (None, req),
+    }
+}
+
+impl TimeBudget {
+    /// Create a budget of `budget`, starting now
+    pub fn new(budget: Duration) -> TimeBudget {
+        TimeBudget { deadline: Instant::now() + budget }
+    }
+
+    /// Returns the time left, which is zero once the budget is exhausted
+    pub fn remaining(&self) -> Duration {
+        let now = Instant::now();
+
+        if now >= self.deadline {
+            Duration::from_secs(0)
+        } else {
+            self.deadline - now
+        }
+    }
+
+    /// Returns true once there is no time left
+    pub fn is_exhausted(&self) -> bool {
+        self.remaining() == Duration::from_secs(0)
+    }
+}
+
+impl Client {
+    /// Send a request carrying a time budget of `budget`, failing with
+    /// `TimedOut` if no response is received within it.
+    ///
+    /// A service processing requests with a `TimeBudget` calls its
+    /// downstream services with `budget.remaining()`. The call fails
+    /// immediately if the budget is already exhausted.
+    pub fn call_with_budget(&self, req: String, budget: Duration, timer: &Timer) -> Box<Future<Item = String, Error = io::Error>> {
+        if budget == Duration::from_secs(0) {
+            return Box::new(future::err(exhausted()));
+        }
+
+        self.call_deadline(encode(&req, budget), timer, budget)
+    }
+}
+
+impl<T> EnforceBudget<T> {
+    /// Create a new `EnforceBudget`
+    pub fn new(inner: T) -> EnforceBudget<T> {
+        EnforceBudget { inner: inner }
+    }
+}
+
+impl<T> Service for EnforceBudget<T>
+    where T: Service<Request = (Option<TimeBudget>, String), Response = String, Error = io::Error>,
+          T::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        let (budget, req) = decode(req);
+        let budget = budget.map(TimeBudget::new);
+
+        if budget.map(|b| b.is_exhausted()).unwrap_or(false) {
+            return Box::new(future::err(exhausted()));
+        }
+
+        Box::new(self.inner.call((budget, req)))
+    }
+}
+
+impl<T> NewEnforceBudget<T> {
+    /// Create a new `NewEnforceBudget`
+    pub fn new(inner: T) -> NewEnforceBudget<T> {
+        NewEnforceBudget { inner: inner }
+    }
+}
+
+impl<T> NewService for NewEnforceBudget<T>
+    where T: NewService<Request = (Option<TimeBudget>, String), Response = String, Error = io::Error>,
+          <T::Instance as Service>::Future: 'static
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Instance = EnforceBudget<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = try!(self.inner.new_service());
+        Ok(EnforceBudget::new(inner))
+    }
+}
+
+fn exhausted() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "time budget exhausted")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EnforceBudget, TimeBudget};
+
+    use {Client, LineProto, Validate};
+
+    use futures::{future, Future, Stream};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::{Core, Handle};
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+    use tokio_timer::Timer;
+
+    use std::io;
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    type Request = (Option<TimeBudget>, String);
+
+    /// The last hop, responding with the milliseconds left in its budget
+    struct Report;
+
+    impl Service for Report {
+        type Request = Request;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, (budget, _): Request) -> Self::Future {
+            let remaining = budget.expect("no budget").remaining();
+            let millis = remaining.as_secs() * 1_000 + (remaining.subsec_nanos() / 1_000_000) as u64;
+
+            future::ok(millis.to_string())
+        }
+    }
+
+    /// The first hop, working for a while before calling the last hop
+    struct Forward {
+        downstream: Rc<Client>,
+        timer: Timer,
+    }
+
+    impl Service for Forward {
+        type Request = Request;
+        type Response = String;
+        type Error = io::Error;
+        type Future = Box<Future<Item = String, Error = io::Error>>;
+
+        fn call(&self, (budget, req): Request) -> Self::Future {
+            let budget = budget.expect("no budget");
+            let downstream = self.downstream.clone();
+            let timer = self.timer.clone();
+
+            let resp = self.timer.sleep(Duration::from_millis(200))
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                .and_then(move |_| downstream.call_with_budget(req, budget.remaining(), &timer));
+
+            Box::new(resp)
+        }
+    }
+
+    fn serve<T>(handle: &Handle, service: T) -> SocketAddr
+        where T: Service<Request = Request, Response = String, Error = io::Error> + 'static,
+              T::Future: 'static,
+    {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+        let mut service = Some(service);
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            let service = Validate::new(EnforceBudget::new(service.take().unwrap()));
+            LineProto::new().bind_server(&handle2, socket, service);
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        addr
+    }
+
+    #[test]
+    fn second_hop_sees_the_reduced_budget() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let timer = Timer::default();
+
+        let last = serve(&handle, Report);
+        let downstream = core.run(Client::connect(&last, &handle)).unwrap();
+
+        let first = serve(&handle, Forward {
+            downstream: Rc::new(downstream),
+            timer: timer.clone(),
+        });
+
+        let client = core.run(Client::connect(&first, &handle)).unwrap();
+
+        let resp = core.run(client.call_with_budget("req".to_string(), Duration::from_millis(1_000), &timer)).unwrap();
+        let remaining: u64 = resp.parse().unwrap();
+
+        // The first hop spent about 200ms of the budget
+        assert!(remaining > 0);
+        assert!(remaining <= 850, "{}ms left", remaining);
+
+        // With less than the first hop needs, the chain times out
+        let err = core.run(client.call_with_budget("req".to_string(), Duration::from_millis(100), &timer)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
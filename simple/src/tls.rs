@@ -0,0 +1,177 @@
+//! Running the line protocol over TLS.
+//!
+//! The TLS handshake is performed in `bind_transport`, before the stream is
+//! framed with `LineCodec`, so that tokio-proto only ever sees a transport of
+//! lines, exactly as with plain TCP.
+//!
+//! Certificates and keys are configured with `native_tls`, which is re-exported
+//! as `tls::native_tls` so that callers use the same version as this crate.
+//!
+//! This module is only available with the `tls` feature enabled.
+
+pub use native_tls;
+
+use {Client, LineCodec, Validate};
+
+use futures::Future;
+use native_tls::{TlsAcceptor, TlsConnector};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_proto::{TcpClient, TcpServer};
+use tokio_proto::pipeline::{ServerProto, ClientProto, ClientService};
+use tokio_service::NewService;
+use tokio_tls::{TlsAcceptorExt, TlsConnectorExt, TlsStream};
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Server protocol, accepting the TLS handshake
+struct TlsServerProto {
+    acceptor: Arc<TlsAcceptor>,
+}
+
+/// Client protocol, initiating the TLS handshake for `domain`
+struct TlsClientProto {
+    connector: Arc<TlsConnector>,
+    domain: String,
+}
+
+/// Start a server accepting TLS connections on `addr`.
+///
+/// Other than the transport, this behaves exactly like `serve`.
+pub fn serve_tls<T>(addr: SocketAddr, new_service: T, acceptor: TlsAcceptor)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    let new_service = Validate::new(new_service);
+    let proto = TlsServerProto { acceptor: Arc::new(acceptor) };
+
+    TcpServer::new(proto, addr)
+        .serve(new_service);
+}
+
+impl Client {
+    /// Establish a TLS connection to a line-based server at the provided
+    /// `addr`.
+    ///
+    /// The certificate of the server is validated against `domain`.
+    pub fn connect_tls(addr: &SocketAddr, handle: &Handle, connector: TlsConnector, domain: &str) -> Box<Future<Item = Client, Error = io::Error>> {
+        let handle = handle.clone();
+
+        let proto = TlsClientProto {
+            connector: Arc::new(connector),
+            domain: domain.to_string(),
+        };
+
+        let ret = TcpClient::new(proto)
+            .connect(addr, &handle)
+            .map(move |client_service: ClientService<TcpStream, TlsClientProto>| {
+                Client::new(client_service, handle)
+            });
+
+        Box::new(ret)
+    }
+}
+
+fn to_io_error(e: native_tls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for TlsServerProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = Framed<TlsStream<T>, LineCodec>;
+    type BindTransport = Box<Future<Item = Self::Transport, Error = io::Error>>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        let handshake = self.acceptor.accept_async(io)
+            .map(|tls| tls.framed(LineCodec::new()))
+            .map_err(to_io_error);
+
+        Box::new(handshake)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for TlsClientProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = Framed<TlsStream<T>, LineCodec>;
+    type BindTransport = Box<Future<Item = Self::Transport, Error = io::Error>>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        let handshake = self.connector.connect_async(&self.domain, io)
+            .map(|tls| tls.framed(LineCodec::new()))
+            .map_err(to_io_error);
+
+        Box::new(handshake)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TlsServerProto;
+
+    use {Client, Validate};
+
+    use futures::{future, Future, Stream};
+    use native_tls::{Certificate, Pkcs12, TlsAcceptor, TlsConnector};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+
+    use std::io;
+    use std::sync::Arc;
+
+    /// A self-signed identity for `localhost`, and its certificate
+    const IDENTITY: &'static [u8] = include_bytes!("../tests/tls/identity.p12");
+    const CERT: &'static [u8] = include_bytes!("../tests/tls/cert.der");
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn line_round_trips_over_tls() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let identity = Pkcs12::from_der(IDENTITY, "password").unwrap();
+        let acceptor = TlsAcceptor::builder(identity).unwrap().build().unwrap();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+        let proto = TlsServerProto { acceptor: Arc::new(acceptor) };
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            proto.bind_server(&handle2, socket, Validate::new(Echo));
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        // Trust the self-signed certificate
+        let mut connector = TlsConnector::builder().unwrap();
+        connector.add_root_certificate(Certificate::from_der(CERT).unwrap()).unwrap();
+        let connector = connector.build().unwrap();
+
+        let client = core.run(Client::connect_tls(&addr, &handle, connector, "localhost")).unwrap();
+
+        assert_eq!(core.run(client.call("hello".to_string())).unwrap(), "hello");
+    }
+}
@@ -0,0 +1,240 @@
+//! W3C trace context propagation.
+//!
+//! A request may carry a W3C `traceparent`, encoded as a
+//! `[traceparent=<value>] ` prefix on the line. The `ExtractTrace` middleware
+//! strips the prefix on the server, validates it, and passes it on to the inner
+//! service alongside the request line as a `TracedRequest`.
+//!
+//! Only version `00` of the format is understood:
+//!
+//! ```text
+//! 00-<32 hex digit trace id>-<16 hex digit parent id>-<2 hex digit flags>
+//! ```
+//!
+//! Hex digits must be lower case, and neither the trace id nor the parent id
+//! may be all zeros. Requests with a malformed `traceparent` are rejected with
+//! `InvalidInput` without reaching the inner service.
+
+use futures::{future, Future};
+use tokio_service::{Service, NewService};
+
+use std::{fmt, io};
+
+const PREFIX: &'static str = "[traceparent=";
+
+/// A validated W3C `traceparent` value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceParent {
+    trace_id: String,
+    parent_id: String,
+    flags: u8,
+}
+
+/// A request line along with the trace context it was sent with, if any.
+#[derive(Debug)]
+pub struct TracedRequest {
+    /// The trace context sent by the client
+    pub traceparent: Option<TraceParent>,
+    /// The request line
+    pub line: String,
+}
+
+/// A `Service` middleware extracting the trace context of requests.
+pub struct ExtractTrace<T> {
+    inner: T,
+}
+
+impl TraceParent {
+    /// Parse and validate a `traceparent` value.
+    pub fn parse(s: &str) -> io::Result<TraceParent> {
+        let parts: Vec<&str> = s.split('-').collect();
+
+        if parts.len() != 4 ||
+            parts[0] != "00" ||
+            !is_hex(parts[1], 32) ||
+            !is_hex(parts[2], 16) ||
+            !is_hex(parts[3], 2) ||
+            is_zero(parts[1]) ||
+            is_zero(parts[2])
+        {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "malformed traceparent"));
+        }
+
+        Ok(TraceParent {
+            trace_id: parts[1].to_string(),
+            parent_id: parts[2].to_string(),
+            flags: u8::from_str_radix(parts[3], 16).unwrap(),
+        })
+    }
+
+    /// Returns the trace id, as 32 hex digits
+    pub fn trace_id(&self) -> &str {
+        &self.trace_id
+    }
+
+    /// Returns the id of the caller's span, as 16 hex digits
+    pub fn parent_id(&self) -> &str {
+        &self.parent_id
+    }
+
+    /// Returns `true` if the caller recorded its span
+    pub fn is_sampled(&self) -> bool {
+        self.flags & 0x01 == 0x01
+    }
+}
+
+impl fmt::Display for TraceParent {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "00-{}-{}-{:02x}", self.trace_id, self.parent_id, self.flags)
+    }
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| (b >= b'0' && b <= b'9') || (b >= b'a' && b <= b'f'))
+}
+
+fn is_zero(s: &str) -> bool {
+    s.bytes().all(|b| b == b'0')
+}
+
+/// Prefix `req` with `traceparent`, to be decoded by the `ExtractTrace`
+/// middleware on the server.
+pub fn encode(req: &str, traceparent: &TraceParent) -> String {
+    format!("{}{}] {}", PREFIX, traceparent, req)
+}
+
+/// Split a request into its trace context (if any) and the actual request
+/// line.
+///
+/// Lines with a prefix that holds a malformed `traceparent` are rejected.
+pub fn decode(req: String) -> io::Result<TracedRequest> {
+    if !req.starts_with(PREFIX) {
+        return Ok(TracedRequest {
+            traceparent: None,
+            line: req,
+        });
+    }
+
+    let end = match req[PREFIX.len()..].find("] ") {
+        Some(end) => PREFIX.len() + end,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "malformed traceparent")),
+    };
+
+    let traceparent = try!(TraceParent::parse(&req[PREFIX.len()..end]));
+
+    Ok(TracedRequest {
+        traceparent: Some(traceparent),
+        line: req[end + 2..].to_string(),
+    })
+}
+
+impl<T> ExtractTrace<T> {
+    /// Create a new `ExtractTrace`
+    pub fn new(inner: T) -> ExtractTrace<T> {
+        ExtractTrace { inner: inner }
+    }
+}
+
+impl<T> Service for ExtractTrace<T>
+    where T: Service<Request = TracedRequest, Response = String, Error = io::Error>,
+          T::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        match decode(req) {
+            Ok(req) => Box::new(self.inner.call(req)),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+}
+
+impl<T> NewService for ExtractTrace<T>
+    where T: NewService<Request = TracedRequest, Response = String, Error = io::Error>,
+          <T::Instance as Service>::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Instance = ExtractTrace<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = try!(self.inner.new_service());
+        Ok(ExtractTrace::new(inner))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ExtractTrace, TracedRequest};
+
+    use {Client, LineProto, Validate};
+
+    use futures::{future, Future, Stream};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+
+    use std::io;
+
+    /// Responds with the trace context the request was received with
+    struct Report;
+
+    impl Service for Report {
+        type Request = TracedRequest;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: TracedRequest) -> Self::Future {
+            let resp = match req.traceparent {
+                Some(traceparent) => format!("{} {}", traceparent, req.line),
+                None => format!("untraced {}", req.line),
+            };
+
+            future::ok(resp)
+        }
+    }
+
+    const TRACEPARENT: &'static str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn traceparent_round_trips_and_malformed_ones_are_rejected() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            LineProto::new().bind_server(&handle2, socket, Validate::new(ExtractTrace::new(Report)));
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        let resp = core.run(client.call_traced("hello".to_string(), TRACEPARENT.to_string())).unwrap();
+        assert_eq!(resp, format!("{} hello", TRACEPARENT));
+
+        let resp = core.run(client.call("plain".to_string())).unwrap();
+        assert_eq!(resp, "untraced plain");
+
+        // Upper case hex digits are not allowed, the client refuses to send it
+        let err = core.run(client.call_traced("hello".to_string(), TRACEPARENT.to_uppercase())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // The server rejects it too. This closes the connection, so it goes
+        // last.
+        let res = core.run(client.call("[traceparent=00-zz] hello".to_string()));
+        assert!(res.is_err());
+    }
+}
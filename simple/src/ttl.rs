@@ -0,0 +1,198 @@
+//! Per-request time-to-live enforcement.
+//!
+//! A request may carry a relative TTL, encoded as a `[ttl=<millis>] ` prefix on
+//! the line. The `Expire` middleware processes requests one at a time, in the
+//! order in which they were received. When a request reaches the front of the
+//! queue, the time it spent waiting is compared against its TTL. If the TTL has
+//! elapsed, the inner service is never called and the request is answered with
+//! an `EXPIRED` line instead.
+
+use futures::{future, Future};
+use futures::sync::oneshot;
+use tokio_service::{Service, NewService};
+
+use std::io;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Response line sent for requests whose TTL elapsed while queued.
+pub const EXPIRED: &'static str = "EXPIRED";
+
+const PREFIX: &'static str = "[ttl=";
+
+/// A `Service` middleware that drops requests that have been queued for longer
+/// than their TTL.
+///
+/// Requests without a TTL prefix are passed through to the inner service
+/// unchanged, but are still queued behind earlier requests.
+pub struct Expire<T> {
+    inner: Rc<T>,
+    // Completes once the most recently queued request has been processed
+    tail: RefCell<Option<oneshot::Receiver<()>>>,
+}
+
+/// Builds an `Expire` service for each new connection.
+pub struct NewExpire<T> {
+    inner: T,
+}
+
+/// Prefix `req` with `ttl`, to be decoded by the `Expire` middleware on the
+/// server.
+pub fn encode(req: &str, ttl: Duration) -> String {
+    let millis = ttl.as_secs() * 1_000 + (ttl.subsec_nanos() / 1_000_000) as u64;
+    format!("{}{}] {}", PREFIX, millis, req)
+}
+
+/// Split a request into its TTL (if any) and the actual request line.
+///
+/// Lines with a malformed prefix are returned unchanged.
+pub fn decode(req: String) -> (Option<Duration>, String) {
+    if !req.starts_with(PREFIX) {
+        return (None, req);
+    }
+
+    let ttl = req[PREFIX.len()..].find("] ").and_then(|end| {
+        let end = PREFIX.len() + end;
+
+        req[PREFIX.len()..end].parse::<u64>().ok()
+            .map(|millis| (Duration::from_millis(millis), end + 2))
+    });
+
+    match ttl {
+        Some((ttl, start)) => (Some(ttl), req[start..].to_string()),
+        None => (None, req),
+    }
+}
+
+impl<T> Expire<T> {
+    /// Create a new `Expire`
+    pub fn new(inner: T) -> Expire<T> {
+        Expire {
+            inner: Rc::new(inner),
+            tail: RefCell::new(None),
+        }
+    }
+}
+
+impl<T> Service for Expire<T>
+    where T: Service<Request = String, Response = String, Error = io::Error> + 'static,
+          T::Future: 'static,
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        let received = Instant::now();
+        let (ttl, req) = decode(req);
+
+        // Queue the request behind the previously received one. `done` is
+        // signaled once this request has been processed, releasing the next
+        // request in line.
+        let (done, rx) = oneshot::channel();
+        let prev = self.tail.borrow_mut().take();
+        *self.tail.borrow_mut() = Some(rx);
+
+        let prev: Box<Future<Item = (), Error = ()>> = match prev {
+            // The previous request completing or being dropped both release
+            // this one.
+            Some(prev) => Box::new(prev.then(|_| Ok(()))),
+            None => Box::new(future::ok(())),
+        };
+
+        let inner = self.inner.clone();
+
+        Box::new(prev
+            .then(move |_| {
+                let expired = ttl.map(|ttl| received.elapsed() > ttl)
+                    .unwrap_or(false);
+
+                if expired {
+                    Box::new(future::ok(EXPIRED.to_string())) as Self::Future
+                } else {
+                    Box::new(inner.call(req))
+                }
+            })
+            .then(move |res| {
+                let _ = done.send(());
+                res
+            }))
+    }
+}
+
+impl<T> NewExpire<T> {
+    /// Create a new `NewExpire`
+    pub fn new(inner: T) -> NewExpire<T> {
+        NewExpire { inner: inner }
+    }
+}
+
+impl<T> NewService for NewExpire<T>
+    where T: NewService<Request = String, Response = String, Error = io::Error>,
+          T::Instance: 'static,
+          <T::Instance as Service>::Future: 'static
+{
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Instance = Expire<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = try!(self.inner.new_service());
+        Ok(Expire::new(inner))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode, Expire, EXPIRED};
+
+    use futures::{future, Future};
+    use tokio_core::reactor::Core;
+    use tokio_service::Service;
+    use tokio_timer::Timer;
+
+    use std::io;
+    use std::time::Duration;
+
+    /// Responds after 300ms
+    struct Slow {
+        timer: Timer,
+    }
+
+    impl Service for Slow {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = Box<Future<Item = String, Error = io::Error>>;
+
+        fn call(&self, req: String) -> Self::Future {
+            let resp = self.timer.sleep(Duration::from_millis(300))
+                .map(move |_| req)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+
+            Box::new(resp)
+        }
+    }
+
+    #[test]
+    fn short_ttl_expires_while_queued() {
+        let mut core = Core::new().unwrap();
+
+        let service = Expire::new(Slow { timer: Timer::default() });
+
+        // Both requests are queued behind the first one for about 300ms
+        let calls = vec![
+            service.call("first".to_string()),
+            service.call(encode("short", Duration::from_millis(50))),
+            service.call(encode("long", Duration::from_secs(10))),
+        ];
+
+        let resps = core.run(future::join_all(calls)).unwrap();
+
+        assert_eq!(resps, vec!["first", EXPIRED, "long"]);
+    }
+}
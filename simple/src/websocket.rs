@@ -0,0 +1,223 @@
+//! Running the line protocol over a WebSocket.
+//!
+//! This is useful for browser clients, which cannot open raw TCP sockets. Each
+//! WebSocket text message carries exactly one line. Ping, pong and binary
+//! messages are ignored, and a close message ends the stream of lines.
+//!
+//! The WebSocket handshake is performed in `bind_transport`, in the same way
+//! as the handshake example, so tokio-proto only ever sees a transport of
+//! lines.
+//!
+//! This module is only available with the `websocket` feature enabled.
+
+use {Client, Validate};
+
+use futures::{Future, Stream, Sink, Poll, Async, AsyncSink, StartSend};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_proto::{TcpClient, TcpServer};
+use tokio_proto::pipeline::{ServerProto, ClientProto, ClientService};
+use tokio_service::NewService;
+use tokio_tungstenite::{accept_async, client_async, WebSocketStream};
+use tungstenite::{self, Message};
+use url::Url;
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+/// Transport mapping WebSocket text messages to lines.
+pub struct WsTransport<T> {
+    upstream: WebSocketStream<T>,
+}
+
+/// Server protocol, accepting the WebSocket handshake
+struct WsServerProto;
+
+/// Client protocol, initiating the WebSocket handshake with `url`
+struct WsClientProto {
+    url: Url,
+}
+
+/// Start a server accepting WebSocket connections on `addr`.
+///
+/// Other than the transport, this behaves exactly like `serve`.
+pub fn serve_websocket<T>(addr: SocketAddr, new_service: T)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    let new_service = Validate::new(new_service);
+
+    TcpServer::new(WsServerProto, addr)
+        .serve(new_service);
+}
+
+impl Client {
+    /// Establish a WebSocket connection to a line-based server at `url`, for
+    /// example `ws://127.0.0.1:12345/`.
+    pub fn connect_websocket(url: &str, handle: &Handle) -> Box<Future<Item = Client, Error = io::Error>> {
+        let url = match Url::parse(url) {
+            Ok(url) => url,
+            Err(e) => return Box::new(::futures::future::err(io::Error::new(io::ErrorKind::InvalidInput, e))),
+        };
+
+        let addr = match resolve(&url) {
+            Ok(addr) => addr,
+            Err(e) => return Box::new(::futures::future::err(e)),
+        };
+
+        let handle = handle.clone();
+
+        let ret = TcpClient::new(WsClientProto { url: url })
+            .connect(&addr, &handle)
+            .map(move |client_service: ClientService<TcpStream, WsClientProto>| {
+                Client::new(client_service, handle)
+            });
+
+        Box::new(ret)
+    }
+}
+
+/// Returns the socket address of the host in `url`
+fn resolve(url: &Url) -> io::Result<SocketAddr> {
+    let host = try!(url.host_str().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "url has no host")
+    }));
+
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    try!((host, port).to_socket_addrs()).next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "could not resolve host")
+    })
+}
+
+fn to_io_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+impl<T> WsTransport<T> {
+    /// Wrap an established WebSocket stream
+    pub fn new(upstream: WebSocketStream<T>) -> WsTransport<T> {
+        WsTransport { upstream: upstream }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> Stream for WsTransport<T> {
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+        loop {
+            match try_ready!(self.upstream.poll().map_err(to_io_error)) {
+                Some(Message::Text(line)) => return Ok(Async::Ready(Some(line))),
+                Some(Message::Close(_)) | None => return Ok(Async::Ready(None)),
+                // Pings are answered by tungstenite, anything else is not part
+                // of the line protocol.
+                Some(_) => {}
+            }
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> Sink for WsTransport<T> {
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: String) -> StartSend<String, io::Error> {
+        match try!(self.upstream.start_send(Message::Text(item)).map_err(to_io_error)) {
+            AsyncSink::Ready => Ok(AsyncSink::Ready),
+            AsyncSink::NotReady(Message::Text(item)) => Ok(AsyncSink::NotReady(item)),
+            AsyncSink::NotReady(_) => unreachable!(),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.upstream.poll_complete().map_err(to_io_error)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for WsServerProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = WsTransport<T>;
+    type BindTransport = Box<Future<Item = Self::Transport, Error = io::Error>>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        let handshake = accept_async(io)
+            .map(WsTransport::new)
+            .map_err(to_io_error);
+
+        Box::new(handshake)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for WsClientProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = WsTransport<T>;
+    type BindTransport = Box<Future<Item = Self::Transport, Error = io::Error>>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        let handshake = client_async(self.url.clone(), io)
+            .map(|(ws, _)| WsTransport::new(ws))
+            .map_err(to_io_error);
+
+        Box::new(handshake)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::WsServerProto;
+
+    use {Client, Validate};
+
+    use futures::{future, Future, Stream};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+
+    use std::io;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: String) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    #[test]
+    fn line_round_trips_over_a_websocket() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            WsServerProto.bind_server(&handle2, socket, Validate::new(Echo));
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let url = format!("ws://{}/", addr);
+        let client = core.run(Client::connect_websocket(&url, &handle)).unwrap();
+
+        assert_eq!(core.run(client.call("hello".to_string())).unwrap(), "hello");
+        assert_eq!(core.run(client.call("world".to_string())).unwrap(), "world");
+    }
+}
@@ -0,0 +1,444 @@
+//! Bounding the outbound buffer of a connection.
+//!
+//! When a client reads responses slowly, encoded responses accumulate in the
+//! write buffer of the transport. `BoundedFramed` is a line transport that
+//! stops accepting responses once its write buffer holds `high_water_mark`
+//! bytes, until enough of it is flushed to the socket.
+//!
+//! Refusing a response applies backpressure to the service: the pipeline
+//! dispatcher keeps the pending responses, and stops reading new requests
+//! once too many of them are in flight, so the service is no longer called
+//! until the client catches up.
+//!
+//! After a burst of large frames, the buffers of a connection keep their
+//! capacity. With `shrink_when_idle`, buffers holding little data are
+//! reallocated with a baseline capacity once the connection has been idle
+//! for a while.
+
+use {LineCodec, Validate};
+
+use futures::{Future, Stream, Sink, Poll, Async, AsyncSink, StartSend};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::{Encoder, Decoder};
+use tokio_proto::TcpServer;
+use tokio_proto::pipeline::ServerProto;
+use tokio_service::NewService;
+use tokio_timer::{Timer, Sleep};
+
+use bytes::BytesMut;
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+// Space reserved in the read buffer before each read
+const READ_CAPACITY: usize = 8 * 1024;
+
+/// A line transport with a bounded write buffer.
+pub struct BoundedFramed<T> {
+    io: T,
+    codec: LineCodec,
+    rd: BytesMut,
+    wr: BytesMut,
+    high_water_mark: usize,
+    eof: bool,
+    shrink: Option<Shrink>,
+}
+
+/// Idle-driven shrinking of the buffers
+struct Shrink {
+    timer: Timer,
+    idle: Duration,
+    baseline: usize,
+    // Fires once the connection has been idle for `idle`
+    sleep: Sleep,
+}
+
+/// Protocol definition for a server bounding its write buffers
+struct BoundedProto {
+    high_water_mark: usize,
+    // Idle duration and baseline capacity, to shrink buffers
+    shrink: Option<(Timer, Duration, usize)>,
+}
+
+/// Start a server whose connections stop accepting responses once
+/// `high_water_mark` bytes are waiting to be written, listening for
+/// connections on `addr`.
+pub fn serve_with_write_buffer_limit<T>(addr: SocketAddr, new_service: T, high_water_mark: usize)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    let new_service = Validate::new(new_service);
+
+    let proto = BoundedProto {
+        high_water_mark: high_water_mark,
+        shrink: None,
+    };
+
+    TcpServer::new(proto, addr)
+        .serve(new_service);
+}
+
+/// Start a server like `serve_with_write_buffer_limit`, whose connections
+/// also shrink their buffers back to `baseline` bytes of capacity after being
+/// idle for `idle`.
+pub fn serve_with_idle_buffer_shrink<T>(addr: SocketAddr, new_service: T, high_water_mark: usize, idle: Duration, baseline: usize)
+    where T: NewService<Request = String, Response = String, Error = io::Error> + Send + Sync + 'static,
+{
+    let new_service = Validate::new(new_service);
+
+    let proto = BoundedProto {
+        high_water_mark: high_water_mark,
+        shrink: Some((Timer::default(), idle, baseline)),
+    };
+
+    TcpServer::new(proto, addr)
+        .serve(new_service);
+}
+
+impl<T> BoundedFramed<T> {
+    /// Create a line transport from `io`, buffering up to `high_water_mark`
+    /// bytes of outbound frames.
+    ///
+    /// A single frame larger than `high_water_mark` is still accepted once
+    /// the write buffer is empty.
+    pub fn new(io: T, codec: LineCodec, high_water_mark: usize) -> BoundedFramed<T> {
+        BoundedFramed {
+            io: io,
+            codec: codec,
+            rd: BytesMut::new(),
+            wr: BytesMut::new(),
+            high_water_mark: high_water_mark,
+            eof: false,
+            shrink: None,
+        }
+    }
+
+    /// Reallocate the buffers with a capacity of `baseline` bytes once the
+    /// connection has been idle for `idle`, if they hold at most `baseline`
+    /// bytes and have grown larger.
+    ///
+    /// As room for 8KB is reserved in the read buffer before each read, it
+    /// does not shrink any smaller than that.
+    pub fn shrink_when_idle(mut self, timer: Timer, idle: Duration, baseline: usize) -> BoundedFramed<T> {
+        let sleep = timer.sleep(idle);
+
+        self.shrink = Some(Shrink {
+            timer: timer,
+            idle: idle,
+            baseline: baseline,
+            sleep: sleep,
+        });
+
+        self
+    }
+
+    /// Returns the capacity of the read buffer
+    pub fn read_capacity(&self) -> usize {
+        self.rd.capacity()
+    }
+
+    /// Returns the capacity of the write buffer
+    pub fn write_capacity(&self) -> usize {
+        self.wr.capacity()
+    }
+
+    /// Returns the number of bytes waiting to be written
+    pub fn buffered(&self) -> usize {
+        self.wr.len()
+    }
+
+    /// Returns a reference to the underlying I/O object
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    /// Restart the idle period
+    fn active(&mut self) {
+        if let Some(ref mut shrink) = self.shrink {
+            shrink.sleep = shrink.timer.sleep(shrink.idle);
+        }
+    }
+
+    /// Shrink the buffers if the idle period elapsed
+    fn poll_idle(&mut self) -> io::Result<()> {
+        let baseline = match self.shrink {
+            Some(ref mut shrink) => {
+                let elapsed = try!(shrink.sleep.poll().map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+
+                if !elapsed.is_ready() {
+                    return Ok(());
+                }
+
+                // Check again after the next idle period
+                shrink.sleep = shrink.timer.sleep(shrink.idle);
+                try!(shrink.sleep.poll().map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+
+                shrink.baseline
+            }
+            None => return Ok(()),
+        };
+
+        shrink_buf(&mut self.rd, baseline);
+        shrink_buf(&mut self.wr, baseline);
+
+        Ok(())
+    }
+}
+
+/// Reallocate `buf` with a capacity of `baseline`, if its contents fit
+fn shrink_buf(buf: &mut BytesMut, baseline: usize) {
+    if buf.capacity() > baseline && buf.len() <= baseline {
+        let mut shrunk = BytesMut::with_capacity(baseline);
+        shrunk.extend_from_slice(&buf[..]);
+        *buf = shrunk;
+    }
+}
+
+impl<T: AsyncRead> Stream for BoundedFramed<T> {
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+        loop {
+            if self.eof {
+                return Ok(Async::Ready(try!(self.codec.decode_eof(&mut self.rd))));
+            }
+
+            if let Some(line) = try!(self.codec.decode(&mut self.rd)) {
+                self.active();
+                return Ok(Async::Ready(Some(line)));
+            }
+
+            // Polling the sleep also ensures the task is notified once the
+            // idle period elapses
+            try!(self.poll_idle());
+
+            self.rd.reserve(READ_CAPACITY);
+
+            if try_ready!(self.io.read_buf(&mut self.rd)) == 0 {
+                self.eof = true;
+            }
+        }
+    }
+}
+
+impl<T: AsyncWrite> Sink for BoundedFramed<T> {
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: String) -> StartSend<String, io::Error> {
+        if self.wr.len() >= self.high_water_mark {
+            // Try to make some room before refusing the frame
+            try!(self.poll_complete());
+
+            if self.wr.len() >= self.high_water_mark {
+                return Ok(AsyncSink::NotReady(item));
+            }
+        }
+
+        try!(self.codec.encode(item, &mut self.wr));
+        self.active();
+
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        while !self.wr.is_empty() {
+            let n = try_ready!(self.io.poll_write(&self.wr));
+
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write frame to transport"));
+            }
+
+            self.wr.split_to(n);
+        }
+
+        try_ready!(self.io.poll_flush());
+        Ok(Async::Ready(()))
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.poll_complete());
+        self.io.shutdown()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for BoundedProto {
+    type Request = String;
+    type Response = String;
+
+    type Transport = BoundedFramed<T>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        let transport = BoundedFramed::new(io, LineCodec::new(), self.high_water_mark);
+
+        match self.shrink {
+            Some((ref timer, idle, baseline)) => Ok(transport.shrink_when_idle(timer.clone(), idle, baseline)),
+            None => Ok(transport),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BoundedFramed, BoundedProto};
+
+    use {LineCodec, Validate};
+
+    use futures::{future, Future, Stream, Sink, Poll, Async};
+    use tokio_io::{AsyncRead, AsyncWrite};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+    use tokio_timer::Timer;
+
+    use std::{cmp, io, net, thread};
+    use std::cell::Cell;
+    use std::io::{Read, Write};
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    /// Responds to every request with a large line, counting the calls
+    struct Large(Rc<Cell<usize>>);
+
+    impl Service for Large {
+        type Request = String;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, _: String) -> Self::Future {
+            self.0.set(self.0.get() + 1);
+            future::ok(String::from_utf8(vec![b'x'; 64 * 1024]).unwrap())
+        }
+    }
+
+    #[test]
+    fn service_is_not_called_while_the_client_does_not_read() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let timer = Timer::default();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let calls = Rc::new(Cell::new(0));
+        let calls2 = calls.clone();
+
+        let handle2 = handle.clone();
+        let proto = BoundedProto {
+            high_water_mark: 128 * 1024,
+            shrink: None,
+        };
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            proto.bind_server(&handle2, socket, Validate::new(Large(calls2.clone())));
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        // A client sending many requests, and never reading a response
+        thread::spawn(move || {
+            let mut socket = net::TcpStream::connect(&addr).unwrap();
+
+            for _ in 0..1_000 {
+                socket.write_all(b"req\n").unwrap();
+            }
+
+            thread::sleep(Duration::from_secs(2));
+        });
+
+        core.run(timer.sleep(Duration::from_millis(500))).unwrap();
+        let stalled_at = calls.get();
+
+        core.run(timer.sleep(Duration::from_millis(300))).unwrap();
+
+        // The responses would total 64MB. Once the socket and write buffers
+        // are full, the service is no longer called.
+        assert!(stalled_at < 1_000, "service was called for every request");
+        assert_eq!(calls.get(), stalled_at);
+    }
+
+    /// Reads a single large request, then blocks. Accepts every write.
+    struct Burst {
+        rd: Vec<u8>,
+    }
+
+    impl Read for Burst {
+        fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+            if self.rd.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+
+            let n = cmp::min(dst.len(), self.rd.len());
+            dst[..n].copy_from_slice(&self.rd[..n]);
+            self.rd.drain(..n);
+
+            Ok(n)
+        }
+    }
+
+    impl Write for Burst {
+        fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+            Ok(src.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Burst {}
+
+    impl AsyncWrite for Burst {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn buffers_shrink_after_idle_period() {
+        let large = vec![b'x'; 256 * 1024];
+
+        let mut rd = large.clone();
+        rd.push(b'\n');
+
+        let timer = Timer::default();
+        let baseline = 1024;
+
+        let mut transport = BoundedFramed::new(Burst { rd: rd }, LineCodec::new(), 1024 * 1024)
+            .shrink_when_idle(timer.clone(), Duration::from_millis(200), baseline);
+
+        let mut transport = future::lazy(move || {
+            // A large request, answered with a large response
+            let req = match transport.poll().unwrap() {
+                Async::Ready(Some(req)) => req,
+                _ => panic!("request not decoded"),
+            };
+
+            assert!(transport.start_send(req).unwrap().is_ready());
+            assert!(transport.poll_complete().unwrap().is_ready());
+
+            assert!(transport.read_capacity() >= large.len());
+            assert!(transport.write_capacity() >= large.len());
+
+            Ok::<_, ()>(transport)
+        }).wait().unwrap();
+
+        // Let the connection go idle
+        thread::sleep(Duration::from_millis(400));
+
+        future::lazy(move || {
+            assert_eq!(transport.poll().unwrap(), Async::NotReady);
+
+            // Room for a read is reserved again after shrinking
+            assert!(transport.read_capacity() <= baseline + 16 * 1024);
+            assert!(transport.write_capacity() <= baseline);
+
+            Ok::<_, ()>(())
+        }).wait().unwrap();
+    }
+}
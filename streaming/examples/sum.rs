@@ -0,0 +1,71 @@
+//! Incrementally processing a streaming request body
+//!
+//! The client streams numbers to the server, one per chunk. The server adds
+//! each chunk to a running total as it is received, using `Line::fold`, rather
+//! than buffering the whole body first. Once the body is complete, the total is
+//! sent back as the response.
+
+extern crate tokio_line_streaming as line;
+
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_service;
+extern crate service_fn;
+
+use line::{Client, Line, LineStream};
+
+use futures::{Future, Sink};
+use tokio_core::reactor::Core;
+use tokio_service::Service;
+use service_fn::service_fn;
+
+use std::{io, thread};
+use std::time::Duration;
+
+pub fn main() {
+    let mut core = Core::new().unwrap();
+
+    // This brings up our server.
+    let addr = "127.0.0.1:12345".parse().unwrap();
+
+    thread::spawn(move || {
+        line::serve(
+            addr,
+            || {
+                Ok(service_fn(|msg: Line| {
+                    msg.fold(0, |total, chunk| {
+                            println!(" + {}", chunk);
+
+                            chunk.parse::<u64>()
+                                .map(|n| total + n)
+                                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+                        })
+                        .map(|total| Line::Once(total.to_string()))
+                }))
+            });
+    });
+
+    // A bit annoying, but we need to wait for the server to connect
+    thread::sleep(Duration::from_millis(100));
+
+    let handle = core.handle();
+
+    core.run(
+        Client::connect(&addr, &handle)
+            .and_then(|client| {
+                let (mut tx, rx) = LineStream::pair();
+
+                thread::spawn(move || {
+                    for n in 1..5 {
+                        tx = tx.send(Ok(n.to_string())).wait().unwrap();
+                    }
+                });
+
+                client.call(Line::Stream(rx))
+            })
+            .and_then(|response| {
+                println!("CLIENT: {:?}", response);
+                Ok(())
+            })
+    ).unwrap();
+}
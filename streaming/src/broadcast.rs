@@ -0,0 +1,186 @@
+//! A live feed of lines that clients can subscribe to.
+//!
+//! Lines published on a `LogBroadcast` are sent to every subscriber. Clients
+//! subscribe by sending a `SUBSCRIBE logs` request to a server running the
+//! `LogBroadcastService` middleware, and receive the feed as a streaming
+//! response.
+
+use {Line, LineStream};
+
+use futures::{future, Future};
+use futures::sync::mpsc;
+use tokio_proto::streaming::Body;
+use tokio_service::{Service, NewService};
+
+use std::{io, mem};
+use std::sync::{Arc, Mutex};
+
+/// The request used to subscribe to the feed
+pub const SUBSCRIBE: &'static str = "SUBSCRIBE logs";
+
+/// Number of lines buffered for each subscriber
+const BUFFER: usize = 128;
+
+/// Handle used to publish lines to all subscribers.
+///
+/// Handles are cheap to clone, and all clones publish to the same set of
+/// subscribers.
+#[derive(Clone)]
+pub struct LogBroadcast {
+    subscribers: Arc<Mutex<Vec<mpsc::Sender<Result<String, io::Error>>>>>,
+}
+
+/// A `Service` middleware answering `SUBSCRIBE logs` requests with a stream of
+/// the lines published on a `LogBroadcast`.
+///
+/// All other requests are passed to the inner service.
+pub struct LogBroadcastService<T> {
+    inner: T,
+    broadcast: LogBroadcast,
+}
+
+impl LogBroadcast {
+    /// Create a new `LogBroadcast` with no subscribers
+    pub fn new() -> LogBroadcast {
+        LogBroadcast {
+            subscribers: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// Returns a stream of all lines published from now on.
+    pub fn subscribe(&self) -> LineStream {
+        let (tx, rx) = mpsc::channel(BUFFER);
+        self.subscribers.lock().unwrap().push(tx);
+
+        LineStream::new(Body::from(rx))
+    }
+
+    /// Send `line` to every subscriber.
+    ///
+    /// Subscribers that have been dropped are removed. A subscriber that is
+    /// too slow to keep up with the feed misses lines rather than blocking
+    /// the publisher.
+    pub fn publish(&self, line: &str) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let current = mem::replace(&mut *subscribers, vec![]);
+
+        for mut tx in current {
+            match tx.try_send(Ok(line.to_string())) {
+                Ok(()) => subscribers.push(tx),
+                Err(ref e) if !e.is_disconnected() => subscribers.push(tx),
+                // The subscriber is gone
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Returns the number of subscribers
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+}
+
+impl<T> LogBroadcastService<T> {
+    /// Create a new `LogBroadcastService`
+    pub fn new(inner: T, broadcast: LogBroadcast) -> LogBroadcastService<T> {
+        LogBroadcastService {
+            inner: inner,
+            broadcast: broadcast,
+        }
+    }
+}
+
+impl<T> Service for LogBroadcastService<T>
+    where T: Service<Request = Line, Response = Line, Error = io::Error>,
+          T::Future: 'static,
+{
+    type Request = Line;
+    type Response = Line;
+    type Error = io::Error;
+    type Future = Box<Future<Item = Line, Error = io::Error>>;
+
+    fn call(&self, req: Line) -> Self::Future {
+        match req {
+            Line::Once(ref line) if line == SUBSCRIBE => {
+                let feed = self.broadcast.subscribe();
+                return Box::new(future::ok(Line::Stream(feed)));
+            }
+            _ => {}
+        }
+
+        Box::new(self.inner.call(req))
+    }
+}
+
+impl<T> NewService for LogBroadcastService<T>
+    where T: NewService<Request = Line, Response = Line, Error = io::Error>,
+          <T::Instance as Service>::Future: 'static,
+{
+    type Request = Line;
+    type Response = Line;
+    type Error = io::Error;
+    type Instance = LogBroadcastService<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = try!(self.inner.new_service());
+        Ok(LogBroadcastService::new(inner, self.broadcast.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LogBroadcast, LogBroadcastService, SUBSCRIBE};
+
+    use {Line, LineStream};
+
+    use futures::{future, Future, Stream};
+    use tokio_service::Service;
+
+    use std::io;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = Line;
+        type Response = Line;
+        type Error = io::Error;
+        type Future = future::FutureResult<Line, io::Error>;
+
+        fn call(&self, req: Line) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    fn subscribe(service: &LogBroadcastService<Echo>) -> LineStream {
+        match service.call(Line::Once(SUBSCRIBE.to_string())).wait().unwrap() {
+            Line::Stream(feed) => feed,
+            Line::Once(line) => panic!("expected a feed, got {:?}", line),
+        }
+    }
+
+    #[test]
+    fn every_subscriber_receives_published_lines() {
+        let broadcast = LogBroadcast::new();
+        let service = LogBroadcastService::new(Echo, broadcast.clone());
+
+        // Lines published before subscribing are not received
+        broadcast.publish("too early");
+
+        let first = subscribe(&service);
+        let second = subscribe(&service);
+        assert_eq!(broadcast.subscriber_count(), 2);
+
+        broadcast.publish("hello");
+
+        let (line, first) = first.into_future().wait().ok().unwrap();
+        assert_eq!(line, Some("hello".to_string()));
+
+        let (line, _second) = second.into_future().wait().ok().unwrap();
+        assert_eq!(line, Some("hello".to_string()));
+
+        // Dropped subscribers are removed on the next publish
+        drop(first);
+        broadcast.publish("bye");
+        assert_eq!(broadcast.subscriber_count(), 1);
+    }
+}
@@ -0,0 +1,167 @@
+//! Coalescing small streaming body chunks on the sending side.
+//!
+//! Each chunk sent on a `LineStream` becomes a line on the wire. A producer
+//! sending many tiny chunks pays the framing and flushing overhead for each of
+//! them. `CoalescingSender` buffers chunks and sends them concatenated, in
+//! chunks of up to `max_chunk_bytes` bytes. Chunks larger than that are sent
+//! on their own, and are never split.
+//!
+//! The buffered chunks are sent whenever the sink is flushed, so a producer
+//! using `Sink::send` for each chunk gets no coalescing. Use `Sink::send_all`
+//! instead. As chunks are concatenated, the receiver sees fewer, larger
+//! chunks than were sent.
+
+use LineStream;
+
+use futures::{Sink, Poll, Async, AsyncSink, StartSend};
+use futures::sync::mpsc;
+
+use std::{io, mem};
+
+/// Sending half of a `LineStream` created with `LineStream::buffered_pair`
+#[derive(Debug)]
+pub struct CoalescingSender {
+    inner: mpsc::Sender<Result<String, io::Error>>,
+    max_chunk_bytes: usize,
+    // Chunks not sent yet, concatenated
+    buf: String,
+}
+
+impl LineStream {
+    /// Returns a `LineStream` with a sending half coalescing chunks of up to
+    /// `max_chunk_bytes` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_chunk_bytes` is zero.
+    pub fn buffered_pair(max_chunk_bytes: usize) -> (CoalescingSender, LineStream) {
+        assert!(max_chunk_bytes > 0, "chunk size must be at least 1");
+
+        let (tx, body) = LineStream::pair();
+
+        let tx = CoalescingSender {
+            inner: tx,
+            max_chunk_bytes: max_chunk_bytes,
+            buf: String::new(),
+        };
+
+        (tx, body)
+    }
+}
+
+impl CoalescingSender {
+    /// Returns the number of bytes buffered and not sent yet
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Send the buffered chunks, if any
+    fn send_buf(&mut self) -> Poll<(), io::Error> {
+        if self.buf.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+
+        let chunk = mem::replace(&mut self.buf, String::new());
+
+        match try!(self.inner.start_send(Ok(chunk)).map_err(closed)) {
+            AsyncSink::Ready => Ok(Async::Ready(())),
+            AsyncSink::NotReady(chunk) => {
+                // Only `Ok` chunks are sent from here
+                self.buf = chunk.unwrap_or_default();
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+impl Sink for CoalescingSender {
+    type SinkItem = Result<String, io::Error>;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Result<String, io::Error>) -> StartSend<Result<String, io::Error>, io::Error> {
+        let chunk = match item {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                // The buffered chunks precede the error
+                if !try!(self.send_buf()).is_ready() {
+                    return Ok(AsyncSink::NotReady(Err(e)));
+                }
+
+                return self.inner.start_send(Err(e)).map_err(closed);
+            }
+        };
+
+        if !self.buf.is_empty() && self.buf.len() + chunk.len() > self.max_chunk_bytes {
+            if !try!(self.send_buf()).is_ready() {
+                return Ok(AsyncSink::NotReady(Ok(chunk)));
+            }
+        }
+
+        self.buf.push_str(&chunk);
+
+        if self.buf.len() >= self.max_chunk_bytes {
+            // If the body is not ready, the chunk stays buffered and is sent
+            // before the next one is accepted.
+            try!(self.send_buf());
+        }
+
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.send_buf());
+        self.inner.poll_complete().map_err(closed)
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.send_buf());
+        self.inner.close().map_err(closed)
+    }
+}
+
+fn closed<T>(_: mpsc::SendError<T>) -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "body receiver dropped")
+}
+
+#[cfg(test)]
+mod test {
+    use LineStream;
+
+    use futures::{stream, Future, Sink, Stream};
+
+    use std::{io, thread};
+
+    /// Sends `chunks` through a coalescing sender, returning the chunks
+    /// received on the other side
+    fn coalesce(max_chunk_bytes: usize, chunks: Vec<String>) -> Vec<String> {
+        let (tx, body) = LineStream::buffered_pair(max_chunk_bytes);
+
+        let producer = thread::spawn(move || {
+            let chunks = stream::iter_ok::<_, io::Error>(chunks.into_iter().map(Ok));
+            // The sender is dropped here, ending the body
+            tx.send_all(chunks).wait().unwrap();
+        });
+
+        let received = body.collect().wait().unwrap();
+        producer.join().unwrap();
+        received
+    }
+
+    #[test]
+    fn small_chunks_are_sent_in_fewer_frames() {
+        let chunks: Vec<String> = (0..20).map(|_| "ab".to_string()).collect();
+        let received = coalesce(8, chunks.clone());
+
+        assert!(received.len() < chunks.len());
+        assert_eq!(received, vec!["abababab", "abababab", "abababab", "abababab", "abababab"]);
+        assert_eq!(received.concat(), chunks.concat());
+    }
+
+    #[test]
+    fn large_chunks_are_not_split() {
+        let chunks = vec!["a".to_string(), "bcdefgh".to_string(), "i".to_string()];
+        let received = coalesce(4, chunks);
+
+        assert_eq!(received, ["a", "bcdefgh", "i"]);
+    }
+}
@@ -0,0 +1,261 @@
+//! Returning partial results when a streaming response hits a deadline.
+//!
+//! `DeadlineService` wraps a service producing streaming responses, such as a
+//! search yielding results as they are found. If the body of a response is
+//! not complete within `timeout` of the request, the chunks produced so far
+//! are kept, and the body is terminated cleanly with a `truncated: true`
+//! trailer instead of running on or failing the whole response.
+//!
+//! Once the deadline is hit, the body of the inner service is dropped, so the
+//! producer sees its sender fail and can stop working on the request.
+//!
+//! The trailer is only sent on the wire by connections using the framing of
+//! the `trailers` module. Clients check for it with `LineStream::is_truncated`
+//! once the body is complete.
+
+use {Line, LineStream};
+
+use futures::{Future, Stream, Sink, Poll, Async};
+use tokio_proto::streaming::Body;
+use tokio_service::{Service, NewService};
+use tokio_timer::{Timer, Sleep};
+
+use trailers::trailer;
+
+use std::{io, thread};
+use std::time::Duration;
+
+/// A `Service` middleware truncating streaming responses at a deadline.
+pub struct DeadlineService<T> {
+    inner: T,
+    timer: Timer,
+    timeout: Duration,
+}
+
+/// Builds a `DeadlineService` for each new connection.
+pub struct NewDeadline<T> {
+    inner: T,
+    timer: Timer,
+    timeout: Duration,
+}
+
+/// Yields the chunks of a body until the deadline, then the truncation
+/// trailer.
+struct Truncate {
+    // Dropped once the deadline is hit
+    body: Option<Body<String, io::Error>>,
+    sleep: Sleep,
+}
+
+impl<T> DeadlineService<T> {
+    /// Create a new `DeadlineService`, truncating the streaming responses of
+    /// `inner` that are not complete within `timeout` of the request.
+    pub fn new(inner: T, timer: Timer, timeout: Duration) -> DeadlineService<T> {
+        DeadlineService {
+            inner: inner,
+            timer: timer,
+            timeout: timeout,
+        }
+    }
+}
+
+impl<T> Service for DeadlineService<T>
+    where T: Service<Request = Line, Response = Line, Error = io::Error>,
+          T::Future: 'static,
+{
+    type Request = Line;
+    type Response = Line;
+    type Error = io::Error;
+    // For simplicity, box the future.
+    type Future = Box<Future<Item = Line, Error = io::Error>>;
+
+    fn call(&self, req: Line) -> Self::Future {
+        // The deadline runs from the time of the request
+        let sleep = self.timer.sleep(self.timeout);
+
+        Box::new(self.inner.call(req).map(move |resp| {
+            let body = match resp {
+                Line::Stream(LineStream { inner, .. }) => inner,
+                resp => return resp,
+            };
+
+            let (mut tx, truncated) = LineStream::pair();
+
+            let chunks = Truncate {
+                body: Some(body),
+                sleep: sleep,
+            };
+
+            // Forward the chunks on a dedicated thread, like the producer,
+            // so that the reactor is never blocked.
+            thread::spawn(move || {
+                for chunk in chunks.wait() {
+                    let failed = chunk.is_err();
+
+                    tx = match tx.send(chunk).wait() {
+                        Ok(tx) => tx,
+                        // The client went away
+                        Err(_) => return,
+                    };
+
+                    if failed {
+                        return;
+                    }
+                }
+            });
+
+            Line::Stream(truncated)
+        }))
+    }
+}
+
+impl<T> NewDeadline<T> {
+    /// Create a new `NewDeadline`
+    pub fn new(inner: T, timer: Timer, timeout: Duration) -> NewDeadline<T> {
+        NewDeadline {
+            inner: inner,
+            timer: timer,
+            timeout: timeout,
+        }
+    }
+}
+
+impl<T> NewService for NewDeadline<T>
+    where T: NewService<Request = Line, Response = Line, Error = io::Error>,
+          <T::Instance as Service>::Future: 'static,
+{
+    type Request = Line;
+    type Response = Line;
+    type Error = io::Error;
+    type Instance = DeadlineService<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = try!(self.inner.new_service());
+        Ok(DeadlineService::new(inner, self.timer.clone(), self.timeout))
+    }
+}
+
+impl LineStream {
+    /// Returns true if the body was cut short by a `DeadlineService`.
+    ///
+    /// This is only known once the stream is complete.
+    pub fn is_truncated(&self) -> bool {
+        self.trailers().map_or(false, |trailers| {
+            trailers.iter().any(|&(ref key, ref value)| key == "truncated" && value == "true")
+        })
+    }
+}
+
+impl Stream for Truncate {
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+        if self.body.is_none() {
+            return Ok(Async::Ready(None));
+        }
+
+        if let Async::Ready(()) = try!(self.sleep.poll().map_err(|e| io::Error::new(io::ErrorKind::Other, e))) {
+            // Stop forwarding the body, keeping what was sent so far
+            self.body = None;
+            return Ok(Async::Ready(Some(trailer("truncated", "true"))));
+        }
+
+        match self.body {
+            Some(ref mut body) => body.poll(),
+            None => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::NewDeadline;
+    use {Client, Line, LineStream};
+
+    use futures::{future, Future, Sink, Stream};
+    use tokio_core::reactor::Core;
+    use tokio_service::Service;
+    use tokio_timer::Timer;
+
+    use trailers::serve_with_trailers;
+
+    use std::{io, net, thread};
+    use std::time::Duration;
+
+    /// Streams ten rows, pausing between them for the number of milliseconds
+    /// given in the request
+    struct Rows;
+
+    impl Service for Rows {
+        type Request = Line;
+        type Response = Line;
+        type Error = io::Error;
+        type Future = future::FutureResult<Line, io::Error>;
+
+        fn call(&self, req: Line) -> Self::Future {
+            let delay = match req {
+                Line::Once(delay) => Duration::from_millis(delay.parse().unwrap()),
+                Line::Stream(_) => panic!("unexpected streaming request"),
+            };
+
+            let (mut tx, body) = LineStream::pair();
+
+            thread::spawn(move || {
+                for i in 0..10 {
+                    thread::sleep(delay);
+
+                    tx = match tx.send(Ok(i.to_string())).wait() {
+                        Ok(tx) => tx,
+                        // Truncated, stop producing
+                        Err(_) => return,
+                    };
+                }
+            });
+
+            future::ok(Line::Stream(body))
+        }
+    }
+
+    fn body(resp: Line) -> LineStream {
+        match resp {
+            Line::Stream(body) => body,
+            Line::Once(_) => panic!("expected a streaming response"),
+        }
+    }
+
+    #[test]
+    fn slow_body_is_truncated_at_the_deadline() {
+        let addr = net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        thread::spawn(move || {
+            let new_service = NewDeadline::new(|| Ok(Rows), Timer::default(), Duration::from_millis(350));
+            serve_with_trailers(addr, new_service);
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        // Keep the client alive until the bodies are consumed
+        let client = core.run(Client::connect_with_trailers(&addr, &handle)).unwrap();
+
+        let mut slow = body(core.run(client.call(Line::Once("100".to_string()))).unwrap());
+        let chunks = core.run(slow.by_ref().collect()).unwrap();
+
+        // The rows produced before the deadline are kept, in order
+        assert!(!chunks.is_empty());
+        assert!(chunks.len() < 10);
+
+        let expected: Vec<String> = (0..chunks.len()).map(|i| i.to_string()).collect();
+        assert_eq!(chunks, expected);
+        assert!(slow.is_truncated());
+
+        let mut fast = body(core.run(client.call(Line::Once("0".to_string()))).unwrap());
+        let chunks = core.run(fast.by_ref().collect()).unwrap();
+
+        assert_eq!(chunks.len(), 10);
+        assert!(!fast.is_truncated());
+    }
+}
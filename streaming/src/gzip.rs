@@ -0,0 +1,181 @@
+//! Serving the lines of gzip compressed files.
+//!
+//! Each request names a `.gz` file, relative to the directory the service was
+//! created with. The response is a `Line::Stream` with one chunk per line of
+//! the decompressed file.
+//!
+//! Files are decompressed incrementally on a dedicated thread, so they are
+//! never loaded into memory in full and the reactor is not blocked on file IO.
+//! If the file turns out to be truncated or corrupt part way through, the
+//! lines decoded so far are sent and the body ends with an error.
+//!
+//! This module is only available with the `compression` feature enabled.
+
+use {Line, LineStream};
+
+use futures::{future, Future, Sink};
+use flate2::read::GzDecoder;
+use tokio_service::Service;
+
+use std::{io, thread};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Component, Path, PathBuf};
+
+/// A `Service` streaming the decompressed lines of gzip files.
+pub struct GzipFileStreamService {
+    root: PathBuf,
+}
+
+impl GzipFileStreamService {
+    /// Create a new `GzipFileStreamService` serving the files in `root`
+    pub fn new<P: Into<PathBuf>>(root: P) -> GzipFileStreamService {
+        GzipFileStreamService { root: root.into() }
+    }
+
+    /// Returns the path of the file named by `name`
+    fn resolve(&self, name: &str) -> io::Result<PathBuf> {
+        let path = Path::new(name);
+
+        if path.extension().map_or(true, |ext| ext != "gz") {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a .gz file"));
+        }
+
+        // Only allow plain file names, so that requests cannot escape `root`
+        if !path.components().all(|c| match c { Component::Normal(_) => true, _ => false }) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid file name"));
+        }
+
+        Ok(self.root.join(path))
+    }
+}
+
+impl Service for GzipFileStreamService {
+    type Request = Line;
+    type Response = Line;
+    type Error = io::Error;
+    type Future = Box<Future<Item = Line, Error = io::Error>>;
+
+    fn call(&self, req: Line) -> Self::Future {
+        let name = match req {
+            Line::Once(name) => name,
+            Line::Stream(_) => {
+                let err = io::Error::new(io::ErrorKind::InvalidInput, "expected a file name");
+                return Box::new(future::err(err));
+            }
+        };
+
+        // Open the file up front, so that a missing file fails the request
+        // rather than the body.
+        let file = match self.resolve(&name).and_then(File::open) {
+            Ok(file) => file,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        let (mut tx, body) = LineStream::pair();
+
+        thread::spawn(move || {
+            let lines = BufReader::new(GzDecoder::new(file)).lines();
+
+            for line in lines {
+                let failed = line.is_err();
+
+                tx = match tx.send(line).wait() {
+                    Ok(tx) => tx,
+                    // The client went away
+                    Err(_) => return,
+                };
+
+                if failed {
+                    return;
+                }
+            }
+        });
+
+        Box::new(future::ok(Line::Stream(body)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::GzipFileStreamService;
+
+    use {Client, Line, LineProto, ServerTypeMap};
+
+    use futures::{Future, Stream};
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+
+    use std::{env, fs, process};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    const CONTENT: &'static str = "first line\nsecond line\nthird line\n";
+
+    /// Create a directory for the test `name`, holding `lines.gz` and a
+    /// truncated copy, `broken.gz`
+    fn fixtures(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("tokio-line-gzip-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(CONTENT.repeat(100).as_bytes()).unwrap();
+        let gz = encoder.finish().unwrap();
+
+        fs::write(dir.join("lines.gz"), &gz).unwrap();
+        fs::write(dir.join("broken.gz"), &gz[..gz.len() / 2]).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn client_receives_the_decompressed_lines() {
+        let dir = fixtures("lines");
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+        let dir2 = dir.clone();
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            let service = GzipFileStreamService::new(dir2.clone());
+            LineProto.bind_server(&handle2, socket, ServerTypeMap { inner: service, trailers: false });
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        let lines: Vec<String> = match core.run(client.call(Line::Once("lines.gz".to_string()))).unwrap() {
+            Line::Stream(body) => core.run(body.collect()).unwrap(),
+            Line::Once(line) => panic!("expected a stream, got {:?}", line),
+        };
+
+        assert_eq!(lines.len(), 300);
+        assert_eq!(lines.join("\n") + "\n", CONTENT.repeat(100));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_file_fails_the_body() {
+        let dir = fixtures("broken");
+        let service = GzipFileStreamService::new(dir.clone());
+
+        match service.call(Line::Once("broken.gz".to_string())).wait().unwrap() {
+            Line::Stream(body) => assert!(body.collect().wait().is_err()),
+            Line::Once(line) => panic!("expected a stream, got {:?}", line),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -4,17 +4,32 @@
 //! The protocol is line-based, however if a line is empty, this implies that it
 //! is being streamed. All subsequent lines are the streaming body until another
 //! empty line is reached.
+//!
+//! Instead of an empty line, a streaming body may start with a
+//! `STREAM length=<n>` line, declaring that the chunks of the body add up to
+//! exactly `n` bytes, not counting new lines. The receiving side can use the
+//! length to pre-allocate, and fails the body if it turns out to be shorter or
+//! longer than declared.
+//!
+//! A "oneshot" line that would be read as such a head, once any leading `\`
+//! are removed, is sent with an extra leading `\`, which the receiving side
+//! removes. All other lines are sent as is.
 
 #![deny(warnings, missing_docs)]
 
+#[macro_use]
 extern crate futures;
 extern crate tokio_io;
 extern crate tokio_core;
 extern crate tokio_proto;
 extern crate tokio_service;
+extern crate tokio_timer;
 extern crate bytes;
 
-use futures::{Future, Stream, Poll};
+#[cfg(feature = "compression")]
+extern crate flate2;
+
+use futures::{future, Future, Stream, Poll, Async};
 use futures::sync::mpsc;
 
 use tokio_io::{AsyncRead, AsyncWrite};
@@ -30,6 +45,18 @@ use bytes::{BytesMut, BufMut};
 
 use std::{io, str};
 use std::net::SocketAddr;
+use std::sync::Arc;
+
+pub mod broadcast;
+pub mod coalesce;
+pub mod deadline;
+#[cfg(feature = "compression")]
+pub mod gzip;
+pub mod pause;
+pub mod rechunk;
+pub mod resume;
+pub mod trailers;
+pub mod upgrade;
 
 /// Line-based client handle
 ///
@@ -65,13 +92,62 @@ pub enum Line {
 #[derive(Debug)]
 pub struct LineStream {
     inner: Body<String, io::Error>,
+    // Declared in the head of the stream
+    content_length: Option<usize>,
+    // Whether trailers are decoded from the body, see the `trailers` module
+    parse_trailers: bool,
+    // Trailers received so far
+    trailers: Vec<(String, String)>,
+    complete: bool,
+    // Shared with the sender of a pausable body, see the `pause` module
+    control: Option<Arc<pause::Control>>,
 }
 
 impl LineStream {
     /// Returns a `LineStream` with its sender half.
     pub fn pair() -> (mpsc::Sender<Result<String, io::Error>>, LineStream) {
         let (tx, rx) = Body::pair();
-        (tx, LineStream { inner: rx })
+        (tx, LineStream::new(rx))
+    }
+
+    /// Returns a `LineStream` declaring a body of `content_length` bytes,
+    /// with its sender half.
+    ///
+    /// The chunks sent must add up to exactly `content_length` bytes,
+    /// otherwise the peer fails the body.
+    pub fn pair_with_length(content_length: usize) -> (mpsc::Sender<Result<String, io::Error>>, LineStream) {
+        let (tx, mut body) = LineStream::pair();
+        body.content_length = Some(content_length);
+        (tx, body)
+    }
+
+    fn new(inner: Body<String, io::Error>) -> LineStream {
+        LineStream {
+            inner: inner,
+            content_length: None,
+            parse_trailers: false,
+            trailers: vec![],
+            complete: false,
+            control: None,
+        }
+    }
+
+    /// Returns the length of the body, if it was declared by the sender
+    pub fn content_length(&self) -> Option<usize> {
+        self.content_length
+    }
+
+    /// Returns the trailers sent after the body, once the stream is complete.
+    ///
+    /// Returns `None` while chunks are still being received. Trailers are only
+    /// decoded on connections using the framing of the `trailers` module, so
+    /// on other connections, there are none.
+    pub fn trailers(&self) -> Option<&[(String, String)]> {
+        if self.complete {
+            Some(&self.trailers)
+        } else {
+            None
+        }
     }
 }
 
@@ -80,7 +156,25 @@ impl Stream for LineStream {
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Option<String>, io::Error> {
-        self.inner.poll()
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(chunk) => {
+                    if !self.parse_trailers {
+                        return Ok(Async::Ready(Some(chunk)));
+                    }
+
+                    // Trailers travel through tokio-proto as marked chunks
+                    match trailers::decode(&chunk) {
+                        Some(trailer) => self.trailers.push(trailer),
+                        None => return Ok(Async::Ready(Some(chunk))),
+                    }
+                }
+                None => {
+                    self.complete = true;
+                    return Ok(Async::Ready(None));
+                }
+            }
+        }
     }
 }
 
@@ -91,19 +185,55 @@ type LineMessage = Message<String, Body<String, io::Error>>;
 /// Maps types between Line <-> LineMessage for the server service
 struct ServerTypeMap<T> {
     inner: T,
+    // Whether the connection uses the framing of the `trailers` module
+    trailers: bool,
 }
 
 /// Maps types between Line <-> LineMessage for the client service
 struct ClientTypeMap<T> {
     inner: T,
+    // Whether the connection uses the framing of the `trailers` module
+    trailers: bool,
 }
 
 /// Our line-based codec
 ///
 /// In this version of the `LineCodec`, some state is required. We need to track
-/// if we are currently decoding a message "head" or the streaming body.
+/// if we are currently decoding a message "head" or the streaming body, and
+/// how much of a declared body length is left.
 pub struct LineCodec {
     decoding_head: bool,
+    length: BodyLength,
+}
+
+/// Tracks the declared length of the body being decoded
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BodyLength {
+    // No length was declared
+    Unknown,
+    // Number of bytes left to receive
+    Remaining(usize),
+    // The body went over its declared length or contained invalid UTF-8, and
+    // was failed. The rest of the body is skipped.
+    Failed,
+}
+
+/// Prefix of the head of a stream declaring its length
+const STREAM_HEAD: &'static str = "STREAM length=";
+
+/// Returns the length declared by a stream head
+fn parse_stream_head(line: &str) -> Option<usize> {
+    if !line.starts_with(STREAM_HEAD) {
+        return None;
+    }
+
+    line[STREAM_HEAD.len()..].parse().ok()
+}
+
+/// Returns true if the "oneshot" line `line` must be escaped, as it is a
+/// stream head once its leading `\` are removed
+fn needs_escape(line: &str) -> bool {
+    parse_stream_head(line.trim_left_matches('\\')).is_some()
 }
 
 /// Protocol definition
@@ -118,7 +248,7 @@ struct LineProto;
 pub fn serve<T>(addr: SocketAddr, new_service: T)
     where T: NewService<Request = Line, Response = Line, Error = io::Error> + Send + Sync + 'static,
 {
-    let new_service = ServerTypeMap { inner: new_service };
+    let new_service = ServerTypeMap { inner: new_service, trailers: false };
 
     // Use the tokio-proto TCP server builder, this will handle creating a
     // reactor instance and other details needed to run a server.
@@ -134,7 +264,7 @@ impl Client {
             .map(|client_proxy| {
                 // Wrap the returned client handle with our `ClientTypeMap`
                 // service middleware
-                let type_map = ClientTypeMap { inner: client_proxy };
+                let type_map = ClientTypeMap { inner: client_proxy, trailers: false };
                 Client { inner: type_map }
             });
 
@@ -160,25 +290,77 @@ impl Service for Client {
  *
  */
 
-impl From<LineMessage> for Line {
-    fn from(src: LineMessage) -> Line {
+impl Line {
+    /// Fold over the contents of the line, one chunk at a time.
+    ///
+    /// For a streaming line, `f` is called for each chunk as it is received,
+    /// so the body never has to be buffered in full. A "oneshot" line is
+    /// treated as a body consisting of a single chunk. The returned future
+    /// resolves with the final accumulated value once the body is complete.
+    pub fn fold<T, F>(self, init: T, f: F) -> Box<Future<Item = T, Error = io::Error>>
+        where F: FnMut(T, String) -> io::Result<T> + 'static,
+              T: 'static,
+    {
+        match self {
+            Line::Once(line) => {
+                let mut f = f;
+                Box::new(future::done(f(init, line)))
+            }
+            Line::Stream(body) => Box::new(body.fold(init, f)),
+        }
+    }
+
+    /// Convert a message received from the peer.
+    ///
+    /// Fails with `InvalidData` if the message has a streaming body but its
+    /// head is neither empty nor declares the length of the body.
+    pub fn try_from_message(src: LineMessage) -> io::Result<Line> {
+        Line::from_message(src, false)
+    }
+
+    /// Convert a message received from the peer, decoding the trailers of
+    /// its body if `trailers` is set.
+    fn from_message(src: LineMessage, trailers: bool) -> io::Result<Line> {
         match src {
-            Message::WithoutBody(line) => Line::Once(line),
+            Message::WithoutBody(line) => Ok(Line::Once(line)),
             Message::WithBody(head, body) => {
-                assert_eq!(head, "");
-                Line::Stream(LineStream { inner: body })
+                let mut body = LineStream::new(body);
+                body.content_length = parse_stream_head(&head);
+                body.parse_trailers = trailers;
+
+                if !head.is_empty() && body.content_length.is_none() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid streaming body head"));
+                }
+
+                Ok(Line::Stream(body))
             }
         }
     }
 }
 
+/// # Panics
+///
+/// Panics if the message has an invalid streaming body head. Use
+/// `Line::try_from_message` to convert messages received from a peer.
+impl From<LineMessage> for Line {
+    fn from(src: LineMessage) -> Line {
+        Line::try_from_message(src).unwrap()
+    }
+}
+
 impl From<Line> for Message<String, Body<String, io::Error>> {
     fn from(src: Line) -> Self {
         match src {
             Line::Once(line) => Message::WithoutBody(line),
             Line::Stream(body) => {
-                let LineStream { inner } = body;
-                Message::WithBody("".to_string(), inner)
+                let LineStream { inner, content_length, .. } = body;
+
+                let head = match content_length {
+                    Some(n) => format!("{}{}", STREAM_HEAD, n),
+                    None => "".to_string(),
+                };
+
+                Message::WithBody(head, inner)
             }
         }
     }
@@ -200,7 +382,12 @@ impl<T> Service for ServerTypeMap<T>
     type Future = Box<Future<Item = LineMessage, Error = io::Error>>;
 
     fn call(&self, req: LineMessage) -> Self::Future {
-        Box::new(self.inner.call(req.into())
+        let req = match Line::from_message(req, self.trailers) {
+            Ok(req) => req,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        Box::new(self.inner.call(req)
                  .map(LineMessage::from))
     }
 }
@@ -216,7 +403,7 @@ impl<T> NewService for ServerTypeMap<T>
 
     fn new_service(&self) -> io::Result<Self::Instance> {
         let inner = try!(self.inner.new_service());
-        Ok(ServerTypeMap { inner: inner })
+        Ok(ServerTypeMap { inner: inner, trailers: self.trailers })
     }
 }
 
@@ -236,8 +423,10 @@ impl<T> Service for ClientTypeMap<T>
     type Future = Box<Future<Item = Line, Error = io::Error>>;
 
     fn call(&self, req: Line) -> Self::Future {
+        let trailers = self.trailers;
+
         Box::new(self.inner.call(req.into())
-                 .map(Line::from))
+                 .and_then(move |resp| Line::from_message(resp, trailers)))
     }
 }
 
@@ -250,8 +439,15 @@ impl Decoder for LineCodec {
 
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
-        // Check to see if the frame contains a new line
-        if let Some(n) = buf.as_ref().iter().position(|b| *b == b'\n') {
+        // Lines of a failed body are skipped, so keep going until a frame is
+        // decoded
+        loop {
+            // Check to see if the frame contains a new line
+            let n = match buf.as_ref().iter().position(|b| *b == b'\n') {
+                Some(n) => n,
+                None => return Ok(None),
+            };
+
             // remove the serialized frame from the buffer.
             let line = buf.split_to(n);
 
@@ -259,7 +455,7 @@ impl Decoder for LineCodec {
             buf.split_to(1);
 
             // Turn this data into a UTF string and return it in a Frame.
-            return match str::from_utf8(&line.as_ref()) {
+            let frame = match str::from_utf8(&line.as_ref()) {
                 Ok(s) => {
                     // Got an empty line, which means that the state should be
                     // toggled.
@@ -269,40 +465,140 @@ impl Decoder for LineCodec {
                         self.decoding_head = !decoding_head;
 
                         if decoding_head {
-                            Ok(Some(Frame::Message {
+                            Frame::Message {
                                 // The message head is an empty line
                                 message: s.to_string(),
                                 // We will be streaming a body after this
                                 body: true,
-                            }))
+                            }
                         } else {
                             // We parsed the streaming body "termination" frame,
                             // which is represented as `None`.
-                            Ok(Some(Frame::Body {
-                                chunk: None
-                            }))
+                            match self.end_body() {
+                                Some(frame) => frame,
+                                None => continue,
+                            }
                         }
                     } else {
                         if self.decoding_head {
-                            // This is a "oneshot" message with no streaming
-                            // body
-                            Ok(Some(Frame::Message {
-                                message: s.to_string(),
-                                body: false,
-                            }))
+                            if s.starts_with('\\') && needs_escape(s) {
+                                // An escaped "oneshot" message
+                                Frame::Message {
+                                    message: s[1..].to_string(),
+                                    body: false,
+                                }
+                            } else if let Some(len) = parse_stream_head(s) {
+                                // The head of a streaming body with a declared
+                                // length
+                                self.decoding_head = false;
+                                self.length = BodyLength::Remaining(len);
+
+                                Frame::Message {
+                                    message: s.to_string(),
+                                    body: true,
+                                }
+                            } else {
+                                // This is a "oneshot" message with no
+                                // streaming body
+                                Frame::Message {
+                                    message: s.to_string(),
+                                    body: false,
+                                }
+                            }
                         } else {
                             // This line is a chunk in a streaming body
-                            Ok(Some(Frame::Body {
-                                chunk: Some(s.to_string()),
-                            }))
+                            match self.chunk(s) {
+                                Some(frame) => frame,
+                                None => continue,
+                            }
                         }
                     }
                 }
-                Err(_) => Err(io::Error::new(io::ErrorKind::Other, "invalid string")),
+                Err(_) => {
+                    // Fail the message, or the body it belongs to, rather
+                    // than the connection.
+                    if !self.decoding_head {
+                        if self.length == BodyLength::Failed {
+                            continue;
+                        }
+
+                        self.length = BodyLength::Failed;
+                    }
+
+                    let error = io::Error::new(io::ErrorKind::InvalidData, "invalid string");
+                    Frame::Error { error: error }
+                }
+            };
+
+            return Ok(Some(frame));
+        }
+    }
+}
+
+impl LineCodec {
+    /// Create a new `LineCodec`, expecting a message head
+    pub fn new() -> LineCodec {
+        LineCodec {
+            decoding_head: true,
+            length: BodyLength::Unknown,
+        }
+    }
+
+    /// Returns true if the next line is decoded as a message head, and false
+    /// if it is decoded as a body chunk.
+    pub fn is_decoding_head(&self) -> bool {
+        self.decoding_head
+    }
+
+    /// Return to the initial state, expecting a message head.
+    ///
+    /// Any body being decoded is abandoned, without its end being signaled.
+    /// This is meant to re-synchronize with the peer after an error, once the
+    /// buffered bytes of the failed message have been discarded.
+    pub fn reset(&mut self) {
+        self.decoding_head = true;
+        self.length = BodyLength::Unknown;
+    }
+
+    /// Account for a body chunk. Returns `None` if the chunk is skipped.
+    fn chunk(&mut self, chunk: &str) -> Option<Frame<String, String, io::Error>> {
+        match self.length {
+            BodyLength::Unknown => {}
+            BodyLength::Remaining(n) if chunk.len() <= n => {
+                self.length = BodyLength::Remaining(n - chunk.len());
+            }
+            BodyLength::Remaining(_) => {
+                self.length = BodyLength::Failed;
+
+                let error = io::Error::new(io::ErrorKind::InvalidData, "body longer than its declared length");
+                return Some(Frame::Error { error: error });
+            }
+            BodyLength::Failed => return None,
+        }
+
+        Some(Frame::Body { chunk: Some(chunk.to_string()) })
+    }
+
+    /// Check the declared length at the end of a body. Returns `None` if the
+    /// body was already failed.
+    fn end_body(&mut self) -> Option<Frame<String, String, io::Error>> {
+        let length = self.length;
+        self.length = BodyLength::Unknown;
+
+        match length {
+            BodyLength::Unknown | BodyLength::Remaining(0) => Some(Frame::Body { chunk: None }),
+            BodyLength::Remaining(_) => {
+                let error = io::Error::new(io::ErrorKind::InvalidData, "body shorter than its declared length");
+                Some(Frame::Error { error: error })
             }
+            BodyLength::Failed => None,
         }
+    }
+}
 
-        Ok(None)
+impl Default for LineCodec {
+    fn default() -> LineCodec {
+        LineCodec::new()
     }
 }
 
@@ -313,10 +609,24 @@ impl Encoder for LineCodec {
 
     fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> io::Result<()> {
         match msg {
-            Frame::Message { message, body } => {
+            Frame::Message { message, body: true } => {
                 // Our protocol dictates that a message head that includes a
-                // streaming body is an empty string.
-                assert!(message.is_empty() == body);
+                // streaming body is an empty string, or declares the length
+                // of the body.
+                if !message.is_empty() && parse_stream_head(&message).is_none() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid message head"));
+                }
+
+                buf.reserve(message.len());
+                buf.extend(message.as_bytes());
+            }
+            Frame::Message { message, body: false } => {
+                // Escape "oneshot" messages that would be decoded as the head
+                // of a streaming body
+                if needs_escape(&message) {
+                    buf.reserve(1);
+                    buf.put_u8(b'\\');
+                }
 
                 buf.reserve(message.len());
                 buf.extend(message.as_bytes());
@@ -353,11 +663,7 @@ impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for LineProto {
     type BindTransport = Result<Self::Transport, io::Error>;
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        let codec = LineCodec {
-            decoding_head: true,
-        };
-
-        Ok(io.framed(codec))
+        Ok(io.framed(LineCodec::new()))
     }
 }
 
@@ -373,10 +679,268 @@ impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for LineProto {
     type BindTransport = Result<Self::Transport, io::Error>;
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        let codec = LineCodec {
-            decoding_head: true,
-        };
+        Ok(io.framed(LineCodec::new()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Client, Line, LineCodec, LineProto, LineStream, ServerTypeMap};
+    use trailers::trailer;
+
+    use futures::{Future, Sink, Stream};
+    use tokio_io::codec::{Encoder, Decoder};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_proto::streaming::{Body, Message};
+    use tokio_proto::streaming::pipeline::Frame;
+    use tokio_service::Service;
+
+    use bytes::BytesMut;
+
+    use std::{io, thread};
+
+    type LineFrame = Frame<String, String, io::Error>;
+
+    fn decode(codec: &mut LineCodec, buf: &mut BytesMut) -> Option<LineFrame> {
+        codec.decode(buf).unwrap()
+    }
+
+    fn is_error(frame: Option<LineFrame>) -> bool {
+        match frame {
+            Some(Frame::Error { .. }) => true,
+            _ => false,
+        }
+    }
+
+    fn message(frame: Option<LineFrame>) -> Option<(String, bool)> {
+        match frame {
+            Some(Frame::Message { message, body }) => Some((message, body)),
+            _ => None,
+        }
+    }
+
+    fn chunk(frame: Option<LineFrame>) -> Option<Option<String>> {
+        match frame {
+            Some(Frame::Body { chunk }) => Some(chunk),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_head_fails_only_its_message() {
+        let mut codec = LineCodec::new();
+        let mut buf = BytesMut::from(&b"\xff\nhello\n"[..]);
+
+        assert!(is_error(decode(&mut codec, &mut buf)));
+        assert_eq!(message(decode(&mut codec, &mut buf)), Some(("hello".to_string(), false)));
+    }
+
+    #[test]
+    fn invalid_utf8_chunk_fails_the_body() {
+        let mut codec = LineCodec::new();
+        let mut buf = BytesMut::from(&b"\none\n\xff\n\xfe\ntwo\n\nhello\n"[..]);
+
+        assert_eq!(message(decode(&mut codec, &mut buf)), Some(("".to_string(), true)));
+        assert_eq!(chunk(decode(&mut codec, &mut buf)), Some(Some("one".to_string())));
+
+        // The body is failed once, and the rest of it, including its end, is
+        // skipped
+        assert!(is_error(decode(&mut codec, &mut buf)));
+        assert_eq!(message(decode(&mut codec, &mut buf)), Some(("hello".to_string(), false)));
+        assert!(decode(&mut codec, &mut buf).is_none());
+    }
+
+    #[test]
+    fn oneshot_lines_looking_like_stream_heads_round_trip() {
+        let mut codec = LineCodec::new();
+        let mut buf = BytesMut::new();
+
+        for line in &["STREAM length=5", "\\STREAM length=5", "\\hello"] {
+            let frame = Frame::Message { message: line.to_string(), body: false };
+            codec.encode(frame, &mut buf).unwrap();
+        }
+
+        assert_eq!(&buf[..], &b"\\STREAM length=5\n\\\\STREAM length=5\n\\hello\n"[..]);
+
+        assert_eq!(message(decode(&mut codec, &mut buf)), Some(("STREAM length=5".to_string(), false)));
+        assert_eq!(message(decode(&mut codec, &mut buf)), Some(("\\STREAM length=5".to_string(), false)));
+        assert_eq!(message(decode(&mut codec, &mut buf)), Some(("\\hello".to_string(), false)));
+        assert!(codec.is_decoding_head());
+    }
+
+    #[test]
+    fn trailers_are_only_decoded_with_trailers_framing() {
+        let chunk = trailer("count", "3");
+
+        for &trailers in &[false, true] {
+            let (tx, body) = Body::pair();
+            tx.send(Ok(chunk.clone())).wait().unwrap();
+
+            let mut stream = match Line::from_message(Message::WithBody("".to_string(), body), trailers).unwrap() {
+                Line::Stream(stream) => stream,
+                Line::Once(_) => panic!("expected a streaming body"),
+            };
+
+            let chunks: Vec<String> = stream.by_ref().collect().wait().unwrap();
+
+            if trailers {
+                assert!(chunks.is_empty());
+                assert_eq!(stream.trailers(), Some(&[("count".to_string(), "3".to_string())][..]));
+            } else {
+                assert_eq!(chunks, vec![chunk.clone()]);
+                assert_eq!(stream.trailers(), Some(&[][..]));
+            }
+        }
+    }
+
+    #[test]
+    fn decoding_head_follows_empty_lines() {
+        let mut codec = LineCodec::new();
+        let mut buf = BytesMut::from(&b"\none\n\n\n"[..]);
+
+        assert!(codec.is_decoding_head());
+
+        // An empty head starts a body
+        assert_eq!(message(decode(&mut codec, &mut buf)), Some(("".to_string(), true)));
+        assert!(!codec.is_decoding_head());
+
+        assert_eq!(chunk(decode(&mut codec, &mut buf)), Some(Some("one".to_string())));
+        assert!(!codec.is_decoding_head());
+
+        // An empty chunk ends it
+        assert_eq!(chunk(decode(&mut codec, &mut buf)), Some(None));
+        assert!(codec.is_decoding_head());
+
+        // Start another body, then reset back to the head state
+        assert_eq!(message(decode(&mut codec, &mut buf)), Some(("".to_string(), true)));
+        assert!(!codec.is_decoding_head());
+
+        codec.reset();
+        assert!(codec.is_decoding_head());
+    }
+
+    /// Sums the numeric chunks of the request body as they are received
+    struct Sum;
+
+    impl Service for Sum {
+        type Request = Line;
+        type Response = Line;
+        type Error = io::Error;
+        type Future = Box<Future<Item = Line, Error = io::Error>>;
+
+        fn call(&self, req: Line) -> Self::Future {
+            let total = req.fold(0, |total, chunk| {
+                chunk.parse::<u64>()
+                    .map(|n| total + n)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            });
+
+            Box::new(total.map(|total| Line::Once(total.to_string())))
+        }
+    }
+
+    #[test]
+    fn server_folds_over_streamed_request_body() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            LineProto.bind_server(&handle2, socket, ServerTypeMap { inner: Sum, trailers: false });
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let (mut tx, body) = LineStream::pair();
+
+        thread::spawn(move || {
+            for n in 1..5 {
+                tx = tx.send(Ok(n.to_string())).wait().unwrap();
+            }
+        });
+
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+        let resp = core.run(client.call(Line::Stream(body))).unwrap();
+
+        match resp {
+            Line::Once(total) => assert_eq!(total, "10"),
+            Line::Stream(_) => panic!("expected a oneshot response"),
+        }
+
+        // A oneshot request is folded as a single chunk
+        let total = Sum.call(Line::Once("7".to_string())).wait().unwrap();
+
+        match total {
+            Line::Once(total) => assert_eq!(total, "7"),
+            Line::Stream(_) => panic!("expected a oneshot response"),
+        }
+    }
+
+    #[test]
+    fn malformed_stream_head_is_a_protocol_error() {
+        let (_tx, body) = Body::pair();
+        let err = Line::try_from_message(Message::WithBody("garbage".to_string(), body)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // The server fails the request instead of panicking
+        let server = ServerTypeMap { inner: Sum, trailers: false };
+        let (_tx, body) = Body::pair();
+        let err = server.call(Message::WithBody("STREAM length=ten".to_string(), body)).wait().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // On the wire, a head with an invalid length is a "oneshot" line
+        let mut codec = LineCodec::new();
+        let mut buf = BytesMut::from(&b"STREAM length=ten\n"[..]);
+        assert_eq!(message(decode(&mut codec, &mut buf)), Some(("STREAM length=ten".to_string(), false)));
+        assert!(codec.is_decoding_head());
+    }
+
+    /// Decode a body declaring 100 bytes, followed by `len` bytes of chunks
+    fn decode_declared_body(len: usize) -> Vec<LineFrame> {
+        let mut codec = LineCodec::new();
+        let mut buf = BytesMut::from(&b"STREAM length=100\n"[..]);
+
+        for _ in 0..len / 10 {
+            buf.extend_from_slice(b"0123456789\n");
+        }
+
+        buf.extend_from_slice(b"\n");
+
+        let mut frames = vec![];
+
+        while let Some(frame) = decode(&mut codec, &mut buf) {
+            frames.push(frame);
+        }
+
+        frames
+    }
+
+    #[test]
+    fn body_shorter_than_its_declared_length_is_rejected() {
+        let mut frames = decode_declared_body(90);
+
+        assert_eq!(frames.len(), 11);
+        assert!(is_error(frames.pop()));
+    }
+
+    #[test]
+    fn body_of_its_declared_length_is_accepted() {
+        let mut frames = decode_declared_body(100);
+
+        assert_eq!(frames.len(), 12);
+        assert_eq!(chunk(frames.pop()), Some(None));
+
+        for frame in frames.drain(1..) {
+            assert_eq!(chunk(Some(frame)), Some(Some("0123456789".to_string())));
+        }
 
-        Ok(io.framed(codec))
+        assert_eq!(message(frames.pop()), Some(("STREAM length=100".to_string(), true)));
     }
 }
@@ -0,0 +1,170 @@
+//! Pausing a streaming body from the consumer side.
+//!
+//! A consumer that stops polling a `LineStream` eventually applies
+//! backpressure to its producer, once the channel and socket buffers fill up.
+//! `LineStream::pause` is more explicit: the sending half of a body created
+//! with `LineStream::pausable_pair` stops accepting chunks as soon as the body
+//! is paused, until `LineStream::resume` is called.
+//!
+//! Chunks accepted before the body was paused are still delivered. Pausing
+//! has no effect on bodies received from the peer.
+
+use LineStream;
+
+use futures::{Sink, Poll, Async, AsyncSink, StartSend};
+use futures::sync::mpsc;
+use futures::task::{self, Task};
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// State shared by a `LineStream` and its `PausableSender`
+#[derive(Debug)]
+pub struct Control {
+    paused: AtomicBool,
+    // The producer waiting for the body to be resumed
+    producer: Mutex<Option<Task>>,
+}
+
+/// Sending half of a `LineStream` created with `LineStream::pausable_pair`
+#[derive(Debug)]
+pub struct PausableSender {
+    inner: mpsc::Sender<Result<String, io::Error>>,
+    control: Arc<Control>,
+}
+
+impl LineStream {
+    /// Returns a `LineStream` that can be paused, with its sender half.
+    pub fn pausable_pair() -> (PausableSender, LineStream) {
+        let (tx, mut body) = LineStream::pair();
+
+        let control = Arc::new(Control {
+            paused: AtomicBool::new(false),
+            producer: Mutex::new(None),
+        });
+
+        body.control = Some(control.clone());
+
+        let tx = PausableSender {
+            inner: tx,
+            control: control,
+        };
+
+        (tx, body)
+    }
+
+    /// Stop the producer from sending chunks until `resume` is called.
+    pub fn pause(&self) {
+        if let Some(ref control) = self.control {
+            control.paused.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Let the producer send chunks again after `pause`.
+    pub fn resume(&self) {
+        if let Some(ref control) = self.control {
+            control.paused.store(false, Ordering::SeqCst);
+
+            if let Some(task) = control.producer.lock().unwrap().take() {
+                task.notify();
+            }
+        }
+    }
+
+    /// Returns true if the body is paused
+    pub fn is_paused(&self) -> bool {
+        self.control.as_ref()
+            .map(|control| control.paused.load(Ordering::SeqCst))
+            .unwrap_or(false)
+    }
+}
+
+impl PausableSender {
+    /// Returns false if the body is paused, registering the current task to
+    /// be notified once it is resumed.
+    fn poll_resumed(&self) -> bool {
+        if !self.control.paused.load(Ordering::SeqCst) {
+            return true;
+        }
+
+        *self.control.producer.lock().unwrap() = Some(task::current());
+
+        // The body may have been resumed before the task was registered
+        !self.control.paused.load(Ordering::SeqCst)
+    }
+}
+
+impl Sink for PausableSender {
+    type SinkItem = Result<String, io::Error>;
+    type SinkError = mpsc::SendError<Result<String, io::Error>>;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if !self.poll_resumed() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+
+        self.inner.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        if !self.poll_resumed() {
+            return Ok(Async::NotReady);
+        }
+
+        self.inner.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        self.inner.close()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use LineStream;
+
+    use futures::{Future, Sink, Stream};
+
+    use std::thread;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn paused_body_stops_the_producer_until_resumed() {
+        let (mut tx, body) = LineStream::pausable_pair();
+        let sent = Arc::new(AtomicUsize::new(0));
+
+        let sent2 = sent.clone();
+        let producer = thread::spawn(move || {
+            for i in 0..10 {
+                tx = tx.send(Ok(i.to_string())).wait().unwrap();
+                sent2.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        let (first, body) = body.into_future().wait().map_err(|(e, _)| e).unwrap();
+        assert_eq!(first, Some("0".to_string()));
+
+        body.pause();
+        assert!(body.is_paused());
+
+        // Let the chunks accepted before the pause settle
+        thread::sleep(Duration::from_millis(200));
+        let before = sent.load(Ordering::SeqCst);
+
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(sent.load(Ordering::SeqCst), before);
+        assert!(before < 10);
+
+        body.resume();
+        assert!(!body.is_paused());
+
+        let rest: Vec<String> = body.collect().wait().unwrap();
+        producer.join().unwrap();
+
+        let expected: Vec<String> = (1..10).map(|i| i.to_string()).collect();
+        assert_eq!(rest, expected);
+    }
+}
@@ -0,0 +1,135 @@
+//! Normalizing the size of streaming body chunks.
+//!
+//! Servers are free to produce chunks of any size. `RechunkStream` buffers the
+//! chunks of a `LineStream` and re-emits them in pieces of a fixed number of
+//! bytes, splitting large chunks and coalescing small ones. Only the last
+//! chunk of the stream may be shorter.
+//!
+//! Chunks are only ever split on UTF-8 character boundaries. When the target
+//! size falls within a multi-byte character, the chunk is cut short before
+//! that character, unless the character alone is larger than the target size,
+//! in which case it is emitted on its own.
+
+use LineStream;
+
+use futures::{Stream, Poll, Async};
+
+use std::{io, mem};
+
+/// Stream adapter re-emitting the chunks of a `LineStream` in fixed-size
+/// pieces.
+///
+/// Created by `LineStream::rechunk`.
+#[derive(Debug)]
+pub struct RechunkStream {
+    upstream: LineStream,
+    size: usize,
+    buf: String,
+    // Set once the upstream stream is done
+    eof: bool,
+}
+
+impl LineStream {
+    /// Re-emit the chunks of this stream in pieces of `size` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn rechunk(self, size: usize) -> RechunkStream {
+        assert!(size > 0, "chunk size must be at least 1");
+
+        RechunkStream {
+            upstream: self,
+            size: size,
+            buf: String::new(),
+            eof: false,
+        }
+    }
+}
+
+impl RechunkStream {
+    /// Split the next chunk off the buffer
+    fn split_chunk(&mut self) -> String {
+        let mut at = self.size;
+
+        if at >= self.buf.len() {
+            return mem::replace(&mut self.buf, String::new());
+        }
+
+        // Back off to the start of the character containing `at`
+        while !self.buf.is_char_boundary(at) {
+            at -= 1;
+        }
+
+        // A single character larger than `size`, emit it whole
+        if at == 0 {
+            at = self.size;
+
+            while !self.buf.is_char_boundary(at) {
+                at += 1;
+            }
+        }
+
+        let rest = self.buf.split_off(at);
+        mem::replace(&mut self.buf, rest)
+    }
+}
+
+impl Stream for RechunkStream {
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+        // Buffer chunks until there is enough for a full one
+        while !self.eof && self.buf.len() < self.size {
+            match try_ready!(self.upstream.poll()) {
+                Some(chunk) => self.buf.push_str(&chunk),
+                None => self.eof = true,
+            }
+        }
+
+        if self.buf.is_empty() {
+            return Ok(Async::Ready(None));
+        }
+
+        Ok(Async::Ready(Some(self.split_chunk())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use LineStream;
+
+    use futures::{Future, Stream};
+    use futures::sync::mpsc;
+    use tokio_proto::streaming::Body;
+
+    /// Returns a stream of `chunks`
+    fn stream_of(chunks: &[&str]) -> LineStream {
+        let (mut tx, rx) = mpsc::channel(chunks.len());
+
+        for chunk in chunks {
+            tx.try_send(Ok(chunk.to_string())).unwrap();
+        }
+
+        LineStream::new(Body::from(rx))
+    }
+
+    #[test]
+    fn irregular_chunks_are_reemitted_in_fixed_sizes() {
+        let upstream = stream_of(&["a", "bcdefghijk", "lmn"]);
+        let chunks: Vec<String> = upstream.rechunk(4).collect().wait().unwrap();
+
+        assert_eq!(chunks, ["abcd", "efgh", "ijkl", "mn"]);
+        assert!(chunks[..chunks.len() - 1].iter().all(|chunk| chunk.len() == 4));
+    }
+
+    #[test]
+    fn chunks_are_split_on_character_boundaries() {
+        // 'é' takes two bytes, and '€' three
+        let upstream = stream_of(&["aé", "€b"]);
+        let chunks: Vec<String> = upstream.rechunk(2).collect().wait().unwrap();
+
+        assert_eq!(chunks, ["a", "é", "€", "b"]);
+    }
+}
@@ -0,0 +1,209 @@
+//! Streamed downloads that can be resumed part way through.
+//!
+//! A client starts a download by sending a `DOWNLOAD` request to a server
+//! running the `Resumable` middleware, and receives the content as a streaming
+//! response. If the connection drops, the client can pick up where it left off
+//! by sending `RESUME <offset>`.
+//!
+//! The offset is a chunk index, not a byte offset: `RESUME 3` skips the first
+//! three chunks and streams the rest. A client that counts the chunks it has
+//! received can therefore pass that count straight back. An offset past the
+//! end of the content results in an empty stream.
+
+use {Client, Line, LineStream};
+
+use futures::{future, Future};
+use futures::sync::mpsc;
+use tokio_proto::streaming::Body;
+use tokio_service::{Service, NewService};
+
+use std::io;
+use std::sync::Arc;
+
+/// The request used to download the content from the start
+pub const DOWNLOAD: &'static str = "DOWNLOAD";
+
+const RESUME: &'static str = "RESUME ";
+
+/// A `Service` middleware answering `DOWNLOAD` and `RESUME <offset>` requests
+/// with a stream of the chunks returned by `content`.
+///
+/// All other requests are passed to the inner service.
+pub struct Resumable<T, F> {
+    inner: T,
+    content: Arc<F>,
+}
+
+impl<T, F> Resumable<T, F>
+    where F: Fn() -> io::Result<Vec<String>>,
+{
+    /// Create a new `Resumable`.
+    ///
+    /// `content` is called for every download, so that each request sees the
+    /// current content, for example by reading the lines of a file.
+    pub fn new(inner: T, content: F) -> Resumable<T, F> {
+        Resumable {
+            inner: inner,
+            content: Arc::new(content),
+        }
+    }
+
+    /// Returns a stream of the content, starting at chunk `offset`
+    fn stream_from(&self, offset: usize) -> io::Result<LineStream> {
+        let chunks: Vec<String> = try!((self.content)()).into_iter().skip(offset).collect();
+
+        // The channel has room for every chunk, so sending never fails
+        let (mut tx, rx) = mpsc::channel(chunks.len());
+
+        for chunk in chunks {
+            tx.try_send(Ok(chunk)).unwrap();
+        }
+
+        Ok(LineStream::new(Body::from(rx)))
+    }
+}
+
+/// Returns the offset of a `RESUME <offset>` request
+fn parse_resume(line: &str) -> Option<io::Result<usize>> {
+    if !line.starts_with(RESUME) {
+        return None;
+    }
+
+    Some(line[RESUME.len()..].parse().map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, "invalid resume offset")
+    }))
+}
+
+impl<T, F> Service for Resumable<T, F>
+    where T: Service<Request = Line, Response = Line, Error = io::Error>,
+          T::Future: 'static,
+          F: Fn() -> io::Result<Vec<String>>,
+{
+    type Request = Line;
+    type Response = Line;
+    type Error = io::Error;
+    type Future = Box<Future<Item = Line, Error = io::Error>>;
+
+    fn call(&self, req: Line) -> Self::Future {
+        let offset = match req {
+            Line::Once(ref line) if line == DOWNLOAD => Some(Ok(0)),
+            Line::Once(ref line) => parse_resume(line),
+            _ => None,
+        };
+
+        match offset {
+            Some(offset) => {
+                let body = offset.and_then(|offset| self.stream_from(offset));
+                Box::new(future::done(body.map(Line::Stream)))
+            }
+            None => Box::new(self.inner.call(req)),
+        }
+    }
+}
+
+impl<T, F> NewService for Resumable<T, F>
+    where T: NewService<Request = Line, Response = Line, Error = io::Error>,
+          <T::Instance as Service>::Future: 'static,
+          F: Fn() -> io::Result<Vec<String>>,
+{
+    type Request = Line;
+    type Response = Line;
+    type Error = io::Error;
+    type Instance = Resumable<T::Instance, F>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = try!(self.inner.new_service());
+
+        Ok(Resumable {
+            inner: inner,
+            content: self.content.clone(),
+        })
+    }
+}
+
+impl Client {
+    /// Resume a download from a server running the `Resumable` middleware,
+    /// skipping the first `offset` chunks.
+    pub fn resume(&self, offset: usize) -> Box<Future<Item = Line, Error = io::Error>> {
+        self.call(Line::Once(format!("{}{}", RESUME, offset)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Resumable, DOWNLOAD};
+
+    use {Client, Line, LineProto, ServerTypeMap};
+
+    use futures::{future, Future, Stream};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+
+    use std::{env, fs, io, process};
+    use std::io::{BufRead, BufReader};
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = Line;
+        type Response = Line;
+        type Error = io::Error;
+        type Future = future::FutureResult<Line, io::Error>;
+
+        fn call(&self, req: Line) -> Self::Future {
+            future::ok(req)
+        }
+    }
+
+    fn chunks(core: &mut Core, resp: Line) -> Vec<String> {
+        match resp {
+            Line::Stream(body) => core.run(body.collect()).unwrap(),
+            Line::Once(line) => panic!("expected a stream, got {:?}", line),
+        }
+    }
+
+    #[test]
+    fn resuming_from_offset_yields_the_remaining_chunks() {
+        let path = env::temp_dir().join(format!("tokio-line-resume-{}", process::id()));
+        fs::write(&path, "zero\none\ntwo\nthree\nfour\n").unwrap();
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+        let path2 = path.clone();
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            let path = path2.clone();
+
+            // The content is the lines of the file
+            let service = Resumable::new(Echo, move || {
+                BufReader::new(try!(fs::File::open(&path))).lines().collect()
+            });
+
+            LineProto.bind_server(&handle2, socket, ServerTypeMap { inner: service, trailers: false });
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        let resp = core.run(client.call(Line::Once(DOWNLOAD.to_string()))).unwrap();
+        assert_eq!(chunks(&mut core, resp), ["zero", "one", "two", "three", "four"]);
+
+        let resp = core.run(client.resume(3)).unwrap();
+        assert_eq!(chunks(&mut core, resp), ["three", "four"]);
+
+        // Past the end, the stream is empty
+        let resp = core.run(client.resume(10)).unwrap();
+        assert!(chunks(&mut core, resp).is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
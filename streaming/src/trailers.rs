@@ -0,0 +1,357 @@
+//! Trailers sent after a streamed body.
+//!
+//! Trailers are `key: value` metadata, such as a checksum or a row count, that
+//! is only known once the body has been produced. Connections established with
+//! `serve_with_trailers` and `Client::connect_with_trailers` follow the
+//! body-terminating empty line of every streamed message with a trailer block:
+//! zero or more `key: value` lines, terminated by another empty line.
+//!
+//! ```text
+//!                  <- message head, starting a streaming body
+//! one              <- body chunks
+//! two
+//! three
+//!                  <- end of the body
+//! count: 3         <- trailers
+//!                  <- end of the trailer block
+//! ```
+//!
+//! To send a trailer, push the line returned by `trailer` on the sender half
+//! of a `LineStream` after the last chunk of the body. On the receiving side,
+//! trailers are not yielded as chunks, but are available from
+//! `LineStream::trailers` once the stream is complete. Connections established
+//! otherwise do not decode trailers, and yield every chunk as is.
+//!
+//! Internally, trailers are passed to and from tokio-proto as chunks starting
+//! with a NUL character, so body chunks must not start with one.
+
+use {Client, ClientTypeMap, Line, LineCodec, ServerTypeMap};
+
+use futures::Future;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::{Encoder, Decoder, Framed};
+use tokio_core::reactor::Handle;
+use tokio_proto::{TcpClient, TcpServer};
+use tokio_proto::streaming::pipeline::{Frame, ServerProto, ClientProto};
+use tokio_service::NewService;
+
+use bytes::{BytesMut, BufMut};
+
+use std::io;
+use std::net::SocketAddr;
+
+/// Marks the chunks carrying trailers
+const MARKER: &'static str = "\0trailer\0";
+
+/// Codec for the streaming protocol followed by trailer blocks.
+pub struct TrailerCodec {
+    inner: LineCodec,
+    // Set while decoding a trailer block
+    decoding_trailers: bool,
+    // Set once a line of the trailer block being decoded was invalid
+    trailers_failed: bool,
+    // Trailers to send once the body being encoded is complete
+    pending: Vec<String>,
+}
+
+/// Protocol definition
+struct TrailerProto;
+
+/// Returns the chunk to send on a `LineStream` sender to add a trailer.
+///
+/// # Panics
+///
+/// Panics if `key` contains `:`, or if either contains a new line.
+pub fn trailer(key: &str, value: &str) -> String {
+    assert!(!key.contains(':') && !key.contains('\n'), "invalid trailer key");
+    assert!(!value.contains('\n'), "invalid trailer value");
+
+    format!("{}{}: {}", MARKER, key, value)
+}
+
+/// Returns the key and value of a trailer chunk
+pub fn decode(chunk: &str) -> Option<(String, String)> {
+    if !chunk.starts_with(MARKER) {
+        return None;
+    }
+
+    split(&chunk[MARKER.len()..])
+}
+
+/// Split a `key: value` line
+fn split(line: &str) -> Option<(String, String)> {
+    line.find(": ").map(|i| (line[..i].to_string(), line[i + 2..].to_string()))
+}
+
+/// Start a server exchanging trailers, listening for connections on `addr`.
+pub fn serve_with_trailers<T>(addr: SocketAddr, new_service: T)
+    where T: NewService<Request = Line, Response = Line, Error = io::Error> + Send + Sync + 'static,
+{
+    let new_service = ServerTypeMap { inner: new_service, trailers: true };
+
+    TcpServer::new(TrailerProto, addr)
+        .serve(new_service);
+}
+
+impl Client {
+    /// Establish a connection to a server started with `serve_with_trailers`.
+    pub fn connect_with_trailers(addr: &SocketAddr, handle: &Handle) -> Box<Future<Item = Client, Error = io::Error>> {
+        let ret = TcpClient::new(TrailerProto)
+            .connect(addr, handle)
+            .map(|client_proxy| {
+                let type_map = ClientTypeMap { inner: client_proxy, trailers: true };
+                Client { inner: type_map }
+            });
+
+        Box::new(ret)
+    }
+}
+
+impl TrailerCodec {
+    /// Create a new `TrailerCodec`
+    pub fn new() -> TrailerCodec {
+        TrailerCodec {
+            inner: LineCodec::new(),
+            decoding_trailers: false,
+            trailers_failed: false,
+            pending: vec![],
+        }
+    }
+}
+
+impl Decoder for TrailerCodec {
+    type Item = Frame<String, String, io::Error>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
+        loop {
+            let frame = match try!(self.inner.decode(buf)) {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+
+            if !self.decoding_trailers {
+                match frame {
+                    // The end of the body, the trailer block follows
+                    Frame::Body { chunk: None } => {
+                        self.decoding_trailers = true;
+                        continue;
+                    }
+                    frame => return Ok(Some(frame)),
+                }
+            }
+
+            // The inner codec is back to decoding message heads, so every line
+            // of the trailer block is decoded as a message.
+            let line = match frame {
+                Frame::Message { message, .. } => message,
+                Frame::Error { error } => {
+                    // The line is not valid UTF-8. Fail the body, which is
+                    // still open, and skip the rest of the trailer block.
+                    if self.trailers_failed {
+                        continue;
+                    }
+
+                    self.trailers_failed = true;
+                    return Ok(Some(Frame::Error { error: error }));
+                }
+                Frame::Body { .. } => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed trailer"));
+                }
+            };
+
+            if line.is_empty() {
+                // The end of the trailer block, which the inner codec took for
+                // the start of a streaming body.
+                self.inner.reset();
+                self.decoding_trailers = false;
+
+                if self.trailers_failed {
+                    // The body was already failed
+                    self.trailers_failed = false;
+                    continue;
+                }
+
+                return Ok(Some(Frame::Body { chunk: None }));
+            }
+
+            if self.trailers_failed {
+                continue;
+            }
+
+            if split(&line).is_none() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed trailer"));
+            }
+
+            return Ok(Some(Frame::Body { chunk: Some(format!("{}{}", MARKER, line)) }));
+        }
+    }
+}
+
+impl Encoder for TrailerCodec {
+    type Item = Frame<String, String, io::Error>;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> io::Result<()> {
+        match msg {
+            Frame::Body { chunk: Some(chunk) } => {
+                if chunk.starts_with(MARKER) {
+                    self.pending.push(chunk[MARKER.len()..].to_string());
+                    return Ok(());
+                }
+
+                if !self.pending.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "body chunk sent after a trailer"));
+                }
+
+                self.inner.encode(Frame::Body { chunk: Some(chunk) }, buf)
+            }
+            Frame::Body { chunk: None } => {
+                // Terminate the body, then write the trailer block
+                try!(self.inner.encode(Frame::Body { chunk: None }, buf));
+
+                for line in self.pending.drain(..) {
+                    buf.reserve(line.len() + 1);
+                    buf.extend(line.as_bytes());
+                    buf.put_u8(b'\n');
+                }
+
+                buf.reserve(1);
+                buf.put_u8(b'\n');
+
+                Ok(())
+            }
+            msg => self.inner.encode(msg, buf),
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for TrailerProto {
+    type Request = String;
+    type RequestBody = String;
+    type Response = String;
+    type ResponseBody = String;
+    type Error = io::Error;
+
+    type Transport = Framed<T, TrailerCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(TrailerCodec::new()))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for TrailerProto {
+    type Request = String;
+    type RequestBody = String;
+    type Response = String;
+    type ResponseBody = String;
+    type Error = io::Error;
+
+    type Transport = Framed<T, TrailerCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(TrailerCodec::new()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{trailer, TrailerCodec, TrailerProto};
+    use {Client, Line, LineStream, ServerTypeMap};
+
+    use futures::{future, Future, Sink, Stream};
+    use tokio_io::codec::Decoder;
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_proto::streaming::pipeline::Frame;
+    use tokio_service::Service;
+
+    use bytes::BytesMut;
+
+    use std::{io, thread};
+
+    #[test]
+    fn invalid_utf8_trailer_fails_the_body() {
+        let mut codec = TrailerCodec::new();
+        let mut buf = BytesMut::from(&b"\none\n\n\xff\ncount: 3\n\nhello\n"[..]);
+
+        let mut frames = vec![];
+
+        while let Some(frame) = codec.decode(&mut buf).unwrap() {
+            frames.push(frame);
+        }
+
+        assert_eq!(frames.len(), 4);
+
+        match frames[2] {
+            Frame::Error { .. } => {}
+            _ => panic!("expected the body to be failed"),
+        }
+
+        match frames[3] {
+            Frame::Message { ref message, body: false } => assert_eq!(message, "hello"),
+            _ => panic!("expected the next message"),
+        }
+    }
+
+    /// Streams three rows, followed by their count as a trailer
+    struct Rows;
+
+    impl Service for Rows {
+        type Request = Line;
+        type Response = Line;
+        type Error = io::Error;
+        type Future = future::FutureResult<Line, io::Error>;
+
+        fn call(&self, _: Line) -> Self::Future {
+            let (mut tx, body) = LineStream::pair();
+
+            thread::spawn(move || {
+                let chunks = vec!["one".to_string(), "two".to_string(), "three".to_string(), trailer("count", "3")];
+
+                for chunk in chunks {
+                    tx = tx.send(Ok(chunk)).wait().unwrap();
+                }
+            });
+
+            future::ok(Line::Stream(body))
+        }
+    }
+
+    #[test]
+    fn client_reads_trailer_after_the_body() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle2 = handle.clone();
+
+        let server = listener.incoming().take(1).for_each(move |(socket, _)| {
+            TrailerProto.bind_server(&handle2, socket, ServerTypeMap { inner: Rows, trailers: true });
+            Ok(())
+        });
+
+        handle.spawn(server.map_err(|_| ()));
+
+        // Keep the client alive until the body is consumed
+        let client = core.run(Client::connect_with_trailers(&addr, &handle)).unwrap();
+        let resp = core.run(client.call(Line::Once("rows".to_string()))).unwrap();
+
+        let mut body = match resp {
+            Line::Stream(body) => body,
+            Line::Once(_) => panic!("expected a streaming response"),
+        };
+
+        assert_eq!(body.trailers(), None);
+
+        let chunks = core.run(body.by_ref().collect()).unwrap();
+
+        assert_eq!(chunks, vec!["one", "two", "three"]);
+        assert_eq!(body.trailers(), Some(&[("count".to_string(), "3".to_string())][..]));
+    }
+}
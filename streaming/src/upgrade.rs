@@ -0,0 +1,397 @@
+//! Upgrading a connection from simple to streaming framing.
+//!
+//! A connection established with `Client::connect_upgradable` starts out
+//! using the simple line framing, where every line is a complete message. In
+//! particular, an empty line is just an empty message rather than the start of
+//! a streaming body. Once both peers agree, the connection switches to the
+//! streaming framing used by the rest of this crate, so that large transfers
+//! can be streamed.
+//!
+//! # Handshake
+//!
+//! 1. The client sends an `UPGRADE stream` request.
+//! 2. The server responds with `UPGRADED`.
+//!
+//! Each direction of the connection switches framing independently, right
+//! after the handshake line travelling in that direction:
+//!
+//! * client to server: every line after `UPGRADE stream` uses streaming
+//!   framing.
+//! * server to client: every line after `UPGRADED` uses streaming framing.
+//!
+//! As a consequence, the client must not send any other request until the
+//! upgrade has completed. `Client::upgrade` takes care of this as long as the
+//! caller waits on the returned future.
+
+use {Client, ClientTypeMap, Line, LineCodec, ServerTypeMap};
+
+use futures::{future, Future};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::{Encoder, Decoder, Framed};
+use tokio_core::reactor::Handle;
+use tokio_proto::{TcpClient, TcpServer};
+use tokio_proto::streaming::pipeline::{Frame, ServerProto, ClientProto};
+use tokio_service::{Service, NewService};
+
+use bytes::BytesMut;
+
+use std::io;
+use std::net::SocketAddr;
+
+/// The request sent by the client to initiate an upgrade
+pub const UPGRADE: &'static str = "UPGRADE stream";
+
+/// The response sent by the server to acknowledge an upgrade
+pub const UPGRADED: &'static str = "UPGRADED";
+
+/// Codec starting with simple framing and switching to streaming framing once
+/// the upgrade handshake is seen.
+pub struct UpgradeCodec {
+    inner: LineCodec,
+    role: Role,
+    // Whether the respective direction uses streaming framing
+    decode_upgraded: bool,
+    encode_upgraded: bool,
+}
+
+/// A `Service` middleware answering upgrade requests.
+///
+/// All other requests are passed to the inner service.
+pub struct Upgrade<T> {
+    inner: T,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Role {
+    Server,
+    Client,
+}
+
+/// Protocol definition for connections supporting the upgrade
+struct UpgradeProto;
+
+/// Start a server, listening for connections on `addr`, that start with simple
+/// framing and accept upgrades to streaming framing.
+pub fn serve_upgradable<T>(addr: SocketAddr, new_service: T)
+    where T: NewService<Request = Line, Response = Line, Error = io::Error> + Send + Sync + 'static,
+{
+    let new_service = ServerTypeMap { inner: Upgrade { inner: new_service }, trailers: false };
+
+    TcpServer::new(UpgradeProto, addr)
+        .serve(new_service);
+}
+
+impl Client {
+    /// Establish a connection to a server started with `serve_upgradable`.
+    ///
+    /// The connection uses simple framing until `upgrade` is called.
+    pub fn connect_upgradable(addr: &SocketAddr, handle: &Handle) -> Box<Future<Item = Client, Error = io::Error>> {
+        let ret = TcpClient::new(UpgradeProto)
+            .connect(addr, handle)
+            .map(|client_proxy| {
+                let type_map = ClientTypeMap { inner: client_proxy, trailers: false };
+                Client { inner: type_map }
+            });
+
+        Box::new(ret)
+    }
+
+    /// Upgrade the connection to streaming framing.
+    ///
+    /// No other request may be issued until the returned future completes.
+    pub fn upgrade(&self) -> Box<Future<Item = (), Error = io::Error>> {
+        let resp = self.call(Line::Once(UPGRADE.to_string()))
+            .and_then(|resp| {
+                match resp {
+                    Line::Once(ref line) if line == UPGRADED => Ok(()),
+                    _ => Err(io::Error::new(io::ErrorKind::Other, "upgrade rejected")),
+                }
+            });
+
+        Box::new(resp)
+    }
+}
+
+impl UpgradeCodec {
+    /// Create a codec for the server side of the connection
+    pub fn server() -> UpgradeCodec {
+        UpgradeCodec::new(Role::Server)
+    }
+
+    /// Create a codec for the client side of the connection
+    pub fn client() -> UpgradeCodec {
+        UpgradeCodec::new(Role::Client)
+    }
+
+    fn new(role: Role) -> UpgradeCodec {
+        UpgradeCodec {
+            inner: LineCodec::new(),
+            role: role,
+            decode_upgraded: false,
+            encode_upgraded: false,
+        }
+    }
+
+    /// Returns `true` once both directions use streaming framing
+    pub fn is_upgraded(&self) -> bool {
+        self.decode_upgraded && self.encode_upgraded
+    }
+
+    /// The handshake line received by this side
+    fn incoming(&self) -> &'static str {
+        match self.role {
+            Role::Server => UPGRADE,
+            Role::Client => UPGRADED,
+        }
+    }
+
+    /// The handshake line sent by this side
+    fn outgoing(&self) -> &'static str {
+        match self.role {
+            Role::Server => UPGRADED,
+            Role::Client => UPGRADE,
+        }
+    }
+}
+
+impl Decoder for UpgradeCodec {
+    type Item = Frame<String, String, io::Error>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
+        if self.decode_upgraded {
+            return self.inner.decode(buf);
+        }
+
+        // Before the upgrade, every line is a complete message. The streaming
+        // codec is still in the head state, so it only has to be corrected
+        // for empty lines.
+        let frame = try!(self.inner.decode(buf));
+
+        let line = match frame {
+            Some(Frame::Message { message, .. }) => message,
+            // The line is not valid UTF-8, which fails the message
+            Some(Frame::Error { error }) => return Ok(Some(Frame::Error { error: error })),
+            Some(Frame::Body { .. }) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "body chunk before the upgrade"));
+            }
+            None => return Ok(None),
+        };
+
+        self.inner.reset();
+
+        if line == self.incoming() {
+            self.decode_upgraded = true;
+        }
+
+        Ok(Some(Frame::Message {
+            message: line,
+            body: false,
+        }))
+    }
+}
+
+impl Encoder for UpgradeCodec {
+    type Item = Frame<String, String, io::Error>;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> io::Result<()> {
+        if self.encode_upgraded {
+            return self.inner.encode(msg, buf);
+        }
+
+        let line = match msg {
+            Frame::Message { message, body: false } => message,
+            Frame::Message { .. } | Frame::Body { .. } => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "streaming bodies require an upgraded connection"));
+            }
+            Frame::Error { error } => return Err(error),
+        };
+
+        if line == self.outgoing() {
+            self.encode_upgraded = true;
+        }
+
+        self.inner.encode(Frame::Message { message: line, body: false }, buf)
+    }
+}
+
+impl<T> Service for Upgrade<T>
+    where T: Service<Request = Line, Response = Line, Error = io::Error>,
+          T::Future: 'static,
+{
+    type Request = Line;
+    type Response = Line;
+    type Error = io::Error;
+    type Future = Box<Future<Item = Line, Error = io::Error>>;
+
+    fn call(&self, req: Line) -> Self::Future {
+        match req {
+            Line::Once(ref line) if line == UPGRADE => {
+                return Box::new(future::ok(Line::Once(UPGRADED.to_string())));
+            }
+            _ => {}
+        }
+
+        Box::new(self.inner.call(req))
+    }
+}
+
+impl<T> NewService for Upgrade<T>
+    where T: NewService<Request = Line, Response = Line, Error = io::Error>,
+          <T::Instance as Service>::Future: 'static,
+{
+    type Request = Line;
+    type Response = Line;
+    type Error = io::Error;
+    type Instance = Upgrade<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = try!(self.inner.new_service());
+        Ok(Upgrade { inner: inner })
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for UpgradeProto {
+    type Request = String;
+    type RequestBody = String;
+    type Response = String;
+    type ResponseBody = String;
+    type Error = io::Error;
+
+    type Transport = Framed<T, UpgradeCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(UpgradeCodec::client()))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for UpgradeProto {
+    type Request = String;
+    type RequestBody = String;
+    type Response = String;
+    type ResponseBody = String;
+    type Error = io::Error;
+
+    type Transport = Framed<T, UpgradeCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(UpgradeCodec::server()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{UpgradeCodec, UPGRADE, UPGRADED};
+
+    use tokio_io::codec::{Encoder, Decoder};
+    use tokio_proto::streaming::pipeline::Frame;
+
+    use bytes::BytesMut;
+
+    #[test]
+    fn invalid_utf8_before_upgrade_fails_only_its_message() {
+        let mut codec = UpgradeCodec::server();
+        let mut buf = BytesMut::from(&b"\xff\nhello\n"[..]);
+
+        match codec.decode(&mut buf).unwrap() {
+            Some(Frame::Error { .. }) => {}
+            _ => panic!("expected an error frame"),
+        }
+
+        match codec.decode(&mut buf).unwrap() {
+            Some(Frame::Message { ref message, body: false }) => assert_eq!(message, "hello"),
+            _ => panic!("expected the next message"),
+        }
+    }
+
+    #[test]
+    fn stream_head_before_upgrade_does_not_leak_its_length() {
+        let mut codec = UpgradeCodec::server();
+        let mut buf = BytesMut::from(&b"STREAM length=3\n"[..]);
+
+        match codec.decode(&mut buf).unwrap() {
+            Some(Frame::Message { ref message, body: false }) => assert_eq!(message, "STREAM length=3"),
+            _ => panic!("expected a oneshot message"),
+        }
+
+        buf.extend_from_slice(UPGRADE.as_bytes());
+        buf.extend_from_slice(b"\n\nlonger than three\n\n");
+
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+
+        match codec.decode(&mut buf).unwrap() {
+            Some(Frame::Message { ref message, body: true }) => assert_eq!(message, ""),
+            _ => panic!("expected a streaming message"),
+        }
+
+        // The body has no declared length, so it is not failed
+        match codec.decode(&mut buf).unwrap() {
+            Some(Frame::Body { chunk: Some(ref chunk) }) => assert_eq!(chunk, "longer than three"),
+            _ => panic!("expected a body chunk"),
+        }
+
+        match codec.decode(&mut buf).unwrap() {
+            Some(Frame::Body { chunk: None }) => {}
+            _ => panic!("expected the end of the body"),
+        }
+    }
+
+    #[test]
+    fn streamed_body_is_framed_after_upgrade() {
+        let mut codec = UpgradeCodec::server();
+        let body = b"\none\n\n";
+
+        // Before the upgrade, the empty lines are messages of their own
+        let mut buf = BytesMut::from(&body[..]);
+
+        for expect in &["", "one", ""] {
+            match codec.decode(&mut buf).unwrap() {
+                Some(Frame::Message { ref message, body: false }) => assert_eq!(message, expect),
+                _ => panic!("expected a oneshot message"),
+            }
+        }
+
+        let mut buf = BytesMut::from(UPGRADE.as_bytes());
+        buf.extend_from_slice(b"\n");
+        buf.extend_from_slice(&body[..]);
+
+        match codec.decode(&mut buf).unwrap() {
+            Some(Frame::Message { ref message, body: false }) => assert_eq!(message, UPGRADE),
+            _ => panic!("expected the upgrade request"),
+        }
+
+        // After it, the same lines are a streamed body
+        match codec.decode(&mut buf).unwrap() {
+            Some(Frame::Message { ref message, body: true }) => assert_eq!(message, ""),
+            _ => panic!("expected a streaming message"),
+        }
+
+        match codec.decode(&mut buf).unwrap() {
+            Some(Frame::Body { chunk: Some(ref chunk) }) => assert_eq!(chunk, "one"),
+            _ => panic!("expected a body chunk"),
+        }
+
+        match codec.decode(&mut buf).unwrap() {
+            Some(Frame::Body { chunk: None }) => {}
+            _ => panic!("expected the end of the body"),
+        }
+
+        // Streamed responses can only be sent once the upgrade is acknowledged
+        let mut buf = BytesMut::new();
+        let head = || Frame::Message { message: "".to_string(), body: true };
+
+        assert!(codec.encode(head(), &mut buf).is_err());
+
+        codec.encode(Frame::Message { message: UPGRADED.to_string(), body: false }, &mut buf).unwrap();
+        codec.encode(head(), &mut buf).unwrap();
+        codec.encode(Frame::Body { chunk: Some("one".to_string()) }, &mut buf).unwrap();
+        codec.encode(Frame::Body { chunk: None }, &mut buf).unwrap();
+
+        assert!(codec.is_upgraded());
+        assert_eq!(&buf[..], &b"UPGRADED\n\none\n\n"[..]);
+    }
+}